@@ -0,0 +1,65 @@
+//! Benchmark mode, rendering a scene several times without a window and reporting the timings
+
+use crate::{load_scene, scene_name, Args};
+use crusty::raytracer::Workers;
+use std::time::{Duration, Instant};
+
+struct Run {
+    parse: Duration,
+    build: Duration,
+    render: Duration,
+    rays: u64,
+}
+
+pub fn run(args: &Args, runs: u32, workers: Workers) -> Result<(), String> {
+    let mut results = Vec::with_capacity(runs as usize);
+    for _ in 0..runs {
+        let start = Instant::now();
+        let scene = load_scene(args)?;
+        let parse = start.elapsed();
+
+        let start = Instant::now();
+        let raytracer = scene.build()?;
+        let build = start.elapsed();
+
+        let start = Instant::now();
+        raytracer.start_with(workers.clone()).join().map_err(|_| "Render thread panicked".to_string())?;
+        let render = start.elapsed();
+
+        results.push(Run { parse, build, render, rays: raytracer.rays() });
+    }
+
+    println!("{} runs of {} (threads: {})", runs, scene_name(args), workers.threads());
+    println!("{:<8} {:>10} {:>10} {:>10}", "", "min", "median", "stddev");
+    for (name, times) in [
+        ("parse", results.iter().map(|run| run.parse).collect::<Vec<_>>()),
+        ("build", results.iter().map(|run| run.build).collect()),
+        ("render", results.iter().map(|run| run.render).collect()),
+    ] {
+        let (min, median, stddev) = statistics(times.iter().map(Duration::as_secs_f64).collect());
+        println!("{:<8} {:>9.3}s {:>9.3}s {:>9.3}s", name, min, median, stddev);
+    }
+
+    let rays_per_sec = results.iter()
+        .map(|run| run.rays as f64 / run.render.as_secs_f64())
+        .collect();
+    let (min, median, stddev) = statistics(rays_per_sec);
+    println!("{:<8} {:>10.0} {:>10.0} {:>10.0}", "rays/s", min, median, stddev);
+
+    Ok(())
+}
+
+/// Returns the minimum, median and standard deviation of the values
+fn statistics(mut values: Vec<f64>) -> (f64, f64, f64) {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    let median = if n.is_multiple_of(2) { (values[n / 2 - 1] + values[n / 2]) / 2.0 } else { values[n / 2] };
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let variance = if n > 1 {
+        values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+    } else {
+        0.0
+    };
+
+    (values[0], median, variance.sqrt())
+}
@@ -0,0 +1,46 @@
+//! Comparison of the render with a previously saved one in the window, with a wipe line or as
+//! their difference
+
+/// Saved render shown next to the current one
+pub struct Comparison {
+    /// Colors of the saved render as RGBA8888 in native byte order, like the render's snapshots
+    pub pixels: Vec<u8>,
+    /// Position of the wipe line, as a fraction of the render's width, the saved render is shown
+    /// on its left
+    pub wipe: f64,
+    /// Whether the absolute difference of the renders is shown instead
+    pub show_difference: bool,
+}
+
+impl Comparison {
+    /// Loads a saved render, which must have the same resolution as the current one
+    pub fn load(path: &str, width: u32, height: u32) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|err| format!("Failed to load comparison image {}: {}", path, err))?
+            .to_rgba8();
+        if image.dimensions() != (width, height) {
+            return Err(format!(
+                "Comparison image {} is {}x{}, the render is {}x{}",
+                path, image.width(), image.height(), width, height,
+            ));
+        }
+
+        let pixels = image.pixels()
+            .flat_map(|pixel| u32::from_be_bytes(pixel.0).to_ne_bytes())
+            .collect();
+        Ok(Self { pixels, wipe: 0.5, show_difference: false })
+    }
+
+    /// Writes the absolute difference of the render's colors with the saved ones into `difference`,
+    /// opaque
+    pub fn difference(&self, pixels: &[u8], difference: &mut Vec<u8>) {
+        difference.clear();
+        difference.extend(pixels.chunks_exact(4).zip(self.pixels.chunks_exact(4)).flat_map(|(render, saved)| {
+            let render = u32::from_ne_bytes(render.try_into().unwrap());
+            let saved = u32::from_ne_bytes(saved.try_into().unwrap());
+            let channel = |shift: u32| ((render >> shift & 0xff) as i32 - (saved >> shift & 0xff) as i32).unsigned_abs();
+            let [r, g, b] = [24, 16, 8].map(channel);
+            (r << 24 | g << 16 | b << 8 | 0xff).to_ne_bytes()
+        }));
+    }
+}
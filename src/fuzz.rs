@@ -0,0 +1,46 @@
+//! Fuzz mode, rendering random scenes at a low resolution to find the seeds of scenes which panic
+//! or produce NaN, infinite or negative colors
+
+use crate::Args;
+use crusty::log;
+use crusty::raytracer::{random_scene, Workers};
+use std::panic;
+use std::time::Instant;
+
+pub fn run(args: &Args, scenes: u32, workers: Workers) -> Result<(), String> {
+    let first = args.random_scene.unwrap_or(0);
+    let start = Instant::now();
+    let mut failures = Vec::new();
+    for seed in first..first + scenes as u64 {
+        let result = panic::catch_unwind(|| random_scene(seed).output(64, 48).samples(2).build())
+            .map_err(|_| "Scene build panicked".to_string())
+            .and_then(|result| result)
+            .and_then(|raytracer| {
+                raytracer.start_with(workers.clone()).join().map_err(|_| "Render thread panicked".to_string())?;
+                let invalid = raytracer.output().pixels().iter()
+                    .filter(|pixel| pixel.iter().any(|c| !c.is_finite() || *c < 0.0))
+                    .count();
+                if invalid > 0 {
+                    return Err(format!("{} pixels with NaN, infinite or negative colors", invalid));
+                }
+                Ok(())
+            });
+        if let Err(err) = result {
+            log::error!("Random scene {}: {}", seed, err);
+            failures.push(seed);
+        }
+    }
+
+    println!(
+        "{} random scenes rendered in {:.3}s, {} failed",
+        scenes,
+        start.elapsed().as_secs_f64(),
+        failures.len(),
+    );
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        let seeds: Vec<String> = failures.iter().map(u64::to_string).collect();
+        Err(format!("Failing seeds, render them with --random-scene SEED: {}", seeds.join(", ")))
+    }
+}
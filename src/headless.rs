@@ -0,0 +1,131 @@
+//! Headless mode, rendering the scene once without a window for scripts and render farms
+//!
+//! The process exits with a distinct code for each outcome, and can print a JSON summary of the
+//! render to stdout (logs are written to stderr).
+
+use crate::{tile_log, with_overrides, Args};
+use crusty::log;
+use crusty::raytracer::{self, Raytracer, SceneBuilder, Workers};
+use serde_json::json;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Set by the signal handler, the render is then stopped
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Outcomes of headless renders, their values are the process exit codes
+#[derive(Clone, Copy)]
+pub enum Exit {
+    Success = 0,
+    /// Invalid arguments, or the render crashed
+    Failure = 1,
+    /// The scene couldn't be parsed or built
+    SceneError = 2,
+    /// The scene file couldn't be read or the render couldn't be saved
+    IoError = 3,
+    /// The render was interrupted by SIGINT or SIGTERM
+    Cancelled = 4,
+}
+
+/// Renders the scene and saves it, returning the exit code
+pub fn run(args: &Args, workers: Workers) -> Exit {
+    let start = Instant::now();
+    let (exit, error, raytracer) = match render(args, workers) {
+        Ok(raytracer) => {
+            let (exit, error) = if raytracer.is_stopped() {
+                (Exit::Cancelled, Some("Render cancelled".to_string()))
+            } else {
+                match raytracer.file_error() {
+                    Some(err) => (Exit::IoError, Some(err)),
+                    None => (Exit::Success, None),
+                }
+            };
+            (exit, error, Some(raytracer))
+        }
+        Err((exit, err)) => {
+            log::error!("{}", err);
+            (exit, Some(err), None)
+        }
+    };
+
+    if args.summary {
+        let status = match exit {
+            Exit::Success => "completed",
+            Exit::Cancelled => "cancelled",
+            Exit::Failure | Exit::SceneError | Exit::IoError => "failed",
+        };
+        let summary = json!({
+            "status": status,
+            "exit_code": exit as u8,
+            "error": error,
+            "scene": args.scene_path,
+            "time": start.elapsed().as_secs_f64(),
+            "samples": raytracer.as_ref().map(|raytracer| raytracer.output().samples_per_pixel()),
+            "rays": raytracer.as_ref().map(|raytracer| raytracer.rays()),
+            "output": raytracer.as_ref().and_then(|raytracer| raytracer.output_path()),
+        });
+        println!("{}", summary);
+    }
+    exit
+}
+
+fn render(args: &Args, workers: Workers) -> Result<Arc<Raytracer>, (Exit, String)> {
+    let scene = match args.random_scene {
+        Some(seed) => raytracer::random_scene(seed),
+        None => {
+            let scene_file = fs::File::open(&args.scene_path)
+                .map_err(|err| (Exit::IoError, format!("Failed to open scene file: {}", err)))?;
+            SceneBuilder::from_reader(scene_file).map_err(|err| (Exit::SceneError, err))?
+        }
+    };
+    let raytracer = with_overrides(scene, args)
+        .and_then(SceneBuilder::build)
+        .map_err(|err| (Exit::SceneError, err))?;
+
+    raytracer.record_tiles(args.tile_log.is_some());
+    handle_signals();
+    wait(&raytracer, raytracer.start_with(workers)).map_err(|err| (Exit::Failure, err))?;
+
+    if let Some(path) = &args.tile_log {
+        tile_log::write(path, &raytracer).map_err(|err| (Exit::IoError, err))?;
+        log::info!("Tile log saved to {}", path);
+    }
+    Ok(raytracer)
+}
+
+/// Waits for a render to complete, stopping it once the process is signalled
+pub fn wait(raytracer: &Arc<Raytracer>, render_thread: JoinHandle<()>) -> Result<(), String> {
+    while !render_thread.is_finished() {
+        if cancelled() {
+            raytracer.stop();
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    render_thread.join().map_err(|_| "Render thread panicked".to_string())
+}
+
+/// Whether SIGINT or SIGTERM was received since [`handle_signals`] was called
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+/// Stops the render on SIGINT and SIGTERM instead of killing the process, so that the summary is
+/// still printed
+#[cfg(target_os = "linux")]
+pub fn handle_signals() {
+    extern "C" fn handler(_: libc::c_int) {
+        CANCELLED.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn handle_signals() {}
@@ -0,0 +1,108 @@
+//! Comparison of rendered images against references, for regression tests
+
+use crate::raytracer::Output;
+use image::{ImageBuffer, Rgba};
+
+/// Color difference (CIE76) from which two colors can be told apart
+const NOTICEABLE_DELTA_E: f64 = 2.3;
+
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Linear RGBA pixels, row by row
+    pub pixels: Vec<[f32; 4]>,
+}
+
+pub struct ImageDiff {
+    /// Root mean square error over all channels
+    pub rmse: f64,
+    /// Fraction of the pixels whose color difference is noticeable
+    pub noticeable: f64,
+}
+
+/// Maximum differences for images to be considered the same
+pub struct Tolerance {
+    pub rmse: f64,
+    pub noticeable: f64,
+}
+
+impl Image {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|err| format!("Failed to load image {}: {}", path, err))?
+            .into_rgba32f();
+
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().map(|pixel| pixel.0).collect(),
+        })
+    }
+
+    /// Saves the image as a 16 bits PNG
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let pixels = self.pixels.iter()
+            .flatten()
+            .map(|&c| (c.clamp(0.0, 1.0) * 65535.0).round() as u16)
+            .collect();
+        ImageBuffer::<Rgba<u16>, Vec<u16>>::from_raw(self.width, self.height, pixels)
+            .unwrap()
+            .save(path)
+            .map_err(|err| format!("Failed to save image {}: {}", path, err))
+    }
+
+    pub fn from_output(output: &Output) -> Self {
+        Self {
+            width: output.width,
+            height: output.height,
+            pixels: output.colors().iter().map(|color| color.map(|c| c as f32 / 65535.0)).collect(),
+        }
+    }
+}
+
+/// Compares an image against a reference of the same size
+pub fn compare(image: &Image, reference: &Image) -> Result<ImageDiff, String> {
+    if (image.width, image.height) != (reference.width, reference.height) {
+        return Err(format!(
+            "Image is {}x{} but the reference is {}x{}",
+            image.width, image.height, reference.width, reference.height,
+        ));
+    }
+
+    let mut squared_error = 0.0;
+    let mut noticeable = 0;
+    for (a, b) in image.pixels.iter().zip(&reference.pixels) {
+        squared_error += a.iter().zip(b).map(|(a, b)| ((a - b) as f64).powi(2)).sum::<f64>();
+
+        let (lab_a, lab_b) = (lab(a), lab(b));
+        let delta_e = ((lab_a.0 - lab_b.0).powi(2) + (lab_a.1 - lab_b.1).powi(2) + (lab_a.2 - lab_b.2).powi(2)).sqrt();
+        if delta_e > NOTICEABLE_DELTA_E || (a[3] - b[3]).abs() as f64 * 100.0 > NOTICEABLE_DELTA_E {
+            noticeable += 1;
+        }
+    }
+
+    let pixels = image.pixels.len().max(1) as f64;
+    Ok(ImageDiff {
+        rmse: (squared_error / (pixels * 4.0)).sqrt(),
+        noticeable: noticeable as f64 / pixels,
+    })
+}
+
+impl ImageDiff {
+    pub fn within(&self, tolerance: &Tolerance) -> bool {
+        self.rmse <= tolerance.rmse && self.noticeable <= tolerance.noticeable
+    }
+}
+
+/// Converts a linear sRGB color, premultiplied by its alpha, to CIELAB
+fn lab(color: &[f32; 4]) -> (f64, f64, f64) {
+    let [r, g, b] = [color[0], color[1], color[2]].map(|c| c.clamp(0.0, 1.0) as f64 * color[3] as f64);
+    // D65 white point
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.9505;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.089;
+
+    let f = |t: f64| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
@@ -0,0 +1,3 @@
+pub mod image_diff;
+pub mod log;
+pub mod raytracer;
@@ -1,23 +1,525 @@
-mod raytracer;
+mod benchmark;
+mod compare;
+mod fuzz;
+mod headless;
+mod notify;
+mod overlay;
+mod panorama;
+mod settings;
+mod tile_log;
+mod turntable;
+mod watch;
 
-use raytracer::Raytracer;
+use crusty::log::{self, Level};
+use crusty::raytracer::{self, BoundsOverlay, DebugShading, Raytracer, SceneBuilder, Workers};
 use sdl2::event::{Event, WindowEvent};
-use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::{BlendMode, ScaleMode};
 use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+use compare::Comparison;
+use overlay::Histogram;
+use panorama::PanoramaView;
+use settings::{Action, Settings};
+use turntable::Turntable;
+
+const USAGE: &str = "\
+Usage: crusty [OPTIONS] [SCENE]
+
+Renders SCENE (scenes/test.json by default)
+
+Options:
+  --resolution WxH  Override the output resolution
+  --samples N       Override the number of samples per pixel
+  --output PATH     Save the render to PATH (.png, .tiff or .exr)
+  --threads N       Render with N threads (one per logical core by default)
+  --reserve-ui-core Leave a core to the window by default, so that it stays responsive
+  --low-priority    Run the render threads at a lower priority than other programs
+  --pin-cores LIST  Pin the render threads to the comma separated cores, in turn
+  --no-pause        Keep rendering while the window is minimized
+  --no-notify       Don't show a desktop notification when the render completes or fails
+  --time-limit S    Stop starting new tiles after S seconds and save what was rendered
+  --shading MODE    Replace the materials with normal, uv, wireframe or depth shading
+  --bounds MODE     Draw the bounding boxes of the objects, or of their BVH nodes too, over
+                    the render, with objects or bvh
+  --layer NAME      Render only the objects of the scene's render layer NAME
+  --tile-log PATH   Record when each tile is rendered and by which thread, saved as CSV
+                    or as a Chrome trace for .json paths
+  --headless        Render without a window, exiting with 0 on success, 1 on invalid
+                    arguments, 2 if the scene can't be parsed or built, 3 if the scene
+                    can't be read or the render saved, 4 if cancelled by SIGINT or SIGTERM
+  --summary         Print a JSON summary of the headless render to stdout
+  --watch           With --headless, render SCENE again whenever it changes, or each scene
+                    file added to or changed in the directory SCENE, until interrupted, saved
+                    next to the --output path with the render number appended
+  --check           Validate the scene and report all its problems without rendering it,
+                    exiting with 0 if it is valid and 2 otherwise
+  --random-scene SEED
+                    Render a random scene of primitives generated from SEED instead of
+                    SCENE, save it with --export-scene to edit it
+  --fuzz N          Render N random scenes at a low resolution without a window, starting
+                    from the --random-scene seed, and report the seeds of those which panic
+                    or produce NaN, infinite or negative colors (use -q to only log them)
+  --benchmark N     Render N times without a window and report the timings
+  --turntable frames=N[,target=X:Y:Z]
+                    Render N frames of the camera orbiting around the vertical axis
+                    through the target (the origin by default) without a window, saved
+                    next to the --output path with the frame number appended
+  --export-scene PATH
+                    Save the scene as the renderer sees it to PATH, with the defaults and
+                    the command line overrides applied and the generators run, then exit
+  --schema          Print the JSON Schema of scene files, for editors to autocomplete and
+                    validate them
+  --convert-mesh PATH
+                    Convert an OBJ, PLY or STL mesh to a .cmesh cache next to it, which
+                    scenes can use as mesh path to map it instead of parsing it
+  --cache DIR       Cache the meshes of the scene with their BVH in DIR, named after the
+                    hash of their contents and options, so that later renders of the same
+                    meshes map them instead of parsing and building them
+  --compare PATH    Compare the render in the window with a saved one (.png or .jpg) of the
+                    same resolution, drag the wipe line with the middle mouse button and
+                    press D to show their difference
+  --debug-pixel X,Y Record every ray traced for a pixel and save them without rendering
+  --debug-output PATH
+                    Where pixel rays are saved, as JSON or OBJ lines (pixel_paths.json
+                    by default), right click a pixel in the window to save its rays
+  -v, --verbose     Log more details, twice to log every tile
+  -q, --quiet       Only log warnings and errors
+  --log-json        Log JSON objects, one per line
+  -h, --help        Print this help
+
+Window, the keys, zoom limits and background color can be changed in crusty/crusty.toml
+in the config directory ($XDG_CONFIG_HOME or ~/.config):
+  Left drag         Pan the render
+  Left click        Log the object under the cursor (its position in the scene's objects,
+                    name, material and transform) and outline it, on the background to unselect
+  Mouse wheel       Zoom on the cursor
+  F, R              Fit the render to the window
+  1                 Show the render at one pixel per window pixel
+  H                 Toggle the histogram of the render's colors and luminance
+  Z                 Toggle stripes over pixels which clip above 1 or are NaN or negative
+  P                 Toggle the perspective view of omnidirectional stereo panoramas, the
+                    left drag turning it and the mouse wheel zooming
+  Escape            Quit";
+
+/// Distance in window pixels the mouse can move between pressing and releasing the left button
+/// for a click, past which the render is panned
+const CLICK_DISTANCE: i32 = 3;
+
+/// Command line arguments, the options override the scene's output settings
+struct Args {
+    scene_path: String,
+    resolution: Option<(u32, u32)>,
+    samples: Option<u32>,
+    output: Option<String>,
+    shading: Option<DebugShading>,
+    bounds: Option<BoundsOverlay>,
+    /// Render layer of the scene rendered instead of all the objects
+    layer: Option<String>,
+    time_limit: Option<f64>,
+    threads: Option<u32>,
+    reserve_ui_core: bool,
+    low_priority: bool,
+    /// Whether the render is paused while the window is minimized
+    pause_minimized: bool,
+    /// Whether a desktop notification is shown when the render completes or fails
+    notify: bool,
+    cores: Vec<usize>,
+    log_level: Level,
+    log_json: bool,
+    /// Where the tiles' timings are saved
+    tile_log: Option<String>,
+    headless: bool,
+    /// Whether a JSON summary of headless renders is printed
+    summary: bool,
+    /// Whether the scene file or directory is rendered again whenever it changes
+    watch: bool,
+    check: bool,
+    schema: bool,
+    /// Where the resolved scene is saved
+    export_scene: Option<String>,
+    /// Seed of the random scene rendered instead of the scene file
+    random_scene: Option<u64>,
+    /// Number of random scenes rendered to find rendering bugs
+    fuzz: Option<u32>,
+    benchmark: Option<u32>,
+    turntable: Option<Turntable>,
+    convert_mesh: Option<String>,
+    /// Directory the built meshes are cached in
+    cache: Option<String>,
+    /// Saved render compared with the current one in the window
+    compare: Option<String>,
+    debug_pixel: Option<(u32, u32)>,
+    debug_output: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        scene_path: "scenes/test.json".to_string(),
+        resolution: None,
+        samples: None,
+        output: None,
+        shading: None,
+        bounds: None,
+        layer: None,
+        time_limit: None,
+        threads: None,
+        reserve_ui_core: false,
+        low_priority: false,
+        pause_minimized: true,
+        notify: true,
+        cores: Vec::new(),
+        log_level: Level::Info,
+        log_json: false,
+        tile_log: None,
+        headless: false,
+        summary: false,
+        watch: false,
+        check: false,
+        schema: false,
+        export_scene: None,
+        random_scene: None,
+        fuzz: None,
+        benchmark: None,
+        turntable: None,
+        convert_mesh: None,
+        cache: None,
+        compare: None,
+        debug_pixel: None,
+        debug_output: "pixel_paths.json".to_string(),
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| iter.next().ok_or_else(|| format!("Missing value for {}", name));
+        match arg.as_str() {
+            "--resolution" => {
+                let value = value("--resolution")?;
+                let resolution = value.split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .filter(|&(width, height)| width > 0 && height > 0)
+                    .ok_or_else(|| format!("Invalid resolution {}, expected WIDTHxHEIGHT", value))?;
+                args.resolution = Some(resolution);
+            }
+            "--samples" => {
+                let value = value("--samples")?;
+                args.samples = Some(value.parse().map_err(|_| format!("Invalid number of samples {}", value))?);
+            }
+            "--output" => args.output = Some(value("--output")?),
+            "--time-limit" => {
+                let value = value("--time-limit")?;
+                let time_limit = value.parse().ok()
+                    .filter(|&time_limit: &f64| time_limit > 0.0)
+                    .ok_or_else(|| format!("Invalid time limit {}, expected a number of seconds", value))?;
+                args.time_limit = Some(time_limit);
+            }
+            "--threads" => {
+                let value = value("--threads")?;
+                let threads = value.parse().ok()
+                    .filter(|&threads| threads > 0)
+                    .ok_or_else(|| format!("Invalid number of threads {}", value))?;
+                args.threads = Some(threads);
+            }
+            "--reserve-ui-core" => args.reserve_ui_core = true,
+            "--low-priority" => args.low_priority = true,
+            "--no-pause" => args.pause_minimized = false,
+            "--no-notify" => args.notify = false,
+            "--pin-cores" => {
+                let value = value("--pin-cores")?;
+                args.cores = value.split(',')
+                    .map(|core| core.trim().parse().map_err(|_| format!("Invalid core {}, expected a comma separated list", core)))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--shading" => args.shading = Some(value("--shading")?.parse()?),
+            "--bounds" => args.bounds = Some(value("--bounds")?.parse()?),
+            "--layer" => args.layer = Some(value("--layer")?),
+            "--tile-log" => args.tile_log = Some(value("--tile-log")?),
+            "--headless" => args.headless = true,
+            "--summary" => args.summary = true,
+            "--watch" => args.watch = true,
+            "--check" => args.check = true,
+            "--schema" => args.schema = true,
+            "--export-scene" => args.export_scene = Some(value("--export-scene")?),
+            "--random-scene" => {
+                let value = value("--random-scene")?;
+                args.random_scene = Some(value.parse().map_err(|_| format!("Invalid random scene seed {}", value))?);
+            }
+            "--fuzz" => {
+                let value = value("--fuzz")?;
+                let scenes = value.parse().ok()
+                    .filter(|&scenes| scenes > 0)
+                    .ok_or_else(|| format!("Invalid number of fuzzed scenes {}", value))?;
+                args.fuzz = Some(scenes);
+            }
+            "--benchmark" => {
+                let value = value("--benchmark")?;
+                let runs = value.parse().ok()
+                    .filter(|&runs| runs > 0)
+                    .ok_or_else(|| format!("Invalid number of benchmark runs {}", value))?;
+                args.benchmark = Some(runs);
+            }
+            "--turntable" => args.turntable = Some(Turntable::parse(&value("--turntable")?)?),
+            "--convert-mesh" => args.convert_mesh = Some(value("--convert-mesh")?),
+            "--cache" => args.cache = Some(value("--cache")?),
+            "--compare" => args.compare = Some(value("--compare")?),
+            "--debug-pixel" => {
+                let value = value("--debug-pixel")?;
+                let pixel = value.split_once(',')
+                    .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+                    .ok_or_else(|| format!("Invalid pixel {}, expected X,Y", value))?;
+                args.debug_pixel = Some(pixel);
+            }
+            "--debug-output" => args.debug_output = value("--debug-output")?,
+            "-v" | "--verbose" => args.log_level = if args.log_level >= Level::Debug { Level::Trace } else { Level::Debug },
+            "-vv" => args.log_level = Level::Trace,
+            "-q" | "--quiet" => args.log_level = Level::Warn,
+            "--log-json" => args.log_json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}, see --help", arg)),
+            _ => args.scene_path = arg,
+        }
+    }
+
+    Ok(args)
+}
+
+/// Parses the scene file, or generates the random scene, and applies the overrides from the
+/// command line
+fn load_scene(args: &Args) -> Result<SceneBuilder, String> {
+    if let Some(seed) = args.random_scene {
+        return with_overrides(raytracer::random_scene(seed), args);
+    }
+    let scene_file = fs::File::open(&args.scene_path).map_err(|err| format!("Failed to open scene file: {}", err))?;
+    with_overrides(SceneBuilder::from_reader(scene_file)?, args)
+}
+
+/// Applies the overrides from the command line to a scene
+fn with_overrides(mut scene: SceneBuilder, args: &Args) -> Result<SceneBuilder, String> {
+    if let Some((width, height)) = args.resolution {
+        scene = scene.output(width, height);
+    }
+    if let Some(samples) = args.samples {
+        scene = scene.samples(samples);
+    }
+    if let Some(output) = &args.output {
+        scene = scene.output_path(output);
+    }
+    if let Some(time_limit) = args.time_limit {
+        scene = scene.time_limit(time_limit);
+    }
+    if let Some(shading) = args.shading {
+        scene = scene.shading(shading);
+    }
+    if let Some(overlay) = args.bounds {
+        scene = scene.bounds_overlay(overlay);
+    }
+    if let Some(layer) = &args.layer {
+        scene = scene.layer(layer)?;
+    }
+    Ok(scene)
+}
+
+/// Validates the scene and logs all its problems, returning the exit code
+fn check_scene(args: &Args) -> headless::Exit {
+    let scene = match load_scene(args) {
+        Ok(scene) => scene,
+        Err(err) => {
+            log::error!("{}", err);
+            return headless::Exit::SceneError;
+        }
+    };
+    let problems = scene.check();
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: scene OK", scene_name(args));
+        headless::Exit::Success
+    } else {
+        println!("{}: {} problem{} found", scene_name(args), problems.len(), if problems.len() == 1 { "" } else { "s" });
+        headless::Exit::SceneError
+    }
+}
+
+/// Converts a mesh to a cache with the same path and the `.cmesh` extension
+fn convert_mesh(path: &str) -> Result<(), String> {
+    let cache_path = Path::new(path).with_extension("cmesh");
+    let cache_path = cache_path.to_str().ok_or("Invalid mesh path")?;
+    let start = Instant::now();
+    let triangles = raytracer::convert_mesh(path, cache_path)?;
+    log::info!(
+        "Mesh of {} triangles converted to {} in {:.3}s",
+        triangles,
+        cache_path,
+        start.elapsed().as_secs_f64(),
+    );
+    Ok(())
+}
+
+/// Saves every ray traced for a pixel
+fn debug_pixel(raytracer: &Arc<Raytracer>, x: u32, y: u32, path: &str) -> Result<(), String> {
+    let output = raytracer.output();
+    if x >= output.width || y >= output.height {
+        return Err(format!("Pixel {},{} is outside of the {}x{} image", x, y, output.width, output.height));
+    }
+
+    raytracer.debug_pixel(x, y).save(path)?;
+    log::info!("Rays of pixel {},{} saved to {}", x, y, path);
+    Ok(())
+}
+
+/// Logs the object seen through a point of the render, returning its ID to select it
+fn pick(raytracer: &Arc<Raytracer>, x: f64, y: f64) -> Option<u32> {
+    let Some(picked) = raytracer.pick(x, y) else {
+        log::info!("No object at {:.0},{:.0}", x, y);
+        return None;
+    };
+    let name = picked.name.map_or(String::new(), |name| format!(" {:?}", name));
+    let material = picked.material_name.map_or("inline material".to_string(), |name| format!("material {:?}", name));
+    let transform = serde_json::to_string(&picked.transform).unwrap_or_default();
+    let (px, py, pz) = picked.position;
+    log::info!(
+        "Picked objects[{}]{} (ID {}), {}, at {:.3},{:.3},{:.3}, transform {}",
+        picked.index, name, picked.id, material, px, py, pz, transform,
+    );
+    Some(picked.id)
+}
+
+/// Returns the point of the render under a point of the window, in pixels from their top left
+/// corners, through the panorama view if shown
+fn render_point(raytracer: &Arc<Raytracer>, panorama: Option<&PanoramaView>, display_rect: Rect, window_sz: (u32, u32), (x, y): (i32, i32)) -> (f64, f64) {
+    let output = raytracer.output();
+    match panorama {
+        Some(view) => view.render_point((x as f64, y as f64), window_sz, (output.width, output.height)),
+        None => (
+            (x - display_rect.x()) as f64 / display_rect.width() as f64 * output.width as f64,
+            (y - display_rect.y()) as f64 / display_rect.height() as f64 * output.height as f64,
+        ),
+    }
+}
+
+/// Returns the render's size, in pixels
+fn output_sz(raytracer: &Arc<Raytracer>) -> (f64, f64) {
+    (raytracer.output().width as f64, raytracer.output().height as f64)
+}
+
+/// Calculates the size and offset to fit the render to the window size, preserving its aspect ratio
+fn fit(window_sz: (u32, u32), output_sz: (f64, f64)) -> ((f64, f64), (f64, f64)) {
+    let window_sz = (window_sz.0 as f64, window_sz.1 as f64);
+    let display_sz = if window_sz.0 / window_sz.1 > output_sz.0 / output_sz.1 {
+        (output_sz.0 * window_sz.1 / output_sz.1, window_sz.1)
+    } else {
+        (window_sz.0, output_sz.1 * window_sz.0 / output_sz.0)
+    };
+    let display_pan = (
+        (window_sz.0 - display_sz.0) / 2.0,
+        (window_sz.1 - display_sz.1) / 2.0,
+    );
+    (display_sz, display_pan)
+}
+
+/// Returns the file name of the scene
+fn scene_name(args: &Args) -> String {
+    if let Some(seed) = args.random_scene {
+        return format!("random scene {}", seed);
+    }
+    Path::new(&args.scene_path)
+        .file_name()
+        .map_or(args.scene_path.clone(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Shows a desktop notification of the render's result, unless disabled
+fn notify_result(args: &Args, result: &Result<(), String>, start: Instant) {
+    if !args.notify {
+        return;
+    }
+    match result {
+        Ok(()) => notify::send(
+            "Render completed",
+            &format!("{} rendered in {:.1}s", scene_name(args), start.elapsed().as_secs_f64()),
+        ),
+        Err(err) => notify::send("Render failed", &format!("{}: {}", scene_name(args), err)),
+    }
+}
 
 fn main() -> Result<(), String> {
-    // TODO argparse
-    let scene_path = "scenes/test.json";
-    let threads = thread::available_parallelism().map_err(|err| err.to_string())?.get() as u32;
+    let args = parse_args()?;
+    log::set_level(args.log_level);
+    log::set_json(args.log_json);
+    if let Some(path) = &args.convert_mesh {
+        return convert_mesh(path);
+    }
+    if let Some(dir) = &args.cache {
+        raytracer::set_mesh_cache_dir(dir)?;
+    }
+    if args.schema {
+        println!("{}", SceneBuilder::schema());
+        return Ok(());
+    }
+    if let Some(path) = &args.export_scene {
+        let json = load_scene(&args)?.export()?;
+        fs::write(path, json).map_err(|err| format!("Failed to save scene to {}: {}", path, err))?;
+        log::info!("Scene exported to {}", path);
+        return Ok(());
+    }
+    if args.check {
+        std::process::exit(check_scene(&args) as i32);
+    }
+    let threads = args.threads.unwrap_or_else(Raytracer::default_threads);
+    let workers = |threads| Workers::new(threads)
+        .with_low_priority(args.low_priority)
+        .with_cores(args.cores.clone());
 
-    let scene_file = fs::File::open(scene_path).map_err(|err| format!("Failed to open scene file: {}", err))?;
+    if let Some(scenes) = args.fuzz {
+        return fuzz::run(&args, scenes, workers(threads));
+    }
+    if let Some(runs) = args.benchmark {
+        return benchmark::run(&args, runs, workers(threads));
+    }
+    if args.watch {
+        if !args.headless {
+            return Err("--watch renders without a window, add --headless".to_string());
+        }
+        return watch::run(&args, workers(threads));
+    }
+    if args.headless {
+        let exit = headless::run(&args, workers(threads));
+        std::process::exit(exit as i32);
+    }
+    if let Some(turntable) = &args.turntable {
+        let start = Instant::now();
+        let result = turntable::run(&args, turntable, workers(threads));
+        notify_result(&args, &result, start);
+        return result;
+    }
 
-    let raytracer = Raytracer::new(scene_file)?;
-    let render_thread = raytracer.start(threads);
+    let raytracer = load_scene(&args)?.build()?;
+    raytracer.record_tiles(args.tile_log.is_some());
+    if let Some((x, y)) = args.debug_pixel {
+        return debug_pixel(&raytracer, x, y, &args.debug_output);
+    }
+    let mut comparison = args.compare.as_deref()
+        .map(|path| Comparison::load(path, raytracer.output().width, raytracer.output().height))
+        .transpose()?;
+    let settings = Settings::load()?;
+    let bindings = settings.bindings()?;
+    let zoom_range = settings.zoom_range();
+    let threads = match args.threads {
+        Some(threads) => threads,
+        None if args.reserve_ui_core => (threads - 1).max(1),
+        None => threads,
+    };
+    let start = Instant::now();
+    // Joined as soon as the render completes, to notify of its result
+    let mut render_thread = Some(raytracer.start_with(workers(threads)));
 
     let sdl = sdl2::init()?;
     let sdl_video = sdl.video()?;
@@ -35,71 +537,258 @@ fn main() -> Result<(), String> {
         .build()
         .unwrap();
     let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(
-            PixelFormatEnum::RGBA8888,
-            raytracer.output().width,
-            raytracer.output().height,
-        )
-        .unwrap();
-    texture.set_blend_mode(BlendMode::Blend);
-    texture.set_scale_mode(ScaleMode::Linear);
+    let new_texture = || {
+        let mut texture = texture_creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGBA8888,
+                raytracer.output().width,
+                raytracer.output().height,
+            )
+            .unwrap();
+        texture.set_blend_mode(BlendMode::Blend);
+        texture.set_scale_mode(ScaleMode::Linear);
+        texture
+    };
+    let mut texture = new_texture();
+    let comparison_texture = comparison.as_ref().map(|comparison| {
+        let mut texture = new_texture();
+        texture.update(None, &comparison.pixels, 4 * raytracer.output().width as usize).unwrap();
+        texture
+    });
 
     let mut window_sz = canvas.output_size().unwrap();
     let mut pan = (0.0, 0.0);
     let mut zoom = 0.0;
+    // Last display colors copied to the texture
+    let mut pixels = Vec::new();
+    let mut version = u64::MAX;
+    // Difference with the compared render, when shown
+    let mut difference = Vec::new();
+    // Exposure overlays, toggled with H and Z
+    let mut histogram: Option<Histogram> = None;
+    let mut show_histogram = false;
+    let mut show_zebra = false;
+    let mut zebra_texture = new_texture();
+    let mut zebra = Vec::new();
+    // Object picked with a left click, outlined from the object ID pass
+    let mut selected: Option<u32> = None;
+    let mut selection_texture = new_texture();
+    let mut selection = Vec::new();
+    // Where the left button was pressed, until the mouse moves enough to pan instead of picking
+    let mut click: Option<(i32, i32)> = None;
+    // Perspective view of panorama renders, toggled with P, and the view, view size and render
+    // version last reprojected
+    let mut panorama: Option<PanoramaView> = None;
+    let mut panorama_texture = None;
+    let mut panorama_pixels = Vec::new();
+    let mut reprojected = None;
+    // Where the render was last drawn in the window
+    let mut display_rect = Rect::new(0, 0, 1, 1);
+
+    // Progress shown in the window title, which is also shown in the taskbar of most desktops,
+    // SDL 2 has no API for taskbar progress bars
+    let scene_name = scene_name(&args);
+    let mut title = String::new();
 
     // Main thread window event loop / drawing
     let mut event_pump = sdl.event_pump().unwrap();
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
-                Event::MouseMotion { mousestate, xrel, yrel, ..} => {
+                Event::MouseMotion { mousestate, x, y, xrel, yrel, ..} => {
                     if mousestate.left() {
-                        pan.0 += xrel as f64;
-                        pan.1 += yrel as f64;
+                        match &mut panorama {
+                            Some(view) => view.drag((xrel as f64, yrel as f64), window_sz.1),
+                            None => {
+                                pan.0 += xrel as f64;
+                                pan.1 += yrel as f64;
+                            }
+                        }
+                        click.take_if(|click| (x - click.0).abs() + (y - click.1).abs() > CLICK_DISTANCE);
+                    }
+                    if mousestate.middle() && let Some(comparison) = &mut comparison {
+                        comparison.wipe = ((x - display_rect.x()) as f64 / display_rect.width() as f64).clamp(0.0, 1.0);
                     }
                 }
-                Event::MouseWheel { precise_y, .. } => {
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => {
+                    click = Some((x, y));
+                }
+                Event::MouseButtonUp { mouse_btn: MouseButton::Left, x, y, .. } if click.take().is_some() => {
+                    let output = raytracer.output();
+                    let (x, y) = render_point(&raytracer, panorama.as_ref(), display_rect, window_sz, (x, y));
+                    selected = (x >= 0.0 && y >= 0.0 && x < output.width as f64 && y < output.height as f64)
+                        .then(|| pick(&raytracer, x, y))
+                        .flatten();
+                    version = u64::MAX;
+                }
+                Event::MouseButtonDown { mouse_btn: MouseButton::Middle, x, .. } => {
+                    if let Some(comparison) = &mut comparison {
+                        comparison.wipe = ((x - display_rect.x()) as f64 / display_rect.width() as f64).clamp(0.0, 1.0);
+                    }
+                }
+                Event::MouseButtonDown { mouse_btn: MouseButton::Right, x, y, .. } => {
+                    let (x, y) = render_point(&raytracer, panorama.as_ref(), display_rect, window_sz, (x, y));
+                    if x >= 0.0 && y >= 0.0
+                        && let Err(err) = debug_pixel(&raytracer, x as u32, y as u32, &args.debug_output)
+                    {
+                        log::error!("{}", err);
+                    }
+                }
+                Event::MouseWheel { precise_y, mouse_x, mouse_y, .. } => {
+                    if let Some(view) = &mut panorama {
+                        view.zoom(precise_y as f64);
+                        continue;
+                    }
                     let old_zoom = zoom;
-                    zoom = (zoom + precise_y as f64 / 4.0).clamp(-4.0, 4.0);
+                    zoom = (zoom + precise_y as f64 / 4.0).clamp(zoom_range.0, zoom_range.1);
 
-                    let delta = 2f64.powf(zoom) - 2f64.powf(old_zoom);
-                    pan.0 -= delta / 2.0 * window_sz.0 as f64;
-                    pan.1 -= delta / 2.0 * window_sz.1 as f64;
-                }
-                Event::KeyDown { keycode: Some(Keycode::R), .. } => {
-                    pan = (0.0, 0.0);
-                    zoom = 0.0;
+                    // Scale the distance from the cursor to the render's corner, so that the pixel
+                    // under the cursor stays in place
+                    let (_, display_pan) = fit(window_sz, output_sz(&raytracer));
+                    let scale = 2f64.powf(zoom - old_zoom);
+                    let cursor = (mouse_x as f64, mouse_y as f64);
+                    pan.0 = cursor.0 + (pan.0 + display_pan.0 - cursor.0) * scale - display_pan.0;
+                    pan.1 = cursor.1 + (pan.1 + display_pan.1 - cursor.1) * scale - display_pan.1;
                 }
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
+                Event::KeyDown { keycode: Some(keycode), .. } => match bindings.action(keycode) {
+                    Some(Action::Quit) => break 'running,
+                    Some(Action::Fit) => {
+                        pan = (0.0, 0.0);
+                        zoom = 0.0;
+                        panorama = panorama.map(|_| PanoramaView::default());
+                    }
+                    Some(Action::ActualSize) => {
+                        // One render pixel per window pixel, centered
+                        let output_sz = output_sz(&raytracer);
+                        let (display_sz, display_pan) = fit(window_sz, output_sz);
+                        zoom = (output_sz.0 / display_sz.0).log2();
+                        pan = (
+                            (window_sz.0 as f64 - output_sz.0) / 2.0 - display_pan.0,
+                            (window_sz.1 as f64 - output_sz.1) / 2.0 - display_pan.1,
+                        );
+                    }
+                    Some(Action::Histogram) => {
+                        show_histogram = !show_histogram;
+                        version = u64::MAX;
+                    }
+                    Some(Action::Zebra) => {
+                        show_zebra = !show_zebra;
+                        version = u64::MAX;
+                    }
+                    Some(Action::Difference) => {
+                        if let Some(comparison) = &mut comparison {
+                            comparison.show_difference = !comparison.show_difference;
+                            // Upload the render again, with or without the difference
+                            version = u64::MAX;
+                        }
+                    }
+                    Some(Action::Panorama) => {
+                        if raytracer.is_panorama() {
+                            panorama = match panorama {
+                                Some(_) => None,
+                                None => Some(PanoramaView::default()),
+                            };
+                        } else {
+                            log::warning!("The panorama view needs an omnidirectional stereo camera");
+                        }
+                    }
+                    None => {}
+                },
                 Event::Quit { .. } => {
                     break 'running;
                 }
                 Event::Window { win_event: WindowEvent::SizeChanged(..), .. } => {
                     window_sz = canvas.output_size().unwrap();
                 }
+                Event::Window { win_event: WindowEvent::Minimized, .. } if args.pause_minimized => {
+                    raytracer.pause();
+                    log::info!("Render paused while the window is minimized");
+                }
+                Event::Window { win_event: WindowEvent::Restored | WindowEvent::FocusGained, .. }
+                    if raytracer.is_paused() =>
+                {
+                    raytracer.resume();
+                    log::info!("Render resumed");
+                }
                 _ => {}
             }
         }
 
-        // TODO: it would be more efficient to use texture.with_lock / texture streaming, but this is good enough™ for now
-        texture
-            .update(None, raytracer.output().get(), 4 * raytracer.output().width as usize)
-            .unwrap();
-
-        // Calculate the sizes and offsets to fit the texture to the window size (preserving the aspect ratio).
-        let window_sz = (window_sz.0 as f64, window_sz.1 as f64);
-        let output_sz = (raytracer.output().width as f64, raytracer.output().height as f64);
-        let display_sz = if window_sz.0 / window_sz.1 > output_sz.0 / output_sz.1 {
-            (output_sz.0 * window_sz.1 / output_sz.1, window_sz.1)
+        if let Some(thread) = render_thread.take_if(|thread| thread.is_finished()) {
+            let result = thread.join()
+                .map_err(|_| "Render thread panicked".to_string())
+                .and_then(|_| match raytracer.file_error() {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                });
+            if let Err(err) = &result {
+                log::error!("{}", err);
+            }
+            notify_result(&args, &result, start);
+        }
+        let status = if render_thread.is_none() {
+            "done".to_string()
+        } else if raytracer.is_paused() {
+            "paused".to_string()
+        } else if raytracer.output().passes() > 0 {
+            format!("pass {}, {:.0}%", raytracer.output().passes() + 1, raytracer.progress() * 100.0)
         } else {
-            (window_sz.0, output_sz.1 * window_sz.0 / output_sz.0)
+            format!("{:.0}%", raytracer.progress() * 100.0)
         };
-        let display_pan = (
-            (window_sz.0 - display_sz.0) / 2.0,
-            (window_sz.1 - display_sz.1) / 2.0,
-        );
+        let new_title = format!("{} - {} - Crusty", scene_name, status);
+        if new_title != title {
+            canvas.window_mut().set_title(&new_title).unwrap();
+            title = new_title;
+        }
+
+        // TODO: it would be more efficient to use texture.with_lock / texture streaming, but this is good enough™ for now
+        if raytracer.output().version() != version {
+            version = raytracer.output().snapshot(&mut pixels);
+            let pixels = match &comparison {
+                Some(comparison) if comparison.show_difference => {
+                    comparison.difference(&pixels, &mut difference);
+                    &difference
+                }
+                _ => &pixels,
+            };
+            texture
+                .update(None, pixels, 4 * raytracer.output().width as usize)
+                .unwrap();
+
+            if show_histogram || show_zebra {
+                let hdr = raytracer.output().pixels();
+                histogram = show_histogram.then(|| Histogram::new(&hdr));
+                if show_zebra {
+                    overlay::zebra(&hdr, raytracer.output().width, &mut zebra);
+                    zebra_texture.update(None, &zebra, 4 * raytracer.output().width as usize).unwrap();
+                }
+            }
+            if let Some(id) = selected {
+                overlay::selection(&raytracer.output().object_ids(), raytracer.output().width, id, &mut selection);
+                selection_texture.update(None, &selection, 4 * raytracer.output().width as usize).unwrap();
+            }
+        }
+
+        if let Some(view) = panorama {
+            let output = raytracer.output();
+            let view_sz = ((window_sz.0 / panorama::VIEW_SCALE).max(1), (window_sz.1 / panorama::VIEW_SCALE).max(1));
+            if reprojected != Some((view, view_sz, version)) {
+                view.reproject(&pixels, (output.width, output.height), view_sz, &mut panorama_pixels);
+                let (mut view_texture, _) = panorama_texture.take_if(|(_, size)| *size == view_sz).unwrap_or_else(|| {
+                    let mut texture = texture_creator
+                        .create_texture_streaming(PixelFormatEnum::RGBA8888, view_sz.0, view_sz.1)
+                        .unwrap();
+                    texture.set_blend_mode(BlendMode::Blend);
+                    texture.set_scale_mode(ScaleMode::Linear);
+                    (texture, view_sz)
+                });
+                view_texture.update(None, &panorama_pixels, 4 * view_sz.0 as usize).unwrap();
+                panorama_texture = Some((view_texture, view_sz));
+                reprojected = Some((view, view_sz, version));
+            }
+        }
+
+        let (display_sz, display_pan) = fit(window_sz, output_sz(&raytracer));
         let r = Rect::new(
             (pan.0 + display_pan.0) as i32,
             (pan.1 + display_pan.1) as i32,
@@ -107,17 +796,60 @@ fn main() -> Result<(), String> {
             (2f64.powf(zoom) * display_sz.1) as u32,
         );
 
+        display_rect = r;
+
         // Draw and present frame
-        canvas.set_draw_color(Color::RGB(64, 64, 64)); // background
+        let [red, green, blue] = settings.background;
+        canvas.set_draw_color(Color::RGB(red, green, blue)); // background
         canvas.clear();
-        canvas.set_draw_color(Color::RGB(255, 255, 255)); // border
-        canvas.draw_rect(r).unwrap();
-        canvas.copy(&texture, None, r).unwrap();
+        if let Some((panorama_texture, _)) = panorama_texture.as_ref().filter(|_| panorama.is_some()) {
+            canvas.copy(panorama_texture, None, None).unwrap();
+        } else {
+            canvas.set_draw_color(Color::RGB(255, 255, 255)); // border
+            canvas.draw_rect(r).unwrap();
+            canvas.copy(&texture, None, r).unwrap();
+            // The saved render is drawn over the left of the wipe line
+            if let (Some(comparison), Some(saved)) = (&comparison, &comparison_texture)
+                && !comparison.show_difference
+            {
+                let width = (comparison.wipe * raytracer.output().width as f64) as u32;
+                let display_width = (comparison.wipe * r.width() as f64) as u32;
+                if width > 0 && display_width > 0 {
+                    let src = Rect::new(0, 0, width, raytracer.output().height);
+                    canvas.copy(saved, src, Rect::new(r.x(), r.y(), display_width, r.height())).unwrap();
+                }
+                let x = r.x() + display_width as i32;
+                canvas.set_draw_color(Color::RGB(255, 255, 0)); // wipe line
+                canvas.draw_line((x, r.top()), (x, r.bottom())).unwrap();
+            }
+            if show_zebra {
+                canvas.copy(&zebra_texture, None, r).unwrap();
+            }
+            if selected.is_some() {
+                canvas.copy(&selection_texture, None, r).unwrap();
+            }
+        }
+        if let Some(histogram) = &histogram
+            && show_histogram
+        {
+            histogram.draw(&mut canvas);
+        }
         canvas.present();
+
+        // Vsync doesn't throttle minimized windows
+        if raytracer.is_paused() {
+            thread::sleep(Duration::from_millis(50));
+        }
     }
 
     raytracer.stop();
-    render_thread.join().unwrap();
+    if let Some(thread) = render_thread {
+        thread.join().unwrap();
+    }
+    if let Some(path) = &args.tile_log {
+        tile_log::write(path, &raytracer)?;
+        log::info!("Tile log saved to {}", path);
+    }
 
     Ok(())
 }
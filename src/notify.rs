@@ -0,0 +1,11 @@
+//! Desktop notifications, sent through the platform's notification service with notify-rust
+
+use crusty::log;
+use notify_rust::Notification;
+
+/// Shows a desktop notification, failures are only logged since it is a convenience
+pub fn send(summary: &str, body: &str) {
+    if let Err(err) = Notification::new().appname("Crusty").summary(summary).body(body).show() {
+        log::warning!("Failed to send desktop notification: {}, disable them with --no-notify", err);
+    }
+}
@@ -0,0 +1,128 @@
+//! Overlays drawn over the render in the window to check its exposure, computed from its HDR colors,
+//! and to show the selected object
+
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+
+const HISTOGRAM_BINS: usize = 64;
+/// Size of the histogram in the window, and its distance from the bottom left corner
+const HISTOGRAM_SIZE: (u32, u32) = (256, 128);
+const HISTOGRAM_MARGIN: i32 = 16;
+/// Width of the zebra stripes, in render pixels
+const ZEBRA_WIDTH: u32 = 4;
+
+/// Number of pixels in bins of values from 0 to 1, values above 1 being counted in the last bin
+pub struct Histogram {
+    /// Bins of the red, green and blue channels, then of the luminance
+    bins: [[u32; HISTOGRAM_BINS]; 4],
+}
+
+impl Histogram {
+    /// Counts the pixels' straight colors, invalid ones are left out
+    pub fn new(pixels: &[[f32; 4]]) -> Self {
+        let mut bins = [[0; HISTOGRAM_BINS]; 4];
+        for pixel in pixels.iter().filter(|pixel| !invalid(pixel)) {
+            let [r, g, b] = straight(pixel);
+            let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            for (channel, value) in [r, g, b, luminance].into_iter().enumerate() {
+                let bin = ((value * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1);
+                bins[channel][bin] += 1;
+            }
+        }
+        Self { bins }
+    }
+
+    /// Draws the histogram in the bottom left corner of the window, the luminance as gray bars and
+    /// the channels as lines, with logarithmic heights so that dark backgrounds don't flatten it
+    pub fn draw(&self, canvas: &mut Canvas<Window>) {
+        let (_, window_height) = canvas.output_size().unwrap();
+        let area = Rect::new(
+            HISTOGRAM_MARGIN,
+            window_height as i32 - HISTOGRAM_MARGIN - HISTOGRAM_SIZE.1 as i32,
+            HISTOGRAM_SIZE.0,
+            HISTOGRAM_SIZE.1,
+        );
+        let max = self.bins.iter().flatten().copied().max().unwrap_or(0);
+        let height = |count: u32| {
+            let height = (count as f64).ln_1p() / (max as f64).ln_1p().max(1.0);
+            (height * area.height() as f64) as i32
+        };
+        let bin_width = area.width() as i32 / HISTOGRAM_BINS as i32;
+
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 160)); // background
+        canvas.fill_rect(area).unwrap();
+
+        canvas.set_draw_color(Color::RGBA(160, 160, 160, 160)); // luminance
+        for (i, &count) in self.bins[3].iter().enumerate() {
+            let bar_height = height(count);
+            if bar_height > 0 {
+                let x = area.x() + i as i32 * bin_width;
+                canvas.fill_rect(Rect::new(x, area.bottom() - bar_height, bin_width as u32, bar_height as u32)).unwrap();
+            }
+        }
+
+        let colors = [Color::RGB(255, 64, 64), Color::RGB(64, 255, 64), Color::RGB(64, 64, 255)];
+        for (bins, color) in self.bins.iter().zip(colors) {
+            let points: Vec<Point> = bins.iter()
+                .enumerate()
+                .map(|(i, &count)| Point::new(area.x() + i as i32 * bin_width + bin_width / 2, area.bottom() - height(count)))
+                .collect();
+            canvas.set_draw_color(color);
+            canvas.draw_lines(points.as_slice()).unwrap();
+        }
+        canvas.set_blend_mode(BlendMode::None);
+    }
+}
+
+/// Writes zebra stripes over the pixels which clip above 1 (black and white) or are NaN or negative
+/// (magenta and black) into `overlay`, as RGBA8888 in native byte order, transparent elsewhere
+pub fn zebra(pixels: &[[f32; 4]], width: u32, overlay: &mut Vec<u8>) {
+    overlay.clear();
+    overlay.extend(pixels.iter().enumerate().flat_map(|(i, pixel)| {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let stripe = ((x + y) / ZEBRA_WIDTH).is_multiple_of(2);
+        let color: u32 = if invalid(pixel) {
+            if stripe { 0xff00ffff } else { 0x000000ff }
+        } else if straight(pixel).iter().any(|&c| c > 1.0) {
+            if stripe { 0xffffffff } else { 0x000000ff }
+        } else {
+            0
+        };
+        color.to_ne_bytes()
+    }));
+}
+
+/// Writes the outline of the object with the given ID, from the object ID pass, and a tint over
+/// the object into `overlay`, as RGBA8888 in native byte order, transparent elsewhere
+pub fn selection(ids: &[u32], width: u32, id: u32, overlay: &mut Vec<u8>) {
+    let height = ids.len() as u32 / width;
+    let selected = |x: u32, y: u32| ids[(x + y * width) as usize] == id;
+    overlay.clear();
+    overlay.extend((0..ids.len() as u32).flat_map(|i| {
+        let (x, y) = (i % width, i / width);
+        let color: u32 = if !selected(x, y) {
+            0
+        } else if x == 0 || y == 0 || x == width - 1 || y == height - 1
+            || !selected(x - 1, y) || !selected(x + 1, y) || !selected(x, y - 1) || !selected(x, y + 1)
+        {
+            0xffc000ff
+        } else {
+            0xffc00040
+        };
+        color.to_ne_bytes()
+    }));
+}
+
+/// Whether a pixel has NaN or negative values, which materials should never return
+fn invalid(pixel: &[f32; 4]) -> bool {
+    pixel.iter().any(|c| c.is_nan() || *c < 0.0)
+}
+
+/// Returns the color of a pixel divided by its alpha
+fn straight(pixel: &[f32; 4]) -> [f32; 3] {
+    let [r, g, b, a] = *pixel;
+    if a > 0.0 { [r / a, g / a, b / a] } else { [0.0; 3] }
+}
@@ -0,0 +1,76 @@
+//! Panorama view, showing equirectangular renders through a perspective view which is turned by
+//! dragging the mouse, to check panoramas without an external viewer
+//!
+//! Omnidirectional stereo renders have a panorama for each eye, only the left eye's in the top
+//! half of the image is shown. The view is reprojected from the render's last snapshot, so that
+//! the tiles show up in it as they are rendered.
+
+use std::f64::consts::PI;
+
+/// Window pixels per pixel of the reprojected view, which is scaled up to the window
+pub const VIEW_SCALE: u32 = 2;
+/// Vertical field of view of the view in degrees, and its limits when zooming
+const DEFAULT_FOV: f64 = 75.0;
+const MIN_FOV: f64 = 10.0;
+const MAX_FOV: f64 = 150.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct PanoramaView {
+    /// Angle in degrees from the camera's view direction towards its right
+    yaw: f64,
+    /// Angle in degrees above the camera's horizon
+    pitch: f64,
+    /// Vertical field of view in degrees
+    fov: f64,
+}
+
+impl Default for PanoramaView {
+    fn default() -> Self {
+        Self { yaw: 0.0, pitch: 0.0, fov: DEFAULT_FOV }
+    }
+}
+
+impl PanoramaView {
+    /// Turns the view so that the panorama follows the mouse moving by `delta` window pixels
+    pub fn drag(&mut self, delta: (f64, f64), window_height: u32) {
+        let degrees = self.fov / window_height as f64;
+        self.yaw = (self.yaw - delta.0 * degrees).rem_euclid(360.0);
+        self.pitch = (self.pitch + delta.1 * degrees).clamp(-90.0, 90.0);
+    }
+
+    /// Narrows the field of view by mouse wheel steps, widening it for negative steps
+    pub fn zoom(&mut self, steps: f64) {
+        self.fov = (self.fov * 2f64.powf(-steps / 4.0)).clamp(MIN_FOV, MAX_FOV);
+    }
+
+    /// Returns the point of the render seen through a point of a view of size `view_sz`, both in
+    /// pixels from their top left corner
+    pub fn render_point(&self, (x, y): (f64, f64), view_sz: (u32, u32), render_sz: (u32, u32)) -> (f64, f64) {
+        let (width, height) = (view_sz.0 as f64, view_sz.1 as f64);
+        let tan = (self.fov.to_radians() / 2.0).tan();
+        // Direction in the camera's space, y being forward and z up, pitched then turned
+        let (dx, dz) = ((2.0 * x / width - 1.0) * tan * width / height, (1.0 - 2.0 * y / height) * tan);
+        let (sin, cos) = self.pitch.to_radians().sin_cos();
+        let (dy, dz) = (cos - dz * sin, sin + dz * cos);
+        let (sin, cos) = self.yaw.to_radians().sin_cos();
+        let (dx, dy) = (dx * cos + dy * sin, dy * cos - dx * sin);
+
+        let longitude = dx.atan2(dy);
+        let latitude = dz.atan2((dx * dx + dy * dy).sqrt());
+        let eye_height = render_sz.1 as f64 / 2.0;
+        ((longitude / (2.0 * PI) + 0.5) * render_sz.0 as f64, (0.5 - latitude / PI) * eye_height)
+    }
+
+    /// Reprojects the render's RGBA8888 pixels into `view`, of size `view_sz`
+    pub fn reproject(&self, pixels: &[u8], render_sz: (u32, u32), view_sz: (u32, u32), view: &mut Vec<u8>) {
+        view.clear();
+        for y in 0..view_sz.1 {
+            for x in 0..view_sz.0 {
+                let (u, v) = self.render_point((x as f64 + 0.5, y as f64 + 0.5), view_sz, render_sz);
+                let (u, v) = ((u as u32).min(render_sz.0 - 1), (v as u32).min((render_sz.1 / 2).max(1) - 1));
+                let i = 4 * (u + v * render_sz.0) as usize;
+                view.extend_from_slice(&pixels[i..i + 4]);
+            }
+        }
+    }
+}
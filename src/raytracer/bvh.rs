@@ -0,0 +1,362 @@
+use crate::raytracer::Ray;
+use crate::raytracer::mesh_cache::{self, Buffer, CacheReader, Plain};
+use crate::raytracer::stats::{self, Counter};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::thread;
+
+/// Number of buckets the centroids are sorted into when evaluating SAH splits
+const SAH_BINS: usize = 12;
+
+/// Bounding volume hierarchy over primitives, used to only intersect the primitives near a ray
+#[derive(Default)]
+pub struct Bvh {
+    /// Depth-first nodes, the first child of an interior node directly follows it
+    nodes: Buffer<Node>,
+    /// Primitive indices, leaves reference contiguous ranges of them
+    indices: Buffer<usize>,
+}
+
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+pub struct BvhOptions {
+    #[serde(default)]
+    pub split: BvhSplit,
+    /// Maximum number of primitives in a leaf, leaves can be larger when primitives can't be split
+    #[serde(default = "default_bvh_max_leaf_size")]
+    pub max_leaf_size: usize,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BvhSplit {
+    /// Binned surface area heuristic, slower to build but faster to traverse
+    #[default]
+    Sah,
+    /// Splits at the median centroid along the largest axis, fast to build for previews
+    Median,
+}
+
+/// Visitor of BVH nodes, called with their bounds, depth and [`Aabb::slabs`]
+pub type NodeVisitor<'a> = dyn FnMut(&Aabb, u32, (f64, f64)) + 'a;
+
+/// Axis-aligned bounding box
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: (f64, f64, f64),
+    pub max: (f64, f64, f64),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    /// First index for leaves, second child for interior nodes
+    offset: usize,
+    /// Number of primitives of leaves, 0 for interior nodes
+    count: usize,
+}
+
+// SAFETY: nodes are only made of floats and integers, and bounds checked when mapped
+unsafe impl Plain for Node {}
+
+/// Node of the tree while it is built, before being flattened
+enum BuildNode {
+    Leaf(Aabb, Vec<usize>),
+    Interior(Aabb, Box<BuildNode>, Box<BuildNode>),
+}
+
+impl Bvh {
+    /// Builds the hierarchy over primitives with the given bounds, in parallel
+    pub fn build(bounds: &[Aabb], options: &BvhOptions) -> Self {
+        let centroids = bounds.iter().map(Aabb::centroid).collect::<Vec<_>>();
+        let mut indices = (0..bounds.len()).collect::<Vec<_>>();
+
+        // Split the work on separate threads for the first levels of the tree
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let parallel_depth = threads.next_power_of_two().trailing_zeros();
+        let root = build_node(bounds, &centroids, &mut indices, options, parallel_depth);
+
+        let mut bvh = Self::default();
+        bvh.indices.reserve(bounds.len());
+        bvh.flatten(root);
+        bvh
+    }
+
+    /// Maps the hierarchy from the next buffers of a mesh cache, over `primitives` primitives
+    pub(super) fn map_cache(reader: &mut CacheReader, primitives: usize) -> Result<Self, String> {
+        let bvh = Self { nodes: reader.next()?, indices: reader.next()? };
+
+        // Children always follow their parent, so that traversals can't loop
+        let valid_node = |(i, node): (usize, &Node)| if node.count > 0 {
+            node.offset.checked_add(node.count).is_some_and(|end| end <= bvh.indices.len())
+        } else {
+            node.offset > i + 1 && node.offset < bvh.nodes.len()
+        };
+        if !bvh.nodes.iter().enumerate().all(valid_node) || !bvh.indices.iter().all(|&index| index < primitives) {
+            return Err("BVH index out of range".to_string());
+        }
+
+        Ok(bvh)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Bounds of all the primitives
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |node| node.bounds)
+    }
+
+    /// Calls `visit` with the bounds, depth and [`Aabb::slabs`] of each node whose bounds the
+    /// ray's line hits past its `min_distance`, the root being at depth 0
+    pub fn visit(&self, ray: &Ray, mut visit: impl FnMut(&Aabb, u32, (f64, f64))) {
+        let inv_dir = (1.0 / ray.direction.0, 1.0 / ray.direction.1, 1.0 / ray.direction.2);
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push((0, 0));
+        }
+
+        while let Some((i, depth)) = stack.pop() {
+            let node = &self.nodes[i];
+            let Some(slabs) = node.bounds.slabs(ray, inv_dir).filter(|slabs| slabs.1 >= ray.min_distance) else {
+                continue;
+            };
+            visit(&node.bounds, depth, slabs);
+            if node.count == 0 && i + 1 < self.nodes.len() {
+                stack.extend([(node.offset, depth + 1), (i + 1, depth + 1)]);
+            }
+        }
+    }
+
+    /// Raw bytes of the hierarchy's buffers, to be written to a mesh cache after the mesh's
+    pub(super) fn cache_buffers(&self) -> [&[u8]; 2] {
+        [mesh_cache::bytes(&self.nodes), mesh_cache::bytes(&self.indices)]
+    }
+
+    fn flatten(&mut self, node: BuildNode) {
+        match node {
+            BuildNode::Leaf(bounds, indices) => {
+                self.nodes.push(Node { bounds, offset: self.indices.len(), count: indices.len() });
+                self.indices.extend(indices);
+            }
+            BuildNode::Interior(bounds, left, right) => {
+                let i = self.nodes.len();
+                self.nodes.push(Node { bounds, offset: 0, count: 0 });
+                self.flatten(*left);
+                self.nodes[i].offset = self.nodes.len();
+                self.flatten(*right);
+            }
+        }
+    }
+
+    /// Returns the closest hit along the ray, `intersect` being called with the index of each
+    /// primitive whose bounds the ray hits and returning the hit's distance and data
+    ///
+    /// Embree intersects the meshes instead with the `embree` feature.
+    #[cfg_attr(feature = "embree", allow(dead_code))]
+    pub fn closest<T>(&self, ray: &Ray, mut intersect: impl FnMut(usize) -> Option<(f64, T)>) -> Option<(f64, T)> {
+        let inv_dir = (1.0 / ray.direction.0, 1.0 / ray.direction.1, 1.0 / ray.direction.2);
+        let mut closest: Option<(f64, T)> = None;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            stats::count(Counter::BvhNodes);
+            let node = &self.nodes[i];
+            let max_distance = closest.as_ref().map_or(f64::INFINITY, |(distance, _)| *distance);
+            if node.bounds.intersect(ray, inv_dir, max_distance).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &index in &self.indices[node.offset..node.offset + node.count] {
+                    if let Some((distance, data)) = intersect(index)
+                        && closest.as_ref().is_none_or(|(closest, _)| distance < *closest)
+                    {
+                        closest = Some((distance, data));
+                    }
+                }
+            } else {
+                // Visit the closest child first so that the other can more likely be culled
+                let (left, right) = (i + 1, node.offset);
+                let left_distance = self.nodes[left].bounds.intersect(ray, inv_dir, max_distance);
+                let right_distance = self.nodes[right].bounds.intersect(ray, inv_dir, max_distance);
+                match (left_distance, right_distance) {
+                    (Some(l), Some(r)) if l <= r => stack.extend([right, left]),
+                    (Some(_), Some(_)) => stack.extend([left, right]),
+                    (Some(_), None) => stack.push(left),
+                    (None, Some(_)) => stack.push(right),
+                    (None, None) => {}
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+fn build_node(
+    bounds: &[Aabb],
+    centroids: &[(f64, f64, f64)],
+    indices: &mut [usize],
+    options: &BvhOptions,
+    parallel_depth: u32,
+) -> BuildNode {
+    let node_bounds = indices.iter().fold(Aabb::empty(), |acc, &i| acc.union(&bounds[i]));
+    if indices.len() <= options.max_leaf_size.max(1) {
+        return BuildNode::Leaf(node_bounds, indices.to_vec());
+    }
+
+    let centroid_bounds = indices.iter().fold(Aabb::empty(), |acc, &i| acc.union(&Aabb::point(centroids[i])));
+    let axis = centroid_bounds.largest_axis();
+    let (min, max) = (axis_of(centroid_bounds.min, axis), axis_of(centroid_bounds.max, axis));
+    if max <= min {
+        // All the centroids are at the same place, no split can separate them
+        return BuildNode::Leaf(node_bounds, indices.to_vec());
+    }
+
+    let mid = match options.split {
+        BvhSplit::Median => {
+            let mid = indices.len() / 2;
+            indices.select_nth_unstable_by(mid, |&a, &b| {
+                axis_of(centroids[a], axis).total_cmp(&axis_of(centroids[b], axis))
+            });
+            mid
+        }
+        BvhSplit::Sah => {
+            let bin = |i: usize| (((axis_of(centroids[i], axis) - min) / (max - min) * SAH_BINS as f64) as usize).min(SAH_BINS - 1);
+            let mut bins = [(Aabb::empty(), 0usize); SAH_BINS];
+            for &i in indices.iter() {
+                let b = &mut bins[bin(i)];
+                *b = (b.0.union(&bounds[i]), b.1 + 1);
+            }
+
+            // Cost of splitting after each bin, from the bounds accumulated from both sides
+            let mut best = (f64::INFINITY, 0);
+            for split in 1..SAH_BINS {
+                let side = |bins: &[(Aabb, usize)]| bins.iter()
+                    .fold((Aabb::empty(), 0), |(bounds, count), b| (bounds.union(&b.0), count + b.1));
+                let (left, left_count) = side(&bins[..split]);
+                let (right, right_count) = side(&bins[split..]);
+                if left_count == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = left.surface_area() * left_count as f64 + right.surface_area() * right_count as f64;
+                if cost < best.0 {
+                    best = (cost, split);
+                }
+            }
+
+            let leaf_cost = node_bounds.surface_area() * indices.len() as f64;
+            if best.0 == f64::INFINITY || (best.0 >= leaf_cost && indices.len() <= options.max_leaf_size * 4) {
+                return BuildNode::Leaf(node_bounds, indices.to_vec());
+            }
+
+            partition(indices, |&i| bin(i) < best.1)
+        }
+    };
+
+    let (left, right) = indices.split_at_mut(mid);
+    let (left, right) = if parallel_depth > 0 {
+        thread::scope(|scope| {
+            let left = scope.spawn(|| build_node(bounds, centroids, left, options, parallel_depth - 1));
+            let right = build_node(bounds, centroids, right, options, parallel_depth - 1);
+            (left.join().unwrap(), right)
+        })
+    } else {
+        (
+            build_node(bounds, centroids, left, options, 0),
+            build_node(bounds, centroids, right, options, 0),
+        )
+    };
+
+    BuildNode::Interior(node_bounds, Box::new(left), Box::new(right))
+}
+
+/// Moves the elements matching the predicate first, returning how many there are
+fn partition(indices: &mut [usize], predicate: impl Fn(&usize) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..indices.len() {
+        if predicate(&indices[i]) {
+            indices.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+const fn axis_of(v: (f64, f64, f64), axis: usize) -> f64 {
+    match axis {
+        0 => v.0,
+        1 => v.1,
+        _ => v.2,
+    }
+}
+
+impl Aabb {
+    pub const fn empty() -> Self {
+        Self {
+            min: (f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: (f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    pub const fn point(p: (f64, f64, f64)) -> Self {
+        Self { min: p, max: p }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: (self.min.0.min(other.min.0), self.min.1.min(other.min.1), self.min.2.min(other.min.2)),
+            max: (self.max.0.max(other.max.0), self.max.1.max(other.max.1), self.max.2.max(other.max.2)),
+        }
+    }
+
+    pub fn centroid(&self) -> (f64, f64, f64) {
+        ((self.min.0 + self.max.0) / 2.0, (self.min.1 + self.max.1) / 2.0, (self.min.2 + self.max.2) / 2.0)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let (dx, dy, dz) = (self.max.0 - self.min.0, self.max.1 - self.min.1, self.max.2 - self.min.2);
+        if dx < 0.0 { 0.0 } else { 2.0 * (dx * dy + dy * dz + dz * dx) }
+    }
+
+    fn largest_axis(&self) -> usize {
+        let (dx, dy, dz) = (self.max.0 - self.min.0, self.max.1 - self.min.1, self.max.2 - self.min.2);
+        if dx >= dy && dx >= dz { 0 } else if dy >= dz { 1 } else { 2 }
+    }
+
+    /// Returns the distance at which the ray enters the box, if it does between its `min_distance`
+    /// and `max_distance`
+    #[cfg_attr(feature = "embree", allow(dead_code))]
+    pub fn intersect(&self, ray: &Ray, inv_dir: (f64, f64, f64), max_distance: f64) -> Option<f64> {
+        let (tmin, tmax) = self.slabs(ray, inv_dir)?;
+        (tmax >= ray.min_distance && tmin <= max_distance).then_some(tmin)
+    }
+
+    /// Returns the distances at which the ray's line enters and exits the box, if it hits it
+    pub fn slabs(&self, ray: &Ray, inv_dir: (f64, f64, f64)) -> Option<(f64, f64)> {
+        let (min, max) = (self.min, self.max);
+        let t1 = ((min.0 - ray.origin.0) * inv_dir.0, (min.1 - ray.origin.1) * inv_dir.1, (min.2 - ray.origin.2) * inv_dir.2);
+        let t2 = ((max.0 - ray.origin.0) * inv_dir.0, (max.1 - ray.origin.1) * inv_dir.1, (max.2 - ray.origin.2) * inv_dir.2);
+        let tmin = f64::max(f64::max(f64::min(t1.0, t2.0), f64::min(t1.1, t2.1)), f64::min(t1.2, t2.2));
+        let tmax = f64::min(f64::min(f64::max(t1.0, t2.0), f64::max(t1.1, t2.1)), f64::max(t1.2, t2.2));
+
+        (tmin <= tmax).then_some((tmin, tmax))
+    }
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        Self {
+            split: BvhSplit::default(),
+            max_leaf_size: default_bvh_max_leaf_size(),
+        }
+    }
+}
+
+const fn default_bvh_max_leaf_size() -> usize { 4 }
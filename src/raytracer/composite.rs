@@ -0,0 +1,144 @@
+use crate::raytracer::RGBA;
+use crate::raytracer::ggx::GgxMaterial;
+use crate::raytracer::materials::{Material, MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::scene::SceneTexture;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::utils::vec3dot;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Returns the material with the given name, building it if needed
+pub type ResolveMaterialFn<'a> = dyn FnMut(&str) -> Result<Arc<Material>, String> + 'a;
+
+/// Blend of two materials, by a constant factor or by the red channel of a mask texture
+pub struct MixMaterial {
+    a: Arc<Material>,
+    b: Arc<Material>,
+    factor: MixFactor,
+}
+
+enum MixFactor {
+    Constant(f64),
+    Mask(Arc<Texture>),
+}
+
+/// Coat material over a base material, the coat is weighted by its Fresnel reflectance
+///
+/// Without a coat material the coat is a clear coat, a second specular lobe like the lacquer of
+/// car paint or varnished wood.
+pub struct LayerMaterial {
+    base: Arc<Material>,
+    coat: Arc<Material>,
+    weight: f64,
+    /// Reflectance of the coat at normal incidence
+    f0: f64,
+}
+
+#[derive(Deserialize)]
+struct MixData {
+    a: String,
+    b: String,
+    #[serde(default = "default_mix_factor")]
+    factor: f64,
+    #[serde(default)]
+    mask: Option<SceneTexture>,
+}
+
+#[derive(Deserialize)]
+struct LayerData {
+    base: String,
+    /// Clear coat if not set
+    #[serde(default)]
+    coat: Option<String>,
+    #[serde(default = "default_layer_weight")]
+    weight: f64,
+    #[serde(default = "default_layer_ior")]
+    ior: f64,
+    /// Roughness of the clear coat
+    #[serde(default)]
+    roughness: f64,
+}
+
+impl MixMaterial {
+    pub fn new(data: &Value, resolve: &mut ResolveMaterialFn) -> Result<Self, String> {
+        let data: MixData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid mix material: {}", err))?;
+
+        Ok(Self {
+            a: resolve(&data.a)?,
+            b: resolve(&data.b)?,
+            factor: match &data.mask {
+                Some(mask) => MixFactor::Mask(mask.load()?),
+                None => MixFactor::Constant(data.factor.clamp(0.0, 1.0)),
+            },
+        })
+    }
+}
+
+impl LayerMaterial {
+    pub fn new(data: &Value, resolve: &mut ResolveMaterialFn) -> Result<Self, String> {
+        let data: LayerData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid layer material: {}", err))?;
+
+        Ok(Self {
+            base: resolve(&data.base)?,
+            coat: match &data.coat {
+                Some(coat) => resolve(coat)?,
+                // Fully reflective, the layer weighting it by its Fresnel reflectance
+                None => Arc::new(Material::from_type(Box::new(GgxMaterial::isotropic((1.0, 1.0, 1.0), data.roughness)))),
+            },
+            weight: data.weight.clamp(0.0, 1.0),
+            f0: ((data.ior - 1.0) / (data.ior + 1.0)).powi(2),
+        })
+    }
+}
+
+impl MaterialType for MixMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let factor = match &self.factor {
+            MixFactor::Constant(factor) => *factor,
+            MixFactor::Mask(texture) => texture.sample(oh.hit.uv, oh.footprint).r.clamp(0.0, 1.0),
+        };
+
+        blend(factor, oh, ctx, &self.a, &self.b)
+    }
+}
+
+impl MaterialType for LayerMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let cos = -vec3dot(oh.ray.direction, oh.hit.normal);
+        let fresnel = self.f0 + (1.0 - self.f0) * (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+
+        blend(fresnel * self.weight, oh, ctx, &self.base, &self.coat)
+    }
+}
+
+/// Linearly interpolates between the colors of `a` and `b`, only shading the materials that contribute
+fn blend(
+    factor: f64,
+    oh: &ObjectHit,
+    ctx: &ShadeContext,
+    a: &Material,
+    b: &Material,
+) -> RGBA {
+    let shade = |material: &Material| material.shade(oh, ctx);
+    if factor <= 0.0 {
+        return shade(a);
+    } else if factor >= 1.0 {
+        return shade(b);
+    }
+
+    let (a, b) = (shade(a), shade(b));
+    RGBA::new(
+        a.r + (b.r - a.r) * factor,
+        a.g + (b.g - a.g) * factor,
+        a.b + (b.b - a.b) * factor,
+        a.a + (b.a - a.a) * factor,
+    )
+}
+
+const fn default_mix_factor() -> f64 { 0.5 }
+const fn default_layer_weight() -> f64 { 1.0 }
+const fn default_layer_ior() -> f64 { 1.5 }
@@ -0,0 +1,157 @@
+use crate::raytracer::{Ray, RGBA};
+use crate::raytracer::bvh::Aabb;
+use crate::raytracer::objects::{self, Object, ObjectHit};
+use crate::raytracer::utils::vec3dot;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Depth range shown by the depth shading when the scene doesn't set one
+const DEFAULT_DEPTH_RANGE: (f64, f64) = (0.0, 100.0);
+/// Width of the wireframe lines, in barycentric coordinates or UV space for surfaces which aren't
+/// meshes
+const WIREFRAME_WIDTH: f64 = 0.02;
+/// Number of UV lines across surfaces which aren't meshes, in wireframe shading
+const WIREFRAME_UV_LINES: f64 = 8.0;
+/// Width of the bounds overlay's lines, in pixels
+const BOUNDS_LINE_WIDTH: f64 = 1.0;
+/// Depth from which BVH nodes are drawn in the deepest nodes' color
+const BVH_COLOR_DEPTH: f64 = 16.0;
+
+/// Shading overriding the scene's materials, to inspect the geometry
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugShading {
+    /// Shading normals, mapped from [-1, 1] to [0, 1]
+    Normal,
+    /// Texture coordinates, U in red and V in green
+    Uv,
+    /// Triangle edges of meshes and UV lines of other surfaces
+    Wireframe,
+    /// Distance from the camera, from white at the near distance to black at the far one
+    Depth,
+}
+
+/// Wireframes of bounding boxes drawn over the render, through the objects, to find bad bounds
+/// and overly deep hierarchies
+#[derive(Clone, Copy, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundsOverlay {
+    /// Bounding box of each object in its space, in yellow
+    Objects,
+    /// Bounding boxes of the objects and of the BVH nodes of meshes, from cyan at the root to
+    /// magenta for the deepest nodes
+    Bvh,
+}
+
+impl DebugShading {
+    /// Returns the color of a hit, `depth` being its distance along the camera's view axis
+    pub(super) fn shade(self, oh: &ObjectHit, depth: f64, depth_range: Option<(f64, f64)>) -> RGBA {
+        match self {
+            DebugShading::Normal => {
+                let (x, y, z) = oh.hit.normal;
+                RGBA::new(x * 0.5 + 0.5, y * 0.5 + 0.5, z * 0.5 + 0.5, 1.0)
+            }
+            DebugShading::Uv => RGBA::new(oh.hit.uv.0.rem_euclid(1.0), oh.hit.uv.1.rem_euclid(1.0), 0.0, 1.0),
+            DebugShading::Wireframe => {
+                let edge = match oh.hit.barycentric {
+                    Some((w, u, v)) => w.min(u).min(v),
+                    None => {
+                        let line = |t: f64| {
+                            let t = (t * WIREFRAME_UV_LINES).rem_euclid(1.0);
+                            t.min(1.0 - t) / WIREFRAME_UV_LINES
+                        };
+                        line(oh.hit.uv.0).min(line(oh.hit.uv.1))
+                    }
+                };
+                if edge < WIREFRAME_WIDTH { RGBA::white() } else { RGBA::black() }
+            }
+            DebugShading::Depth => {
+                let (near, far) = depth_range.unwrap_or(DEFAULT_DEPTH_RANGE);
+                let value = 1.0 - ((depth - near) / (far - near)).clamp(0.0, 1.0);
+                RGBA::new(value, value, value, 1.0)
+            }
+        }
+    }
+}
+
+impl BoundsOverlay {
+    /// Draws the edges of the bounds which a camera ray passes along over its color
+    pub(super) fn draw(self, objects: &[Object], ray: &Ray, color: RGBA) -> RGBA {
+        let mut closest_depth: Option<u32> = None;
+        for object in objects {
+            let local_ray = object.local_ray(ray);
+            // Width of the lines at a distance of 1 along the ray, along each axis of the object's space
+            let width = BOUNDS_LINE_WIDTH * ray.spread * vec3dot(ray.direction, ray.direction).sqrt();
+            let scales = object.axis_scales();
+            let on_edge = |bounds: &Aabb, (entry, exit): (f64, f64)| [entry, exit].into_iter()
+                .filter(|&distance| distance >= ray.min_distance)
+                .any(|distance| {
+                    let width = distance * width;
+                    on_edge(bounds, objects::intersection(&local_ray, distance), (width / scales.0, width / scales.1, width / scales.2))
+                });
+
+            let bounds = object.local_bounds();
+            let inv_dir = (1.0 / local_ray.direction.0, 1.0 / local_ray.direction.1, 1.0 / local_ray.direction.2);
+            if bounds.slabs(&local_ray, inv_dir).is_some_and(|slabs| on_edge(&bounds, slabs)) {
+                return RGBA::new(1.0, 1.0, 0.0, 1.0);
+            }
+            if self == BoundsOverlay::Bvh {
+                object.visit_bvh(&local_ray, &mut |bounds, depth, slabs| {
+                    if closest_depth.is_none_or(|closest| depth < closest) && on_edge(bounds, slabs) {
+                        closest_depth = Some(depth);
+                    }
+                });
+            }
+        }
+
+        match closest_depth {
+            Some(depth) => {
+                let t = (depth as f64 / BVH_COLOR_DEPTH).min(1.0);
+                RGBA::new(t, 1.0 - t, 1.0, 1.0)
+            }
+            None => color,
+        }
+    }
+}
+
+/// Whether a point on the surface of a box is within `width` (along each axis) of one of its
+/// edges, being close to the faces of two axes, or of one axis for flat boxes like planes'
+fn on_edge(bounds: &Aabb, point: (f64, f64, f64), width: (f64, f64, f64)) -> bool {
+    let axes = [
+        (point.0, bounds.min.0, bounds.max.0, width.0),
+        (point.1, bounds.min.1, bounds.max.1, width.1),
+        (point.2, bounds.min.2, bounds.max.2, width.2),
+    ];
+    let solid = axes.iter().filter(|&&(_, min, max, width)| max - min >= width).count();
+    let near = axes.iter()
+        .filter(|&&(value, min, max, width)| max - min >= width && ((value - min).abs() < width || (value - max).abs() < width))
+        .count();
+    near >= if solid == 3 { 2 } else { 1 }
+}
+
+impl FromStr for BoundsOverlay {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "objects" => Ok(BoundsOverlay::Objects),
+            "bvh" => Ok(BoundsOverlay::Bvh),
+            _ => Err(format!("Unknown bounds overlay {}, expected objects or bvh", name)),
+        }
+    }
+}
+
+impl FromStr for DebugShading {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "normal" => Ok(DebugShading::Normal),
+            "uv" => Ok(DebugShading::Uv),
+            "wireframe" => Ok(DebugShading::Wireframe),
+            "depth" => Ok(DebugShading::Depth),
+            _ => Err(format!("Unknown shading mode {}, expected normal, uv, wireframe or depth", name)),
+        }
+    }
+}
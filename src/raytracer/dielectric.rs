@@ -0,0 +1,313 @@
+//! Dielectrics like glass and water, reflecting and refracting light
+//!
+//! Rough dielectrics, like frosted glass, reflect and refract about the normals of a GGX
+//! distribution of microfacets, sampled from the ones visible from the ray.
+//!
+//! Where dielectrics overlap, like water filling a glass, the medium inside the overlap is the one
+//! with the highest priority and the surfaces of the others are ignored there. Rays carry the
+//! stack of media they are in, so that both sides of an interface are known.
+//!
+//! Dielectrics with an Abbe number disperse light in spectral renders, their index of refraction
+//! varying with the wavelength following Cauchy's equation.
+
+use crate::raytracer::{Ray, RayType, Wavelengths, RGBA};
+use crate::raytracer::ggx::{Frame, Microfacets};
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::thin_film::ThinFilm;
+use crate::raytracer::utils::{random, vec3add, vec3dot, vec3norm, vec3scale};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Maximum number of nested media tracked along a ray, more are ignored
+const MAX_MEDIA: usize = 8;
+
+/// Wavelengths of the Fraunhofer d, F and C lines in nanometers, which define Abbe numbers
+const D_LINE: f64 = 587.6;
+const F_LINE: f64 = 486.1;
+const C_LINE: f64 = 656.3;
+
+/// Dielectric reflecting and refracting according to the Fresnel equations
+pub struct DielectricMaterial {
+    /// Color the refracted light is multiplied by
+    color: (f64, f64, f64),
+    /// Index of refraction at the d line
+    ior: f64,
+    /// Coefficients A and B of Cauchy's equation n = A + B / λ², in nanometers, if dispersive
+    dispersion: Option<(f64, f64)>,
+    microfacets: Microfacets,
+    /// Film coating the surface, if any
+    thin_film: Option<ThinFilm>,
+    priority: u32,
+}
+
+#[derive(Deserialize)]
+struct DielectricData {
+    #[serde(default = "default_dielectric_color")]
+    color: [f64; 3],
+    #[serde(default = "default_dielectric_ior")]
+    ior: f64,
+    /// Abbe number, lower numbers dispersing light more (about 55 for glass and 25 for
+    /// flint glass), not dispersive if not set
+    #[serde(default)]
+    abbe: Option<f64>,
+    /// Roughness of the surface, 0 for smooth glass
+    #[serde(default)]
+    roughness: f64,
+    /// Roughness along the tangent, defaults to `roughness`
+    roughness_u: Option<f64>,
+    /// Roughness along the bitangent, defaults to `roughness`
+    roughness_v: Option<f64>,
+    thin_film: Option<ThinFilm>,
+    /// Where dielectrics overlap, the one with the highest priority fills the overlap
+    #[serde(default)]
+    priority: u32,
+}
+
+/// Both sides of the surface being shaded
+struct Interface {
+    /// Index of refraction on the ray's side
+    n1: f64,
+    /// Index of refraction on the other side
+    n2: f64,
+    wavelengths: Wavelengths,
+}
+
+/// Media a ray is inside of, see the module's documentation
+#[derive(Clone, Copy, Default)]
+pub struct Media {
+    media: [Medium; MAX_MEDIA],
+    len: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Medium {
+    /// ID of the object the medium is the inside of
+    object: u32,
+    ior: f64,
+    priority: u32,
+}
+
+impl DielectricMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let data: DielectricData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid dielectric material: {}", err))?;
+        if !data.ior.is_finite() || data.ior <= 0.0 {
+            return Err(format!("Invalid dielectric material: index of refraction {} isn't positive", data.ior));
+        }
+        let dispersion = match data.abbe {
+            Some(abbe) if !abbe.is_finite() || abbe <= 0.0 => {
+                return Err(format!("Invalid dielectric material: Abbe number {} isn't positive", abbe));
+            }
+            Some(abbe) => {
+                let b = (data.ior - 1.0) / (abbe * (F_LINE.powi(-2) - C_LINE.powi(-2)));
+                Some((data.ior - b / (D_LINE * D_LINE), b))
+            }
+            None => None,
+        };
+        if let Some(thin_film) = &data.thin_film {
+            thin_film.check().map_err(|err| format!("Invalid dielectric material: {}", err))?;
+        }
+        let [r, g, b] = data.color;
+        let microfacets = Microfacets::new(
+            data.roughness_u.unwrap_or(data.roughness),
+            data.roughness_v.unwrap_or(data.roughness),
+        );
+
+        Ok(Self {
+            color: (r, g, b),
+            ior: data.ior,
+            dispersion,
+            microfacets,
+            thin_film: data.thin_film,
+            priority: data.priority,
+        })
+    }
+}
+
+impl MaterialType for DielectricMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let object = oh.object.id();
+        let media = oh.ray.media;
+        let continued = |media| Ray { origin: oh.hit.intersection, media, ..oh.ray };
+
+        // Each wavelength refracts differently through dispersive dielectrics, the path then
+        // carries only one
+        let (wavelengths, weight, ior) = match self.dispersion {
+            Some((a, b)) => {
+                let (wavelengths, wavelength, weight) = oh.ray.wavelengths.disperse();
+                (wavelengths, weight, wavelength.map_or(self.ior, |wavelength| a + b / (wavelength * wavelength)))
+            }
+            None => (oh.ray.wavelengths, (1.0, 1.0, 1.0), self.ior),
+        };
+
+        // Indices of refraction on both sides, and the media past the surface
+        let (n1, n2, inside) = if oh.hit.front_face {
+            let inside = media.with(Medium { object, ior, priority: self.priority });
+            if media.top().is_some_and(|top| top.priority > self.priority) {
+                // Entering a medium of a lower priority, the current one still fills it
+                return ctx.pass_through(continued(inside));
+            }
+            (media.ior(), ior, inside)
+        } else {
+            let outside = media.without(object);
+            if media.top().is_some_and(|top| top.object != object) {
+                // Leaving a medium which a medium of a higher priority fills
+                return ctx.pass_through(continued(outside));
+            }
+            (ior, outside.ior(), outside)
+        };
+
+        let normal = oh.hit.normal;
+        let direction = oh.ray.direction;
+        let eta = n1 / n2;
+        let interface = Interface { n1, n2, wavelengths };
+        let tint = (weight.0 * self.color.0, weight.1 * self.color.1, weight.2 * self.color.2);
+
+        // Rough surfaces reflect and refract about a microfacet normal, and are lit by the lights
+        let frame = Frame::new(oh);
+        let view = vec3norm(vec3add(frame.to_local(vec3scale(direction, -1.0)), (0.0, 0.0, 1e-6)));
+        let (m, direct) = if self.microfacets.is_mirror() {
+            (normal, (0.0, 0.0, 0.0))
+        } else {
+            let m = frame.to_world(self.microfacets.sample_normal(view, random()));
+            (m, self.direct(oh, ctx, &frame, view, &interface, tint))
+        };
+
+        let cos_i = (-vec3dot(direction, m)).clamp(0.0, 1.0);
+        let (reflectance, cos_t) = self.reflectance(cos_i, &interface);
+        // With visible normal sampling the estimator weight is the masking of the traced ray
+        let masking = |direction| if self.microfacets.is_mirror() { 1.0 } else { self.microfacets.g1(frame.to_local(direction)) };
+
+        // Colored reflectances of thin films are picked by their average and weighted by their ratio to it
+        let probability = (reflectance.0 + reflectance.1 + reflectance.2) / 3.0;
+        let ratio = |a: (f64, f64, f64), b: (f64, f64, f64), p: f64| {
+            if p > 0.0 { (a.0 * b.0 / p, a.1 * b.1 / p, a.2 * b.2 / p) } else { (0.0, 0.0, 0.0) }
+        };
+        let (direction, ray_type, media, tint) = if random::<f64>() < probability {
+            let ray_type = if self.microfacets.is_mirror() { RayType::Reflection } else { RayType::Glossy };
+            (vec3add(direction, vec3scale(m, 2.0 * cos_i)), ray_type, media, ratio(weight, reflectance, probability))
+        } else {
+            let direction = vec3norm(vec3add(vec3scale(direction, eta), vec3scale(m, eta * cos_i - cos_t)));
+            let transmittance = (1.0 - reflectance.0, 1.0 - reflectance.1, 1.0 - reflectance.2);
+            (direction, RayType::Transmission, inside, ratio(tint, transmittance, 1.0 - probability))
+        };
+        // Microfacets can send rays to the wrong side of the surface, they are then absorbed
+        let reflected = matches!(ray_type, RayType::Reflection | RayType::Glossy);
+        if !self.microfacets.is_mirror() && (vec3dot(direction, normal) > 0.0) != reflected {
+            return RGBA::new(direct.0, direct.1, direct.2, 1.0);
+        }
+
+        let color = ctx.trace(Ray { ray_type, direction, wavelengths, ..continued(media) });
+        let masking = masking(direction);
+        RGBA::new(
+            direct.0 + color.r * tint.0 * masking,
+            direct.1 + color.g * tint.1 * masking,
+            direct.2 + color.b * tint.2 * masking,
+            if reflected && self.microfacets.is_mirror() { color.a } else { 1.0 },
+        )
+    }
+}
+
+impl DielectricMaterial {
+    /// Light from the lights reflected and refracted by a rough surface towards `view`, given in
+    /// the local frame
+    fn direct(&self, oh: &ObjectHit, ctx: &ShadeContext, frame: &Frame, view: (f64, f64, f64), interface: &Interface, tint: (f64, f64, f64)) -> (f64, f64, f64) {
+        let microfacets = &self.microfacets;
+        let eta = interface.n1 / interface.n2;
+        let mut direct = (0.0, 0.0, 0.0);
+        for sample in ctx.sample_lights(oh.hit.intersection) {
+            let light = frame.to_local(sample.direction);
+            let (f, tint) = if light.2 > 0.0 {
+                let m = vec3norm(vec3add(view, light));
+                let (reflectance, _) = self.reflectance(vec3dot(view, m), interface);
+                let f = microfacets.d(m) * microfacets.g1(view) * microfacets.g1(light) / (4.0 * view.2);
+                (f, reflectance)
+            } else if light.2 < 0.0 {
+                // Half vector of the refraction, facing the view's side
+                let m = vec3norm(vec3add(view, vec3scale(light, 1.0 / eta)));
+                let m = if m.2 < 0.0 { vec3scale(m, -1.0) } else { m };
+                let (cos_v, cos_l) = (vec3dot(view, m), vec3dot(light, m));
+                if cos_v <= 0.0 || cos_l >= 0.0 {
+                    continue;
+                }
+                let (reflectance, _) = self.reflectance(cos_v, interface);
+                let denominator = cos_v + cos_l / eta;
+                let f = microfacets.d(m) * microfacets.g1(view) * microfacets.g1(vec3scale(light, -1.0))
+                    * cos_v * -cos_l / (eta * eta * view.2 * denominator * denominator);
+                (f, (tint.0 * (1.0 - reflectance.0), tint.1 * (1.0 - reflectance.1), tint.2 * (1.0 - reflectance.2)))
+            } else {
+                continue;
+            };
+            direct = vec3add(direct, (
+                sample.radiance.0 * f * tint.0,
+                sample.radiance.1 * f * tint.1,
+                sample.radiance.2 * f * tint.2,
+            ));
+        }
+        direct
+    }
+
+    /// Returns the reflectance of each channel, and the cosine of the refracted direction
+    fn reflectance(&self, cos_i: f64, interface: &Interface) -> ((f64, f64, f64), f64) {
+        let (reflectance, cos_t) = refraction(cos_i, interface.n1 / interface.n2);
+        match &self.thin_film {
+            // Totally reflected whatever the film
+            Some(thin_film) if reflectance < 1.0 => {
+                let n2 = interface.n2;
+                (thin_film.reflectance(cos_i, interface.n1, (n2, n2, n2), interface.wavelengths), cos_t)
+            }
+            _ => ((reflectance, reflectance, reflectance), cos_t),
+        }
+    }
+}
+
+impl Media {
+    /// Returns the medium filling the ray's position, the highest priority and latest entered one
+    fn top(&self) -> Option<&Medium> {
+        self.media[..self.len].iter().max_by_key(|medium| medium.priority)
+    }
+
+    /// Index of refraction at the ray's position, 1 outside of any medium
+    fn ior(&self) -> f64 {
+        self.top().map_or(1.0, |medium| medium.ior)
+    }
+
+    fn with(mut self, medium: Medium) -> Self {
+        if self.len < MAX_MEDIA {
+            self.media[self.len] = medium;
+            self.len += 1;
+        }
+        self
+    }
+
+    fn without(mut self, object: u32) -> Self {
+        if let Some(i) = self.media[..self.len].iter().rposition(|medium| medium.object == object) {
+            self.media.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+        }
+        self
+    }
+}
+
+/// Returns the Fresnel reflectance and the cosine of the refracted direction, from the cosine of
+/// the incident direction and the ratio of the indices of refraction
+fn refraction(cos_i: f64, eta: f64) -> (f64, f64) {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    // Total internal reflection when there is no refracted direction
+    if sin2_t >= 1.0 {
+        return (1.0, 0.0);
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    (fresnel(cos_i, cos_t, eta), cos_t)
+}
+
+/// Unpolarized Fresnel reflectance from the cosines of the incident and refracted directions
+fn fresnel(cos_i: f64, cos_t: f64, eta: f64) -> f64 {
+    let s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (s * s + p * p) / 2.0
+}
+
+const fn default_dielectric_color() -> [f64; 3] { [1.0, 1.0, 1.0] }
+const fn default_dielectric_ior() -> f64 { 1.5 }
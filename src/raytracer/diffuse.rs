@@ -0,0 +1,95 @@
+use crate::raytracer::{Ray, RayType, RGBA};
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::scene::SceneTexture;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::utils::{random_unit_vector, vec3add, vec3dot, vec3norm};
+use serde::Deserialize;
+use serde_json::Value;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// Matte (Lambertian) material, lit by the lights and by the light bouncing off other surfaces
+pub struct DiffuseMaterial {
+    color: (f64, f64, f64),
+    /// Texture multiplied with the color
+    texture: Option<Arc<Texture>>,
+    /// Whether the color is multiplied by the mesh's vertex colors
+    vertex_colors: bool,
+}
+
+#[derive(Deserialize)]
+struct DiffuseData {
+    #[serde(default = "default_diffuse_color")]
+    color: [f64; 3],
+    #[serde(default)]
+    texture: Option<SceneTexture>,
+    #[serde(default)]
+    vertex_colors: bool,
+}
+
+impl DiffuseMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let data: DiffuseData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid diffuse material: {}", err))?;
+        let [r, g, b] = data.color;
+
+        Ok(Self {
+            color: (r, g, b),
+            texture: data.texture.as_ref().map(SceneTexture::load).transpose()?,
+            vertex_colors: data.vertex_colors,
+        })
+    }
+
+    pub(super) const fn from_color(color: (f64, f64, f64)) -> Self {
+        Self { color, texture: None, vertex_colors: false }
+    }
+}
+
+impl MaterialType for DiffuseMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let (normal, point) = (oh.hit.normal, oh.hit.intersection);
+
+        let mut light = (0.0, 0.0, 0.0);
+        for sample in ctx.sample_lights(point).into_iter().chain(ctx.sample_portals(point)) {
+            let cos = vec3dot(normal, sample.direction);
+            if cos > 0.0 {
+                light = vec3add(light, (
+                    sample.radiance.0 * cos / PI,
+                    sample.radiance.1 * cos / PI,
+                    sample.radiance.2 * cos / PI,
+                ));
+            }
+        }
+
+        // Cosine-weighted bounce, its probability cancels out with the BRDF's cosine
+        let mut direction = vec3norm(vec3add(normal, random_unit_vector()));
+        if direction.0.is_nan() {
+            direction = normal;
+        }
+        let bounce = ctx.trace(Ray {
+            ray_type: RayType::Diffuse,
+            origin: point,
+            direction,
+            ..oh.ray
+        });
+        light = vec3add(light, (bounce.r, bounce.g, bounce.b));
+
+        let mut albedo = match &self.texture {
+            Some(texture) => {
+                let texel = texture.sample(oh.hit.uv, oh.footprint);
+                (self.color.0 * texel.r, self.color.1 * texel.g, self.color.2 * texel.b)
+            }
+            None => self.color,
+        };
+        if self.vertex_colors
+            && let Some(color) = oh.hit.color
+        {
+            albedo = (albedo.0 * color.0, albedo.1 * color.1, albedo.2 * color.2);
+        }
+
+        RGBA::new(albedo.0 * light.0, albedo.1 * light.1, albedo.2 * light.2, 1.0)
+    }
+}
+
+const fn default_diffuse_color() -> [f64; 3] { [0.8, 0.8, 0.8] }
@@ -0,0 +1,418 @@
+//! Intersections through Embree 4 with the `embree` feature, for the fastest CPU traversal of big
+//! scenes, the BVHs of the crate being used otherwise
+//!
+//! Meshes are triangle geometries intersected in their space. The scene's objects are the
+//! primitives of a user geometry, Embree finding the objects a ray may hit and calling back
+//! [`Object::intersect`] for them.
+//!
+//! The bindings are written for the default build of Embree, in particular with
+//! `RTC_MAX_INSTANCE_LEVEL_COUNT` 1 and instance arrays enabled, which the layout of the ray
+//! structures depends on.
+
+use crate::raytracer::Ray;
+use crate::raytracer::bvh::Aabb;
+use crate::raytracer::objects::{Object, ObjectHit};
+use std::ffi::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::LazyLock;
+
+const RTC_GEOMETRY_TYPE_TRIANGLE: c_uint = 0;
+const RTC_GEOMETRY_TYPE_USER: c_uint = 120;
+const RTC_BUFFER_TYPE_INDEX: c_uint = 0;
+const RTC_BUFFER_TYPE_VERTEX: c_uint = 1;
+const RTC_FORMAT_UINT3: c_uint = 0x5003;
+const RTC_FORMAT_FLOAT3: c_uint = 0x9003;
+const RTC_RAY_QUERY_FLAG_INCOHERENT: c_uint = 0;
+const RTC_FEATURE_FLAG_ALL: c_uint = 0xffffffff;
+const RTC_INVALID_GEOMETRY_ID: c_uint = c_uint::MAX;
+const RTC_MAX_INSTANCE_LEVEL_COUNT: usize = 1;
+
+static DEVICE: LazyLock<Device> = LazyLock::new(|| {
+    // SAFETY: a null configuration selects the default one
+    Device(unsafe { rtcNewDevice(ptr::null()) })
+});
+
+type RTCDevice = *mut c_void;
+type RTCScene = *mut c_void;
+type RTCGeometry = *mut c_void;
+type RTCBoundsFunction = extern "C" fn(args: *const RTCBoundsFunctionArguments);
+type RTCIntersectFunctionN = extern "C" fn(args: *const RTCIntersectFunctionNArguments);
+type RTCFilterFunctionN = extern "C" fn(args: *const c_void);
+
+#[link(name = "embree4")]
+unsafe extern "C" {
+    fn rtcNewDevice(config: *const c_char) -> RTCDevice;
+    fn rtcGetDeviceError(device: RTCDevice) -> c_uint;
+    fn rtcNewScene(device: RTCDevice) -> RTCScene;
+    fn rtcCommitScene(scene: RTCScene);
+    fn rtcReleaseScene(scene: RTCScene);
+    fn rtcNewGeometry(device: RTCDevice, geometry_type: c_uint) -> RTCGeometry;
+    fn rtcSetNewGeometryBuffer(
+        geometry: RTCGeometry,
+        buffer_type: c_uint,
+        slot: c_uint,
+        format: c_uint,
+        byte_stride: usize,
+        item_count: usize,
+    ) -> *mut c_void;
+    fn rtcSetGeometryUserPrimitiveCount(geometry: RTCGeometry, count: c_uint);
+    fn rtcSetGeometryBoundsFunction(geometry: RTCGeometry, bounds: RTCBoundsFunction, user_ptr: *mut c_void);
+    fn rtcSetGeometryIntersectFunction(geometry: RTCGeometry, intersect: RTCIntersectFunctionN);
+    fn rtcCommitGeometry(geometry: RTCGeometry);
+    fn rtcAttachGeometry(scene: RTCScene, geometry: RTCGeometry) -> c_uint;
+    fn rtcReleaseGeometry(geometry: RTCGeometry);
+    fn rtcIntersect1(scene: RTCScene, rayhit: *mut RTCRayHit, args: *mut RTCIntersectArguments);
+}
+
+#[repr(C, align(16))]
+struct RTCRay {
+    org_x: f32,
+    org_y: f32,
+    org_z: f32,
+    tnear: f32,
+    dir_x: f32,
+    dir_y: f32,
+    dir_z: f32,
+    time: f32,
+    tfar: f32,
+    mask: c_uint,
+    id: c_uint,
+    flags: c_uint,
+}
+
+#[repr(C, align(16))]
+struct RTCHit {
+    ng_x: f32,
+    ng_y: f32,
+    ng_z: f32,
+    u: f32,
+    v: f32,
+    prim_id: c_uint,
+    geom_id: c_uint,
+    inst_id: [c_uint; RTC_MAX_INSTANCE_LEVEL_COUNT],
+    inst_prim_id: [c_uint; RTC_MAX_INSTANCE_LEVEL_COUNT],
+}
+
+#[repr(C, align(16))]
+struct RTCRayHit {
+    ray: RTCRay,
+    hit: RTCHit,
+}
+
+#[repr(C)]
+struct RTCRayQueryContext {
+    inst_id: [c_uint; RTC_MAX_INSTANCE_LEVEL_COUNT],
+    inst_prim_id: [c_uint; RTC_MAX_INSTANCE_LEVEL_COUNT],
+}
+
+#[repr(C)]
+struct RTCIntersectArguments {
+    flags: c_uint,
+    feature_mask: c_uint,
+    context: *mut RTCRayQueryContext,
+    filter: Option<RTCFilterFunctionN>,
+    intersect: Option<RTCIntersectFunctionN>,
+}
+
+#[repr(C, align(16))]
+struct RTCBounds {
+    lower_x: f32,
+    lower_y: f32,
+    lower_z: f32,
+    align0: f32,
+    upper_x: f32,
+    upper_y: f32,
+    upper_z: f32,
+    align1: f32,
+}
+
+#[repr(C)]
+struct RTCBoundsFunctionArguments {
+    geometry_user_ptr: *mut c_void,
+    prim_id: c_uint,
+    time_step: c_uint,
+    bounds_o: *mut RTCBounds,
+}
+
+#[repr(C)]
+struct RTCIntersectFunctionNArguments {
+    valid: *mut c_int,
+    geometry_user_ptr: *mut c_void,
+    prim_id: c_uint,
+    context: *mut RTCRayQueryContext,
+    /// `RTCRayHitN` of `n` rays, laid out as an `RTCRayHit` for the single rays of `rtcIntersect1`
+    rayhit: *mut RTCRayHit,
+    n: c_uint,
+    geom_id: c_uint,
+}
+
+struct Device(RTCDevice);
+
+/// Committed scene, which Embree intersects from any thread
+struct Scene(RTCScene);
+
+/// Triangles of a mesh
+pub struct Triangles {
+    scene: Scene,
+}
+
+/// The scene's objects, see the module documentation
+pub struct Objects {
+    scene: Scene,
+}
+
+/// Query context passed to [`intersect_object`], Embree passing back the `RTCRayQueryContext` it
+/// starts with
+#[repr(C)]
+struct ObjectsContext<'a> {
+    base: RTCRayQueryContext,
+    objects: &'a [Object],
+    ray: Ray,
+    hit: Option<ObjectHit<'a>>,
+}
+
+// SAFETY: Embree devices and committed scenes can be used from any thread
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+unsafe impl Send for Scene {}
+unsafe impl Sync for Scene {}
+
+impl Scene {
+    fn new() -> Result<Self, String> {
+        if DEVICE.0.is_null() {
+            return Err("Failed to create the Embree device".to_string());
+        }
+        // SAFETY: the device is valid
+        Ok(Self(unsafe { rtcNewScene(DEVICE.0) }))
+    }
+
+    /// Creates a geometry, to be attached once set up
+    fn new_geometry(&self, geometry_type: c_uint) -> Result<RTCGeometry, String> {
+        // SAFETY: the device is valid
+        let geometry = unsafe { rtcNewGeometry(DEVICE.0, geometry_type) };
+        if geometry.is_null() {
+            return Err(device_error("creating a geometry"));
+        }
+        Ok(geometry)
+    }
+
+    /// Attaches and releases a committed geometry
+    fn attach(&self, geometry: RTCGeometry) {
+        // SAFETY: the geometry is valid and only referenced by the scene from then on
+        unsafe {
+            rtcCommitGeometry(geometry);
+            rtcAttachGeometry(self.0, geometry);
+            rtcReleaseGeometry(geometry);
+        }
+    }
+
+    fn commit(self) -> Result<Self, String> {
+        // SAFETY: the scene and the device are valid
+        match unsafe {
+            rtcCommitScene(self.0);
+            rtcGetDeviceError(DEVICE.0)
+        } {
+            0 => Ok(self),
+            err => Err(format!("Embree error {} while building a scene", err)),
+        }
+    }
+
+    /// Intersects the ray, setting the hit's geometry ID if it hits anything
+    fn intersect(&self, rayhit: &mut RTCRayHit, context: *mut RTCRayQueryContext) {
+        let mut args = RTCIntersectArguments {
+            flags: RTC_RAY_QUERY_FLAG_INCOHERENT,
+            feature_mask: RTC_FEATURE_FLAG_ALL,
+            context,
+            filter: None,
+            intersect: None,
+        };
+        // SAFETY: the scene is committed and the arguments valid for the duration of the call
+        unsafe { rtcIntersect1(self.0, rayhit, &mut args) };
+    }
+}
+
+impl Drop for Scene {
+    fn drop(&mut self) {
+        // SAFETY: the scene is valid and not used anymore
+        unsafe { rtcReleaseScene(self.0) };
+    }
+}
+
+/// Returns the device's error as an error while doing `action`
+fn device_error(action: &str) -> String {
+    // SAFETY: the device is valid
+    format!("Embree error {} while {}", unsafe { rtcGetDeviceError(DEVICE.0) }, action)
+}
+
+impl Triangles {
+    /// Builds the geometry of triangles given by the indices of their vertices' positions
+    pub fn new(positions: &[(f64, f64, f64)], triangles: impl Iterator<Item = [usize; 3]>) -> Result<Self, String> {
+        let index = |i: usize| c_uint::try_from(i).map_err(|_| "Too many vertices for Embree".to_string());
+        let triangles = triangles
+            .map(|triangle| Ok([index(triangle[0])?, index(triangle[1])?, index(triangle[2])?]))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let scene = Scene::new()?;
+        let geometry = scene.new_geometry(RTC_GEOMETRY_TYPE_TRIANGLE)?;
+        // SAFETY: the buffers are allocated by Embree for the given number of items and filled
+        // before the geometry is committed
+        unsafe {
+            let vertices = rtcSetNewGeometryBuffer(
+                geometry, RTC_BUFFER_TYPE_VERTEX, 0, RTC_FORMAT_FLOAT3, 12, positions.len(),
+            ) as *mut [f32; 3];
+            let indices = rtcSetNewGeometryBuffer(
+                geometry, RTC_BUFFER_TYPE_INDEX, 0, RTC_FORMAT_UINT3, 12, triangles.len(),
+            ) as *mut [c_uint; 3];
+            if vertices.is_null() || indices.is_null() {
+                let err = device_error("allocating the buffers of triangles");
+                rtcReleaseGeometry(geometry);
+                return Err(err);
+            }
+            for (i, p) in positions.iter().enumerate() {
+                vertices.add(i).write([p.0 as f32, p.1 as f32, p.2 as f32]);
+            }
+            ptr::copy_nonoverlapping(triangles.as_ptr(), indices, triangles.len());
+            scene.attach(geometry);
+        }
+
+        Ok(Self { scene: scene.commit()? })
+    }
+
+    /// Returns the distance, triangle index and barycentric coordinates of the closest hit, in
+    /// single precision
+    pub fn intersect(&self, ray: &Ray) -> Option<(f64, usize, f64, f64)> {
+        let mut rayhit = RTCRayHit::new(ray);
+        self.scene.intersect(&mut rayhit, &mut RTCRayQueryContext::new());
+        (rayhit.hit.geom_id != RTC_INVALID_GEOMETRY_ID).then_some((
+            rayhit.ray.tfar as f64,
+            rayhit.hit.prim_id as usize,
+            rayhit.hit.u as f64,
+            rayhit.hit.v as f64,
+        ))
+    }
+}
+
+impl Objects {
+    pub fn new(objects: &[Object]) -> Result<Self, String> {
+        let mut bounds = objects.iter().map(world_bounds).collect::<Vec<_>>();
+        let count = c_uint::try_from(objects.len()).map_err(|_| "Too many objects for Embree".to_string())?;
+
+        let scene = Scene::new()?;
+        let geometry = scene.new_geometry(RTC_GEOMETRY_TYPE_USER)?;
+        // SAFETY: the bounds are only read while the scene is committed
+        unsafe {
+            rtcSetGeometryUserPrimitiveCount(geometry, count);
+            rtcSetGeometryBoundsFunction(geometry, object_bounds, bounds.as_mut_ptr() as *mut c_void);
+            rtcSetGeometryIntersectFunction(geometry, intersect_object);
+            scene.attach(geometry);
+        }
+
+        Ok(Self { scene: scene.commit()? })
+    }
+
+    /// Returns the closest hit along the ray of the objects the scene was built from
+    pub fn closest<'a>(&self, objects: &'a [Object], ray: &Ray) -> Option<ObjectHit<'a>> {
+        let mut context = ObjectsContext {
+            base: RTCRayQueryContext::new(),
+            objects,
+            ray: *ray,
+            hit: None,
+        };
+        // The callbacks get back the whole context from the pointer to its first field
+        self.scene.intersect(&mut RTCRayHit::new(ray), &mut context as *mut ObjectsContext as *mut RTCRayQueryContext);
+        context.hit
+    }
+}
+
+impl RTCRayHit {
+    fn new(ray: &Ray) -> Self {
+        Self {
+            ray: RTCRay {
+                org_x: ray.origin.0 as f32,
+                org_y: ray.origin.1 as f32,
+                org_z: ray.origin.2 as f32,
+                // Rounded down so that the hits the exact distance keeps aren't culled
+                tnear: (ray.min_distance as f32).next_down().max(0.0),
+                dir_x: ray.direction.0 as f32,
+                dir_y: ray.direction.1 as f32,
+                dir_z: ray.direction.2 as f32,
+                time: 0.0,
+                tfar: f32::INFINITY,
+                mask: c_uint::MAX,
+                id: 0,
+                flags: 0,
+            },
+            hit: RTCHit {
+                ng_x: 0.0,
+                ng_y: 0.0,
+                ng_z: 0.0,
+                u: 0.0,
+                v: 0.0,
+                prim_id: RTC_INVALID_GEOMETRY_ID,
+                geom_id: RTC_INVALID_GEOMETRY_ID,
+                inst_id: [RTC_INVALID_GEOMETRY_ID; RTC_MAX_INSTANCE_LEVEL_COUNT],
+                inst_prim_id: [RTC_INVALID_GEOMETRY_ID; RTC_MAX_INSTANCE_LEVEL_COUNT],
+            },
+        }
+    }
+}
+
+impl RTCRayQueryContext {
+    const fn new() -> Self {
+        Self {
+            inst_id: [RTC_INVALID_GEOMETRY_ID; RTC_MAX_INSTANCE_LEVEL_COUNT],
+            inst_prim_id: [RTC_INVALID_GEOMETRY_ID; RTC_MAX_INSTANCE_LEVEL_COUNT],
+        }
+    }
+}
+
+/// Bounds of an object in the world, rounded outwards to single precision
+fn world_bounds(object: &Object) -> RTCBounds {
+    let Aabb { min, max } = object.local_bounds();
+    let bounds = [min.0, max.0].into_iter()
+        .flat_map(|x| [min.1, max.1].into_iter().map(move |y| (x, y)))
+        .flat_map(|(x, y)| [min.2, max.2].into_iter().map(move |z| (x, y, z)))
+        .fold(Aabb::empty(), |bounds, corner| bounds.union(&Aabb::point(object.transform().apply(corner))));
+    RTCBounds {
+        lower_x: (bounds.min.0 as f32).next_down(),
+        lower_y: (bounds.min.1 as f32).next_down(),
+        lower_z: (bounds.min.2 as f32).next_down(),
+        align0: 0.0,
+        upper_x: (bounds.max.0 as f32).next_up(),
+        upper_y: (bounds.max.1 as f32).next_up(),
+        upper_z: (bounds.max.2 as f32).next_up(),
+        align1: 0.0,
+    }
+}
+
+extern "C" fn object_bounds(args: *const RTCBoundsFunctionArguments) {
+    // SAFETY: the user pointer is the bounds of the objects, one per primitive
+    unsafe {
+        let args = &*args;
+        let bounds = args.geometry_user_ptr as *const RTCBounds;
+        ptr::copy_nonoverlapping(bounds.add(args.prim_id as usize), args.bounds_o, 1);
+    }
+}
+
+extern "C" fn intersect_object(args: *const RTCIntersectFunctionNArguments) {
+    // SAFETY: `rtcIntersect1` calls back for a single ray with the context of `Objects::closest`
+    let (args, context, rayhit) = unsafe {
+        let args = &*args;
+        if *args.valid == 0 {
+            return;
+        }
+        (args, &mut *(args.context as *mut ObjectsContext), &mut *args.rayhit)
+    };
+
+    let Some(hit) = context.objects[args.prim_id as usize].intersect(&context.ray) else {
+        return;
+    };
+    if context.hit.is_some_and(|closest| closest.hit.distance <= hit.hit.distance) {
+        return;
+    }
+    // Rounded up so that the objects hit just as far are still tried
+    rayhit.ray.tfar = (hit.hit.distance as f32).next_up();
+    rayhit.hit.prim_id = args.prim_id;
+    rayhit.hit.geom_id = args.geom_id;
+    context.hit = Some(hit);
+}
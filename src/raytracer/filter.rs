@@ -0,0 +1,63 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Standard deviation of the gaussian filter, in pixels
+const GAUSSIAN_SIGMA: f64 = 0.5;
+
+/// Reconstruction filter weighting the samples of the pixels around them
+///
+/// Filters wider than a pixel splat each sample into the neighboring pixels, which smooths edges
+/// at the same sample count. Samples from neighboring tiles are then added in the order they are
+/// rendered, so renders with the same seed can differ in the last bits of their colors.
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Average of the samples taken in each pixel
+    #[default]
+    Box,
+    /// Linear falloff over a radius of one pixel
+    Tent,
+    /// Gaussian falloff truncated at a radius of 1.5 pixels
+    Gaussian,
+    /// Blackman-Harris window over a radius of 2 pixels, sharper than the gaussian
+    BlackmanHarris,
+}
+
+impl Filter {
+    /// Distance from the center of a pixel, in pixels, beyond which samples don't contribute to it
+    pub(super) fn radius(self) -> f64 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::BlackmanHarris => 2.0,
+        }
+    }
+
+    /// Returns the weight of a sample at an offset from the center of a pixel, in pixels
+    pub(super) fn weight(self, dx: f64, dy: f64) -> f64 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(self, d: f64) -> f64 {
+        let radius = self.radius();
+        if d.abs() > radius {
+            return 0.0;
+        }
+
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - d.abs(),
+            Filter::Gaussian => {
+                let gaussian = |d: f64| (-d * d / (2.0 * GAUSSIAN_SIGMA * GAUSSIAN_SIGMA)).exp();
+                // Offset so that the weights reach zero at the radius
+                (gaussian(d) - gaussian(radius)).max(0.0)
+            }
+            Filter::BlackmanHarris => {
+                let t = 2.0 * PI * (d + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+}
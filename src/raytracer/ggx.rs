@@ -0,0 +1,217 @@
+use crate::raytracer::{Ray, RayType, RGBA};
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::thin_film::{self, ThinFilm};
+use crate::raytracer::utils::{random, vec3add, vec3cross, vec3dot, vec3norm, vec3scale, vec3sub};
+use serde::Deserialize;
+use serde_json::Value;
+use std::f64::consts::PI;
+
+/// Distribution width below which reflections are traced as mirror reflections rather than glossy
+const MIRROR_ALPHA: f64 = 1e-4;
+
+/// Glossy metal with a GGX microfacet distribution
+///
+/// The roughness can differ along the tangent (`roughness_u`) and the bitangent (`roughness_v`)
+/// of the surface, stretching the highlights like on brushed metal.
+pub struct GgxMaterial {
+    /// Reflectance at normal incidence
+    color: (f64, f64, f64),
+    microfacets: Microfacets,
+    /// Film coating the metal, and the indices of refraction the color is approximated by under it
+    thin_film: Option<(ThinFilm, (f64, f64, f64))>,
+}
+
+/// GGX distribution of microfacet normals, shared with rough dielectrics
+#[derive(Clone, Copy)]
+pub(super) struct Microfacets {
+    /// Distribution widths along the tangent and the bitangent
+    alpha: (f64, f64),
+}
+
+/// Orthonormal shading frame around a hit's normal, the tangent along the surface's U direction
+pub(super) struct Frame {
+    tangent: (f64, f64, f64),
+    bitangent: (f64, f64, f64),
+    normal: (f64, f64, f64),
+}
+
+#[derive(Deserialize)]
+struct GgxData {
+    #[serde(default = "default_ggx_color")]
+    color: [f64; 3],
+    #[serde(default = "default_ggx_roughness")]
+    roughness: f64,
+    roughness_u: Option<f64>,
+    roughness_v: Option<f64>,
+    thin_film: Option<ThinFilm>,
+}
+
+impl GgxMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let data: GgxData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid ggx material: {}", err))?;
+        if let Some(thin_film) = &data.thin_film {
+            thin_film.check().map_err(|err| format!("Invalid ggx material: {}", err))?;
+        }
+        let [r, g, b] = data.color;
+
+        Ok(Self {
+            color: (r, g, b),
+            microfacets: Microfacets::new(
+                data.roughness_u.unwrap_or(data.roughness),
+                data.roughness_v.unwrap_or(data.roughness),
+            ),
+            thin_film: data.thin_film.map(|thin_film| {
+                (thin_film, (thin_film::base_ior(r), thin_film::base_ior(g), thin_film::base_ior(b)))
+            }),
+        })
+    }
+
+    /// Creates a material with the same roughness along the tangent and the bitangent
+    pub(super) fn isotropic(color: (f64, f64, f64), roughness: f64) -> Self {
+        Self { color, microfacets: Microfacets::new(roughness, roughness), thin_film: None }
+    }
+}
+
+impl Microfacets {
+    /// Creates a distribution from perceptually linear roughnesses along the tangent and the bitangent
+    pub(super) fn new(roughness_u: f64, roughness_v: f64) -> Self {
+        Self { alpha: (alpha(roughness_u), alpha(roughness_v)) }
+    }
+
+    /// Whether the distribution is narrow enough for mirror reflections rather than glossy ones
+    pub(super) fn is_mirror(&self) -> bool {
+        self.alpha.0.max(self.alpha.1) < MIRROR_ALPHA
+    }
+
+    /// Samples a microfacet normal visible from `view`, in the local frame (Heitz 2018)
+    pub(super) fn sample_normal(&self, view: (f64, f64, f64), (u1, u2): (f64, f64)) -> (f64, f64, f64) {
+        let (ax, ay) = self.alpha;
+
+        // Stretch the view to the hemisphere configuration
+        let vh = vec3norm((ax * view.0, ay * view.1, view.2));
+        let len2 = vh.0 * vh.0 + vh.1 * vh.1;
+        let t1 = if len2 > 0.0 { vec3scale((-vh.1, vh.0, 0.0), 1.0 / len2.sqrt()) } else { (1.0, 0.0, 0.0) };
+        let t2 = vec3cross(vh, t1);
+
+        // Sample the projected area of the visible hemisphere
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.2);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = vec3add(
+            vec3add(vec3scale(t1, p1), vec3scale(t2, p2)),
+            vec3scale(vh, (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt()),
+        );
+
+        // Unstretch
+        vec3norm((ax * nh.0, ay * nh.1, nh.2.max(0.0)))
+    }
+
+    /// Normal distribution function for microfacet normal `m` in the local frame
+    pub(super) fn d(&self, m: (f64, f64, f64)) -> f64 {
+        let (ax, ay) = self.alpha;
+        let t = (m.0 / ax).powi(2) + (m.1 / ay).powi(2) + m.2 * m.2;
+        1.0 / (PI * ax * ay * t * t)
+    }
+
+    /// Smith masking function for direction `w` in the local frame
+    pub(super) fn g1(&self, w: (f64, f64, f64)) -> f64 {
+        let (ax, ay) = self.alpha;
+        let tan2 = (ax * ax * w.0 * w.0 + ay * ay * w.1 * w.1) / (w.2 * w.2);
+        let lambda = ((1.0 + tan2).sqrt() - 1.0) / 2.0;
+        1.0 / (1.0 + lambda)
+    }
+}
+
+impl MaterialType for GgxMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let frame = Frame::new(oh);
+        let to_local = |w| frame.to_local(w);
+        let microfacets = &self.microfacets;
+
+        let mut view = to_local(vec3scale(oh.ray.direction, -1.0));
+        view.2 = view.2.max(1e-6);
+        let view = vec3norm(view);
+
+        // Schlick's approximation, or the interferences of the thin film if any
+        let reflectance = |cos: f64| match &self.thin_film {
+            Some((thin_film, base_ior)) => thin_film.reflectance(cos.clamp(0.0, 1.0), 1.0, *base_ior, oh.ray.wavelengths),
+            None => {
+                let fresnel = (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+                let fresnel_color = |f0: f64| f0 + (1.0 - f0) * fresnel;
+                (fresnel_color(self.color.0), fresnel_color(self.color.1), fresnel_color(self.color.2))
+            }
+        };
+
+        // Lights, which reflected rays can't hit, are evaluated with the full BRDF
+        let mut direct = (0.0, 0.0, 0.0);
+        for sample in ctx.sample_lights(oh.hit.intersection) {
+            let light = to_local(sample.direction);
+            if light.2 <= 0.0 {
+                continue;
+            }
+            let m = vec3norm(vec3add(view, light));
+            let fresnel = reflectance(vec3dot(view, m));
+            let f = microfacets.d(m) * microfacets.g1(view) * microfacets.g1(light) / (4.0 * view.2 * light.2) * light.2;
+            direct = vec3add(direct, (
+                sample.radiance.0 * f * fresnel.0,
+                sample.radiance.1 * f * fresnel.1,
+                sample.radiance.2 * f * fresnel.2,
+            ));
+        }
+
+        let m = microfacets.sample_normal(view, random());
+        let light = vec3sub(vec3scale(m, 2.0 * vec3dot(view, m)), view);
+        if light.2 <= 0.0 {
+            return RGBA::new(direct.0, direct.1, direct.2, 1.0);
+        }
+
+        // With visible normal sampling the estimator weight is the Fresnel term times the light's masking
+        let fresnel = reflectance(vec3dot(view, m));
+        let weight = microfacets.g1(light);
+        let color = ctx.trace(Ray {
+            ray_type: if microfacets.is_mirror() { RayType::Reflection } else { RayType::Glossy },
+            origin: oh.hit.intersection,
+            direction: frame.to_world(light),
+            ..oh.ray
+        });
+
+        RGBA::new(
+            direct.0 + color.r * fresnel.0 * weight,
+            direct.1 + color.g * fresnel.1 * weight,
+            direct.2 + color.b * fresnel.2 * weight,
+            1.0,
+        )
+    }
+}
+
+impl Frame {
+    /// Creates the frame from the hit's normal and its tangent orthonormalized around it
+    pub(super) fn new(oh: &ObjectHit) -> Self {
+        let normal = oh.hit.normal;
+        let mut tangent = vec3norm(vec3sub(oh.hit.tangent, vec3scale(normal, vec3dot(oh.hit.tangent, normal))));
+        if tangent.0.is_nan() {
+            tangent = vec3norm(vec3cross(normal, if normal.0.abs() < 0.9 { (1.0, 0.0, 0.0) } else { (0.0, 1.0, 0.0) }));
+        }
+        Self { tangent, bitangent: vec3cross(normal, tangent), normal }
+    }
+
+    pub(super) fn to_local(&self, w: (f64, f64, f64)) -> (f64, f64, f64) {
+        (vec3dot(w, self.tangent), vec3dot(w, self.bitangent), vec3dot(w, self.normal))
+    }
+
+    pub(super) fn to_world(&self, w: (f64, f64, f64)) -> (f64, f64, f64) {
+        vec3add(vec3add(vec3scale(self.tangent, w.0), vec3scale(self.bitangent, w.1)), vec3scale(self.normal, w.2))
+    }
+}
+
+/// Returns the distribution width of a perceptually linear roughness
+fn alpha(roughness: f64) -> f64 {
+    roughness.clamp(1e-3, 1.0).powi(2)
+}
+
+const fn default_ggx_color() -> [f64; 3] { [1.0, 1.0, 1.0] }
+const fn default_ggx_roughness() -> f64 { 0.5 }
@@ -0,0 +1,299 @@
+use crate::raytracer::RGBA;
+use crate::raytracer::diffuse::DiffuseMaterial;
+use crate::raytracer::ggx::GgxMaterial;
+use crate::raytracer::lights;
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::scene::SceneTexture;
+use crate::raytracer::spectrum::Spectrum;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::utils::vec3dot;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Color = (f64, f64, f64);
+
+/// Material described by a graph of nodes, evaluated for each hit
+///
+/// Every node outputs a color, numbers being gray colors, and the BSDF nodes output the light
+/// they reflect. Node inputs are either constants or the names of other nodes:
+///
+/// ```json
+/// {
+///   "type": "graph",
+///   "nodes": {
+///     "base": { "type": "diffuse", "color": [0.8, 0.1, 0.1] },
+///     "coat": { "type": "glossy", "roughness": 0.1 },
+///     "fresnel": { "type": "fresnel", "ior": 1.5 },
+///     "out": { "type": "mix", "factor": "fresnel", "a": "base", "b": "coat" }
+///   },
+///   "output": "out"
+/// }
+/// ```
+pub struct GraphMaterial {
+    /// Nodes ordered after their inputs
+    nodes: Vec<Node>,
+    output: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Input {
+    Constant(Color),
+    Node(usize),
+}
+
+enum Node {
+    /// Color of a texture at the hit's UVs
+    Texture(Arc<Texture>),
+    /// Color interpolated from the mesh's vertex colors, white on other surfaces
+    VertexColor,
+    Math { op: MathOp, a: Input, b: Input },
+    /// Schlick's approximation of the reflectance of a dielectric
+    Fresnel { f0: f64 },
+    /// Linear interpolation from `a` to `b`, per channel
+    Mix { factor: Input, a: Input, b: Input },
+    Diffuse { color: Input },
+    /// GGX reflection, the roughness is read from the red channel
+    Glossy { color: Input, roughness: Input },
+    Emission { color: Input, strength: f64 },
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MathOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Minimum,
+    Maximum,
+}
+
+#[derive(Deserialize)]
+struct GraphData {
+    nodes: HashMap<String, NodeData>,
+    output: String,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum InputData {
+    Number(f64),
+    Color([f64; 3]),
+    Node(String),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NodeData {
+    Texture {
+        texture: SceneTexture,
+    },
+    VertexColor,
+    Math {
+        op: MathOp,
+        a: InputData,
+        #[serde(default = "default_math_input")]
+        b: InputData,
+    },
+    Fresnel {
+        #[serde(default = "default_fresnel_ior")]
+        ior: f64,
+    },
+    Mix {
+        #[serde(default = "default_mix_factor")]
+        factor: InputData,
+        a: InputData,
+        b: InputData,
+    },
+    Diffuse {
+        #[serde(default = "default_diffuse_color")]
+        color: InputData,
+    },
+    Glossy {
+        #[serde(default = "default_glossy_color")]
+        color: InputData,
+        #[serde(default = "default_glossy_roughness")]
+        roughness: InputData,
+    },
+    Emission {
+        #[serde(default = "default_emission_color")]
+        color: InputData,
+        /// Color temperature in kelvins replacing the color, as the light of a black body
+        #[serde(default)]
+        temperature: Option<f64>,
+        #[serde(default = "default_emission_strength")]
+        strength: f64,
+    },
+}
+
+/// Converts the named nodes to a list ordered after their inputs
+struct GraphBuilder<'a> {
+    data: &'a HashMap<String, NodeData>,
+    nodes: Vec<Node>,
+    indices: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+}
+
+impl GraphMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let data: GraphData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid graph material: {}", err))?;
+
+        let mut builder = GraphBuilder {
+            data: &data.nodes,
+            nodes: Vec::new(),
+            indices: HashMap::new(),
+            stack: Vec::new(),
+        };
+        let output = builder.build(&data.output)?;
+
+        Ok(Self { nodes: builder.nodes, output })
+    }
+
+    fn evaluate(&self, index: usize, oh: &ObjectHit, ctx: &ShadeContext, cache: &mut [Option<Color>]) -> Color {
+        if let Some(color) = cache[index] {
+            return color;
+        }
+
+        let mut input = |input: Input| match input {
+            Input::Constant(color) => color,
+            Input::Node(index) => self.evaluate(index, oh, ctx, cache),
+        };
+        let color = match &self.nodes[index] {
+            Node::Texture(texture) => {
+                let texel = texture.sample(oh.hit.uv, oh.footprint);
+                (texel.r, texel.g, texel.b)
+            }
+            Node::VertexColor => oh.hit.color.unwrap_or((1.0, 1.0, 1.0)),
+            Node::Math { op, a, b } => {
+                let (a, b) = (input(*a), input(*b));
+                (op.apply(a.0, b.0), op.apply(a.1, b.1), op.apply(a.2, b.2))
+            }
+            Node::Fresnel { f0 } => {
+                let cos = -vec3dot(oh.ray.direction, oh.hit.normal);
+                let fresnel = f0 + (1.0 - f0) * (1.0 - cos.clamp(0.0, 1.0)).powi(5);
+                (fresnel, fresnel, fresnel)
+            }
+            Node::Mix { factor, a, b } => {
+                let factor = input(*factor);
+                let factor = (factor.0.clamp(0.0, 1.0), factor.1.clamp(0.0, 1.0), factor.2.clamp(0.0, 1.0));
+                // Only the inputs that contribute are evaluated, BSDF nodes trace rays
+                let a = if factor.0 < 1.0 || factor.1 < 1.0 || factor.2 < 1.0 { input(*a) } else { (0.0, 0.0, 0.0) };
+                let b = if factor.0 > 0.0 || factor.1 > 0.0 || factor.2 > 0.0 { input(*b) } else { (0.0, 0.0, 0.0) };
+                (
+                    a.0 + (b.0 - a.0) * factor.0,
+                    a.1 + (b.1 - a.1) * factor.1,
+                    a.2 + (b.2 - a.2) * factor.2,
+                )
+            }
+            Node::Diffuse { color } => {
+                let shaded = DiffuseMaterial::from_color(input(*color)).shade(oh, ctx);
+                (shaded.r, shaded.g, shaded.b)
+            }
+            Node::Glossy { color, roughness } => {
+                let (color, roughness) = (input(*color), input(*roughness));
+                let shaded = GgxMaterial::isotropic(color, roughness.0).shade(oh, ctx);
+                (shaded.r, shaded.g, shaded.b)
+            }
+            // Emission isn't in any light group
+            Node::Emission { .. } if !lights::ungrouped_light() => (0.0, 0.0, 0.0),
+            Node::Emission { color, strength } => {
+                let color = input(*color);
+                (color.0 * strength, color.1 * strength, color.2 * strength)
+            }
+        };
+
+        cache[index] = Some(color);
+        color
+    }
+}
+
+impl MaterialType for GraphMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let mut cache = vec![None; self.nodes.len()];
+        let (r, g, b) = self.evaluate(self.output, oh, ctx, &mut cache);
+        RGBA::new(r, g, b, 1.0)
+    }
+}
+
+impl<'a> GraphBuilder<'a> {
+    /// Adds the node with the given name after its inputs, returning its index
+    fn build(&mut self, name: &'a str) -> Result<usize, String> {
+        if let Some(&index) = self.indices.get(name) {
+            return Ok(index);
+        }
+        if self.stack.contains(&name) {
+            return Err(format!("Graph node {} references itself", name));
+        }
+        let data = self.data.get(name)
+            .ok_or_else(|| format!("Graph node {} not found", name))?;
+
+        self.stack.push(name);
+        let node = match data {
+            NodeData::Texture { texture } => Node::Texture(texture.load()?),
+            NodeData::VertexColor => Node::VertexColor,
+            NodeData::Math { op, a, b } => Node::Math { op: *op, a: self.input(a)?, b: self.input(b)? },
+            NodeData::Fresnel { ior } => Node::Fresnel { f0: ((ior - 1.0) / (ior + 1.0)).powi(2) },
+            NodeData::Mix { factor, a, b } => Node::Mix {
+                factor: self.input(factor)?,
+                a: self.input(a)?,
+                b: self.input(b)?,
+            },
+            NodeData::Diffuse { color } => Node::Diffuse { color: self.input(color)? },
+            NodeData::Glossy { color, roughness } => Node::Glossy {
+                color: self.input(color)?,
+                roughness: self.input(roughness)?,
+            },
+            NodeData::Emission { color, temperature, strength } => {
+                let color = match *temperature {
+                    Some(temperature) if !temperature.is_finite() || temperature <= 0.0 => {
+                        return Err(format!("Graph node {} has an invalid temperature {}", name, temperature));
+                    }
+                    Some(temperature) => Input::Constant(Spectrum::blackbody(temperature).to_rgb()),
+                    None => self.input(color)?,
+                };
+                Node::Emission { color, strength: *strength }
+            }
+        };
+        self.stack.pop();
+
+        self.nodes.push(node);
+        self.indices.insert(name, self.nodes.len() - 1);
+        Ok(self.nodes.len() - 1)
+    }
+
+    fn input(&mut self, input: &'a InputData) -> Result<Input, String> {
+        match input {
+            InputData::Number(value) => Ok(Input::Constant((*value, *value, *value))),
+            InputData::Color([r, g, b]) => Ok(Input::Constant((*r, *g, *b))),
+            InputData::Node(name) => Ok(Input::Node(self.build(name)?)),
+        }
+    }
+}
+
+impl MathOp {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            MathOp::Add => a + b,
+            MathOp::Subtract => a - b,
+            MathOp::Multiply => a * b,
+            MathOp::Divide => if b != 0.0 { a / b } else { 0.0 },
+            MathOp::Power => a.powf(b),
+            MathOp::Minimum => a.min(b),
+            MathOp::Maximum => a.max(b),
+        }
+    }
+}
+
+const fn default_math_input() -> InputData { InputData::Number(0.0) }
+const fn default_fresnel_ior() -> f64 { 1.5 }
+const fn default_mix_factor() -> InputData { InputData::Number(0.5) }
+const fn default_diffuse_color() -> InputData { InputData::Color([0.8, 0.8, 0.8]) }
+const fn default_glossy_color() -> InputData { InputData::Color([1.0, 1.0, 1.0]) }
+const fn default_glossy_roughness() -> InputData { InputData::Number(0.5) }
+const fn default_emission_color() -> InputData { InputData::Color([1.0, 1.0, 1.0]) }
+const fn default_emission_strength() -> f64 { 1.0 }
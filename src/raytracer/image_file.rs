@@ -0,0 +1,362 @@
+use crate::raytracer::{to_colors, Output, RGBA};
+use crate::raytracer::textures::Texture;
+use crate::raytracer::tile::{self, Tile, TileOrder};
+use image::{ImageBuffer, Rgba};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Channels of EXR files besides the light groups'
+const EXR_CHANNELS: [(&str, Channel); 8] = [
+    ("A", Channel::Color(3)),
+    ("B", Channel::Color(2)),
+    ("G", Channel::Color(1)),
+    ("P.x", Channel::Position(0)),
+    ("P.y", Channel::Position(1)),
+    ("P.z", Channel::Position(2)),
+    ("R", Channel::Color(0)),
+    ("Z", Channel::Depth),
+];
+
+/// Image file the render is saved to, once completed or as tiles complete for EXR files
+pub struct ImageFile {
+    path: String,
+    format: ImageFormat,
+    /// EXR file being written, between `begin` and the end of the render
+    exr: Mutex<Option<ExrFile>>,
+    /// Image the render is composited over, if any
+    backplate: Option<Arc<Texture>>,
+}
+
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    /// PNG with 8 bits per channel
+    Png,
+    /// PNG with 16 bits per channel
+    Png16,
+    /// Uncompressed TIFF with 16 bits per channel
+    Tiff16,
+    /// Uncompressed tiled OpenEXR with 32 bits float channels, written as tiles complete, with the
+    /// depth (`Z`), world position (`P.x`, `P.y`, `P.z`) and light group (`<group>.R`, ...) passes
+    Exr,
+}
+
+/// What an EXR channel stores
+#[derive(Clone, Copy)]
+enum Channel {
+    /// Component of the premultiplied color, in RGBA order
+    Color(usize),
+    Position(usize),
+    Depth,
+    /// Light group, then component of its color
+    LightGroup(usize, usize),
+}
+
+/// Tiled EXR file, the offset of each tile is filled in once it is written so that the file stays
+/// readable while rendering
+struct ExrFile {
+    file: File,
+    tile_size: u32,
+    tiles_x: u32,
+    /// Position of the tile offset table in the file
+    offsets: u64,
+    /// In the alphabetical order they are stored in
+    channels: Vec<(String, Channel)>,
+    /// Whether tiles split at the end of a pass were left out, to write once the render completes
+    partial: bool,
+}
+
+impl ImageFile {
+    /// Creates the image file settings, guessing the format from the path's extension if not given
+    pub fn new(path: String, format: Option<ImageFormat>) -> Result<Self, String> {
+        let format = match format {
+            Some(format) => format,
+            None => {
+                let extension = Path::new(&path)
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .map(str::to_ascii_lowercase);
+                match extension.as_deref() {
+                    Some("png") => ImageFormat::Png,
+                    Some("tif" | "tiff") => ImageFormat::Tiff16,
+                    Some("exr") => ImageFormat::Exr,
+                    _ => return Err(format!("Unknown output image format for {}, expected a .png, .tiff or .exr file", path)),
+                }
+            }
+        };
+
+        Ok(Self { path, format, exr: Mutex::new(None), backplate: None })
+    }
+
+    pub(super) fn with_backplate(mut self, backplate: Arc<Texture>) -> Self {
+        self.backplate = Some(backplate);
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Creates the file before rendering for formats written progressively
+    pub fn begin(&self, output: &Output) -> Result<(), String> {
+        if let ImageFormat::Exr = self.format {
+            let file = ExrFile::create(&self.path, output)
+                .map_err(|err| format!("Failed to create {}: {}", self.path, err))?;
+            *self.exr.lock().unwrap() = Some(file);
+        }
+        Ok(())
+    }
+
+    /// Writes a completed tile, for formats written progressively
+    pub(super) fn write_tile(&self, tile: &Tile, colors: &[RGBA], output: &Output) -> Result<(), String> {
+        match self.exr.lock().unwrap().as_mut() {
+            Some(exr) => exr.write_tile(tile, colors, output)
+                .map_err(|err| format!("Failed to write tile to {}: {}", self.path, err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the completed render, for formats which aren't written progressively
+    ///
+    /// Light groups are written next to the render for formats without channels for them, to
+    /// `<name>.<group>.<extension>`.
+    pub fn write(&self, output: &Output) -> Result<(), String> {
+        if let ImageFormat::Exr = self.format {
+            return match self.exr.lock().unwrap().take() {
+                // Tiles were written as they completed their first pass, before the post effects, the
+                // samples splatted by their neighbors and the backplate
+                Some(exr) if exr.partial || output.is_post_processed() || output.sampled_past_first_pass() || output.splats() || self.backplate.is_some() => {
+                    rewrite_exr(&self.path, output, &self.pixels(output))
+                        .map_err(|err| format!("Failed to save render to {}: {}", self.path, err))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        write_image(&self.path, self.format, output.width, output.height, &to_colors(self.pixels(output)))
+            .map_err(|err| format!("Failed to save render to {}: {}", self.path, err))?;
+        for (group, name) in output.light_groups().into_iter().enumerate() {
+            let path = light_group_path(&self.path, name);
+            let colors = to_colors(output.light_group_pixels(group));
+            write_image(&path, self.format, output.width, output.height, &colors)
+                .map_err(|err| format!("Failed to save light group {} to {}: {}", name, path, err))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the pixels saved, the render's composited over the backplate if any
+    fn pixels(&self, output: &Output) -> Vec<[f32; 4]> {
+        let mut pixels = output.pixels();
+        let Some(backplate) = &self.backplate else {
+            return pixels;
+        };
+
+        let (width, height) = (output.width as f64, output.height as f64);
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let (x, y) = ((i as u32 % output.width) as f64, (i as u32 / output.width) as f64);
+            let plate = backplate.sample(((x + 0.5) / width, 1.0 - (y + 0.5) / height), 0.0).premultiply();
+            let [r, g, b, a] = pixel.map(f64::from);
+            let color = RGBA::new(r, g, b, a).over(plate);
+            *pixel = [color.r, color.g, color.b, color.a].map(|c| c as f32);
+        }
+        pixels
+    }
+}
+
+/// Writes colors to an image file in a format which isn't written progressively
+fn write_image(path: &str, format: ImageFormat, width: u32, height: u32, colors: &[[u16; 4]]) -> Result<(), String> {
+    match format {
+        ImageFormat::Png => {
+            let pixels = colors.iter()
+                .flat_map(|color| color.map(|c| ((c as u32 * 255 + 32767) / 65535) as u8))
+                .collect();
+            ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(width, height, pixels)
+                .unwrap()
+                .save_with_format(path, image::ImageFormat::Png)
+                .map_err(|err| err.to_string())
+        }
+        ImageFormat::Png16 => {
+            let pixels = colors.iter().flatten().copied().collect();
+            ImageBuffer::<Rgba<u16>, Vec<u16>>::from_raw(width, height, pixels)
+                .unwrap()
+                .save_with_format(path, image::ImageFormat::Png)
+                .map_err(|err| err.to_string())
+        }
+        ImageFormat::Tiff16 => write_tiff16(path, width, height, colors).map_err(|err| err.to_string()),
+        ImageFormat::Exr => unreachable!("EXR files are written by tiles"),
+    }
+}
+
+/// Returns the path a light group is saved to next to the render's, `render.png` becoming
+/// `render.<group>.png`
+fn light_group_path(path: &str, group: &str) -> String {
+    let path = Path::new(path);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => path.with_extension(format!("{}.{}", group, extension)),
+        None => path.with_extension(group),
+    }.to_string_lossy().into_owned()
+}
+
+/// Writes a baseline little-endian TIFF with a single uncompressed strip of 16 bits RGBA pixels
+fn write_tiff16(path: &str, width: u32, height: u32, colors: &[[u16; 4]]) -> std::io::Result<()> {
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+    const ENTRIES: u32 = 11;
+    // Header, then the directory, then the bits per sample which don't fit in their entry, then the pixels
+    let bits_offset = 8 + 2 + ENTRIES * 12 + 4;
+    let pixels_offset = bits_offset + 4 * 2;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"II*\0")?;
+    file.write_all(&8u32.to_le_bytes())?;
+
+    file.write_all(&(ENTRIES as u16).to_le_bytes())?;
+    let mut entry = |tag: u16, kind: u16, count: u32, value: u32| -> std::io::Result<()> {
+        file.write_all(&tag.to_le_bytes())?;
+        file.write_all(&kind.to_le_bytes())?;
+        file.write_all(&count.to_le_bytes())?;
+        // Single short values are stored in the first bytes of the value
+        match (kind, count) {
+            (SHORT, 1) => file.write_all(&(value as u16).to_le_bytes()).and_then(|_| file.write_all(&[0, 0])),
+            _ => file.write_all(&value.to_le_bytes()),
+        }
+    };
+    entry(256, LONG, 1, width)?; // ImageWidth
+    entry(257, LONG, 1, height)?; // ImageLength
+    entry(258, SHORT, 4, bits_offset)?; // BitsPerSample
+    entry(259, SHORT, 1, 1)?; // Compression: none
+    entry(262, SHORT, 1, 2)?; // PhotometricInterpretation: RGB
+    entry(273, LONG, 1, pixels_offset)?; // StripOffsets
+    entry(277, SHORT, 1, 4)?; // SamplesPerPixel
+    entry(278, LONG, 1, height)?; // RowsPerStrip
+    entry(279, LONG, 1, width * height * 8)?; // StripByteCounts
+    entry(284, SHORT, 1, 1)?; // PlanarConfiguration: interleaved
+    entry(338, SHORT, 1, 2)?; // ExtraSamples: unassociated alpha
+    file.write_all(&0u32.to_le_bytes())?;
+
+    for _ in 0..4 {
+        file.write_all(&16u16.to_le_bytes())?;
+    }
+    for channel in colors.iter().flatten() {
+        file.write_all(&channel.to_le_bytes())?;
+    }
+
+    file.flush()
+}
+
+/// Writes all the tiles of an EXR file at once
+fn rewrite_exr(path: &str, output: &Output, pixels: &[[f32; 4]]) -> std::io::Result<()> {
+    let mut exr = ExrFile::create(path, output)?;
+    for tile in tile::tiles(TileOrder::Scanline, output.width, output.height, output.tile_size(), 0) {
+        let colors: Vec<RGBA> = (tile.top..tile.bottom)
+            .flat_map(|y| (tile.left..tile.right).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let [r, g, b, a] = pixels[(x + y * output.width) as usize].map(f64::from);
+                RGBA::new(r, g, b, a)
+            })
+            .collect();
+        exr.write_tile(&tile, &colors, output)?;
+    }
+    Ok(())
+}
+
+impl ExrFile {
+    /// Creates the file with its header and an offset table with no tiles written yet
+    fn create(path: &str, output: &Output) -> std::io::Result<Self> {
+        let (width, height, tile_size) = (output.width, output.height, output.tile_size());
+        let mut channels: Vec<(String, Channel)> = EXR_CHANNELS.iter()
+            .map(|(name, channel)| (name.to_string(), *channel))
+            .chain(output.light_groups().into_iter().enumerate().flat_map(|(group, name)| {
+                ["R", "G", "B"].into_iter().enumerate()
+                    .map(move |(component, suffix)| (format!("{}.{}", name, suffix), Channel::LightGroup(group, component)))
+            }))
+            .collect();
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut header = Vec::new();
+        header.extend(&20000630u32.to_le_bytes());
+        // Version 2, single part tiled
+        header.extend(&(2u32 | 0x200).to_le_bytes());
+
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            header.extend(name.as_bytes());
+            header.push(0);
+            header.extend(kind.as_bytes());
+            header.push(0);
+            header.extend(&(value.len() as u32).to_le_bytes());
+            header.extend(value);
+        };
+        let mut channel_list = Vec::new();
+        for (name, _) in &channels {
+            channel_list.extend(name.as_bytes());
+            // FLOAT pixels, not linear, 3 reserved bytes and no subsampling
+            channel_list.extend([0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        channel_list.push(0);
+        let window = [0, 0, width as i32 - 1, height as i32 - 1].map(i32::to_le_bytes).concat();
+        attribute("channels", "chlist", &channel_list);
+        attribute("compression", "compression", &[0]);
+        attribute("dataWindow", "box2i", &window);
+        attribute("displayWindow", "box2i", &window);
+        // Tiles are written in the order they complete
+        attribute("lineOrder", "lineOrder", &[2]);
+        attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+        attribute("screenWindowCenter", "v2f", &[0f32, 0f32].map(f32::to_le_bytes).concat());
+        attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+        attribute("tiles", "tiledesc", &[tile_size.to_le_bytes(), tile_size.to_le_bytes()].concat().into_iter().chain([0]).collect::<Vec<_>>());
+        header.push(0);
+
+        let tiles_x = width.div_ceil(tile_size);
+        let tiles = tiles_x * height.div_ceil(tile_size);
+        let offsets = header.len() as u64;
+        header.extend(vec![0; tiles as usize * 8]);
+
+        let mut file = File::create(path)?;
+        file.write_all(&header)?;
+        Ok(Self { file, tile_size, tiles_x, offsets, channels, partial: false })
+    }
+
+    fn write_tile(&mut self, tile: &Tile, colors: &[RGBA], output: &Output) -> std::io::Result<()> {
+        let size = self.tile_size;
+        if !tile.left.is_multiple_of(size) || !tile.top.is_multiple_of(size)
+            || tile.right != (tile.left + size).min(output.width)
+            || tile.bottom != (tile.top + size).min(output.height)
+        {
+            self.partial = true;
+            return Ok(());
+        }
+        let (x, y) = (tile.left / self.tile_size, tile.top / self.tile_size);
+        let width = (tile.right - tile.left) as usize;
+
+        // Each row has the channels one after the other, in alphabetical order, premultiplied like
+        // the colors
+        let mut data = Vec::with_capacity(colors.len() * self.channels.len() * 4);
+        for (row, colors) in colors.chunks(width).enumerate() {
+            let y = tile.top + row as u32;
+            for (_, channel) in &self.channels {
+                for (x, color) in (tile.left..).zip(colors) {
+                    let value = match *channel {
+                        Channel::Color(component) => [color.r, color.g, color.b, color.a][component] as f32,
+                        Channel::Position(axis) => output.position(x, y)[axis],
+                        Channel::Depth => output.depth(x, y),
+                        Channel::LightGroup(group, component) => output.light_group_pixel(group, x, y)[component],
+                    };
+                    data.extend(value.to_le_bytes());
+                }
+            }
+        }
+
+        let position = self.file.seek(SeekFrom::End(0))?;
+        let mut chunk = [x, y, 0, 0, data.len() as u32].map(u32::to_le_bytes).concat();
+        chunk.extend(data);
+        self.file.write_all(&chunk)?;
+
+        self.file.seek(SeekFrom::Start(self.offsets + (y * self.tiles_x + x) as u64 * 8))?;
+        self.file.write_all(&position.to_le_bytes())?;
+        self.file.flush()
+    }
+}
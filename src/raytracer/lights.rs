@@ -0,0 +1,332 @@
+use crate::raytracer::Wavelengths;
+use crate::raytracer::spectrum::Spectrum;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::transform::Transform;
+use crate::raytracer::utils::{random, random_unit_vector, vec3add, vec3cross, vec3dot, vec3norm, vec3scale, vec3sub};
+use std::cell::Cell;
+use std::sync::Arc;
+
+thread_local! {
+    /// Light group the current thread shades the light of, all the light if `None`
+    static LIGHT_GROUP: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Scene lights, choosing which ones to sample at each point
+pub struct LightSampler {
+    lights: Vec<Light>,
+    /// Running sums of the lights' intensities, which lights are picked in proportion to
+    cdf: Vec<f64>,
+    /// Number of lights sampled at each point when there are more, 0 to always sample all of them
+    max_samples: usize,
+    /// Names of the light groups, in the order the lights' groups index them
+    groups: Vec<String>,
+}
+
+pub struct Light {
+    kind: LightKind,
+    /// Color multiplied by the intensity
+    color: (f64, f64, f64),
+    /// Emission spectrum multiplied by the intensity, which `color` is the RGB color of, if any
+    spectrum: Option<Spectrum>,
+    /// Exponent of the distance attenuation, 2 being the physical inverse-square law
+    falloff: f64,
+    /// Radius of the light's sphere, lights with a radius cast soft shadows
+    radius: f64,
+    /// Distance past which the light doesn't illuminate anything
+    max_distance: f64,
+    /// Index of the light group the light belongs to, if any
+    group: Option<usize>,
+}
+
+pub enum LightKind {
+    Point {
+        position: (f64, f64, f64),
+    },
+    /// Point light only emitting inside a cone around `direction`
+    Spot {
+        position: (f64, f64, f64),
+        direction: (f64, f64, f64),
+        /// Cosine of the half-angle of the cone, outside of which there is no light
+        cos_outer: f64,
+        /// Cosine of the half-angle inside of which the light isn't faded by the edge blend
+        cos_inner: f64,
+        gobo: Option<Gobo>,
+    },
+    /// Infinitely far light, `direction` being the direction in which the light travels
+    Directional {
+        direction: (f64, f64, f64),
+    },
+}
+
+/// Texture projected by a spot light, like the cut out plates put in front of stage lights to
+/// fake the shadows of windows or leaves
+pub struct Gobo {
+    texture: Arc<Texture>,
+    /// Directions of the texture's u and v axes, scaled so that the texture spans the cone
+    u_axis: (f64, f64, f64),
+    v_axis: (f64, f64, f64),
+    direction: (f64, f64, f64),
+}
+
+/// Opening like a window or a door the environment lights an interior through, sampled like an
+/// area light by diffuse surfaces instead of only being found by their bounces
+pub struct Portal {
+    /// Transform of the unit square in the XY plane, centered on the origin
+    transform: Transform,
+    /// Inverse of the transform, to find where rays cross the square
+    inverse: Transform,
+    normal: (f64, f64, f64),
+    area: f64,
+}
+
+/// Light arriving at a point from one light
+#[derive(Clone, Copy)]
+pub struct LightSample {
+    /// Direction towards the light
+    pub direction: (f64, f64, f64),
+    pub distance: f64,
+    /// Incident light, attenuated by the distance
+    pub radiance: (f64, f64, f64),
+    /// Light group of the light, `None` for the environment and the lights outside of any group
+    pub group: Option<usize>,
+}
+
+impl LightSampler {
+    pub fn new(lights: Vec<Light>, max_samples: usize, groups: Vec<String>) -> Self {
+        let mut sampler = Self { lights: Vec::new(), cdf: Vec::new(), max_samples, groups };
+        for light in lights {
+            sampler.add(light);
+        }
+        sampler
+    }
+
+    /// Adds a light outside of any light group
+    pub fn add(&mut self, light: Light) {
+        let total = self.cdf.last().copied().unwrap_or(0.0);
+        self.cdf.push(total + luminance(light.color));
+        self.lights.push(light);
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    /// Samples the lights reaching `point`, with their radiance weighted so that the sum is an
+    /// unbiased estimate of the light from all of them
+    ///
+    /// When there are more lights than the maximum number of samples, lights are picked with a
+    /// probability proportional to their intensity and only the picked ones are sampled, so that
+    /// the many dim lights of large scenes cost little.
+    ///
+    /// The lights at the `unlinked` indices, sorted, are left out, and picking them counts as
+    /// picking no light.
+    pub fn sample(&self, point: (f64, f64, f64), wavelengths: Wavelengths, unlinked: &[usize]) -> Vec<LightSample> {
+        let linked = |i: usize| unlinked.binary_search(&i).is_err();
+        if self.max_samples == 0 || self.lights.len().saturating_sub(unlinked.len()) <= self.max_samples {
+            return self.lights.iter()
+                .enumerate()
+                .filter(|&(i, _)| linked(i))
+                .filter_map(|(_, light)| light.sample(point, wavelengths))
+                .collect();
+        }
+
+        let total = self.cdf.last().copied().unwrap_or(0.0);
+        if total <= 0.0 {
+            return Vec::new();
+        }
+
+        (0..self.max_samples)
+            .filter_map(|_| {
+                let x = random::<f64>() * total;
+                let i = self.cdf.partition_point(|&c| c <= x).min(self.lights.len() - 1);
+                let light = &self.lights[i];
+                if !linked(i) {
+                    return None;
+                }
+                let sample = light.sample(point, wavelengths)?;
+                let probability = luminance(light.color) / total;
+                Some(LightSample {
+                    radiance: vec3scale(sample.radiance, 1.0 / (probability * self.max_samples as f64)),
+                    ..sample
+                })
+            })
+            .collect()
+    }
+}
+
+impl Light {
+    pub fn new(kind: LightKind, color: (f64, f64, f64), falloff: f64, radius: f64, max_distance: f64) -> Self {
+        Self {
+            kind,
+            color,
+            spectrum: None,
+            falloff,
+            radius,
+            max_distance,
+            group: None,
+        }
+    }
+
+    /// Sets the emission spectrum, already multiplied by the intensity like the color
+    pub fn with_spectrum(mut self, spectrum: Option<Spectrum>) -> Self {
+        self.spectrum = spectrum;
+        self
+    }
+
+    pub fn with_group(mut self, group: Option<usize>) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Samples the light from `point`, returns `None` if it doesn't reach it
+    pub fn sample(&self, point: (f64, f64, f64), wavelengths: Wavelengths) -> Option<LightSample> {
+        // Past dispersive surfaces only the light of the path's wavelength is sampled
+        let color = match (wavelengths, &self.spectrum) {
+            (Wavelengths::Single(wavelength), Some(spectrum)) => {
+                let value = spectrum.value(wavelength);
+                (value, value, value)
+            }
+            _ => self.color,
+        };
+        match self.kind {
+            LightKind::Point { position } => self.sample_position(position, point, color),
+            LightKind::Spot { position, direction, cos_outer, cos_inner, ref gobo } => {
+                // The cone is tested from the center so that the radius softens shadows but not the cone's edge
+                let cos = vec3dot(vec3norm(vec3sub(point, position)), direction);
+                if cos <= cos_outer {
+                    return None;
+                }
+                let t = ((cos - cos_outer) / (cos_inner - cos_outer).max(f64::EPSILON)).min(1.0);
+                let blend = t * t * (3.0 - 2.0 * t);
+
+                let color = match gobo {
+                    Some(gobo) => {
+                        let filter = gobo.color(vec3sub(point, position))?;
+                        (color.0 * filter.0, color.1 * filter.1, color.2 * filter.2)
+                    }
+                    None => color,
+                };
+                let mut sample = self.sample_position(position, point, color)?;
+                sample.radiance = vec3scale(sample.radiance, blend);
+                Some(sample)
+            }
+            LightKind::Directional { direction } => Some(LightSample {
+                direction: vec3norm(vec3scale(direction, -1.0)),
+                distance: f64::INFINITY,
+                radiance: color,
+                group: self.group,
+            }),
+        }
+    }
+
+    /// Samples a light emitting from `position` in all directions
+    fn sample_position(&self, position: (f64, f64, f64), point: (f64, f64, f64), color: (f64, f64, f64)) -> Option<LightSample> {
+        // Pick a random point on the light's sphere for soft shadows
+        let position = if self.radius > 0.0 {
+            vec3add(position, vec3scale(random_unit_vector(), self.radius))
+        } else {
+            position
+        };
+
+        let to_light = vec3sub(position, point);
+        let distance = vec3dot(to_light, to_light).sqrt();
+        if distance > self.max_distance || distance < f64::EPSILON {
+            return None;
+        }
+
+        Some(LightSample {
+            direction: vec3scale(to_light, 1.0 / distance),
+            distance,
+            radiance: vec3scale(color, distance.powf(-self.falloff)),
+            group: self.group,
+        })
+    }
+}
+
+impl Gobo {
+    /// Creates the gobo of a spot light pointing in `direction` with a cone of `half_angle` radians
+    pub fn new(texture: Arc<Texture>, direction: (f64, f64, f64), half_angle: f64) -> Self {
+        let up = if direction.0.abs() < 1e-6 && direction.1.abs() < 1e-6 { (0.0, 1.0, 0.0) } else { (0.0, 0.0, 1.0) };
+        let right = vec3norm(vec3cross(direction, up));
+        let up = vec3cross(right, direction);
+        // The texture's square is inscribed in the cone, wider cones than a half-sphere being
+        // projected as a half-sphere
+        let scale = 0.5 / half_angle.min(89f64.to_radians()).tan();
+        Self {
+            texture,
+            u_axis: vec3scale(right, scale),
+            v_axis: vec3scale(up, scale),
+            direction,
+        }
+    }
+
+    /// Color the gobo lets through towards `offset` from the light, `None` outside of it
+    fn color(&self, offset: (f64, f64, f64)) -> Option<(f64, f64, f64)> {
+        let distance = vec3dot(offset, self.direction);
+        if distance <= 0.0 {
+            return None;
+        }
+        let uv = (
+            0.5 + vec3dot(offset, self.u_axis) / distance,
+            0.5 + vec3dot(offset, self.v_axis) / distance,
+        );
+        if !(0.0..=1.0).contains(&uv.0) || !(0.0..=1.0).contains(&uv.1) {
+            return None;
+        }
+        let color = self.texture.sample(uv, 0.0);
+        Some((color.r, color.g, color.b))
+    }
+}
+
+impl Portal {
+    pub fn new(transform: Transform) -> Self {
+        let cross = vec3cross(transform.apply_notranslate((1.0, 0.0, 0.0)), transform.apply_notranslate((0.0, 1.0, 0.0)));
+        let area = vec3dot(cross, cross).sqrt();
+        Self { inverse: transform.inverse(), transform, normal: vec3scale(cross, 1.0 / area), area }
+    }
+
+    /// Samples a direction from `point` through the portal, returns it with the solid angle the
+    /// portal covers divided by the probability of the direction
+    pub fn sample(&self, point: (f64, f64, f64)) -> Option<((f64, f64, f64), f64)> {
+        let target = self.transform.apply((random::<f64>() - 0.5, random::<f64>() - 0.5, 0.0));
+        let to_portal = vec3sub(target, point);
+        let distance2 = vec3dot(to_portal, to_portal);
+        let direction = vec3scale(to_portal, 1.0 / distance2.sqrt());
+        let cos = vec3dot(direction, self.normal).abs();
+        (cos > 0.0 && distance2 > 0.0).then(|| (direction, self.area * cos / distance2))
+    }
+
+    /// Whether a ray from `origin` along `direction` goes through the portal
+    pub fn contains(&self, origin: (f64, f64, f64), direction: (f64, f64, f64)) -> bool {
+        let (origin, direction) = (self.inverse.apply(origin), self.inverse.apply_notranslate(direction));
+        let t = -origin.2 / direction.2;
+        if t.is_nan() || t <= 0.0 {
+            return false;
+        }
+        let (x, y) = (origin.0 + t * direction.0, origin.1 + t * direction.1);
+        x.abs() <= 0.5 && y.abs() <= 0.5
+    }
+}
+
+/// Runs `f` shading only the light of a light group, as if the other lights, the background and
+/// emissive surfaces were black
+///
+/// Materials are shaded again for each group from the light samples and traced colors of the
+/// whole light, see [`ShadeContext`](crate::raytracer::materials::ShadeContext).
+pub fn shade_group<T>(group: usize, f: impl FnOnce() -> T) -> T {
+    let previous = LIGHT_GROUP.replace(Some(group));
+    let result = f();
+    LIGHT_GROUP.set(previous);
+    result
+}
+
+/// Whether the light of the background and emissive surfaces, which isn't in any light group, is
+/// shaded
+pub fn ungrouped_light() -> bool {
+    LIGHT_GROUP.get().is_none()
+}
+
+/// Perceived brightness of a linear color
+fn luminance(color: (f64, f64, f64)) -> f64 {
+    0.2126 * color.0 + 0.7152 * color.1 + 0.0722 * color.2
+}
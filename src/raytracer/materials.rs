@@ -1,31 +1,119 @@
-use crate::raytracer::{Ray, RGBA};
-use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::{Bounces, Ray, Raytracer, Wavelengths, RGBA};
+use crate::raytracer::dielectric::DielectricMaterial;
+use crate::raytracer::diffuse::DiffuseMaterial;
+use crate::raytracer::ggx::GgxMaterial;
+use crate::raytracer::graph::GraphMaterial;
+use crate::raytracer::lights::LightSample;
+use crate::raytracer::objects::{Object, ObjectHit};
+use crate::raytracer::scripting::ScriptMaterial;
+use crate::raytracer::sheen::SheenMaterial;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::utils::{self, vec3add, vec3cross, vec3dot, vec3norm, vec3scale, vec3sub};
+use rand::rngs::StdRng;
 use serde_json::Value;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::{Arc, LazyLock, Mutex};
 
-pub type MaterialNewFn = fn(&Value) -> Result<Box<dyn MaterialType + Sync + Send>, String>;
+pub type MaterialNewFn = Box<dyn Fn(&Value) -> Result<Box<dyn MaterialType + Sync + Send>, String> + Sync + Send>;
 
+/// Only locked while building scenes, the materials are then shared by the workers through `Arc`s
 static MATERIAL_TYPES: LazyLock<Mutex<HashMap<String, MaterialNewFn>>> =
     LazyLock::new(|| Mutex::new(HashMap::from([
+        ("dielectric".to_string(), new_fn(DielectricMaterial::new)),
+        ("diffuse".to_string(), new_fn(DiffuseMaterial::new)),
+        ("ggx".to_string(), new_fn(GgxMaterial::new)),
+        ("graph".to_string(), new_fn(GraphMaterial::new)),
+        ("script".to_string(), new_fn(ScriptMaterial::new)),
+        ("sheen".to_string(), new_fn(SheenMaterial::new)),
     ])));
 
-static FALLBACK: LazyLock<Arc<Material>> = LazyLock::new(|| Arc::new(Material { inner: Box::new(Fallback) }) );
+static FALLBACK: LazyLock<Arc<Material>> = LazyLock::new(|| Arc::new(Material { inner: Box::new(Fallback), double_sided: true, uv_transform: None, alpha_cutout: None, bump: None, name: None }) );
 
 pub struct Material {
     inner: Box<dyn MaterialType + Send + Sync>,
+    double_sided: bool,
+    uv_transform: Option<UvTransform>,
+    alpha_cutout: Option<AlphaCutout>,
+    bump: Option<Bump>,
+    /// Name of the material in the scene, inline materials have none
+    name: Option<String>,
+}
+
+/// Opacity texture, where the surface is cut out (invisible) below the threshold
+struct AlphaCutout {
+    texture: Arc<Texture>,
+    threshold: f64,
+}
+
+/// Height texture (red channel) perturbing the shading normals
+struct Bump {
+    texture: Arc<Texture>,
+    strength: f64,
+}
+
+/// Transformation of the texture coordinates: scaled, then rotated, then offset
+#[derive(Clone, Copy)]
+pub struct UvTransform {
+    scale: (f64, f64),
+    offset: (f64, f64),
+    /// Sine and cosine of the rotation angle
+    rotation: (f64, f64),
 }
 
 pub trait MaterialType {
-    fn shade<'a>(&self, oh: &'a ObjectHit, raytrace: Box<dyn Fn(Ray) -> RGBA + 'a>) -> RGBA;
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA;
+}
+
+/// Access to the rest of the scene for materials shading a hit
+pub struct ShadeContext<'a> {
+    raytracer: &'a Raytracer,
+    /// Depth of the ray being shaded
+    depth: u32,
+    bounces: Bounces,
+    /// Wavelengths the light sampled is evaluated at
+    wavelengths: Wavelengths,
+    /// Lights which don't illuminate the object being shaded
+    unlinked_lights: &'a [usize],
+    /// What the material got while shading the whole light, when the scene has light groups
+    split: Option<RefCell<Split>>,
+}
+
+/// Records of the light samples and traced colors handed to a material, from which it shades the
+/// light of each light group again without tracing anything
+#[derive(Default)]
+struct Split {
+    records: Vec<Record>,
+    /// Light group being shaded from the records, and the index of the next record to hand out
+    replay: Option<(usize, usize)>,
+}
+
+struct Record {
+    handed: Handed,
+    /// State of the random numbers once it was handed, so that the material draws the same ones
+    /// after it
+    random: StdRng,
+}
+
+enum Handed {
+    Samples(Vec<LightSample>),
+    /// Light of each light group in a traced color
+    Colors(Vec<RGBA>),
 }
 
 struct Fallback;
 
 impl Material {
-    pub fn register_type(name: String, new_fn: MaterialNewFn) {
+    /// Registers material types, none of them if one of their names is already registered
+    pub fn register_new_types(new_types: Vec<(String, MaterialNewFn)>) -> Result<(), String> {
         let mut types = MATERIAL_TYPES.lock().unwrap();
-        types.insert(name, new_fn);
+        for (i, (name, _)) in new_types.iter().enumerate() {
+            if types.contains_key(name) || new_types[..i].iter().any(|(other, _)| other == name) {
+                return Err(format!("Material type {} is already registered", name));
+            }
+        }
+        types.extend(new_types);
+        Ok(())
     }
 
     pub fn new(type_name: &String, data: &Value) -> Result<Self, String> {
@@ -35,22 +123,268 @@ impl Material {
             None => Err(format!("Could not find material type {}", type_name)),
         }?;
 
-        Ok(Material {
+        Ok(Self::from_type(inner))
+    }
+
+    /// Wraps an already created material type
+    pub fn from_type(inner: Box<dyn MaterialType + Send + Sync>) -> Self {
+        Material {
             inner,
-        })
+            double_sided: true,
+            uv_transform: None,
+            alpha_cutout: None,
+            bump: None,
+            name: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Sets whether the back faces of surfaces are visible, when not they are ignored by rays
+    pub fn with_double_sided(mut self, double_sided: bool) -> Self {
+        self.double_sided = double_sided;
+        self
+    }
+
+    pub fn double_sided(&self) -> bool {
+        self.double_sided
+    }
+
+    /// Sets the transformation applied to the hits' UVs before shading
+    pub fn with_uv_transform(mut self, uv_transform: UvTransform) -> Self {
+        self.uv_transform = Some(uv_transform);
+        self
     }
 
     pub fn fallback() -> Arc<Material> {
         FALLBACK.clone()
     }
 
-    pub fn shade<'a>(&self, oh: &'a ObjectHit, raytrace: Box<dyn Fn(Ray) -> RGBA + 'a>) -> RGBA {
-        self.inner.shade(oh, raytrace)
+    /// Sets an opacity texture, hits where it is below the threshold are ignored
+    pub fn with_alpha_cutout(mut self, texture: Arc<Texture>, threshold: f64) -> Self {
+        self.alpha_cutout = Some(AlphaCutout { texture, threshold });
+        self
+    }
+
+    /// Sets a height texture used to perturb the normals when shading
+    pub fn with_bump(mut self, texture: Arc<Texture>, strength: f64) -> Self {
+        self.bump = Some(Bump { texture, strength });
+        self
     }
+
+    /// Returns whether the hit is on a cut out part of the surface, which rays should go through
+    pub fn is_cut_out(&self, oh: &ObjectHit) -> bool {
+        match &self.alpha_cutout {
+            Some(cutout) => {
+                let (uv, footprint) = match self.uv_transform {
+                    Some(uv_transform) => (uv_transform.apply(oh.hit.uv), uv_transform.apply_footprint(oh.footprint)),
+                    None => (oh.hit.uv, oh.footprint),
+                };
+                cutout.texture.sample_opacity(uv, footprint) < cutout.threshold
+            }
+            None => false,
+        }
+    }
+
+    pub fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        if self.uv_transform.is_none() && self.bump.is_none() {
+            return self.inner.shade(oh, ctx);
+        }
+
+        let mut oh = *oh;
+        if let Some(uv_transform) = self.uv_transform {
+            oh.hit.uv = uv_transform.apply(oh.hit.uv);
+            oh.footprint = uv_transform.apply_footprint(oh.footprint);
+        }
+        if let Some(bump) = &self.bump {
+            oh.hit.normal = bump.apply(&oh);
+        }
+        self.inner.shade(&oh, ctx)
+    }
+}
+
+impl<'a> ShadeContext<'a> {
+    pub(crate) fn new(raytracer: &'a Raytracer, ray: &Ray, object: &'a Object) -> Self {
+        Self {
+            raytracer,
+            depth: ray.depth,
+            bounces: ray.bounces,
+            wavelengths: ray.wavelengths,
+            unlinked_lights: object.unlinked_lights(),
+            split: (!raytracer.output.light_groups.is_empty()).then(RefCell::default),
+        }
+    }
+
+    /// Hands out the records of the whole light again, only with the light of a light group, to
+    /// shade it
+    pub(crate) fn replay_group(&self, group: usize) {
+        if let Some(split) = &self.split {
+            split.borrow_mut().replay = Some((group, 0));
+        }
+    }
+
+    /// Traces a secondary ray, ignoring hits too close to its origin to avoid self-intersections
+    pub fn trace(&self, ray: Ray) -> RGBA {
+        self.traced(Ray {
+            min_distance: self.raytracer.output.ray_epsilon,
+            depth: self.depth + 1,
+            bounces: self.bounces.with(ray.ray_type),
+            ..ray
+        })
+    }
+
+    /// Continues a ray past a surface which doesn't change it, without counting a bounce
+    pub fn pass_through(&self, ray: Ray) -> RGBA {
+        self.traced(Ray { min_distance: self.raytracer.output.ray_epsilon, ..ray })
+    }
+
+    fn traced(&self, ray: Ray) -> RGBA {
+        if let Some(replayed) = self.replay(|handed, group| match handed {
+            Handed::Colors(colors) => Some(colors[group]),
+            Handed::Samples(_) => None,
+        }) {
+            return replayed.unwrap_or_else(RGBA::black);
+        }
+        let color = self.raytracer.raytrace(ray);
+        self.record(|| Handed::Colors(self.raytracer.group_colors()));
+        color
+    }
+
+    /// Samples the lights illuminating `point`, leaving out the shadowed ones
+    pub fn sample_lights(&self, point: (f64, f64, f64)) -> Vec<LightSample> {
+        if let Some(replayed) = self.replay_samples() {
+            return replayed;
+        }
+        let samples: Vec<LightSample> = self.raytracer.lights.sample(point, self.wavelengths, self.unlinked_lights)
+            .into_iter()
+            .filter(|sample| !self.raytracer.occluded(point, sample.direction, sample.distance))
+            .collect();
+        self.record(|| Handed::Samples(samples.clone()));
+        samples
+    }
+
+    /// Samples the environment lighting `point` through each portal, leaving out the shadowed
+    /// samples
+    ///
+    /// Materials sampling the portals must bounce diffuse rays: those rays leaving through a
+    /// portal see a black environment, so that its light isn't counted twice.
+    pub fn sample_portals(&self, point: (f64, f64, f64)) -> Vec<LightSample> {
+        if let Some(replayed) = self.replay_samples() {
+            return replayed;
+        }
+        let samples: Vec<LightSample> = self.raytracer.world.portals.iter()
+            .filter_map(|portal| {
+                let (direction, solid_angle) = portal.sample(point)?;
+                // Objects outside also block the environment
+                if self.raytracer.occluded(point, direction, f64::INFINITY) {
+                    return None;
+                }
+                Some(LightSample {
+                    direction,
+                    distance: f64::INFINITY,
+                    radiance: vec3scale(self.raytracer.environment(direction), solid_angle),
+                    // The environment isn't in any light group
+                    group: None,
+                })
+            })
+            .collect();
+        self.record(|| Handed::Samples(samples.clone()));
+        samples
+    }
+
+    /// Records what was handed to the material while shading the whole light
+    fn record(&self, handed: impl FnOnce() -> Handed) {
+        if let Some(split) = &self.split {
+            split.borrow_mut().records.push(Record { handed: handed(), random: utils::random_state() });
+        }
+    }
+
+    /// While shading a light group, hands out the group's light in the next record, `None` inside
+    /// if the material asks for something else than it did for the whole light
+    fn replay<T>(&self, light: impl FnOnce(&Handed, usize) -> Option<T>) -> Option<Option<T>> {
+        let mut split = self.split.as_ref()?.borrow_mut();
+        let (group, next) = split.replay?;
+        split.replay = Some((group, next + 1));
+        let Some(record) = split.records.get(next) else {
+            return Some(None);
+        };
+        utils::restore_random(record.random.clone());
+        Some(light(&record.handed, group))
+    }
+
+    fn replay_samples(&self) -> Option<Vec<LightSample>> {
+        self.replay(|handed, group| match handed {
+            Handed::Samples(samples) => Some(samples.iter().filter(|sample| sample.group == Some(group)).copied().collect()),
+            Handed::Colors(_) => None,
+        })
+        .map(Option::unwrap_or_default)
+    }
+}
+
+impl Bump {
+    /// Returns the hit's normal tilted by the slope of the height texture
+    fn apply(&self, oh: &ObjectHit) -> (f64, f64, f64) {
+        let (hit, footprint) = (&oh.hit, oh.footprint);
+        let (du, dv) = self.texture.texel_size();
+        let (u, v) = hit.uv;
+        let height = self.texture.sample((u, v), footprint).r;
+        let dh_du = (self.texture.sample((u + du, v), footprint).r - height) * self.strength;
+        let dh_dv = (self.texture.sample((u, v + dv), footprint).r - height) * self.strength;
+
+        // Tangent frame orthonormalized around the normal
+        let normal = hit.normal;
+        let tangent = vec3norm(vec3sub(hit.tangent, vec3scale(normal, vec3dot(hit.tangent, normal))));
+        if tangent.0.is_nan() {
+            return normal;
+        }
+        let bitangent = vec3cross(normal, tangent);
+
+        vec3norm(vec3sub(normal, vec3add(vec3scale(tangent, dh_du), vec3scale(bitangent, dh_dv))))
+    }
+}
+
+impl UvTransform {
+    /// Creates a UV transformation, the rotation angle is in degrees
+    pub fn new(scale: (f64, f64), offset: (f64, f64), rotation: f64) -> Self {
+        Self {
+            scale,
+            offset,
+            rotation: rotation.to_radians().sin_cos(),
+        }
+    }
+
+    pub fn apply(&self, uv: (f64, f64)) -> (f64, f64) {
+        let (u, v) = (uv.0 * self.scale.0, uv.1 * self.scale.1);
+        let (sin, cos) = self.rotation;
+        (
+            u * cos - v * sin + self.offset.0,
+            u * sin + v * cos + self.offset.1,
+        )
+    }
+
+    /// Scales a UV space footprint like the UVs, conservatively along the largest scale
+    pub fn apply_footprint(&self, footprint: f64) -> f64 {
+        footprint * self.scale.0.abs().max(self.scale.1.abs())
+    }
+}
+
+/// Wraps a material constructor for the material type registry
+fn new_fn<T>(new: fn(&Value) -> Result<T, String>) -> MaterialNewFn
+where
+    T: MaterialType + Sync + Send + 'static
+{
+    Box::new(move |data| Ok(Box::new(new(data)?)))
 }
 
 impl MaterialType for Fallback {
-    fn shade<'a>(&self, oh: &'a ObjectHit, _: Box<dyn Fn(Ray) -> RGBA +'a>) -> RGBA {
+    fn shade(&self, oh: &ObjectHit, _: &ShadeContext) -> RGBA {
         if ((oh.hit.uv.0 * 20.0) as u8 % 2) ^ ((oh.hit.uv.1 * 20.0) as u8 % 2) == 0 {
             RGBA::new(oh.hit.uv.0, 0.0, oh.hit.uv.1, 1.0)
         } else {
@@ -0,0 +1,1083 @@
+use crate::log;
+use crate::raytracer::Ray;
+use crate::raytracer::bvh::{Aabb, Bvh, BvhOptions, NodeVisitor};
+#[cfg(feature = "embree")]
+use crate::raytracer::embree;
+use crate::raytracer::mesh_cache::{self, Buffer, CacheReader, Plain};
+use crate::raytracer::objects::{Hit, Interval, ObjectType};
+use crate::raytracer::scene::SceneTexture;
+use crate::raytracer::textures::Texture;
+use crate::raytracer::utils::{self, vec3add, vec3cross, vec3dot, vec3norm, vec3scale, vec3sub};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::mem::take;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Triangle mesh loaded from a Wavefront OBJ, PLY or STL file, or from a mesh cache
+pub struct Mesh {
+    positions: Buffer<(f64, f64, f64)>,
+    normals: Buffer<(f64, f64, f64)>,
+    uvs: Buffer<(f64, f64)>,
+    /// Color of each position, empty when the file has no vertex colors
+    colors: Buffer<(f64, f64, f64)>,
+    triangles: Buffer<[Vertex; 3]>,
+    /// Material index of each triangle, empty when the whole mesh uses the first material
+    materials: Buffer<usize>,
+    shading: Shading,
+    bvh: Bvh,
+    /// Embree geometry of the triangles, which rays are intersected with instead of the BVH
+    #[cfg(feature = "embree")]
+    embree: Option<embree::Triangles>,
+}
+
+#[derive(Deserialize)]
+struct MeshData {
+    path: String,
+    #[serde(default)]
+    shading: Shading,
+    /// Number of times the triangles are split in 4 at load time
+    #[serde(default)]
+    subdivision: u32,
+    #[serde(default)]
+    subdivision_scheme: SubdivisionScheme,
+    #[serde(default)]
+    displacement: Option<MeshDisplacement>,
+}
+
+/// Displacement of the vertices along their normal by a height texture
+#[derive(Deserialize)]
+struct MeshDisplacement {
+    texture: SceneTexture,
+    #[serde(default = "default_displacement_scale")]
+    scale: f64,
+    /// Number of times the triangles are split in 4 before displacing, to add detail
+    #[serde(default)]
+    subdivisions: u32,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Shading {
+    /// Normals are interpolated from the vertex normals
+    #[default]
+    Smooth,
+    /// Normals are the triangles' geometric normals
+    Flat,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubdivisionScheme {
+    /// Loop subdivision, smoothing the surface towards a limit surface
+    #[default]
+    Loop,
+    /// Triangles are split without moving the vertices, keeping the shape faceted
+    Linear,
+}
+
+/// Positions after a Loop subdivision step
+struct LoopPositions {
+    /// New positions of the existing vertices
+    vertices: Vec<(f64, f64, f64)>,
+    /// Positions of the edge midpoints, keyed by the edges' sorted position indices
+    edges: HashMap<(usize, usize), (f64, f64, f64)>,
+}
+
+/// Indices of a triangle corner's attributes, [`NONE`] for missing normals and UVs
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Vertex {
+    position: usize,
+    normal: usize,
+    uv: usize,
+}
+
+/// Directory the built meshes are cached in, see [`set_mesh_cache_dir`]
+static CACHE_DIR: Mutex<Option<String>> = Mutex::new(None);
+
+const NONE: usize = usize::MAX;
+/// Number of triangles each thread takes at once while computing the vertex normals
+const NORMALS_CHUNK_SIZE: usize = 65536;
+
+// SAFETY: vertices are only made of integers, and bounds checked when mapped
+unsafe impl Plain for [Vertex; 3] {}
+
+impl Mesh {
+    pub fn new(value: &Value, bvh: &BvhOptions) -> Result<Self, String> {
+        let data: MeshData = serde_json::from_value(value.clone())
+            .map_err(|err| format!("Invalid mesh: {}", err))?;
+        let cache_path = cache_path(value, &data, bvh)?;
+        if let Some(cache_path) = &cache_path
+            && Path::new(cache_path).exists()
+        {
+            match Self::map_cache(cache_path) {
+                Ok(mesh) if !mesh.bvh.is_empty() => {
+                    log::debug!("Mesh {} mapped from {}", data.path, cache_path);
+                    #[cfg(feature = "embree")]
+                    let mesh = mesh.with_embree()?;
+                    return Ok(Self { shading: data.shading, ..mesh });
+                }
+                Ok(_) => log::warning!("Mesh cache {} has no BVH, building {} again", cache_path, data.path),
+                Err(err) => log::warning!("{}, building {} again", err, data.path),
+            }
+        }
+
+        let mut mesh = Self::load(&data.path)?;
+        mesh.shading = data.shading;
+        for _ in 0..data.subdivision {
+            mesh.subdivide(data.subdivision_scheme);
+        }
+        if let Some(displacement) = &data.displacement {
+            for _ in 0..displacement.subdivisions {
+                mesh.subdivide(SubdivisionScheme::Linear);
+            }
+            let texture = displacement.texture.load()?;
+            mesh.displace(&texture, displacement.scale);
+        }
+        if mesh.shading == Shading::Smooth {
+            mesh.fill_vertex_normals();
+        }
+        let start = Instant::now();
+        mesh.build_bvh(bvh);
+        log::debug!(
+            "BVH of {} ({} triangles) built in {:.3}s",
+            data.path,
+            mesh.triangles.len(),
+            start.elapsed().as_secs_f64(),
+        );
+        if let Some(cache_path) = &cache_path
+            && !mesh.triangles.is_empty()
+            && let Err(err) = mesh.write_cache(cache_path, true)
+        {
+            log::warning!("{}", err);
+        }
+        #[cfg(feature = "embree")]
+        let mesh = mesh.with_embree()?;
+
+        Ok(mesh)
+    }
+
+    fn empty() -> Self {
+        Self {
+            positions: Buffer::default(),
+            normals: Buffer::default(),
+            uvs: Buffer::default(),
+            colors: Buffer::default(),
+            triangles: Buffer::default(),
+            materials: Buffer::default(),
+            shading: Shading::default(),
+            bvh: Bvh::default(),
+            #[cfg(feature = "embree")]
+            embree: None,
+        }
+    }
+
+    /// Loads a mesh file, mapping it if it is a mesh cache (`.cmesh`)
+    fn load(path: &str) -> Result<Self, String> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        if extension.as_deref() == Some("cmesh") {
+            return Self::map_cache(path);
+        }
+
+        let source = fs::read(path)
+            .map_err(|err| format!("Failed to open mesh file {}: {}", path, err))?;
+        match extension.as_deref() {
+            Some("obj") => String::from_utf8(source)
+                .map_err(|err| err.to_string())
+                .and_then(|source| Self::parse_obj(&source)),
+            Some("ply") => Self::parse_ply(&source),
+            Some("stl") => Self::parse_stl(&source),
+            _ => Err("unknown format, expected an .obj, .ply, .stl or .cmesh file".to_string()),
+        }.map_err(|err| format!("Invalid mesh file {}: {}", path, err))
+    }
+
+    /// Maps the buffers of a mesh cache written by [`convert_mesh`], or by a render with a cache
+    /// directory along with the BVH
+    fn map_cache(path: &str) -> Result<Self, String> {
+        let start = Instant::now();
+        let mut reader = CacheReader::open(path)?;
+        let mesh = Self {
+            positions: reader.next()?,
+            normals: reader.next()?,
+            uvs: reader.next()?,
+            colors: reader.next()?,
+            triangles: reader.next()?,
+            materials: reader.next()?,
+            ..Self::empty()
+        };
+
+        // Indices are used without bounds checks against their buffers' length in a few places
+        let valid = |index: usize, len: usize| index < len || index == NONE;
+        let valid_vertex = |vertex: &Vertex| {
+            vertex.position < mesh.positions.len()
+                && valid(vertex.normal, mesh.normals.len())
+                && valid(vertex.uv, mesh.uvs.len())
+        };
+        if !mesh.triangles.iter().flatten().all(valid_vertex)
+            || !(mesh.colors.is_empty() || mesh.colors.len() == mesh.positions.len())
+        {
+            return Err(format!("Invalid mesh cache {}: index out of range", path));
+        }
+        let mesh = if reader.is_done() {
+            mesh
+        } else {
+            let bvh = Bvh::map_cache(&mut reader, mesh.triangles.len())
+                .map_err(|err| format!("Invalid mesh cache {}: {}", path, err))?;
+            Self { bvh, ..mesh }
+        };
+        log::debug!("Mesh cache {} mapped in {:.3}s", path, start.elapsed().as_secs_f64());
+
+        Ok(mesh)
+    }
+
+    /// Parses an OBJ file, each `usemtl` name getting the next material index in order of appearance
+    ///
+    /// Vertex colors are read from `v x y z r g b` lines, when every vertex has one.
+    fn parse_obj(source: &str) -> Result<Self, String> {
+        let mut mesh = Self::empty();
+        let mut material_names: Vec<&str> = Vec::new();
+        let mut material = 0;
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            let mut tokens = line.split_whitespace();
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+
+            match tokens.next() {
+                Some("v") => {
+                    let [x, y, z] = parse_floats(&mut tokens).ok_or_else(|| err("invalid vertex"))?;
+                    mesh.positions.push((x, y, z));
+                    if let Some([r, g, b]) = parse_floats(&mut tokens) {
+                        mesh.colors.push((r, g, b));
+                    }
+                }
+                Some("vn") => {
+                    let [x, y, z] = parse_floats(&mut tokens).ok_or_else(|| err("invalid vertex normal"))?;
+                    mesh.normals.push(vec3norm((x, y, z)));
+                }
+                Some("vt") => {
+                    let [u, v] = parse_floats(&mut tokens).ok_or_else(|| err("invalid texture coordinate"))?;
+                    mesh.uvs.push((u, v));
+                }
+                Some("f") => {
+                    let face = tokens
+                        .map(|token| mesh.parse_vertex(token))
+                        .collect::<Option<Vec<Vertex>>>()
+                        .ok_or_else(|| err("invalid face"))?;
+                    if face.len() < 3 {
+                        return Err(err("face has less than 3 vertices"));
+                    }
+
+                    // Triangulate polygons as a fan
+                    for j in 1..face.len() - 1 {
+                        mesh.triangles.push([face[0], face[j], face[j + 1]]);
+                        mesh.materials.push(material);
+                    }
+                }
+                Some("usemtl") => {
+                    let name = tokens.next().ok_or_else(|| err("missing material name"))?;
+                    material = match material_names.iter().position(|&other| other == name) {
+                        Some(index) => index,
+                        None => {
+                            material_names.push(name);
+                            material_names.len() - 1
+                        }
+                    };
+                }
+                _ => {}
+            }
+        }
+        if mesh.colors.len() != mesh.positions.len() {
+            mesh.colors.clear();
+        }
+
+        Ok(mesh)
+    }
+
+    /// Parses an ASCII or binary PLY file, reading the vertex positions, normals, UVs and colors and
+    /// the faces
+    fn parse_ply(source: &[u8]) -> Result<Self, String> {
+        let header_end = source.windows(10)
+            .position(|window| window == b"end_header")
+            .ok_or("missing end_header")?;
+        let header = std::str::from_utf8(&source[..header_end]).map_err(|err| err.to_string())?;
+        let body = &source[header_end + 10..];
+        let body = body.strip_prefix(b"\r").unwrap_or(body);
+        let body = body.strip_prefix(b"\n").unwrap_or(body);
+
+        let mut lines = header.lines().map(str::trim);
+        if lines.next() != Some("ply") {
+            return Err("missing ply magic number".to_string());
+        }
+
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = Vec::new();
+        for line in lines {
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            match tokens.as_slice() {
+                ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+                ["format", "binary_little_endian", _] => format = Some(PlyFormat::LittleEndian),
+                ["format", "binary_big_endian", _] => format = Some(PlyFormat::BigEndian),
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| format!("invalid element count {}", count))?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count_type, item_type, name] => elements.last_mut()
+                    .ok_or("property outside of an element")?
+                    .properties.push(PlyProperty {
+                        name: name.to_string(),
+                        count_type: Some(PlyType::parse(count_type)?),
+                        item_type: PlyType::parse(item_type)?,
+                    }),
+                ["property", item_type, name] => elements.last_mut()
+                    .ok_or("property outside of an element")?
+                    .properties.push(PlyProperty {
+                        name: name.to_string(),
+                        count_type: None,
+                        item_type: PlyType::parse(item_type)?,
+                    }),
+                _ => {}
+            }
+        }
+
+        let mut reader = PlyReader {
+            format: format.ok_or("missing format")?,
+            source: body,
+            offset: 0,
+        };
+        let mut mesh = Self::empty();
+        let (mut has_normals, mut has_uvs, mut has_colors) = (false, false, false);
+        for element in &elements {
+            let find = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+            let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+            let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+            let uv = [find(&["u", "s", "texture_u"]), find(&["v", "t", "texture_v"])];
+            let color = [find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"])];
+            let indices = find(&["vertex_indices", "vertex_index"]);
+            has_normals |= element.name == "vertex" && normal.iter().all(Option::is_some);
+            has_uvs |= element.name == "vertex" && uv.iter().all(Option::is_some);
+            has_colors |= element.name == "vertex" && color.iter().all(Option::is_some);
+            // Integer colors range over the type, floating point ones from 0 to 1
+            let color_scale = color.map(|i| i.map_or(1.0, |i| element.properties[i].item_type.max_value()));
+
+            for _ in 0..element.count {
+                let mut values = Vec::with_capacity(element.properties.len());
+                for property in &element.properties {
+                    values.push(match property.count_type {
+                        Some(count_type) => {
+                            let count = reader.read(count_type)? as usize;
+                            (0..count).map(|_| reader.read(property.item_type)).collect::<Result<Vec<_>, _>>()?
+                        }
+                        None => vec![reader.read(property.item_type)?],
+                    });
+                }
+                let scalar = |i: Option<usize>| i.map(|i| values[i][0]).unwrap_or(0.0);
+
+                match element.name.as_str() {
+                    "vertex" => {
+                        mesh.positions.push((scalar(position[0]), scalar(position[1]), scalar(position[2])));
+                        if has_normals {
+                            mesh.normals.push(vec3norm((scalar(normal[0]), scalar(normal[1]), scalar(normal[2]))));
+                        }
+                        if has_uvs {
+                            mesh.uvs.push((scalar(uv[0]), scalar(uv[1])));
+                        }
+                        if has_colors {
+                            let [r, g, b] = [0, 1, 2].map(|c| scalar(color[c]) / color_scale[c]);
+                            mesh.colors.push((r, g, b));
+                        }
+                    }
+                    "face" => {
+                        let face = indices.map(|i| &values[i]).ok_or("face without vertex indices")?;
+                        if face.len() < 3 {
+                            return Err("face has less than 3 vertices".to_string());
+                        }
+                        let vertex = |index: f64| {
+                            let index = index as usize;
+                            (index < mesh.positions.len())
+                                .then(|| Vertex::new(index, has_normals.then_some(index), has_uvs.then_some(index)))
+                                .ok_or("face vertex index out of range")
+                        };
+
+                        // Triangulate polygons as a fan
+                        for j in 1..face.len() - 1 {
+                            mesh.triangles.push([vertex(face[0])?, vertex(face[j])?, vertex(face[j + 1])?]);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    /// Parses an ASCII or binary STL file, merging the vertices shared by the triangles
+    fn parse_stl(source: &[u8]) -> Result<Self, String> {
+        let mut corners = Vec::new();
+
+        // Binary files can also start with "solid", so check whether the size matches first
+        let binary_count = source.get(80..84).map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize);
+        if binary_count.is_some_and(|count| source.len() == 84 + count * 50) {
+            for triangle in source[84..].chunks_exact(50) {
+                for corner in 1..4 {
+                    let float = |i: usize| {
+                        let offset = corner * 12 + i * 4;
+                        f32::from_le_bytes(triangle[offset..offset + 4].try_into().unwrap()) as f64
+                    };
+                    corners.push((float(0), float(1), float(2)));
+                }
+            }
+        } else {
+            let source = std::str::from_utf8(source).map_err(|_| "not a valid binary or ASCII STL file")?;
+            for (i, line) in source.lines().enumerate() {
+                let mut tokens = line.split_whitespace();
+                if tokens.next() == Some("vertex") {
+                    let [x, y, z] = parse_floats(&mut tokens).ok_or_else(|| format!("line {}: invalid vertex", i + 1))?;
+                    corners.push((x, y, z));
+                }
+            }
+            if corners.len() % 3 != 0 {
+                return Err("facet does not have 3 vertices".to_string());
+            }
+        }
+
+        // STL repeats the positions for each triangle, merge them so that normals can be smoothed
+        let mut mesh = Self::empty();
+        let mut indices = HashMap::new();
+        for triangle in corners.chunks_exact(3) {
+            mesh.triangles.push([0, 1, 2].map(|i| {
+                let p = triangle[i];
+                let position = *indices.entry((p.0.to_bits(), p.1.to_bits(), p.2.to_bits())).or_insert_with(|| {
+                    mesh.positions.push(p);
+                    mesh.positions.len() - 1
+                });
+                Vertex::new(position, None, None)
+            }));
+        }
+
+        Ok(mesh)
+    }
+
+    /// Parses a face vertex (`v`, `v/vt`, `v//vn` or `v/vt/vn`), resolving relative indices
+    fn parse_vertex(&self, token: &str) -> Option<Vertex> {
+        let resolve = |index: Option<&str>, len: usize| -> Option<Option<usize>> {
+            match index {
+                None | Some("") => Some(None),
+                Some(index) => {
+                    let index = index.parse::<isize>().ok()?;
+                    let index = if index < 0 { len as isize + index } else { index - 1 };
+                    (0..len as isize).contains(&index).then_some(Some(index as usize))
+                }
+            }
+        };
+
+        let mut indices = token.split('/');
+        let position = resolve(indices.next(), self.positions.len())??;
+        let uv = resolve(indices.next(), self.uvs.len())?;
+        let normal = resolve(indices.next(), self.normals.len())?;
+        Some(Vertex::new(position, normal, uv))
+    }
+
+    /// Computes angle-weighted normals for each position
+    fn vertex_normals(&self) -> Vec<(f64, f64, f64)> {
+        // The triangles' weighted normals are computed in parallel, then summed for each position
+        let weighted = utils::parallel_map(&self.triangles, NORMALS_CHUNK_SIZE, |triangle| {
+            let p = triangle.map(|vertex| self.positions[vertex.position]);
+            let normal = vec3norm(vec3cross(vec3sub(p[1], p[0]), vec3sub(p[2], p[0])));
+            if normal.0.is_nan() {
+                return None; // Degenerate triangle
+            }
+            Some([0, 1, 2].map(|i| {
+                let a = vec3norm(vec3sub(p[(i + 1) % 3], p[i]));
+                let b = vec3norm(vec3sub(p[(i + 2) % 3], p[i]));
+                vec3scale(normal, vec3dot(a, b).clamp(-1.0, 1.0).acos())
+            }))
+        });
+
+        let mut vertex_normals = vec![(0.0, 0.0, 0.0); self.positions.len()];
+        for (triangle, normals) in self.triangles.iter().zip(weighted) {
+            for (vertex, normal) in triangle.iter().zip(normals.into_iter().flatten()) {
+                let acc = &mut vertex_normals[vertex.position];
+                *acc = vec3add(*acc, normal);
+            }
+        }
+
+        vertex_normals.into_iter().map(vec3norm).collect()
+    }
+
+    /// Computes vertex normals for the triangles which don't specify them
+    fn fill_vertex_normals(&mut self) {
+        if self.triangles.iter().flatten().all(|vertex| vertex.normal().is_some()) {
+            return;
+        }
+
+        // Computed normals are appended after the file's, indexed by position
+        let offset = self.normals.len();
+        self.normals.extend(self.vertex_normals());
+        for vertex in self.triangles.iter_mut().flatten() {
+            if vertex.normal == NONE {
+                vertex.normal = offset + vertex.position;
+            }
+        }
+    }
+
+    /// Splits every triangle in 4 at the middle of its edges, then with Loop subdivision moves the
+    /// positions to smooth the surface
+    fn subdivide(&mut self, scheme: SubdivisionScheme) {
+        let mut position_midpoints = HashMap::new();
+        let mut normal_midpoints = HashMap::new();
+        let mut uv_midpoints = HashMap::new();
+
+        let triangles = take(&mut self.triangles);
+        let smoothed = (scheme == SubdivisionScheme::Loop).then(|| self.loop_positions(&triangles));
+        self.triangles.reserve(triangles.len() * 4);
+        self.materials = self.materials.iter().flat_map(|&material| [material; 4]).collect();
+        for &[a, b, c] in triangles.iter() {
+            let mut midpoint = |a: Vertex, b: Vertex| {
+                let position = midpoint(&mut self.positions, &mut position_midpoints, a.position, b.position, |a, b| {
+                    vec3scale(vec3add(a, b), 0.5)
+                });
+                // Colors are indexed by position, new positions get the average color
+                if position == self.colors.len() && !self.colors.is_empty() {
+                    self.colors.push(vec3scale(vec3add(self.colors[a.position], self.colors[b.position]), 0.5));
+                }
+                let normal = a.normal().zip(b.normal()).map(|(a, b)| {
+                    midpoint(&mut self.normals, &mut normal_midpoints, a, b, |a, b| vec3norm(vec3add(a, b)))
+                });
+                let uv = a.uv().zip(b.uv()).map(|(a, b)| {
+                    midpoint(&mut self.uvs, &mut uv_midpoints, a, b, |a, b| ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0))
+                });
+                Vertex::new(position, normal, uv)
+            };
+            let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+
+            self.triangles.extend([[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]]);
+        }
+
+        if let Some(LoopPositions { vertices, edges }) = smoothed {
+            self.positions[..vertices.len()].copy_from_slice(&vertices);
+            for (edge, midpoint) in position_midpoints {
+                self.positions[midpoint] = edges[&edge];
+            }
+
+            // The normals don't match the smoothed surface anymore
+            self.normals.clear();
+            for vertex in self.triangles.iter_mut().flatten() {
+                vertex.normal = NONE;
+            }
+        }
+    }
+
+    /// Returns the Loop subdivision positions of the existing vertices and of the edge midpoints
+    ///
+    /// Edges with a single triangle are boundaries (as are non-manifold ones), which are smoothed
+    /// along the boundary only, and vertices on more than two boundary edges are kept in place.
+    fn loop_positions(&self, triangles: &[[Vertex; 3]]) -> LoopPositions {
+        let mut opposites: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for triangle in triangles {
+            let p = triangle.map(|vertex| vertex.position);
+            for i in 0..3 {
+                let (a, b) = (p[i], p[(i + 1) % 3]);
+                opposites.entry((a.min(b), a.max(b))).or_default().push(p[(i + 2) % 3]);
+            }
+        }
+
+        let mut neighbors = vec![Vec::new(); self.positions.len()];
+        let mut boundary_neighbors = vec![Vec::new(); self.positions.len()];
+        let mut edges = HashMap::with_capacity(opposites.len());
+        for (&(a, b), opposite) in &opposites {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+            let midpoint = vec3add(self.positions[a], self.positions[b]);
+            let position = match opposite[..] {
+                [c, d] => vec3add(
+                    vec3scale(midpoint, 3.0 / 8.0),
+                    vec3scale(vec3add(self.positions[c], self.positions[d]), 1.0 / 8.0),
+                ),
+                _ => {
+                    boundary_neighbors[a].push(b);
+                    boundary_neighbors[b].push(a);
+                    vec3scale(midpoint, 0.5)
+                }
+            };
+            edges.insert((a, b), position);
+        }
+
+        let vertices = self.positions.iter().enumerate()
+            .map(|(i, &position)| match boundary_neighbors[i][..] {
+                [] if !neighbors[i].is_empty() => {
+                    let n = neighbors[i].len();
+                    let beta = if n == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n as f64) };
+                    let sum = neighbors[i].iter().fold((0.0, 0.0, 0.0), |sum, &j| vec3add(sum, self.positions[j]));
+                    vec3add(vec3scale(position, 1.0 - n as f64 * beta), vec3scale(sum, beta))
+                }
+                [a, b] => vec3add(
+                    vec3scale(position, 3.0 / 4.0),
+                    vec3scale(vec3add(self.positions[a], self.positions[b]), 1.0 / 8.0),
+                ),
+                _ => position,
+            })
+            .collect();
+
+        LoopPositions { vertices, edges }
+    }
+
+    /// Moves the positions along their normal by the height sampled from the texture
+    fn displace(&mut self, texture: &Texture, scale: f64) {
+        let normals = self.vertex_normals();
+        let mut uvs = vec![None; self.positions.len()];
+        for vertex in self.triangles.iter().flatten() {
+            if let Some(uv) = vertex.uv() {
+                // Positions on UV seams get the UV of their first use to keep the mesh watertight
+                uvs[vertex.position].get_or_insert(self.uvs[uv]);
+            }
+        }
+
+        for ((position, normal), uv) in self.positions.iter_mut().zip(normals).zip(uvs) {
+            if let Some(uv) = uv {
+                *position = vec3add(*position, vec3scale(normal, texture.sample(uv, 0.0).r * scale));
+            }
+        }
+
+        // The normals don't match the displaced surface anymore
+        self.normals.clear();
+        for vertex in self.triangles.iter_mut().flatten() {
+            vertex.normal = NONE;
+        }
+    }
+
+    /// Writes the mesh's buffers to a mesh cache, followed by its BVH if `bvh` is set
+    fn write_cache(&self, path: &str, bvh: bool) -> Result<(), String> {
+        let mut buffers = vec![
+            mesh_cache::bytes(&self.positions),
+            mesh_cache::bytes(&self.normals),
+            mesh_cache::bytes(&self.uvs),
+            mesh_cache::bytes(&self.colors),
+            mesh_cache::bytes(&self.triangles),
+            mesh_cache::bytes(&self.materials),
+        ];
+        if bvh {
+            buffers.extend(self.bvh.cache_buffers());
+        }
+        mesh_cache::write(path, &buffers)
+    }
+
+    /// Builds the Embree geometry of the triangles, once they won't change anymore
+    #[cfg(feature = "embree")]
+    fn with_embree(self) -> Result<Self, String> {
+        let triangles = self.triangles.iter().map(|triangle| triangle.map(|vertex| vertex.position));
+        let embree = embree::Triangles::new(&self.positions, triangles)?;
+        Ok(Self { embree: Some(embree), ..self })
+    }
+
+    /// Returns the distance of the closest triangle hit, its index and barycentric coordinates
+    #[cfg(not(feature = "embree"))]
+    fn closest_triangle(&self, ray: &Ray) -> Option<(f64, (usize, f64, f64))> {
+        self.bvh.closest(ray, |i| {
+            let (distance, u, v) = intersect_triangle(ray, self.triangles[i].map(|vertex| self.positions[vertex.position]))?;
+            Some((distance, (i, u, v)))
+        })
+    }
+
+    /// Returns the distance of the closest triangle hit, its index and barycentric coordinates,
+    /// intersecting the triangle Embree finds again in double precision
+    #[cfg(feature = "embree")]
+    fn closest_triangle(&self, ray: &Ray) -> Option<(f64, (usize, f64, f64))> {
+        let (distance, index, u, v) = self.embree.as_ref()?.intersect(ray)?;
+        let (distance, u, v) = intersect_triangle(ray, self.triangles[index].map(|vertex| self.positions[vertex.position]))
+            .unwrap_or((distance, u, v));
+        Some((distance, (index, u, v)))
+    }
+
+    fn build_bvh(&mut self, options: &BvhOptions) {
+        let bounds = self.triangles.iter()
+            .map(|triangle| triangle.iter().fold(Aabb::empty(), |bounds, vertex| {
+                bounds.union(&Aabb::point(self.positions[vertex.position]))
+            }))
+            .collect::<Vec<_>>();
+        self.bvh = Bvh::build(&bounds, options);
+    }
+}
+
+impl Vertex {
+    fn new(position: usize, normal: Option<usize>, uv: Option<usize>) -> Self {
+        Self {
+            position,
+            normal: normal.unwrap_or(NONE),
+            uv: uv.unwrap_or(NONE),
+        }
+    }
+
+    fn normal(self) -> Option<usize> {
+        (self.normal != NONE).then_some(self.normal)
+    }
+
+    fn uv(self) -> Option<usize> {
+        (self.uv != NONE).then_some(self.uv)
+    }
+}
+
+/// Converts a mesh file to a mesh cache, which is mapped instead of parsed when rendering, and
+/// returns the number of triangles
+///
+/// Vertex normals are computed for the meshes without, so that smooth meshes don't need to be
+/// copied into memory to add them.
+pub fn convert_mesh(path: &str, cache_path: &str) -> Result<usize, String> {
+    let mut mesh = Mesh::load(path)?;
+    mesh.fill_vertex_normals();
+    mesh.write_cache(cache_path, false)?;
+
+    Ok(mesh.triangles.len())
+}
+
+/// Caches the meshes built from then on in `dir`, named after the hash of their file, their options
+/// and the BVH options, so that later renders map them with their BVH instead of building them
+pub fn set_mesh_cache_dir(dir: &str) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|err| format!("Failed to create mesh cache directory {}: {}", dir, err))?;
+    *CACHE_DIR.lock().unwrap() = Some(dir.to_string());
+    Ok(())
+}
+
+/// Path of the cache of a mesh in the cache directory, `None` without one or for meshes already
+/// loaded from a mesh cache
+fn cache_path(value: &Value, data: &MeshData, bvh: &BvhOptions) -> Result<Option<String>, String> {
+    let Some(dir) = CACHE_DIR.lock().unwrap().clone() else {
+        return Ok(None);
+    };
+    let path = Path::new(&data.path);
+    if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("cmesh")) {
+        return Ok(None);
+    }
+
+    let source = fs::read(path)
+        .map_err(|err| format!("Failed to open mesh file {}: {}", data.path, err))?;
+    // Displacement textures which can't be read fail to load later on
+    let displacement = data.displacement.as_ref()
+        .and_then(|displacement| fs::read(displacement.texture.path()).ok())
+        .unwrap_or_default();
+    let options = serde_json::to_string(&(value, bvh)).map_err(|err| err.to_string())?;
+    let hash = mesh_cache::content_hash(&[&source, &displacement, options.as_bytes()]);
+
+    Ok(Path::new(&dir).join(format!("{:016x}.cmesh", hash)).to_str().map(str::to_string))
+}
+
+impl ObjectType for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
+        // Meshes aren't necessarily closed, only report the closest hit
+        let (distance, (index, u, v)) = self.closest_triangle(ray)?;
+
+        let triangle = &self.triangles[index];
+        let w = 1.0 - u - v;
+        let p = triangle.map(|vertex| self.positions[vertex.position]);
+        let intersection = vec3add(vec3add(vec3scale(p[0], w), vec3scale(p[1], u)), vec3scale(p[2], v));
+        let normal = match (self.shading, triangle.map(Vertex::normal)) {
+            (Shading::Smooth, [Some(n0), Some(n1), Some(n2)]) => vec3norm(vec3add(
+                vec3add(vec3scale(self.normals[n0], w), vec3scale(self.normals[n1], u)),
+                vec3scale(self.normals[n2], v),
+            )),
+            _ => vec3norm(vec3cross(vec3sub(p[1], p[0]), vec3sub(p[2], p[0]))),
+        };
+        let uvs = match triangle.map(Vertex::uv) {
+            [Some(uv0), Some(uv1), Some(uv2)] => [self.uvs[uv0], self.uvs[uv1], self.uvs[uv2]],
+            _ => [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+        };
+        let uv = (
+            uvs[0].0 * w + uvs[1].0 * u + uvs[2].0 * v,
+            uvs[0].1 * w + uvs[1].1 * u + uvs[2].1 * v,
+        );
+
+        // Solve for the direction of increasing u from the edges and their UV deltas
+        let (edge1, edge2) = (vec3sub(p[1], p[0]), vec3sub(p[2], p[0]));
+        let (duv1, duv2) = ((uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1), (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1));
+        let det = duv1.0 * duv2.1 - duv2.0 * duv1.1;
+        let tangent = if det.abs() < f64::EPSILON {
+            vec3norm(edge1)
+        } else {
+            vec3norm(vec3sub(vec3scale(edge1, duv2.1), vec3scale(edge2, duv1.1)))
+        };
+
+        let hit = Hit {
+            distance,
+            intersection,
+            normal,
+            uv,
+            tangent,
+            front_face: true,
+            material: self.materials.get(index).copied().unwrap_or(0),
+            barycentric: Some((w, u, v)),
+            color: (!self.colors.is_empty()).then(|| {
+                let c = triangle.map(|vertex| self.colors[vertex.position]);
+                vec3add(vec3add(vec3scale(c[0], w), vec3scale(c[1], u)), vec3scale(c[2], v))
+            }),
+        };
+        Some(Interval {
+            entry: hit,
+            exit: hit,
+        })
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn visit_bvh(&self, ray: &Ray, visit: &mut NodeVisitor) {
+        self.bvh.visit(ray, visit);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+}
+
+/// Intersects a ray with a triangle (Möller–Trumbore), returning the distance and barycentric coordinates
+fn intersect_triangle(ray: &Ray, p: [(f64, f64, f64); 3]) -> Option<(f64, f64, f64)> {
+    let edge1 = vec3sub(p[1], p[0]);
+    let edge2 = vec3sub(p[2], p[0]);
+    let pvec = vec3cross(ray.direction, edge2);
+    let det = vec3dot(edge1, pvec);
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let tvec = vec3sub(ray.origin, p[0]);
+    let u = vec3dot(tvec, pvec) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let qvec = vec3cross(tvec, edge1);
+    let v = vec3dot(ray.direction, qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let distance = vec3dot(edge2, qvec) * inv_det;
+    (distance >= ray.min_distance).then_some((distance, u, v))
+}
+
+/// Returns the index of the value halfway between `a` and `b`, adding it if it doesn't exist yet
+fn midpoint<T: Plain>(values: &mut Buffer<T>, midpoints: &mut HashMap<(usize, usize), usize>, a: usize, b: usize, mix: fn(T, T) -> T) -> usize {
+    *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+        values.push(mix(values[a], values[b]));
+        values.len() - 1
+    })
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+struct PlyProperty {
+    name: String,
+    /// Type of the length of list properties
+    count_type: Option<PlyType>,
+    item_type: PlyType,
+}
+
+#[derive(Clone, Copy)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+/// Reads the values of a PLY file's body
+struct PlyReader<'a> {
+    format: PlyFormat,
+    source: &'a [u8],
+    offset: usize,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(format!("unknown property type {}", name)),
+        })
+    }
+
+    /// Returns the largest value of integer types, 1 for floating point types
+    const fn max_value(self) -> f64 {
+        match self {
+            Self::I8 => i8::MAX as f64,
+            Self::U8 => u8::MAX as f64,
+            Self::I16 => i16::MAX as f64,
+            Self::U16 => u16::MAX as f64,
+            Self::I32 => i32::MAX as f64,
+            Self::U32 => u32::MAX as f64,
+            Self::F32 | Self::F64 => 1.0,
+        }
+    }
+
+    const fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+impl PlyReader<'_> {
+    fn read(&mut self, ty: PlyType) -> Result<f64, String> {
+        if let PlyFormat::Ascii = self.format {
+            let rest = &self.source[self.offset..];
+            let start = rest.iter().position(|c| !c.is_ascii_whitespace()).ok_or("unexpected end of file")?;
+            let len = rest[start..].iter().position(u8::is_ascii_whitespace).unwrap_or(rest.len() - start);
+            self.offset += start + len;
+            return std::str::from_utf8(&rest[start..start + len])
+                .ok()
+                .and_then(|token| token.parse().ok())
+                .ok_or_else(|| "invalid value".to_string());
+        }
+
+        let bytes = self.source.get(self.offset..self.offset + ty.size()).ok_or("unexpected end of file")?;
+        self.offset += ty.size();
+        let mut buf = [0; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        if let PlyFormat::BigEndian = self.format {
+            buf[..bytes.len()].reverse();
+        }
+
+        Ok(match ty {
+            PlyType::I8 => buf[0] as i8 as f64,
+            PlyType::U8 => buf[0] as f64,
+            PlyType::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::I32 => i32::from_le_bytes(buf[..4].try_into().unwrap()) as f64,
+            PlyType::U32 => u32::from_le_bytes(buf[..4].try_into().unwrap()) as f64,
+            PlyType::F32 => f32::from_le_bytes(buf[..4].try_into().unwrap()) as f64,
+            PlyType::F64 => f64::from_le_bytes(buf),
+        })
+    }
+}
+
+fn parse_floats<'a, const N: usize>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<[f64; N]> {
+    let mut values = [0.0; N];
+    for value in &mut values {
+        *value = tokens.next()?.parse().ok()?;
+    }
+    Some(values)
+}
+
+const fn default_displacement_scale() -> f64 { 1.0 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIANGLE_PLY: &[u8] = b"ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+0 1 0
+3 0 1 2
+";
+
+    fn cache_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("crusty-test-{}-{}.cmesh", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_ply() {
+        let mesh = Mesh::parse_ply(TRIANGLE_PLY).unwrap();
+        assert_eq!(mesh.positions.len(), 3);
+        assert_eq!(mesh.triangles.len(), 1);
+    }
+
+    #[test]
+    fn malformed_ply() {
+        let ply = std::str::from_utf8(TRIANGLE_PLY).unwrap();
+        for source in [
+            ply.replace("end_header", "end"),
+            ply.replace("ply\n", ""),
+            ply.replace("format ascii 1.0\n", ""),
+            ply.replace("element vertex 3", "element vertex three"),
+            ply.replace("3 0 1 2", "3 0 1 5"),
+            ply.replace("3 0 1 2", "2 0 1"),
+            ply.replace("0 1 0\n3 0 1 2\n", "0 1"),
+        ] {
+            assert!(Mesh::parse_ply(source.as_bytes()).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn truncated_binary_ply() {
+        let mut source = b"ply\nformat binary_little_endian 1.0\nelement vertex 2\nproperty float x\nend_header\n".to_vec();
+        source.extend_from_slice(&1.0f32.to_le_bytes());
+        source.extend_from_slice(&[0; 2]);
+        assert!(Mesh::parse_ply(&source).is_err());
+    }
+
+    #[test]
+    fn malformed_stl() {
+        let facet = "solid test\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid test\n";
+        assert_eq!(Mesh::parse_stl(facet.as_bytes()).unwrap().triangles.len(), 1);
+        assert!(Mesh::parse_stl(facet.replace("vertex 0 1 0\n", "").as_bytes()).is_err());
+        assert!(Mesh::parse_stl(facet.replace("vertex 0 1 0", "vertex 0 1").as_bytes()).is_err());
+
+        // A binary file missing its last triangle isn't read as binary, nor is it text
+        let mut binary = vec![0xff; 80];
+        binary.extend_from_slice(&2u32.to_le_bytes());
+        binary.extend_from_slice(&[0; 50]);
+        assert!(Mesh::parse_stl(&binary).is_err());
+    }
+
+    #[test]
+    fn corrupt_cache() {
+        let path = cache_path("mesh");
+        let mut mesh = Mesh::parse_ply(TRIANGLE_PLY).unwrap();
+        mesh.write_cache(&path, false).unwrap();
+        assert_eq!(Mesh::map_cache(&path).unwrap().triangles.len(), 1);
+
+        // Truncated, losing the end of the last buffer
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 8]).unwrap();
+        assert!(Mesh::map_cache(&path).is_err());
+
+        // Indexing a vertex position out of range
+        mesh.triangles[0][2].position = 3;
+        mesh.write_cache(&path, false).unwrap();
+        assert!(Mesh::map_cache(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
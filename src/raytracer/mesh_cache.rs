@@ -0,0 +1,306 @@
+//! Binary cache of parsed meshes, memory-mapped when loaded so that huge meshes are paged in from
+//! the file as rays reach them instead of being copied into memory
+//!
+//! A cache is a header followed by buffers of raw values, each prefixed by its length. Values are
+//! stored as laid out in memory, so caches can only be read on platforms with the same `usize` and
+//! endianness as the one which wrote them.
+//!
+//! Caches written with `--cache` also hold the mesh's BVH, and are named after the hash of what
+//! they were built from so that renders of the same scene find them instead of building again.
+
+use crate::raytracer::utils::random;
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::marker::PhantomData;
+use std::mem::{align_of, offset_of, size_of};
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"CRUSTYM\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+/// 64-bit FNV-1a constants
+const HASH_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const HASH_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// Tuples have no guaranteed layout, check they are laid out like arrays
+const _: () = assert!(size_of::<(f64, f64)>() == 16 && offset_of!((f64, f64), 1) == 8);
+const _: () = assert!(size_of::<(f64, f64, f64)>() == 24 && offset_of!((f64, f64, f64), 1) == 8 && offset_of!((f64, f64, f64), 2) == 16);
+
+/// Values stored in caches as their raw bytes
+///
+/// # Safety
+///
+/// The type must have no padding, be valid for any bit pattern, and its size must be a multiple of
+/// 8 bytes so that the buffers following it stay aligned.
+pub(super) unsafe trait Plain: Copy {}
+
+unsafe impl Plain for usize {}
+unsafe impl Plain for (f64, f64) {}
+unsafe impl Plain for (f64, f64, f64) {}
+
+/// Values owned in memory or mapped from a cache, mapped values are copied into memory on the first
+/// modification
+pub(super) enum Buffer<T> {
+    Owned(Vec<T>),
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+        marker: PhantomData<T>,
+    },
+}
+
+/// Reads the buffers of a cache in the order they were written
+pub(super) struct CacheReader {
+    path: String,
+    map: Arc<Mmap>,
+    offset: usize,
+}
+
+/// Writes buffers to a cache file, through a temporary file so that readers never see it partly
+/// written
+pub(super) fn write(path: &str, buffers: &[&[u8]]) -> Result<(), String> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + buffers.iter().map(|buffer| 8 + buffer.len()).sum::<usize>());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&layout().to_le_bytes());
+    for buffer in buffers {
+        data.extend_from_slice(&(buffer.len() as u64).to_le_bytes());
+        data.extend_from_slice(buffer);
+    }
+
+    let temporary_path = format!("{}.{:x}.tmp", path, random::<u64>());
+    fs::write(&temporary_path, data)
+        .and_then(|_| fs::rename(&temporary_path, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&temporary_path);
+            format!("Failed to write mesh cache {}: {}", path, err)
+        })
+}
+
+/// Hashes the contents caches are built from, to name them
+pub(super) fn content_hash(contents: &[&[u8]]) -> u64 {
+    // FNV-1a over the version then each content prefixed by its length, so that contents split
+    // differently hash differently
+    let mut hash = HASH_OFFSET;
+    let mut add = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(HASH_PRIME);
+        }
+    };
+    add(&VERSION.to_le_bytes());
+    for content in contents {
+        add(&(content.len() as u64).to_le_bytes());
+        add(content);
+    }
+    hash
+}
+
+/// Returns the raw bytes of values, to be written to a cache
+pub(super) fn bytes<T: Plain>(values: &[T]) -> &[u8] {
+    // SAFETY: Plain types have no padding bytes, which would be uninitialized
+    unsafe { slice::from_raw_parts(values.as_ptr().cast::<u8>(), size_of_val(values)) }
+}
+
+/// Identifies the platforms a cache can be read on, from the size of `usize` and the endianness
+fn layout() -> u32 {
+    size_of::<usize>() as u32 | if cfg!(target_endian = "big") { 0x100 } else { 0 }
+}
+
+impl CacheReader {
+    pub(super) fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("Failed to open mesh cache {}: {}", path, err))?;
+        // SAFETY: the cache must not be modified while mapped, like any file the renderer reads
+        let map = unsafe { Mmap::map(&file) }.map_err(|err| format!("Failed to map mesh cache {}: {}", path, err))?;
+
+        if map.len() < HEADER_SIZE || &map[..8] != MAGIC {
+            return Err(format!("{} is not a mesh cache", path));
+        }
+        let read_u32 = |offset: usize| u32::from_le_bytes(map[offset..offset + 4].try_into().unwrap());
+        if read_u32(8) != VERSION {
+            return Err(format!("Mesh cache {} has an unsupported version, convert the mesh again", path));
+        }
+        if read_u32(12) != layout() {
+            return Err(format!("Mesh cache {} was written on a different platform, convert the mesh again", path));
+        }
+
+        Ok(Self { path: path.to_string(), map: Arc::new(map), offset: HEADER_SIZE })
+    }
+
+    /// Whether all the buffers of the cache were read
+    pub(super) fn is_done(&self) -> bool {
+        self.offset == self.map.len()
+    }
+
+    /// Maps the next buffer of the cache
+    pub(super) fn next<T: Plain>(&mut self) -> Result<Buffer<T>, String> {
+        let invalid = || format!("Mesh cache {} is truncated or invalid", self.path);
+        let size = self.map.get(self.offset..self.offset + 8).ok_or_else(invalid)?;
+        // The size comes from the file, it mustn't wrap around when added to the offset
+        let size = usize::try_from(u64::from_le_bytes(size.try_into().unwrap())).map_err(|_| invalid())?;
+        let offset = self.offset + 8;
+        if !size.is_multiple_of(size_of::<T>())
+            || offset.checked_add(size).is_none_or(|end| end > self.map.len())
+            || !offset.is_multiple_of(align_of::<T>())
+        {
+            return Err(invalid());
+        }
+
+        self.offset = offset + size;
+        Ok(Buffer::Mapped {
+            map: self.map.clone(),
+            offset,
+            len: size / size_of::<T>(),
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Plain> Buffer<T> {
+    /// Returns the values for modification, copying them into memory if they are mapped
+    pub(super) fn to_mut(&mut self) -> &mut Vec<T> {
+        if let Buffer::Mapped { .. } = self {
+            *self = Buffer::Owned(self.to_vec());
+        }
+        match self {
+            Buffer::Owned(values) => values,
+            Buffer::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    pub(super) fn push(&mut self, value: T) {
+        self.to_mut().push(value);
+    }
+
+    pub(super) fn extend(&mut self, values: impl IntoIterator<Item = T>) {
+        self.to_mut().extend(values);
+    }
+
+    pub(super) fn reserve(&mut self, additional: usize) {
+        self.to_mut().reserve(additional);
+    }
+
+    pub(super) fn clear(&mut self) {
+        *self = Buffer::Owned(Vec::new());
+    }
+}
+
+impl<T: Plain> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Buffer::Owned(values) => values,
+            // SAFETY: the reader checked the buffer is within the map and aligned for T, and Plain
+            // types are valid for any bytes
+            Buffer::Mapped { map, offset, len, .. } => unsafe {
+                slice::from_raw_parts(map.as_ptr().add(*offset).cast::<T>(), *len)
+            },
+        }
+    }
+}
+
+impl<T: Plain> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.to_mut()
+    }
+}
+
+impl<T> Default for Buffer<T> {
+    fn default() -> Self {
+        Buffer::Owned(Vec::new())
+    }
+}
+
+impl<T> FromIterator<T> for Buffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Buffer::Owned(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a cache with the given bytes after its header, returning its path
+    fn cache_file(name: &str, body: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("crusty-test-{}-{}.cmesh", name, std::process::id()));
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&layout().to_le_bytes());
+        data.extend_from_slice(body);
+        fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn huge_length_prefix() {
+        let path = cache_file("huge", &(u64::MAX - 7).to_le_bytes());
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert!(reader.next::<usize>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_length_prefix() {
+        let path = cache_file("truncated", &[8, 0, 0, 0]);
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert!(reader.next::<usize>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn buffer_past_the_end() {
+        let mut body = 16u64.to_le_bytes().to_vec();
+        body.extend_from_slice(&1usize.to_le_bytes());
+        let path = cache_file("past-end", &body);
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert!(reader.next::<usize>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn partial_value() {
+        let mut body = 12u64.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 16]);
+        let path = cache_file("partial", &body);
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert!(reader.next::<usize>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_buffers_in_order() {
+        let path = cache_file("valid", &[]);
+        write(&path, &[bytes(&[1usize, 2]), bytes(&[(0.5, 1.5)])]).unwrap();
+        let mut reader = CacheReader::open(&path).unwrap();
+        assert_eq!(*reader.next::<usize>().unwrap(), [1, 2]);
+        assert_eq!(*reader.next::<(f64, f64)>().unwrap(), [(0.5, 1.5)]);
+        assert!(reader.is_done());
+        assert!(reader.next::<usize>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let path = cache_file("header", &[]);
+        let mut data = fs::read(&path).unwrap();
+        data[8] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert!(CacheReader::open(&path).is_err());
+        fs::write(&path, &data[..HEADER_SIZE - 1]).unwrap();
+        assert!(CacheReader::open(&path).is_err());
+        fs::write(&path, b"not a mesh cache").unwrap();
+        assert!(CacheReader::open(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn content_hash_separates_contents() {
+        assert_eq!(content_hash(&[b"mesh", b"options"]), content_hash(&[b"mesh", b"options"]));
+        assert_ne!(content_hash(&[b"mesh", b"options"]), content_hash(&[b"meshoptions", b""]));
+        assert_ne!(content_hash(&[b"mesh"]), content_hash(&[b"mesg"]));
+    }
+}
@@ -1,46 +1,204 @@
+mod bvh;
+mod composite;
+mod debug_shading;
+mod dielectric;
+mod diffuse;
+#[cfg(feature = "embree")]
+mod embree;
+mod filter;
+mod ggx;
+mod graph;
+mod image_file;
+mod lights;
 mod materials;
+mod mesh;
+mod mesh_cache;
 mod objects;
+mod path_debug;
+mod plugins;
+mod post;
+mod random_scene;
 mod scene;
+mod scripting;
+mod sheen;
+mod sky;
+mod spectrum;
+mod stats;
+mod textures;
+mod thin_film;
 mod tile;
 mod transform;
 mod utils;
+mod uv_projection;
+mod workers;
 
-use rand;
-use std::collections::{HashMap, VecDeque};
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
-use std::ptr;
+use crate::log;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use materials::Material;
-use objects::Object;
+/// Luminance added to the pixels' when estimating their relative noise, so that dark pixels don't dominate
+const NOISE_LUMINANCE_OFFSET: f64 = 0.01;
+/// Iterations inverting the lens distortion of camera rays, enough for the distortions of real lenses
+const UNDISTORT_ITERATIONS: u32 = 8;
+
+use image_file::ImageFile;
+use lights::{LightSampler, Portal};
+use materials::ShadeContext;
+use objects::{Object, ObjectHit};
 use scene::Scene;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use textures::Texture;
+use sky::Sky;
 use tile::Tile;
 use transform::Transform;
-use utils::vec3norm;
+use utils::{vec3add, vec3dot, vec3norm, vec3scale, vec3sub};
+
+pub use debug_shading::{BoundsOverlay, DebugShading};
+pub use dielectric::Media;
+pub use filter::Filter;
+pub use mesh::{convert_mesh, set_mesh_cache_dir};
+pub use path_debug::{PathHit, PathVertex, PixelPaths};
+pub use stats::{Counter, Stats};
+pub use post::{register_post_effect, PostEffect, PostEffectNewFn, PostImage};
+pub use random_scene::random_scene;
+pub use spectrum::Wavelengths;
+pub use scene::{SceneBuilder, SceneDistortion, SceneExposure, SceneMaterial, SceneObjectMaterial, SceneStereo, SceneTransform, SceneWhiteBalance};
+pub use tile::{TileOrder, TileTiming};
+pub use workers::Workers;
 
+thread_local! {
+    /// Rays traced by the current thread, added to the raytracer's total after each tile
+    static RAYS: Cell<u64> = const { Cell::new(0) };
+    /// Light of each light group in the color last returned by [`Raytracer::raytrace`], when the
+    /// scene has light groups
+    static GROUP_COLORS: RefCell<Vec<RGBA>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Scene and render state, shared between the render threads through an `Arc`
+///
+/// The scene data (objects, their materials and BVHs, lights) is immutable once built, so shading
+/// reads it without any lock. The workers only lock the tiles once per tile.
 pub struct Raytracer {
     camera: Camera,
     output: Output,
+    /// Where the render is saved once completed
+    file: Option<ImageFile>,
+    /// First error writing the image file, if any
+    file_error: Mutex<Option<String>>,
+    world: World,
     objects: Vec<Object>,
+    /// Embree scene of the objects, which rays are intersected with instead of each object in turn
+    #[cfg(feature = "embree")]
+    embree: embree::Objects,
+    lights: LightSampler,
+    /// Seed of the random numbers, renders with the same seed are identical
+    seed: u64,
+    /// Shading replacing the materials, if any
+    shading: Option<DebugShading>,
+    /// Bounding boxes drawn over the render, if any
+    bounds_overlay: Option<BoundsOverlay>,
+    /// Whether camera rays sample wavelengths, for dispersion
+    spectral: bool,
+    /// Progressive passes are rendered until the image's noise is below the threshold, if any
+    noise_threshold: Option<f64>,
+    /// Maximum number of samples per pixel with a noise threshold
+    max_samples: u32,
+    /// Time after which no more tiles are started, the render then completes with the tiles rendered so far
+    time_limit: Option<Duration>,
+    /// Applied in order once the render completes
+    post_effects: Vec<Box<dyn PostEffect + Send + Sync>>,
     progress: AtomicU32,
+    /// Number of rays traced, including shadow rays
+    rays: AtomicU64,
+    stats: Stats,
     stop: AtomicBool,
+    /// Whether the workers wait before starting new tiles, read by the workers without locking
+    paused: AtomicBool,
+    /// Held while changing `paused` and by the paused workers, so that they can't miss `resumed`
+    pause_lock: Mutex<()>,
+    resumed: Condvar,
     tiles: Mutex<VecDeque<Tile>>,
+    /// Whether the tiles' timings are recorded
+    record_tiles: AtomicBool,
+    tile_timings: Mutex<Vec<TileTiming>>,
 }
 
 struct Camera {
     fov: f64,
+    /// Distance of the image plane, see `SceneCamera`
     near: f64,
+    /// Distances along the view axis between which camera rays see objects
+    clip: (f64, f64),
+    stereo: Option<SceneStereo>,
+    /// Radial distortion coefficients k1 and k2, see `SceneDistortion`
+    distortion: (f64, f64),
+    /// Factors the samples' channels are multiplied by, from `SceneExposure` and
+    /// `SceneWhiteBalance`
+    exposure: (f64, f64, f64),
     transform: Transform,
 }
 
+struct World {
+    background: (f64, f64, f64),
+    /// Whether camera rays which miss are transparent instead of showing the background
+    transparent_background: bool,
+    /// Image camera rays which miss see instead of the background, if any
+    backplate: Option<Arc<Texture>>,
+    /// Sky replacing the background, if any
+    sky: Option<Sky>,
+    /// Openings diffuse surfaces sample the environment through
+    portals: Vec<Portal>,
+}
+
 pub struct Output {
     pub width: u32,
     pub height: u32,
     samples: u32,
-    tile_size: u32,
+    /// Size of the tiles, picked for each render from the resolution and the number of workers if
+    /// not set
+    tile_size: Option<u32>,
+    /// Size of the tiles of the last render started
+    render_tile_size: AtomicU32,
+    tile_order: TileOrder,
+    ray_epsilon: f64,
+    depth_limits: DepthLimits,
+    filter: Filter,
+    /// Display colors packed from the accumulated samples, as RGBA8888
     buffer: Vec<AtomicU32>,
+    /// Incremented whenever the display colors change
+    version: AtomicU64,
+    /// Number of progressive passes rendered
+    passes: AtomicU32,
+    /// Pixels with the post effects applied, once the render completes
+    post_processed: Mutex<Option<Vec<[f32; 4]>>>,
+    /// Sums of the samples of each pixel as f32 bits, see [`Accumulator`], the squared luminances last
+    accumulation: Vec<[AtomicU32; 5]>,
+    /// Sum of the filter weights of the samples of each pixel, as f32 bits
+    weights: Vec<AtomicU32>,
+    /// Number of samples accumulated in each pixel
+    sample_counts: Vec<AtomicU32>,
+    /// ID of the object seen through the center of each pixel, 0 for none
+    object_ids: Vec<AtomicU32>,
+    /// Distance along the camera's view axis to the surface seen through the center of each pixel,
+    /// as f32 bits
+    depths: Vec<AtomicU32>,
+    /// Near and far distances the depths are normalized between, raw distances if not set
+    depth_range: Option<(f64, f64)>,
+    /// World position of the surface seen through the center of each pixel, as f32 bits
+    positions: Vec<[AtomicU32; 3]>,
+    light_groups: Vec<LightGroup>,
+}
+
+/// Pass of the light of a light group, see [`lights::shade_group`]
+struct LightGroup {
+    name: String,
+    /// Sums of the samples of each pixel as f32 bits, premultiplied RGBA then the filter weights
+    accumulation: Vec<[AtomicU32; 5]>,
 }
 
 #[derive(Clone, Copy)]
@@ -48,8 +206,52 @@ pub struct Ray {
     pub ray_type: RayType,
     pub origin: (f64, f64, f64),
     pub direction: (f64, f64, f64),
+    /// Hits closer than this distance along the ray are ignored
+    pub min_distance: f64,
+    /// Number of bounces since the camera
+    pub depth: u32,
+    /// Number of bounces since the camera, by type
+    pub bounces: Bounces,
+    /// Angle covered by the ray's pixel, in radians, used to filter textures
+    pub spread: f64,
+    /// Dielectric media the ray is traveling through
+    pub media: Media,
+    /// Wavelengths of the light the ray carries, see the `spectrum` module
+    pub wavelengths: Wavelengths,
 }
 
+/// Surface hit by a ray traced with [`Raytracer::trace_ray`]
+#[derive(Clone, Copy)]
+pub struct RayHitInfo<'a> {
+    pub distance: f64,
+    pub position: (f64, f64, f64),
+    /// Surface normal, facing against the ray
+    pub normal: (f64, f64, f64),
+    pub uv: (f64, f64),
+    pub object_id: u32,
+    pub object_name: Option<&'a str>,
+    /// Name of the material in the scene, `None` for inline materials
+    pub material_name: Option<&'a str>,
+}
+
+/// Object seen through a point of the image, picked with [`Raytracer::pick`]
+pub struct PickedObject<'a> {
+    /// Position in the scene's objects, the objects of generators and prefab instances coming
+    /// after the objects of the file, and only the objects of the layer being counted for layers
+    pub index: usize,
+    pub id: u32,
+    pub name: Option<&'a str>,
+    /// Name of the material in the scene, `None` for inline materials
+    pub material_name: Option<&'a str>,
+    pub transform: SceneTransform,
+    /// Point of the object seen
+    pub position: (f64, f64, f64),
+}
+
+/// Color premultiplied by its alpha, as returned by materials
+///
+/// Texture samples are the exception, their colors are straight.
+#[derive(Clone, Copy)]
 struct RGBA {
     r: f64,
     g: f64,
@@ -57,9 +259,49 @@ struct RGBA {
     a: f64,
 }
 
-#[derive(Clone, Copy)]
+/// Running sum of a pixel's premultiplied samples, weighted by the reconstruction filter
+#[derive(Default)]
+struct Accumulator {
+    sum: (f64, f64, f64, f64),
+    weight: f64,
+    /// Sum of the squared luminances of the samples taken in the pixel, to estimate their variance
+    luminance_squares: f64,
+    /// Number of samples taken in the pixel, not counting the ones splatted from its neighbors
+    count: u32,
+}
+
+/// Kind of light path segment a ray is, which materials can read from their hit's ray and objects
+/// can be hidden from
+#[derive(Clone, Copy, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RayType {
     Camera,
+    /// Mirror reflection off a smooth surface
+    Reflection,
+    Diffuse,
+    /// Towards a light, to find whether it is occluded
+    Shadow,
+    /// Refraction through a surface
+    Transmission,
+    /// Reflection off a rough surface
+    Glossy,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Bounces {
+    pub diffuse: u32,
+    /// Reflection bounces, mirror and glossy
+    pub glossy: u32,
+    pub transmission: u32,
+}
+
+/// Maximum number of bounces of secondary rays, in total and by type
+#[derive(Clone, Copy)]
+struct DepthLimits {
+    total: u32,
+    diffuse: u32,
+    glossy: u32,
+    transmission: u32,
 }
 
 impl Raytracer {
@@ -67,70 +309,202 @@ impl Raytracer {
     where
         R: std::io::Read
     {
-        let scene: Scene = serde_json::from_reader(reader)
-            .map_err(|err| format!("Failed to parse scene: {}", err))?;
+        SceneBuilder::from_reader(reader)?.build()
+    }
+
+    fn from_scene(mut scene: Scene) -> Result<Arc<Self>, String> {
+        let start = Instant::now();
+        for path in &scene.plugins {
+            plugins::load(path)?;
+        }
 
-        let materials = scene.materials.iter()
-            .map(|(id, scene_material)| Material::try_from(scene_material).map(|mat| (id.clone(), Arc::new(mat))))
-            .collect::<Result<HashMap<String, Arc<Material>>, String>>()?;
+        for generator in &scene.generators {
+            scene.objects.extend(generator.generate()?);
+        }
 
-        Ok(Arc::new(Self {
+        let materials = scene.build_materials()?;
+        let sky = scene.world.sky()?;
+        let mut lights = LightSampler::try_from(&scene)?;
+        if let Some(sun) = sky.as_ref().and_then(Sky::sun_light) {
+            lights.add(sun);
+        }
+
+        // Meshes are loaded and their BVHs built in parallel
+        let objects = utils::parallel_map(&scene.objects.iter().enumerate().collect::<Vec<_>>(), 1, |&(i, scene_object)| {
+            Ok(Object::try_from(i, scene_object, &materials, &scene.output.bvh)?
+                .with_unlinked_lights(scene.unlinked_lights(scene_object)))
+        })
+        .into_iter()
+        .collect::<Result<Vec<Object>, String>>()?;
+
+        let raytracer = Arc::new(Self {
             camera: Camera::from(&scene.camera),
-            output: Output::from(&scene.output),
-            objects: scene.objects.iter()
-                .map(|scene_object| Object::try_from(scene_object, &materials))
-                .collect::<Result<Vec<Object>, String>>()?,
+            output: Output::from(&scene.output).with_light_groups(lights.groups()),
+            file: scene.output.image_file()?,
+            file_error: Mutex::new(None),
+            world: World { backplate: scene.output.visible_backplate()?, sky, ..World::from(&scene) },
+            #[cfg(feature = "embree")]
+            embree: embree::Objects::new(&objects)?,
+            objects,
+            lights,
             stop: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            pause_lock: Mutex::new(()),
+            resumed: Condvar::new(),
+            record_tiles: AtomicBool::new(false),
+            tile_timings: Mutex::new(Vec::new()),
+            seed: scene.output.seed,
+            shading: scene.output.shading,
+            bounds_overlay: scene.output.bounds_overlay,
+            spectral: scene.output.spectral,
+            noise_threshold: scene.output.noise_threshold,
+            max_samples: scene.output.max_samples,
+            time_limit: scene.output.time_limit.map(Duration::from_secs_f64),
+            post_effects: scene.output.post_effects()?,
             progress: AtomicU32::new(0),
+            rays: AtomicU64::new(0),
+            stats: Stats::default(),
             tiles: Mutex::new(VecDeque::new()),
-        }))
+        });
+        log::debug!("Scene with {} objects built in {:.3}s", raytracer.objects.len(), start.elapsed().as_secs_f64());
+
+        Ok(raytracer)
     }
 
+    /// Returns the number of threads renders use by default, one per logical core
+    pub fn default_threads() -> u32 {
+        thread::available_parallelism().map_or(1, |threads| threads.get() as u32)
+    }
+
+    /// Starts rendering with the given number of worker threads (at least one), see
+    /// [`Raytracer::default_threads`]
     pub fn start(self: &Arc<Self>, threads: u32) -> thread::JoinHandle<()> {
+        self.start_with(Workers::new(threads))
+    }
+
+    /// Starts rendering with worker threads run as configured
+    pub fn start_with(self: &Arc<Self>, workers: Workers) -> thread::JoinHandle<()> {
         let clone = self.clone();
         thread::Builder::new()
             .name("Raytracer".to_string())
             .spawn(move || {
+                clone.output.clear();
+                let tile_size = clone.output.tile_size
+                    .unwrap_or_else(|| tile::auto_tile_size(clone.output.width, clone.output.height, workers.threads()));
+                clone.output.render_tile_size.store(tile_size, Ordering::Relaxed);
+                log::debug!("Rendering tiles of {}x{} pixels", tile_size, tile_size);
+                *clone.file_error.lock().unwrap() = None;
+                clone.tile_timings.lock().unwrap().clear();
+                if let Some(file) = &clone.file
+                    && let Err(err) = file.begin(&clone.output)
                 {
-                    let output = &clone.output;
-                    let mut tiles = clone.tiles.lock().unwrap();
-                    tiles.clear();
-                    for tile in tile::hilbert_tiles(output.width, output.height, output.tile_size) {
-                        tiles.push_front(tile);
-                    }
+                    clone.file_failed(err);
                 }
 
-                println!("Render starting");
+                log::info!("Render starting (threads: {})", workers.threads());
                 let start = Instant::now();
+                let deadline = clone.time_limit.map(|time_limit| start + time_limit);
+
+                for pass in 0.. {
+                    {
+                        let output = &clone.output;
+                        let mut tiles = clone.tiles.lock().unwrap();
+                        tiles.clear();
+                        let seed = clone.seed.wrapping_add(pass as u64);
+                        tiles.extend(tile::tiles(output.tile_order, output.width, output.height, output.tile_size(), seed));
+                        clone.progress.store(0, Ordering::Relaxed);
+                    }
 
-                let threads = (0..threads)
-                    .map(|i| clone.start_worker(i + 1))
-                    .collect::<Vec<_>>();
-                threads.into_iter().for_each(|t| t.join().unwrap());
+                    let threads = (0..workers.threads())
+                        .map(|i| clone.start_worker(i, pass, deadline, &workers))
+                        .collect::<Vec<_>>();
+                    threads.into_iter().for_each(|t| t.join().unwrap());
+
+                    let remaining = clone.tiles.lock().unwrap().len();
+                    if clone.stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if remaining > 0 {
+                        log::info!("Time limit reached, {} tiles not rendered", remaining);
+                        break;
+                    }
+                    // Only completed passes are counted
+                    clone.output.passes.fetch_add(1, Ordering::Relaxed);
+
+                    // Progressive passes until the noise threshold or the maximum number of samples
+                    let Some(threshold) = clone.noise_threshold else { break };
+                    let samples = (pass + 1) * clone.output.samples;
+                    let noise = clone.output.noise();
+                    log::debug!("Pass {} done ({} samples per pixel), noise {:.4}", pass + 1, samples, noise);
+                    if noise <= threshold {
+                        log::info!("Noise threshold reached with {} samples per pixel", samples);
+                        break;
+                    } else if samples + clone.output.samples > clone.max_samples {
+                        log::info!("Maximum of {} samples per pixel reached, noise {:.4}", clone.max_samples, noise);
+                        break;
+                    }
+                }
 
                 if clone.stop.load(Ordering::Relaxed) {
-                    println!("Render cancelled");
+                    log::info!("Render cancelled");
                 } else {
                     let end = Instant::now();
                     let d = end - start;
-                    println!("Render completed in {}.{:03}s", d.as_secs(), d.subsec_millis());
+                    log::info!("Render completed in {}.{:03}s", d.as_secs(), d.subsec_millis());
+                    if stats::ENABLED {
+                        for (name, count) in clone.stats.report() {
+                            log::info!("{:>12} {}", count, name);
+                        }
+                    }
+
+                    if !clone.post_effects.is_empty() {
+                        clone.output.post_process(&clone.post_effects);
+                    }
+
+                    if let Some(file) = &clone.file {
+                        match file.write(&clone.output) {
+                            Ok(()) => log::info!("Render saved to {}", file.path()),
+                            Err(err) => clone.file_failed(err),
+                        }
+                    }
                 }
             })
             .unwrap()
     }
 
-    fn start_worker(self: &Arc<Self>, i: u32) -> thread::JoinHandle<()> {
+    /// Starts a thread rendering tiles until there are none left, or until the deadline if any
+    fn start_worker(self: &Arc<Self>, i: u32, pass: u32, deadline: Option<Instant>, workers: &Workers) -> thread::JoinHandle<()> {
         let clone = self.clone();
+        let workers = workers.clone();
         thread::Builder::new()
-            .name(format!("RT-Worker-{i}"))
+            .name(format!("RT-Worker-{}", i + 1))
             .spawn(move || {
+                // Workers are started again for each pass, only report failures once
+                if let Err(err) = workers.configure(i)
+                    && pass == 0
+                {
+                    log::warning!("{}", err);
+                }
+                let (mut colors, mut groups) = (Vec::new(), Vec::new());
                 loop {
-                    if clone.stop.load(Ordering::Relaxed) {
+                    clone.wait_while_paused();
+                    if clone.stop.load(Ordering::Relaxed) || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
                         break;
                     }
-                    let tile = clone.tiles.lock().unwrap().pop_front();
+                    let tile = {
+                        let mut tiles = clone.tiles.lock().unwrap();
+                        let tile = tiles.pop_front();
+                        // Split the last tiles so that the workers finish the pass together
+                        match tile.as_ref().and_then(|tile| (tiles.len() + 1 < workers.threads() as usize).then(|| tile.split())?) {
+                            Some([first, rest @ ..]) => {
+                                rest.into_iter().rev().for_each(|tile| tiles.push_front(tile));
+                                Some(first)
+                            }
+                            None => tile,
+                        }
+                    };
                     match tile {
-                        Some(tile) => clone.work(tile),
+                        Some(tile) => clone.work(tile, pass, i, &mut colors, &mut groups),
                         None => break,
                     }
                 }
@@ -140,7 +514,40 @@ impl Raytracer {
 
     #[inline]
     pub fn stop(self: &Arc<Self>) {
+        // Under the pause lock so that paused workers can't miss the notification
+        let _lock = self.pause_lock.lock().unwrap();
         self.stop.store(true, Ordering::Relaxed);
+        self.resumed.notify_all();
+    }
+
+    /// Pauses the render once the tiles being rendered are completed, until [`Raytracer::resume`]
+    ///
+    /// The time limit keeps running while paused.
+    pub fn pause(&self) {
+        let _lock = self.pause_lock.lock().unwrap();
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        let _lock = self.pause_lock.lock().unwrap();
+        self.paused.store(false, Ordering::Relaxed);
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Blocks the calling worker while the render is paused, unless it is stopped
+    fn wait_while_paused(&self) {
+        // Only lock when paused, the workers check between every tile
+        if !self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        let lock = self.pause_lock.lock().unwrap();
+        let _lock = self.resumed
+            .wait_while(lock, |_| self.paused.load(Ordering::Relaxed) && !self.stop.load(Ordering::Relaxed))
+            .unwrap();
     }
 
     #[inline]
@@ -148,73 +555,831 @@ impl Raytracer {
         &self.output
     }
 
+    /// Path of the image file the render is saved to, if any
+    pub fn output_path(&self) -> Option<&str> {
+        self.file.as_ref().map(ImageFile::path)
+    }
+
+    /// Returns the first error writing the image file during the last render, if any
+    pub fn file_error(&self) -> Option<String> {
+        self.file_error.lock().unwrap().clone()
+    }
+
+    fn file_failed(&self, err: String) {
+        log::error!("{}", err);
+        self.file_error.lock().unwrap().get_or_insert(err);
+    }
+
+    /// Whether the render was stopped with [`Raytracer::stop`]
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Whether the render is an equirectangular panorama, of each eye for omnidirectional stereo
+    pub fn is_panorama(&self) -> bool {
+        matches!(self.camera.stereo, Some(SceneStereo::Ods { .. }))
+    }
+
+    /// Returns the name of the object with the given ID, if it has one
+    pub fn object_name(&self, id: u32) -> Option<&str> {
+        self.objects.iter().find(|object| object.id() == id)?.name()
+    }
+
+    /// Returns the closest surface along a ray, outside of rendering
+    pub fn trace_ray(&self, origin: (f64, f64, f64), direction: (f64, f64, f64)) -> Option<RayHitInfo<'_>> {
+        let ray = Ray {
+            ray_type: RayType::Camera,
+            origin,
+            direction: vec3norm(direction),
+            min_distance: 0.0,
+            depth: 0,
+            bounces: Bounces::default(),
+            spread: 0.0,
+            media: Media::default(),
+            wavelengths: Wavelengths::All,
+        };
+
+        self.closest_hit(ray).map(|oh| RayHitInfo {
+            distance: oh.hit.distance,
+            position: oh.hit.intersection,
+            normal: oh.hit.normal,
+            uv: oh.hit.uv,
+            object_id: oh.object.id(),
+            object_name: oh.object.name(),
+            material_name: oh.object.material(&oh.hit).name(),
+        })
+    }
+
+    /// Returns the object seen through a point of the image, in pixels from its top left corner
+    pub fn pick(&self, x: f64, y: f64) -> Option<PickedObject<'_>> {
+        let oh = self.closest_hit(self.camera_ray(x, y))?;
+        Some(PickedObject {
+            index: self.objects.iter().position(|object| std::ptr::eq(object, oh.object))?,
+            id: oh.object.id(),
+            name: oh.object.name(),
+            material_name: oh.object.material(&oh.hit).name(),
+            transform: SceneTransform::from(oh.object.transform()),
+            position: oh.hit.intersection,
+        })
+    }
+
+    /// Traces a pixel again as it was rendered, recording every ray of each sample
+    pub fn debug_pixel(&self, x: u32, y: u32) -> PixelPaths {
+        utils::seed_random(self.seed, 0, x, y);
+        let samples = (0..self.output.samples)
+            .map(|_| path_debug::record(|| {
+                let offset: (f64, f64) = utils::random();
+                self.raytrace(self.camera_ray(x as f64 + offset.0, y as f64 + offset.1));
+            }))
+            .collect();
+
+        PixelPaths { x, y, samples }
+    }
+
+    /// Returns the number of rays traced so far, including shadow rays
+    pub fn rays(&self) -> u64 {
+        self.rays.load(Ordering::Relaxed)
+    }
+
+    /// Returns the statistics of the render so far, all zeros in release builds without the `stats` feature
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
     #[inline]
     pub fn progress(self: &Arc<Self>) -> f64 {
         self.progress.load(Ordering::Relaxed) as f64 / (self.output.width * self.output.height) as f64
     }
 
-    fn work(self: &Arc<Self>, tile: Tile) {
+    /// Records when each tile is rendered and by which worker in the next renders, see
+    /// [`Raytracer::tile_timings`]
+    pub fn record_tiles(&self, record: bool) {
+        self.record_tiles.store(record, Ordering::Relaxed);
+    }
+
+    /// Returns the timings of the tiles rendered so far, if recorded
+    pub fn tile_timings(&self) -> Vec<TileTiming> {
+        self.tile_timings.lock().unwrap().clone()
+    }
+
+    /// Renders a progressive pass of a tile with the given worker, `colors` and `groups` are scratch
+    /// space reused between the tiles of a worker
+    fn work(self: &Arc<Self>, tile: Tile, pass: u32, worker: u32, colors: &mut Vec<RGBA>, groups: &mut Vec<Accumulator>) {
+        let start = Instant::now();
+        colors.clear();
         for y in tile.top..tile.bottom {
             for x in tile.left..tile.right {
                 if self.stop.load(Ordering::Relaxed) {
-                    break;
+                    return;
                 }
 
-                let samples: Vec<RGBA> = (0..self.output.samples)
-                    .map(|_| {
-                        let offset: (f64, f64) = rand::random();
-                        let ray = Ray {
-                            ray_type: RayType::Camera,
-                            origin: self.camera.transform.apply((0.0, 0.0, 0.0)),
-                            direction: self.camera.transform.apply_notranslate(vec3norm((
-                                (2.0 * (x as f64 + offset.0) / self.output.width as f64 - 1.0) *
-                                    (self.camera.fov.to_radians() / 2.0).tan() *
-                                    (self.output.width as f64 / self.output.height as f64),
-                                self.camera.near,
-                                (1.0 - 2.0 * (y as f64 + offset.1) / self.output.height as f64) *
-                                    (self.camera.fov.to_radians() / 2.0).tan(),
-                            ))),
-                        };
-
-                        self.raytrace(ray, None)
-                    })
-                    .collect();
-
-                let color = RGBA::average(&samples);
-                self.output.put(x, y, color);
+                utils::seed_random(self.seed, pass, x, y);
+                let mut accumulator = Accumulator::default();
+                groups.clear();
+                groups.resize_with(self.output.light_groups.len(), Accumulator::default);
+                for _ in 0..self.output.samples {
+                    let offset: (f64, f64) = utils::random();
+                    let position = (x as f64 + offset.0, y as f64 + offset.1);
+                    let weight = self.output.filter.weight(offset.0 - 0.5, offset.1 - 0.5);
+                    let ray = self.camera_ray(position.0, position.1);
+                    let mut color = self.raytrace(ray).scaled(self.exposure());
+                    if let Some(overlay) = self.bounds_overlay {
+                        color = overlay.draw(&self.objects, &ray, color);
+                    }
+                    accumulator.add(color, weight);
+                    self.output.splat(x, y, position, color);
+
+                    GROUP_COLORS.with_borrow(|colors| {
+                        for (group, (samples, color)) in groups.iter_mut().zip(colors).enumerate() {
+                            let color = color.scaled(self.exposure());
+                            samples.add(color, weight);
+                            self.output.splat_light_group(group, x, y, position, color);
+                        }
+                    });
+                }
+
+                self.output.accumulate(x, y, &accumulator);
+                self.output.accumulate_light_groups(x, y, groups);
+                colors.push(self.output.pixel(x, y));
+
+                if pass == 0 {
+                    let center = self.closest_hit(self.camera_ray(x as f64 + 0.5, y as f64 + 0.5));
+                    self.output.put_object_id(x, y, center.map_or(0, |hit| hit.object.id()));
+                    self.output.put_surface(x, y, center.map(|hit| (self.camera.depth(hit.hit.intersection), hit.hit.intersection)));
+                }
                 self.progress.fetch_add(1, Ordering::Relaxed);
             }
         }
+        self.output.pack_around(&tile);
+
+        // Later passes are saved once the render completes
+        if let Some(file) = &self.file
+            && pass == 0
+            && let Err(err) = file.write_tile(&tile, colors, &self.output)
+        {
+            self.file_failed(err);
+        }
+        self.rays.fetch_add(RAYS.take(), Ordering::Relaxed);
+        self.stats.flush();
+        log::trace!("Tile at {}x{} rendered in {:.3}s", tile.left, tile.top, start.elapsed().as_secs_f64());
+        if self.record_tiles.load(Ordering::Relaxed) {
+            self.tile_timings.lock().unwrap().push(TileTiming {
+                left: tile.left,
+                right: tile.right,
+                top: tile.top,
+                bottom: tile.bottom,
+                pass,
+                worker,
+                start,
+                end: Instant::now(),
+            });
+        }
+    }
+
+    /// Returns the camera ray through a point of the image, in pixels from its top left corner
+    fn camera_ray(&self, x: f64, y: f64) -> Ray {
+        let camera = &self.camera;
+        let (width, height) = (self.output.width as f64, self.output.height as f64);
+        let tan = (camera.fov.to_radians() / 2.0).tan();
+        let (origin, direction, spread) = match camera.stereo {
+            None => {
+                let (origin, direction) = camera.perspective((x / width, y / height), width / height, 0.0);
+                (origin, direction, 2.0 * tan / (height * camera.near))
+            }
+            Some(SceneStereo::SideBySide { interocular }) => {
+                let eye_width = width / 2.0;
+                let (offset, x) = if x < eye_width { (-interocular / 2.0, x) } else { (interocular / 2.0, x - eye_width) };
+                let (origin, direction) = camera.perspective((x / eye_width, y / height), eye_width / height, offset);
+                (origin, direction, 2.0 * tan / (height * camera.near))
+            }
+            Some(SceneStereo::Ods { interocular }) => {
+                let eye_height = height / 2.0;
+                let (offset, y) = if y < eye_height { (-interocular / 2.0, y) } else { (interocular / 2.0, y - eye_height) };
+                let (origin, direction) = camera.panorama((x / width, y / eye_height), offset);
+                (origin, direction, std::f64::consts::PI / eye_height)
+            }
+        };
+
+        // The near clipping distance is along the view axis, or along the ray for panoramas
+        let min_distance = match camera.stereo {
+            Some(SceneStereo::Ods { .. }) => camera.clip.0,
+            _ => camera.clip.0 / vec3dot(direction, camera.forward()),
+        };
+        Ray {
+            ray_type: RayType::Camera,
+            origin,
+            direction,
+            min_distance,
+            depth: 0,
+            bounces: Bounces::default(),
+            spread,
+            media: Media::default(),
+            wavelengths: if self.spectral { Wavelengths::sample() } else { Wavelengths::All },
+        }
+    }
+
+    /// Returns the color seen by a ray, the light of each light group in it is then in
+    /// [`Raytracer::group_colors`]
+    fn raytrace(&self, ray: Ray) -> RGBA {
+        if self.output.depth_limits.exceeded(&ray) {
+            self.set_group_colors(|_| RGBA::transparent());
+            return RGBA::transparent();
+        }
+
+        path_debug::begin_ray(&ray);
+        let color = match self.closest_hit(ray) {
+            Some(hit) => {
+                path_debug::set_hit(&hit);
+                self.shade(&hit, ray)
+            }
+            None => self.miss(&ray),
+        };
+        path_debug::end_ray(color);
+
+        color
+    }
+
+    /// Returns the factors the samples' channels are multiplied by, the debug shadings aren't exposed
+    fn exposure(&self) -> (f64, f64, f64) {
+        if self.shading.is_some() { (1.0, 1.0, 1.0) } else { self.camera.exposure }
+    }
+
+    /// Sets the light of each light group in the color being returned by [`Raytracer::raytrace`]
+    fn set_group_colors(&self, color: impl FnMut(usize) -> RGBA) {
+        if !self.output.light_groups.is_empty() {
+            GROUP_COLORS.with_borrow_mut(|colors| {
+                colors.clear();
+                colors.extend((0..self.output.light_groups.len()).map(color));
+            });
+        }
+    }
+
+    /// Light of each light group in the color last returned by [`Raytracer::raytrace`] on the current
+    /// thread, empty without light groups
+    fn group_colors(&self) -> Vec<RGBA> {
+        GROUP_COLORS.with_borrow(Vec::clone)
+    }
+
+    /// Returns the color seen by a ray which doesn't hit any object
+    fn miss(&self, ray: &Ray) -> RGBA {
+        // The backplate and the background aren't in any light group
+        let unlit = if ray.depth == 0 && self.world.transparent_background { RGBA::transparent() } else { RGBA::black() };
+        self.set_group_colors(|_| unlit);
+        if ray.depth == 0 {
+            if let Some(backplate) = &self.world.backplate {
+                let uv = self.camera.image_uv(ray.direction, self.output.width as f64 / self.output.height as f64);
+                // Undoes the exposure, the backplate being already exposed and balanced
+                let exposure = self.exposure();
+                return backplate.sample(uv, 0.0).premultiply().scaled((1.0 / exposure.0, 1.0 / exposure.1, 1.0 / exposure.2));
+            }
+            if self.world.transparent_background {
+                return RGBA::transparent();
+            }
+        }
+        // Diffuse surfaces already sampled the environment through the portals
+        if ray.ray_type == RayType::Diffuse && self.world.portals.iter().any(|portal| portal.contains(ray.origin, ray.direction)) {
+            return RGBA::black();
+        }
+        let (r, g, b) = self.environment(ray.direction);
+        RGBA::new(r, g, b, 1.0)
+    }
+
+    /// Returns the light of the environment coming from `direction`
+    fn environment(&self, direction: (f64, f64, f64)) -> (f64, f64, f64) {
+        match &self.world.sky {
+            Some(sky) => sky.radiance(direction),
+            None => self.world.background,
+        }
+    }
+
+    fn shade(&self, hit: &ObjectHit, ray: Ray) -> RGBA {
+        if let Some(shading) = self.shading {
+            let color = shading.shade(hit, self.camera.depth(hit.hit.intersection), self.output.depth_range);
+            self.set_group_colors(|_| color);
+            return color;
+        }
+
+        let ctx = ShadeContext::new(self, &ray, hit.object);
+        let start = (!self.output.light_groups.is_empty()).then(utils::random_state);
+        let color = hit.material().shade(hit, &ctx);
+
+        // Each light group is shaded again from the light samples and colors traced for the whole
+        // light, with the same random numbers
+        let mut groups = Vec::new();
+        if let Some(start) = start {
+            let end = utils::random_state();
+            groups = (0..self.output.light_groups.len())
+                .map(|group| {
+                    utils::restore_random(start.clone());
+                    ctx.replay_group(group);
+                    lights::shade_group(group, || hit.material().shade(hit, &ctx))
+                })
+                .collect();
+            utils::restore_random(end);
+        }
+
+        if color.a < 1.0 {
+            // Semi-transparent surfaces are composited over what is behind them
+            let behind = self.raytrace(Ray { min_distance: hit.hit.distance + self.output.ray_epsilon, ..ray });
+            GROUP_COLORS.with_borrow_mut(|behind| behind.iter_mut().zip(groups).for_each(|(behind, group)| *behind = group.over(*behind)));
+            color.over(behind)
+        } else {
+            GROUP_COLORS.set(groups);
+            color
+        }
+    }
+
+    /// Returns the closest hit along the ray, going through alpha cut outs
+    fn closest_hit(&self, mut ray: Ray) -> Option<ObjectHit<'_>> {
+        RAYS.set(RAYS.get() + 1);
+        stats::count_ray(ray.ray_type);
+        loop {
+            let hit = self.intersect_objects(&ray).map(Self::with_footprint);
+
+            // Continue past alpha cut outs
+            match hit {
+                Some(hit) if hit.material().is_cut_out(&hit) => {
+                    ray.min_distance = hit.hit.distance + self.output.ray_epsilon;
+                }
+                Some(hit) if ray.ray_type == RayType::Camera
+                    && ray.depth == 0
+                    && self.camera.depth(hit.hit.intersection) > self.camera.clip.1 => return None,
+                hit => return hit,
+            }
+        }
+    }
+
+    /// Returns whether an object blocks the segment from `origin` along `direction` up to `distance`
+    fn occluded(&self, origin: (f64, f64, f64), direction: (f64, f64, f64), distance: f64) -> bool {
+        RAYS.set(RAYS.get() + 1);
+        stats::count_ray(RayType::Shadow);
+        let epsilon = self.output.ray_epsilon;
+        let ray = Ray {
+            ray_type: RayType::Shadow,
+            origin,
+            direction,
+            min_distance: epsilon,
+            depth: 0,
+            bounces: Bounces::default(),
+            spread: 0.0,
+            media: Media::default(),
+            wavelengths: Wavelengths::All,
+        };
+
+        #[cfg(not(feature = "embree"))]
+        let blocker = self.objects.iter().find_map(|object| {
+            let mut ray = ray;
+            while let Some(hit) = object.intersect(&ray) {
+                if hit.hit.distance >= distance - epsilon {
+                    return None;
+                }
+                if !hit.material().is_cut_out(&hit) {
+                    return Some(hit);
+                }
+                ray.min_distance = hit.hit.distance + epsilon;
+            }
+            None
+        });
+        #[cfg(feature = "embree")]
+        let blocker = {
+            let mut ray = ray;
+            loop {
+                match self.intersect_objects(&ray) {
+                    Some(hit) if hit.hit.distance >= distance - epsilon => break None,
+                    Some(hit) if hit.material().is_cut_out(&hit) => ray.min_distance = hit.hit.distance + epsilon,
+                    hit => break hit,
+                }
+            }
+        };
+        path_debug::shadow_ray(&ray, distance, blocker.as_ref());
+
+        blocker.is_some()
+    }
+
+    /// Returns the closest hit along the ray among the objects
+    #[cfg(not(feature = "embree"))]
+    fn intersect_objects(&self, ray: &Ray) -> Option<ObjectHit<'_>> {
+        self.objects.iter()
+            .filter_map(|obj| obj.intersect(ray))
+            .min_by(|a, b| a.hit.distance.total_cmp(&b.hit.distance))
+    }
+
+    /// Returns the closest hit along the ray among the objects, found by Embree
+    #[cfg(feature = "embree")]
+    fn intersect_objects(&self, ray: &Ray) -> Option<ObjectHit<'_>> {
+        self.embree.closest(&self.objects, ray)
+    }
+
+    /// Estimates the hit's footprint by intersecting the object with a ray offset by the ray's spread
+    fn with_footprint(mut oh: ObjectHit) -> ObjectHit {
+        if oh.ray.spread <= 0.0 {
+            return oh;
+        }
+
+        // Offset towards the normal, where the footprint is stretched the most at grazing angles
+        let direction = oh.ray.direction;
+        let mut offset = vec3norm(vec3sub(oh.hit.normal, vec3scale(direction, vec3dot(direction, oh.hit.normal))));
+        if offset.0.is_nan() {
+            offset = vec3norm(vec3sub(oh.hit.tangent, vec3scale(direction, vec3dot(direction, oh.hit.tangent))));
+        }
+        let differential = Ray {
+            direction: vec3norm(vec3add(direction, vec3scale(offset, oh.ray.spread))),
+            ..oh.ray
+        };
+
+        if let Some(other) = oh.object.intersect(&differential) {
+            // UVs repeat, so the shortest way around is the actual difference at seams
+            let delta = |a: f64, b: f64| ((a - b).abs() % 1.0).min(1.0 - (a - b).abs() % 1.0);
+            let (du, dv) = (delta(other.hit.uv.0, oh.hit.uv.0), delta(other.hit.uv.1, oh.hit.uv.1));
+            oh.footprint = du.max(dv);
+        }
+
+        oh
+    }
+}
+
+impl Camera {
+    /// Returns the position in the image a camera ray goes through, as UVs from its bottom left
+    /// corner
+    fn image_uv(&self, direction: (f64, f64, f64), aspect: f64) -> (f64, f64) {
+        let (x, y, z) = self.transform.inverse().apply_notranslate(direction);
+        let tan = (self.fov.to_radians() / 2.0).tan();
+        let factor = self.distortion_factor((x / y, z / y)) * self.near;
+        let (x, z) = (x / y * factor, z / y * factor);
+        ((x / (tan * aspect) + 1.0) / 2.0, (z / tan + 1.0) / 2.0)
     }
 
-    fn raytrace(&self, ray: Ray, ignore: Option<&Object>) -> RGBA {
-        let hit = self.objects.iter()
-            .filter(|object| !ignore.is_some_and(|ignore| ptr::eq(*object, ignore)))
-            .filter_map(|obj| obj.intersect(&ray))
-            .min_by(|a, b| a.hit.distance.total_cmp(&b.hit.distance));
+    /// Returns how much the lens scales a point of the image plane at a distance of 1, from its
+    /// undistorted position to where it is seen
+    fn distortion_factor(&self, (x, z): (f64, f64)) -> f64 {
+        let r2 = x * x + z * z;
+        1.0 + self.distortion.0 * r2 + self.distortion.1 * r2 * r2
+    }
 
-        match hit {
-            Some(hit) => hit.object.material().shade(&hit, Box::new(|ray| self.raytrace(ray, Some(hit.object)))),
-            None => RGBA::transparent(),
+    /// Returns the undistorted position of a point seen on the image plane at a distance of 1,
+    /// solving for its distance to the center with Newton's method as the distortion has no closed
+    /// form inverse
+    fn undistort(&self, point: (f64, f64)) -> (f64, f64) {
+        let distorted = (point.0 * point.0 + point.1 * point.1).sqrt();
+        if self.distortion == (0.0, 0.0) || distorted == 0.0 {
+            return point;
         }
+        let (k1, k2) = self.distortion;
+        let r = (0..UNDISTORT_ITERATIONS).fold(distorted, |r, _| {
+            let r2 = r * r;
+            r - (r * (1.0 + k1 * r2 + k2 * r2 * r2) - distorted) / (1.0 + 3.0 * k1 * r2 + 5.0 * k2 * r2 * r2)
+        });
+        (point.0 * r / distorted, point.1 * r / distorted)
+    }
+
+    /// Returns the origin and direction of a perspective ray through a point of the image plane,
+    /// as fractions of its size from its top left corner, from an eye offset along the camera's
+    /// horizontal axis
+    fn perspective(&self, (u, v): (f64, f64), aspect: f64, offset: f64) -> ((f64, f64, f64), (f64, f64, f64)) {
+        let tan = (self.fov.to_radians() / 2.0).tan();
+        let (x, z) = self.undistort(((2.0 * u - 1.0) * tan * aspect / self.near, (1.0 - 2.0 * v) * tan / self.near));
+        let direction = (x, 1.0, z);
+        // Normalized again for cameras with a scale or a shear
+        (self.transform.apply((offset, 0.0, 0.0)), vec3norm(self.transform.apply_notranslate(direction)))
+    }
+
+    /// Returns the origin and direction of an equirectangular panorama ray, the eye being offset
+    /// to the side of the view direction
+    fn panorama(&self, (u, v): (f64, f64), offset: f64) -> ((f64, f64, f64), (f64, f64, f64)) {
+        let longitude = (u - 0.5) * 2.0 * std::f64::consts::PI;
+        let latitude = (0.5 - v) * std::f64::consts::PI;
+        let direction = (
+            longitude.sin() * latitude.cos(),
+            longitude.cos() * latitude.cos(),
+            latitude.sin(),
+        );
+        let origin = (offset * longitude.cos(), -offset * longitude.sin(), 0.0);
+        (self.transform.apply(origin), vec3norm(self.transform.apply_notranslate(direction)))
+    }
+
+    /// Returns the distance from the camera to a point along its view axis
+    fn depth(&self, point: (f64, f64, f64)) -> f64 {
+        vec3dot(vec3sub(point, self.transform.apply((0.0, 0.0, 0.0))), self.forward())
+    }
+
+    /// Direction of the view axis
+    fn forward(&self) -> (f64, f64, f64) {
+        vec3norm(self.transform.apply_notranslate((0.0, 1.0, 0.0)))
     }
 }
 
 impl Output {
-    fn new(width: u32, height: u32, samples: u32, tile_size: u32) -> Output {
+    fn new(width: u32, height: u32, samples: u32, tile_size: Option<u32>, tile_order: TileOrder, ray_epsilon: f64, depth_limits: DepthLimits) -> Output {
         Output {
             width,
             height,
             samples,
             tile_size,
+            render_tile_size: AtomicU32::new(tile_size.unwrap_or(0)),
+            tile_order,
+            ray_epsilon,
+            depth_limits,
+            filter: Filter::Box,
             buffer: vec![0u32; (width * height) as usize].into_iter().map(AtomicU32::new).collect(),
+            version: AtomicU64::new(0),
+            passes: AtomicU32::new(0),
+            post_processed: Mutex::new(None),
+            accumulation: (0..width * height).map(|_| [0.0f32.to_bits(); 5].map(AtomicU32::new)).collect(),
+            weights: vec![0.0f32.to_bits(); (width * height) as usize].into_iter().map(AtomicU32::new).collect(),
+            sample_counts: vec![0u32; (width * height) as usize].into_iter().map(AtomicU32::new).collect(),
+            object_ids: vec![0u32; (width * height) as usize].into_iter().map(AtomicU32::new).collect(),
+            depths: vec![0u32; (width * height) as usize].into_iter().map(AtomicU32::new).collect(),
+            depth_range: None,
+            positions: (0..width * height).map(|_| [0.0f32.to_bits(); 3].map(AtomicU32::new)).collect(),
+            light_groups: Vec::new(),
+        }
+    }
+    /// Adds a pass for each light group, named as given
+    fn with_light_groups(mut self, names: &[String]) -> Output {
+        self.light_groups = names.iter()
+            .map(|name| LightGroup {
+                name: name.clone(),
+                accumulation: (0..self.width * self.height).map(|_| [0.0f32.to_bits(); 5].map(AtomicU32::new)).collect(),
+            })
+            .collect();
+        self
+    }
+    /// Returns the version of the display colors, which changes whenever they do
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+    /// Copies the display colors into `pixels` as RGBA8888 in native byte order, row by row,
+    /// returning their version
+    ///
+    /// Pixels are copied one at a time, so pixels rendered while copying may be of the next version.
+    pub fn snapshot(&self, pixels: &mut Vec<u8>) -> u64 {
+        let version = self.version();
+        pixels.clear();
+        pixels.extend(self.buffer.iter().flat_map(|color| color.load(Ordering::Relaxed).to_ne_bytes()));
+        version
+    }
+    /// Adds samples to a pixel, then packs its display color
+    ///
+    /// Samples of neighboring tiles can be splatted into the pixel at the same time, so the sums are
+    /// added atomically.
+    fn accumulate(&self, x: u32, y: u32, samples: &Accumulator) {
+        let i = (x + y * self.width) as usize;
+        let (r, g, b, a) = samples.sum;
+        for (total, value) in self.accumulation[i].iter().zip([r, g, b, a, samples.luminance_squares]) {
+            add_f32(total, value);
+        }
+        add_f32(&self.weights[i], samples.weight);
+        // Only the thread rendering the pixel takes samples in it
+        self.sample_counts[i].fetch_add(samples.count, Ordering::Relaxed);
+        self.pack(i);
+    }
+    /// Adds a sample taken in a pixel to its neighbors within the radius of the filter, `position`
+    /// being in pixels from the top left corner of the image
+    fn splat(&self, x: u32, y: u32, position: (f64, f64), color: RGBA) {
+        self.splat_weights(x, y, position, |i, weight| {
+            for (total, value) in self.accumulation[i].iter().zip([color.r, color.g, color.b, color.a]) {
+                add_f32(total, value * weight);
+            }
+            add_f32(&self.weights[i], weight);
+        });
+    }
+    /// Adds a sample of a light group to the neighbors of its pixel, like [`Output::splat`]
+    fn splat_light_group(&self, group: usize, x: u32, y: u32, position: (f64, f64), color: RGBA) {
+        let accumulation = &self.light_groups[group].accumulation;
+        self.splat_weights(x, y, position, |i, weight| {
+            for (total, value) in accumulation[i].iter().zip([color.r * weight, color.g * weight, color.b * weight, color.a * weight, weight]) {
+                add_f32(total, value);
+            }
+        });
+    }
+    /// Calls `splat` with the index and filter weight of each neighbor of a pixel which a sample
+    /// taken at `position` is splatted into
+    fn splat_weights(&self, x: u32, y: u32, position: (f64, f64), mut splat: impl FnMut(usize, f64)) {
+        if self.filter == Filter::Box {
+            return;
+        }
+
+        let radius = self.filter.radius();
+        let range = |center: f64, size: u32| {
+            let first = (center - 0.5 - radius).ceil().max(0.0) as u32;
+            let last = (center - 0.5 + radius).floor().min(size as f64 - 1.0) as u32;
+            first..=last
+        };
+        for py in range(position.1, self.height) {
+            for px in range(position.0, self.width) {
+                let weight = self.filter.weight(position.0 - (px as f64 + 0.5), position.1 - (py as f64 + 0.5));
+                if (px, py) == (x, y) || weight <= 0.0 {
+                    continue;
+                }
+
+                splat((px + py * self.width) as usize, weight);
+            }
+        }
+    }
+    /// Adds samples of each light group to a pixel
+    fn accumulate_light_groups(&self, x: u32, y: u32, samples: &[Accumulator]) {
+        let i = (x + y * self.width) as usize;
+        for (group, samples) in self.light_groups.iter().zip(samples) {
+            let (r, g, b, a) = samples.sum;
+            for (total, value) in group.accumulation[i].iter().zip([r, g, b, a, samples.weight]) {
+                add_f32(total, value);
+            }
+        }
+    }
+    /// Packs the display colors of the pixels around a tile, which its samples were splatted into
+    fn pack_around(&self, tile: &Tile) {
+        if self.filter == Filter::Box {
+            return;
+        }
+
+        let margin = self.filter.radius().ceil() as u32;
+        for y in tile.top.saturating_sub(margin)..(tile.bottom + margin).min(self.height) {
+            for x in tile.left.saturating_sub(margin)..(tile.right + margin).min(self.width) {
+                if !(tile.left..tile.right).contains(&x) || !(tile.top..tile.bottom).contains(&y) {
+                    self.pack((x + y * self.width) as usize);
+                }
+            }
         }
     }
-    pub fn get(&self) -> &[u8] {
-        unsafe { &*(self.buffer.as_slice() as *const [AtomicU32] as *const [u8]) }
+    /// Whether the pixels of a tile keep changing after it is rendered, from samples splatted by
+    /// its neighbors
+    pub(super) fn splats(&self) -> bool {
+        self.filter != Filter::Box
     }
-    fn put(&self, x: u32, y: u32, color: RGBA) {
-        self.buffer[(x + y * self.width) as usize].store(color.into(), Ordering::Relaxed)
+    /// Resets the accumulated samples, for a new render
+    fn clear(&self) {
+        *self.post_processed.lock().unwrap() = None;
+        self.passes.store(0, Ordering::Relaxed);
+        for (i, (sums, count)) in self.accumulation.iter().zip(&self.sample_counts).enumerate() {
+            sums.iter().for_each(|sum| sum.store(0.0f32.to_bits(), Ordering::Relaxed));
+            self.weights[i].store(0.0f32.to_bits(), Ordering::Relaxed);
+            count.store(0, Ordering::Relaxed);
+            for group in &self.light_groups {
+                group.accumulation[i].iter().for_each(|sum| sum.store(0.0f32.to_bits(), Ordering::Relaxed));
+            }
+            self.pack(i);
+        }
+    }
+    fn accumulator(&self, i: usize) -> Accumulator {
+        let [r, g, b, a, luminance_squares] = self.accumulation[i].each_ref().map(|sum| f32::from_bits(sum.load(Ordering::Relaxed)) as f64);
+        Accumulator {
+            sum: (r, g, b, a),
+            weight: f32::from_bits(self.weights[i].load(Ordering::Relaxed)) as f64,
+            luminance_squares,
+            count: self.sample_counts[i].load(Ordering::Relaxed),
+        }
+    }
+    /// Packs the display color of a pixel from its accumulated samples
+    fn pack(&self, i: usize) {
+        self.buffer[i].store(self.accumulator(i).average().into(), Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+    fn pixel(&self, x: u32, y: u32) -> RGBA {
+        self.accumulator((x + y * self.width) as usize).average()
+    }
+    /// Estimates the noise of the image, as the average relative error of the pixels' luminance
+    pub fn noise(&self) -> f64 {
+        let total: f64 = (0..self.accumulation.len()).map(|i| self.accumulator(i).relative_error()).sum();
+        total / self.accumulation.len().max(1) as f64
+    }
+    /// Size of the tiles of the last render started, or of the next render if set by the scene
+    pub fn tile_size(&self) -> u32 {
+        self.render_tile_size.load(Ordering::Relaxed)
+    }
+    /// Number of progressive passes rendered, each adding `samples` samples per pixel
+    pub fn passes(&self) -> u32 {
+        self.passes.load(Ordering::Relaxed)
+    }
+    /// Number of samples rendered per pixel, in the passes completed so far
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.passes() * self.samples
+    }
+    /// Whether samples were added after the first pass, even by a pass which didn't complete
+    pub(super) fn sampled_past_first_pass(&self) -> bool {
+        self.sample_counts.iter().any(|count| count.load(Ordering::Relaxed) > self.samples)
+    }
+    /// Applies post effects to the accumulated samples, the results replace them for display and saving
+    fn post_process(&self, effects: &[Box<dyn PostEffect + Send + Sync>]) {
+        let mut image = PostImage { width: self.width, height: self.height, pixels: self.pixels() };
+        for effect in effects {
+            effect.apply(&mut image);
+        }
+
+        for (packed, pixel) in self.buffer.iter().zip(&image.pixels) {
+            let [r, g, b, a] = pixel.map(f64::from);
+            packed.store(RGBA::new(r, g, b, a).into(), Ordering::Relaxed);
+        }
+        *self.post_processed.lock().unwrap() = Some(image.pixels);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+    pub fn is_post_processed(&self) -> bool {
+        self.post_processed.lock().unwrap().is_some()
+    }
+    /// Average of the samples of each pixel in premultiplied RGBA order, row by row, not clamped,
+    /// with the post effects once the render completes
+    pub fn pixels(&self) -> Vec<[f32; 4]> {
+        if let Some(pixels) = &*self.post_processed.lock().unwrap() {
+            return pixels.clone();
+        }
+
+        (0..self.accumulation.len())
+            .map(|i| {
+                let color = self.accumulator(i).average();
+                [color.r, color.g, color.b, color.a].map(|c| c as f32)
+            })
+            .collect()
+    }
+    /// Colors with 16 bits per channel in straight RGBA order, row by row
+    pub fn colors(&self) -> Vec<[u16; 4]> {
+        to_colors(self.pixels())
+    }
+    /// Names of the light groups, in the order of their passes
+    pub fn light_groups(&self) -> Vec<&str> {
+        self.light_groups.iter().map(|group| group.name.as_str()).collect()
+    }
+    /// Pass of the light of a light group, like [`Output::pixels`] but without post effects
+    pub fn light_group_pixels(&self, group: usize) -> Vec<[f32; 4]> {
+        (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y))).map(|(x, y)| self.light_group_pixel(group, x, y)).collect()
+    }
+    fn light_group_pixel(&self, group: usize, x: u32, y: u32) -> [f32; 4] {
+        let [r, g, b, a, weight] = self.light_groups[group].accumulation[(x + y * self.width) as usize]
+            .each_ref()
+            .map(|sum| f32::from_bits(sum.load(Ordering::Relaxed)));
+        if weight <= 0.0 {
+            return [0.0; 4];
+        }
+        [r, g, b, a].map(|c| c / weight)
+    }
+    /// Object ID pass, row by row, the IDs can be looked up with [`Raytracer::object_name`]
+    pub fn object_ids(&self) -> Vec<u32> {
+        self.object_ids.iter().map(|id| id.load(Ordering::Relaxed)).collect()
+    }
+    fn put_object_id(&self, x: u32, y: u32, id: u32) {
+        self.object_ids[(x + y * self.width) as usize].store(id, Ordering::Relaxed)
+    }
+    /// Depth pass, row by row, normalized from 0 at the near distance to 1 at the far distance if
+    /// the scene sets a range, infinite (or 1 when normalized) where no object is seen
+    pub fn depths(&self) -> Vec<f32> {
+        (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y))).map(|(x, y)| self.depth(x, y)).collect()
+    }
+    /// World position pass, row by row, zero where no object is seen
+    pub fn positions(&self) -> Vec<[f32; 3]> {
+        (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y))).map(|(x, y)| self.position(x, y)).collect()
+    }
+    fn depth(&self, x: u32, y: u32) -> f32 {
+        f32::from_bits(self.depths[(x + y * self.width) as usize].load(Ordering::Relaxed))
+    }
+    fn position(&self, x: u32, y: u32) -> [f32; 3] {
+        self.positions[(x + y * self.width) as usize].each_ref().map(|c| f32::from_bits(c.load(Ordering::Relaxed)))
+    }
+    /// Stores the depth and position of the surface seen through the center of a pixel, if any
+    fn put_surface(&self, x: u32, y: u32, surface: Option<(f64, (f64, f64, f64))>) {
+        let i = (x + y * self.width) as usize;
+        let (depth, position) = surface.unwrap_or((f64::INFINITY, (0.0, 0.0, 0.0)));
+        let depth = match self.depth_range {
+            Some((near, far)) => ((depth - near) / (far - near)).clamp(0.0, 1.0),
+            None => depth,
+        };
+
+        self.depths[i].store((depth as f32).to_bits(), Ordering::Relaxed);
+        for (c, value) in self.positions[i].iter().zip([position.0, position.1, position.2]) {
+            c.store((value as f32).to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl RayType {
+    pub const fn name(self) -> &'static str {
+        match self {
+            RayType::Camera => "camera",
+            RayType::Reflection => "reflection",
+            RayType::Diffuse => "diffuse",
+            RayType::Shadow => "shadow",
+            RayType::Transmission => "transmission",
+            RayType::Glossy => "glossy",
+        }
+    }
+}
+
+impl Bounces {
+    /// Returns the bounces counted with one more bounce of the given type
+    fn with(mut self, ray_type: RayType) -> Self {
+        match ray_type {
+            RayType::Diffuse => self.diffuse += 1,
+            RayType::Reflection | RayType::Glossy => self.glossy += 1,
+            RayType::Transmission => self.transmission += 1,
+            RayType::Camera | RayType::Shadow => {}
+        }
+        self
+    }
+}
+
+impl DepthLimits {
+    fn exceeded(&self, ray: &Ray) -> bool {
+        ray.depth > self.total
+            || ray.bounces.diffuse > self.diffuse
+            || ray.bounces.glossy > self.glossy
+            || ray.bounces.transmission > self.transmission
     }
 }
 
@@ -227,27 +1392,98 @@ impl RGBA {
     fn black() -> Self { Self::new(0.0, 0.0, 0.0, 1.0) }
     fn white() -> Self { Self::new(1.0, 1.0, 1.0, 1.0) }
 
-    fn average(samples: &Vec<RGBA>) -> RGBA {
-        let ssum = samples
-            .iter()
-            .fold((0.0, 0.0, 0.0, 0.0), |acc, s| (
-                acc.0 + s.r * s.r * s.a,
-                acc.1 + s.g * s.g * s.a,
-                acc.2 + s.b * s.b * s.a,
-                acc.3 + s.a,
-            ));
+    /// Returns the Rec. 709 luminance of the color
+    fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Composites the color over `other`
+    fn over(self, other: RGBA) -> RGBA {
+        let t = 1.0 - self.a;
+        RGBA::new(self.r + other.r * t, self.g + other.g * t, self.b + other.b * t, self.a + other.a * t)
+    }
+
+    /// Returns the straight color multiplied by its alpha, like texture samples
+    fn premultiply(self) -> RGBA {
+        RGBA::new(self.r * self.a, self.g * self.a, self.b * self.a, self.a)
+    }
+
+    /// Multiplies the channels of the color by `factors`, keeping its alpha
+    fn scaled(self, factors: (f64, f64, f64)) -> RGBA {
+        RGBA::new(self.r * factors.0, self.g * factors.1, self.b * factors.2, self.a)
+    }
+
+    /// Returns the color divided by its alpha, black where it is fully transparent
+    fn unpremultiply(self) -> RGBA {
+        if self.a <= 0.0 {
+            return RGBA::transparent();
+        }
+        RGBA::new(self.r / self.a, self.g / self.a, self.b / self.a, self.a)
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, s: RGBA, weight: f64) {
+        self.sum = (
+            self.sum.0 + s.r * weight,
+            self.sum.1 + s.g * weight,
+            self.sum.2 + s.b * weight,
+            self.sum.3 + s.a * weight,
+        );
+        self.weight += weight;
+        self.luminance_squares += s.luminance().powi(2);
+        self.count += 1;
+    }
+
+    /// Returns the standard error of the mean luminance relative to it, 0 with fewer than 2 samples
+    fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
 
-        RGBA::new(
-            (ssum.0 / ssum.3).sqrt(),
-            (ssum.1 / ssum.3).sqrt(),
-            (ssum.2 / ssum.3).sqrt(),
-            ssum.3 / samples.len() as f64,
-        )
+        let n = self.count as f64;
+        let mean = self.average().luminance();
+        let variance = (self.luminance_squares / n - mean * mean).max(0.0) * n / (n - 1.0);
+        // Offset so that noise in dark pixels doesn't dominate
+        (variance / n).sqrt() / (mean + NOISE_LUMINANCE_OFFSET)
     }
+
+    /// Returns the weighted average color, transparent without samples
+    fn average(&self) -> RGBA {
+        if self.weight <= 0.0 {
+            return RGBA::transparent();
+        }
+
+        let weight = self.weight;
+        RGBA::new(self.sum.0 / weight, self.sum.1 / weight, self.sum.2 / weight, self.sum.3 / weight)
+    }
+}
+
+/// Adds a value to an f32 stored as bits, the addition being done in f64
+fn add_f32(total: &AtomicU32, value: f64) {
+    let _ = total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        Some(((f32::from_bits(bits) as f64 + value) as f32).to_bits())
+    });
+}
+
+/// Converts premultiplied pixels to colors with 16 bits per channel in straight RGBA order
+fn to_colors(pixels: Vec<[f32; 4]>) -> Vec<[u16; 4]> {
+    pixels
+        .into_iter()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.map(f64::from);
+            let color = RGBA::new(r, g, b, a).unpremultiply();
+            [color.r, color.g, color.b, color.a].map(|c| (c.clamp(0.0, 1.0) * 65535.0).round() as u16)
+        })
+        .collect()
 }
 
 impl Into<u32> for RGBA {
     fn into(self) -> u32 {
-        ((self.r * 255.0) as u32) << 24 | ((self.g * 255.0) as u32) << 16 | ((self.b * 255.0) as u32) << 8 | (self.a * 255.0) as u32
+        // Displayed with straight alpha
+        let color = self.unpremultiply();
+        // Lit colors can exceed 1.0, clamp them so they don't overflow into the other channels
+        let [r, g, b, a] = [color.r, color.g, color.b, color.a].map(|c| (c.clamp(0.0, 1.0) * 255.0) as u32);
+        r << 24 | g << 16 | b << 8 | a
     }
 }
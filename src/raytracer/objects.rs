@@ -1,6 +1,10 @@
-use crate::raytracer::{Ray, Transform};
+use crate::raytracer::{Ray, RayType, Transform};
+use crate::raytracer::bvh::{Aabb, BvhOptions, NodeVisitor};
 use crate::raytracer::materials::Material;
-use crate::raytracer::utils::{vec3add, vec3norm, vec3scale};
+use crate::raytracer::mesh::Mesh;
+use crate::raytracer::utils::{vec3add, vec3dot, vec3norm, vec3scale};
+use crate::raytracer::uv_projection::{box_uv, UvProjection};
+use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::f64::consts::PI;
@@ -10,44 +14,137 @@ const HALF_EPSILON: f64 = 0.49999999;
 
 static OBJECT_TYPES: LazyLock<Mutex<HashMap<String, ObjectNewFn>>> =
     LazyLock::new(|| Mutex::new(HashMap::from([
-        ("cone".to_string(), (|_| Ok(Box::new(Cone))) as ObjectNewFn),
-        ("cube".to_string(), |_| Ok(Box::new(Cube))),
-        ("cylinder".to_string(), |_| Ok(Box::new(Cylinder))),
-        ("plane".to_string(), |_| Ok(Box::new(Plane))),
-        ("sphere".to_string(), |_| Ok(Box::new(Sphere))),
+        ("cone".to_string(), (|data, _| Ok(Box::new(Cone::new(data)?))) as ObjectNewFn),
+        ("cube".to_string(), |_, _| Ok(Box::new(Cube))),
+        ("cylinder".to_string(), |data, _| Ok(Box::new(Cylinder::new(data)?))),
+        ("mesh".to_string(), |data, bvh| Ok(Box::new(Mesh::new(data, bvh)?))),
+        ("plane".to_string(), |_, _| Ok(Box::new(Plane))),
+        ("sphere".to_string(), |_, _| Ok(Box::new(Sphere))),
     ])));
 
-type ObjectNewFn = fn(&Value) -> Result<Box<dyn ObjectType + Sync + Send>, String>;
+type ObjectNewFn = fn(&Value, &BvhOptions) -> Result<Box<dyn ObjectType + Sync + Send>, String>;
 
 pub struct Object {
     inner: Box<dyn ObjectType + Sync + Send>,
     transform: Transform,
-    material: Arc<Material>,
+    /// Materials by material index, see [`Hit::material`]
+    materials: Vec<Arc<Material>>,
+    /// Identifier in the object ID pass, 0 is used where no object is hit
+    id: u32,
+    name: Option<String>,
+    /// Replaces the UVs computed by the object type
+    uv_projection: Option<UvProjection>,
+    /// Bit mask of the ray types which go through the object, by [`RayType`] value
+    invisible_to: u32,
+    /// Indices of the lights which don't illuminate the object, sorted
+    unlinked_lights: Vec<usize>,
 }
 
 pub trait ObjectType {
-    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+    fn intersect(&self, ray: &Ray) -> Option<Interval>;
+
+    /// Bounds of the object in its space
+    fn bounds(&self) -> Aabb;
+
+    /// Calls `visit` with the bounds, depth and [`Aabb::slabs`] of the BVH nodes the ray hits,
+    /// for objects with a BVH, see [`crate::raytracer::bvh::Bvh::visit`]
+    fn visit_bvh(&self, _ray: &Ray, _visit: &mut NodeVisitor) {}
+
+    /// Whether the object has no surface that rays can hit, like a mesh without triangles
+    fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// Cone along the Z axis with its apex at the top, one unit high
+struct Cone {
+    /// Radius of the base
+    radius: f64,
+    /// Whether the base is closed
+    cap: bool,
+    /// Fraction of a turn around the Z axis the surface spans, see [`in_sweep`]
+    sweep: f64,
 }
 
-struct Cone;
 struct Cube;
-struct Cylinder;
+
+/// Cylinder along the Z axis, one unit high and wide
+struct Cylinder {
+    /// Whether the ends are closed
+    caps: bool,
+    /// Fraction of a turn around the Z axis the surface spans, see [`in_sweep`]
+    sweep: f64,
+}
+
 struct Plane;
 struct Sphere;
 
+#[derive(Deserialize)]
+struct ConeData {
+    #[serde(default = "default_caps")]
+    cap: bool,
+    /// Angle between the axis and the side, in degrees
+    #[serde(default = "default_cone_angle")]
+    angle: f64,
+    /// Angle around the Z axis the surface spans, in degrees, partial sweeps being closed with
+    /// flat faces when the ends are
+    #[serde(default = "default_sweep")]
+    sweep: f64,
+}
+
+#[derive(Deserialize)]
+struct CylinderData {
+    #[serde(default = "default_caps")]
+    caps: bool,
+    #[serde(default = "default_sweep")]
+    sweep: f64,
+}
+
 #[derive(Clone, Copy)]
 pub struct ObjectHit<'a> {
     pub ray: Ray,
     pub object: &'a Object,
     pub hit: Hit,
+    /// Approximate width of the ray's footprint on the surface in UV space, 0 when unknown
+    pub footprint: f64,
+}
+
+/// Span of a ray inside an object, from where it enters to where it leaves
+///
+/// Distances are not clamped to the ray's `min_distance` (the entry hit is behind the origin for
+/// rays starting inside the object). Surfaces without volume enter and exit at the same hit.
+#[derive(Clone, Copy)]
+pub struct Interval {
+    pub entry: Hit,
+    pub exit: Hit,
+}
+
+#[derive(Clone, Copy)]
+pub struct ObjectInterval<'a> {
+    pub entry: ObjectHit<'a>,
+    pub exit: ObjectHit<'a>,
 }
 
 #[derive(Clone, Copy)]
 pub struct Hit {
     pub distance: f64,
     pub intersection: (f64, f64, f64),
+    /// Surface normal, facing against the ray
     pub normal: (f64, f64, f64),
     pub uv: (f64, f64),
+    /// Direction along the surface in which `uv.0` increases
+    pub tangent: (f64, f64, f64),
+    /// Whether the ray hit the outer side of the surface
+    ///
+    /// Object types report outward normals with this set, the normal is then flipped if needed once
+    /// the hit is in world space.
+    pub front_face: bool,
+    /// Index of the surface's material among the object's materials, 0 for single-material objects
+    pub material: usize,
+    /// Barycentric coordinates of the hit in its triangle, for meshes
+    pub barycentric: Option<(f64, f64, f64)>,
+    /// Vertex color interpolated in the hit's triangle, for meshes with vertex colors
+    pub color: Option<(f64, f64, f64)>,
 }
 
 impl Object {
@@ -56,101 +153,251 @@ impl Object {
         types.insert(name, new_fn);
     }
 
-    pub fn new(type_name: &String, data: &Value, transform: Transform, material: Arc<Material>) -> Result<Self, String> {
+    pub fn new(type_name: &String, data: &Value, bvh: &BvhOptions, transform: Transform, materials: Vec<Arc<Material>>) -> Result<Self, String> {
         let types = OBJECT_TYPES.lock().unwrap();
         let inner = match types.get(type_name) {
-            Some(object_new_fn) => object_new_fn(data),
+            Some(object_new_fn) => object_new_fn(data, bvh),
             None => Err(format!("Could not find object type {}", type_name)),
         }?;
 
         Ok(Self {
             inner,
             transform,
-            material,
+            materials,
+            id: 0,
+            name: None,
+            uv_projection: None,
+            invisible_to: 0,
+            unlinked_lights: Vec::new(),
         })
     }
 
-    pub fn material(&self) -> &Material {
-        &self.material
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    /// Hides the object from rays of the given types, e.g. from camera rays so that it is only
+    /// seen in reflections and through the shadows it casts
+    pub fn with_invisible_to(mut self, ray_types: &[RayType]) -> Self {
+        self.invisible_to = ray_types.iter().fold(0, |mask, &ray_type| mask | 1 << ray_type as u32);
+        self
+    }
+
+    /// Keeps the lights at the given indices from illuminating the object, see light linking
+    pub fn with_unlinked_lights(mut self, lights: Vec<usize>) -> Self {
+        self.unlinked_lights = lights;
+        self
+    }
+
+    pub fn unlinked_lights(&self) -> &[usize] {
+        &self.unlinked_lights
+    }
+
+    pub fn with_uv_projection(mut self, uv_projection: Option<UvProjection>) -> Self {
+        self.uv_projection = uv_projection;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the material of the hit surface, surfaces with no material at their index use the first one
+    pub fn material(&self, hit: &Hit) -> &Material {
+        self.materials.get(hit.material).unwrap_or(&self.materials[0])
+    }
+
+    /// Returns the closest hit along the ray, ignoring those before its `min_distance` and the
+    /// back faces of single-sided materials
+    pub fn intersect(&self, ray: &Ray) -> Option<ObjectHit<'_>> {
+        let interval = self.interval(ray)?;
+        [interval.entry, interval.exit]
+            .into_iter()
+            .find(|oh| oh.hit.distance >= ray.min_distance && (oh.hit.front_face || oh.material().double_sided()))
+    }
+
+    /// Returns the ray in the object's space, the distances along both rays being the same
+    pub fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.transform.inverse().apply(ray.origin),
+            direction: self.transform.inverse().apply_notranslate(ray.direction),
+            ..*ray
+        }
     }
 
-    pub fn intersect(&self, ray: &Ray) -> Option<ObjectHit> {
-        let mut local_ray = ray.clone();
-        local_ray.origin = self.transform.inverse().apply(ray.origin);
-        local_ray.direction = self.transform.inverse().apply_notranslate(ray.direction);
+    /// Lengths in the world of the object's unit axes
+    pub fn axis_scales(&self) -> (f64, f64, f64) {
+        let length = |axis| vec3dot(axis, axis).sqrt();
+        (
+            length(self.transform.apply_notranslate((1.0, 0.0, 0.0))),
+            length(self.transform.apply_notranslate((0.0, 1.0, 0.0))),
+            length(self.transform.apply_notranslate((0.0, 0.0, 1.0))),
+        )
+    }
 
-        match self.inner.intersect(&local_ray) {
-            Some(hit) => {
-                let mut hit = hit;
-                hit.intersection = self.transform.apply(hit.intersection);
-                hit.normal = vec3norm(self.transform.apply_notranslate(hit.normal));
+    /// Bounds of the object in its space
+    pub fn local_bounds(&self) -> Aabb {
+        self.inner.bounds()
+    }
+
+    /// Calls `visit` with the BVH nodes of the object hit by a ray in its space, see
+    /// [`ObjectType::visit_bvh`]
+    pub fn visit_bvh(&self, local_ray: &Ray, visit: &mut NodeVisitor) {
+        self.inner.visit_bvh(local_ray, visit);
+    }
 
-                Some(ObjectHit {
-                    ray: *ray,
-                    object: self,
-                    hit,
-                })
+    /// Returns where the ray's line enters and exits the object, see [`Interval`]
+    pub fn interval(&self, ray: &Ray) -> Option<ObjectInterval<'_>> {
+        if self.invisible_to & 1 << ray.ray_type as u32 != 0 {
+            return None;
+        }
+        let local_ray = self.local_ray(ray);
+        let interval = self.inner.intersect(&local_ray)?;
+        let to_world = |mut hit: Hit| {
+            if let Some(projection) = &self.uv_projection {
+                (hit.uv, hit.tangent) = projection.apply(hit.intersection, hit.normal);
             }
-            _ => None,
+            hit.intersection = self.transform.apply(hit.intersection);
+            hit.normal = vec3norm(self.transform.apply_normal(hit.normal));
+            hit.tangent = vec3norm(self.transform.apply_notranslate(hit.tangent));
+            hit.front_face = vec3dot(ray.direction, hit.normal) < 0.0;
+            if !hit.front_face {
+                hit.normal = vec3scale(hit.normal, -1.0);
+            }
+
+            ObjectHit {
+                ray: *ray,
+                object: self,
+                hit,
+                footprint: 0.0,
+            }
+        };
+
+        Some(ObjectInterval {
+            entry: to_world(interval.entry),
+            exit: to_world(interval.exit),
+        })
+    }
+}
+
+impl ObjectHit<'_> {
+    pub fn material(&self) -> &Material {
+        self.object.material(&self.hit)
+    }
+}
+
+impl Cone {
+    fn new(data: &Value) -> Result<Self, String> {
+        let data: ConeData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid cone: {}", err))?;
+        if !(data.angle > 0.0 && data.angle < 90.0) {
+            return Err(format!("Invalid cone: angle {} must be between 0 and 90 degrees", data.angle));
         }
+
+        Ok(Self {
+            radius: data.angle.to_radians().tan(),
+            cap: data.cap,
+            sweep: sweep_fraction(data.sweep).map_err(|err| format!("Invalid cone: {}", err))?,
+        })
     }
 }
 
 impl ObjectType for Cone {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
+        let r2 = self.radius * self.radius;
+        let sides = solve_quadratic(
+            ray.direction.0 * ray.direction.0 +
+                ray.direction.1 * ray.direction.1 -
+                ray.direction.2 * ray.direction.2 * r2,
+            2.0 * (
+                ray.direction.0 * ray.origin.0 +
+                ray.direction.1 * ray.origin.1 +
+                ray.direction.2 * (0.5 - ray.origin.2) * r2),
+            ray.origin.0 * ray.origin.0 +
+                ray.origin.1 * ray.origin.1 -
+                (0.5 - ray.origin.2) * (0.5 - ray.origin.2) * r2,
+        ).unwrap_or((f64::NAN, f64::NAN));
+        let radius = |z: f64| self.radius * (0.5 - z);
+        let cuts = cut_distances(ray, self.sweep, self.cap, radius);
         let mut dists = [
-            solve_linear(
-                ray.direction.0 * ray.direction.0 +
-                    ray.direction.1 * ray.direction.1 -
-                    ray.direction.2 * ray.direction.2 / 4.0,
-                2.0 * (
-                    ray.direction.0 * ray.origin.0 +
-                    ray.direction.1 * ray.origin.1 +
-                    ray.direction.2 * (0.5 - ray.origin.2) / 4.0),
-                ray.origin.0 * ray.origin.0 +
-                    ray.origin.1 * ray.origin.1 -
-                    (0.5 - ray.origin.2) * (0.5 - ray.origin.2) / 4.0,
-            ),
+            sides.0,
+            sides.1,
             -(0.5 + ray.origin.2) / ray.direction.2,
+            cuts[0],
+            cuts[1],
         ];
 
-        if (ray.direction.2 * dists[0] + ray.origin.2).abs() > 0.5 {
-            dists[0] = f64::NAN
+        for dist in &mut dists[0..2] {
+            let intersection = intersection(ray, *dist);
+            if intersection.2.abs() > 0.5 || !in_sweep(intersection, self.sweep) {
+                *dist = f64::NAN;
+            }
         }
-        if ray.direction.2.abs() < f64::EPSILON ||
-            (dists[1] * ray.direction.0 + ray.origin.0).powf(2.0) +
-            (dists[1] * ray.direction.1 + ray.origin.1).powf(2.0) > 0.25 {
-            dists[1] = f64::NAN;
+        if !self.cap || ray.direction.2.abs() < f64::EPSILON || !on_disk(intersection(ray, dists[2]), self.radius, self.sweep) {
+            dists[2] = f64::NAN;
         }
 
-        let distance = dists.iter().filter(|d| !(d.is_nan() || **d < 0.0)).min_by(|a, b| a.total_cmp(b));
-        if distance.is_none() {
-            return None;
-        }
-
-        let distance = *distance.unwrap();
-        let intersection = intersection(ray, distance);
-        let normal = if intersection.2 >= HALF_EPSILON {
-            (0.0, 0.0, 1.0)
-        } else {
-            vec3norm((intersection.0, intersection.1, intersection.2))
-        };
-        let uv = (
-            0.5 - f64::atan2(intersection.0, intersection.1) / (2.0 * PI),
-            intersection.2 + 0.5,
-        );
-
-        Some(Hit {
-            distance,
-            intersection,
-            normal,
-            uv,
+        indexed_interval(&dists, |index, distance| {
+            let intersection = intersection(ray, distance);
+            let (normal, tangent) = match index {
+                0 | 1 => {
+                    // Horizontally away from the axis and tilted up by the slope, straight up at the apex
+                    let (x, y, _) = intersection;
+                    let distance_to_axis = x.hypot(y);
+                    let (nx, ny) = if distance_to_axis > f64::EPSILON {
+                        (x / distance_to_axis, y / distance_to_axis)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    (vec3norm((nx, ny, self.radius)), azimuthal_tangent(intersection))
+                }
+                2 => ((0.0, 0.0, -1.0), azimuthal_tangent(intersection)),
+                _ => cut_normal_tangent(self.sweep, index - 3),
+            };
+            let uv = (
+                0.5 - f64::atan2(intersection.0, intersection.1) / (2.0 * PI),
+                intersection.2 + 0.5,
+            );
+
+            Hit {
+                distance,
+                intersection,
+                normal,
+                uv,
+                tangent,
+                front_face: true,
+                material: 0,
+                barycentric: None,
+                color: None,
+            }
         })
     }
+
+    fn bounds(&self) -> Aabb {
+        Aabb { min: (-self.radius, -self.radius, -0.5), max: (self.radius, self.radius, 0.5) }
+    }
 }
 
 impl ObjectType for Cube {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
         let inv_dir = (1.0 / ray.direction.0, 1.0 / ray.direction.1, 1.0 / ray.direction.2);
 
         let t1 = ((-0.5 - ray.origin.0) * inv_dir.0, (-0.5 - ray.origin.1) * inv_dir.1, (-0.5 - ray.origin.2) * inv_dir.2);
@@ -158,89 +405,122 @@ impl ObjectType for Cube {
         let tmin = *[f64::min(t1.0, t2.0), f64::min(t1.1, t2.1), f64::min(t1.2, t2.2)].iter().max_by(|a, b| a.total_cmp(b)).unwrap();
         let tmax = *[f64::max(t1.0, t2.0), f64::max(t1.1, t2.1), f64::max(t1.2, t2.2)].iter().min_by(|a, b| a.total_cmp(b)).unwrap();
 
-        if tmax < 1.0 || tmin > tmax {
+        if tmin > tmax {
             return None;
         }
 
-        let distance = tmin;
-        let intersection = intersection(ray, distance);
-        let (normal, uv) = match intersection {
-            (x, y, z) if x <= -HALF_EPSILON => ((-1.0, 0.0, 0.0), (0.5 - y, z + 0.5)),
-            (x, y, z) if x >= HALF_EPSILON => ((1.0, 0.0, 0.0), (y + 0.5, z + 0.5)),
-            (x, y, z) if y <= -HALF_EPSILON => ((0.0, -1.0, 0.0), (x + 0.5, z + 0.5)),
-            (x, y, z) if y >= HALF_EPSILON => ((0.0, 1.0, 0.0), (0.5 - x, z + 0.5)),
-            (x, y, z) if z <= -HALF_EPSILON => ((0.0, 0.0, -1.0), (x + 0.5, 0.5 - y)),
-            (x, y, z) if z >= HALF_EPSILON => ((0.0, 0.0, 1.0), (x + 0.5, y + 0.5)),
-            _ => unreachable!(),
-        };
+        interval(&[tmin, tmax], |distance| {
+            let intersection = intersection(ray, distance);
+            let normal = match intersection {
+                (x, _, _) if x <= -HALF_EPSILON => (-1.0, 0.0, 0.0),
+                (x, _, _) if x >= HALF_EPSILON => (1.0, 0.0, 0.0),
+                (_, y, _) if y <= -HALF_EPSILON => (0.0, -1.0, 0.0),
+                (_, y, _) if y >= HALF_EPSILON => (0.0, 1.0, 0.0),
+                (_, _, z) if z <= -HALF_EPSILON => (0.0, 0.0, -1.0),
+                (_, _, z) if z >= HALF_EPSILON => (0.0, 0.0, 1.0),
+                _ => unreachable!(),
+            };
+            let (uv, tangent) = box_uv(normal, intersection);
+
+            Hit {
+                distance,
+                intersection,
+                normal,
+                uv,
+                tangent,
+                front_face: true,
+                material: 0,
+                barycentric: None,
+                color: None,
+            }
+        })
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb { min: (-0.5, -0.5, -0.5), max: (0.5, 0.5, 0.5) }
+    }
+}
 
-        Some(Hit {
-            distance,
-            intersection,
-            normal,
-            uv,
+impl Cylinder {
+    fn new(data: &Value) -> Result<Self, String> {
+        let data: CylinderData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid cylinder: {}", err))?;
+
+        Ok(Self {
+            caps: data.caps,
+            sweep: sweep_fraction(data.sweep).map_err(|err| format!("Invalid cylinder: {}", err))?,
         })
     }
 }
 
 impl ObjectType for Cylinder {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
+        let sides = solve_quadratic(
+            ray.direction.0 * ray.direction.0 +
+                ray.direction.1 * ray.direction.1,
+            2.0 * (
+                ray.direction.0 * ray.origin.0 +
+                ray.direction.1 * ray.origin.1),
+            ray.origin.0 * ray.origin.0 +
+                ray.origin.1 * ray.origin.1 -
+                0.25,
+        ).unwrap_or((f64::NAN, f64::NAN));
+        let cuts = cut_distances(ray, self.sweep, self.caps, |_| 0.5);
         let mut dists = [
-            solve_linear(
-                ray.direction.0 * ray.direction.0 +
-                    ray.direction.1 * ray.direction.1,
-                2.0 * (
-                    ray.direction.0 * ray.origin.0 +
-                    ray.direction.1 * ray.origin.1),
-                ray.origin.0 * ray.origin.0 +
-                    ray.origin.1 * ray.origin.1 -
-                    0.25,
-            ),
+            sides.0,
+            sides.1,
             -(ray.origin.2 - 0.5) / ray.direction.2,
             -(ray.origin.2 + 0.5) / ray.direction.2,
+            cuts[0],
+            cuts[1],
         ];
 
-        if (ray.direction.2 * dists[0] + ray.origin.2).abs() > 0.5 {
-            dists[0] = f64::NAN
-        }
-        for i in 1..=2 {
-            if ray.direction.2.abs() < f64::EPSILON ||
-                (dists[i] * ray.direction.0 + ray.origin.0).powf(2.0) +
-                (dists[i] * ray.direction.1 + ray.origin.1).powf(2.0) > 0.25 {
-                dists[i] = f64::NAN;
+        for dist in &mut dists[0..2] {
+            let intersection = intersection(ray, *dist);
+            if intersection.2.abs() > 0.5 || !in_sweep(intersection, self.sweep) {
+                *dist = f64::NAN;
             }
         }
-
-        let distance = dists.iter().filter(|d| !(d.is_nan() || **d < 0.0)).min_by(|a, b| a.total_cmp(b));
-        if distance.is_none() {
-            return None;
+        for dist in &mut dists[2..4] {
+            if !self.caps || ray.direction.2.abs() < f64::EPSILON || !on_disk(intersection(ray, *dist), 0.5, self.sweep) {
+                *dist = f64::NAN;
+            }
         }
 
-        let distance = *distance.unwrap();
-        let intersection = intersection(ray, distance);
-        let normal = if intersection.2 <= -HALF_EPSILON {
-            (0.0, 0.0, -1.0)
-        } else if intersection.2 >= HALF_EPSILON {
-            (0.0, 0.0, 1.0)
-        } else {
-            vec3norm((intersection.0, intersection.1, 0.0))
-        };
-        let uv = (
-            0.5 - f64::atan2(intersection.0, intersection.1) / (2.0 * PI),
-            intersection.2 + 0.5,
-        );
-
-        Some(Hit {
-            distance,
-            intersection,
-            normal,
-            uv,
+        indexed_interval(&dists, |index, distance| {
+            let intersection = intersection(ray, distance);
+            let (normal, tangent) = match index {
+                0 | 1 => (vec3norm((intersection.0, intersection.1, 0.0)), azimuthal_tangent(intersection)),
+                2 => ((0.0, 0.0, 1.0), azimuthal_tangent(intersection)),
+                3 => ((0.0, 0.0, -1.0), azimuthal_tangent(intersection)),
+                _ => cut_normal_tangent(self.sweep, index - 4),
+            };
+            let uv = (
+                0.5 - f64::atan2(intersection.0, intersection.1) / (2.0 * PI),
+                intersection.2 + 0.5,
+            );
+
+            Hit {
+                distance,
+                intersection,
+                normal,
+                uv,
+                tangent,
+                front_face: true,
+                material: 0,
+                barycentric: None,
+                color: None,
+            }
         })
     }
+
+    fn bounds(&self) -> Aabb {
+        Aabb { min: (-0.5, -0.5, -0.5), max: (0.5, 0.5, 0.5) }
+    }
 }
 
 impl ObjectType for Plane {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
         if ray.direction.2.abs() < f64::EPSILON {
             return None;
         }
@@ -249,22 +529,34 @@ impl ObjectType for Plane {
             (ray.origin.1 + ray.direction.1 * distance).abs() > 0.5 {
             return None;
         }
-        let intersection = intersection(ray, distance);
-        let normal = (0.0, 0.0, if intersection.2 < 0.0 { 1.0 } else { -1.0 });
-        let uv = (intersection.0 + 0.5, intersection.1 + 0.5);
-
-        Some(Hit {
-            distance,
-            intersection,
-            normal,
-            uv,
+
+        interval(&[distance], |distance| {
+            let intersection = intersection(ray, distance);
+            let normal = (0.0, 0.0, 1.0);
+            let uv = (intersection.0 + 0.5, intersection.1 + 0.5);
+
+            Hit {
+                distance,
+                intersection,
+                normal,
+                uv,
+                tangent: (1.0, 0.0, 0.0),
+                front_face: true,
+                material: 0,
+                barycentric: None,
+                color: None,
+            }
         })
     }
+
+    fn bounds(&self) -> Aabb {
+        Aabb { min: (-0.5, -0.5, 0.0), max: (0.5, 0.5, 0.0) }
+    }
 }
 
 impl ObjectType for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
-        let distance = solve_linear(
+    fn intersect(&self, ray: &Ray) -> Option<Interval> {
+        let (entry, exit) = solve_quadratic(
             ray.direction.0 * ray.direction.0 +
                 ray.direction.1 * ray.direction.1 +
                 ray.direction.2 * ray.direction.2,
@@ -276,39 +568,143 @@ impl ObjectType for Sphere {
                 ray.origin.1 * ray.origin.1 +
                 ray.origin.2 * ray.origin.2 -
                 0.25,
-        );
-        if distance.is_nan() || distance <= 0.0 {
-            return None;
-        }
-        let intersection = intersection(ray, distance);
-        let normal = vec3norm(intersection);
-        let uv = (
-            0.5 - f64::atan2(normal.0, normal.1) / (2.0 * PI),
-            normal.2 * 0.5 + 0.5,
-        );
-
-        Some(Hit {
-            distance,
-            intersection,
-            normal,
-            uv,
+        )?;
+
+        interval(&[entry, exit], |distance| {
+            let intersection = intersection(ray, distance);
+            let normal = vec3norm(intersection);
+            let uv = (
+                0.5 - f64::atan2(normal.0, normal.1) / (2.0 * PI),
+                normal.2 * 0.5 + 0.5,
+            );
+
+            Hit {
+                distance,
+                intersection,
+                normal,
+                uv,
+                tangent: azimuthal_tangent(intersection),
+                front_face: true,
+                material: 0,
+                barycentric: None,
+                color: None,
+            }
         })
     }
+
+    fn bounds(&self) -> Aabb {
+        Aabb { min: (-0.5, -0.5, -0.5), max: (0.5, 0.5, 0.5) }
+    }
 }
 
+/// Solves `a*x^2 + b*x + c = 0`, returning its real roots in ascending order (twice the same for a double root)
 #[inline]
-pub fn solve_linear(a: f64, b: f64, c: f64) -> f64 {
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
     let delta = b * b - 4.0 * a * c;
     match delta {
-        f64::EPSILON.. => f64::min(
-            (-b + delta.sqrt()) / (2.0 * a),
-            (-b - delta.sqrt()) / (2.0 * a),
-        ),
-        0.0.. => -b / (2.0 * a),
-        _ => f64::NAN,
+        f64::EPSILON.. => {
+            let roots = (
+                (-b - delta.sqrt()) / (2.0 * a),
+                (-b + delta.sqrt()) / (2.0 * a),
+            );
+            Some((f64::min(roots.0, roots.1), f64::max(roots.0, roots.1)))
+        }
+        0.0.. => Some((-b / (2.0 * a), -b / (2.0 * a))),
+        _ => None,
+    }
+}
+
+/// Builds the interval spanning the candidate distances (ignoring NaNs, which mark rejected candidates)
+fn interval(dists: &[f64], hit: impl Fn(f64) -> Hit) -> Option<Interval> {
+    indexed_interval(dists, |_, distance| hit(distance))
+}
+
+/// Like [`interval`], passing the index of the candidate to `hit` for shapes made of several surfaces
+///
+/// Shapes which aren't convex (sweeps over half a turn) are only entered and exited once, at their
+/// first and last candidates.
+fn indexed_interval(dists: &[f64], hit: impl Fn(usize, f64) -> Hit) -> Option<Interval> {
+    let dists = dists.iter().enumerate().filter(|(_, d)| !d.is_nan());
+    let entry = dists.clone().min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let exit = dists.max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+    Some(Interval {
+        entry: hit(entry.0, *entry.1),
+        exit: hit(exit.0, *exit.1),
+    })
+}
+
+/// Converts a sweep angle in degrees to a fraction of a turn
+fn sweep_fraction(degrees: f64) -> Result<f64, String> {
+    if degrees > 0.0 && degrees <= 360.0 {
+        Ok(degrees / 360.0)
+    } else {
+        Err(format!("sweep {} must be between 0 and 360 degrees", degrees))
+    }
+}
+
+/// Whether the point is within the sweep around the Z axis, which starts from -Y and spans the
+/// given fraction of a turn in the direction the U coordinate increases
+fn in_sweep(point: (f64, f64, f64), sweep: f64) -> bool {
+    sweep >= 1.0 || 0.5 - f64::atan2(point.0, point.1) / (2.0 * PI) <= sweep
+}
+
+/// Whether the point of a horizontal cap is within its radius and the sweep
+fn on_disk(point: (f64, f64, f64), radius: f64, sweep: f64) -> bool {
+    point.0 * point.0 + point.1 * point.1 <= radius * radius && in_sweep(point, sweep)
+}
+
+/// Horizontal directions of the edges of the sweep, where it starts and where it ends
+fn sweep_edges(sweep: f64) -> [(f64, f64); 2] {
+    let (sin, cos) = (2.0 * PI * sweep).sin_cos();
+    [(0.0, -1.0), (sin, -cos)]
+}
+
+/// Distances to the flat faces closing a partial sweep from the axis to the side, which has the
+/// given radius at each height, NaN where they are missed or for open or full turns
+fn cut_distances(ray: &Ray, sweep: f64, closed: bool, radius: impl Fn(f64) -> f64) -> [f64; 2] {
+    if !closed || sweep >= 1.0 {
+        return [f64::NAN; 2];
+    }
+    sweep_edges(sweep).map(|(ex, ey)| {
+        // The face's plane contains the edge and the Z axis
+        let towards = ray.direction.0 * ey - ray.direction.1 * ex;
+        if towards.abs() < f64::EPSILON {
+            return f64::NAN;
+        }
+        let distance = -(ray.origin.0 * ey - ray.origin.1 * ex) / towards;
+        let (x, y, z) = intersection(ray, distance);
+        let along = x * ex + y * ey;
+        if z.abs() > 0.5 || along < 0.0 || along > radius(z) {
+            f64::NAN
+        } else {
+            distance
+        }
+    })
+}
+
+/// Outward normal and tangent of a face closing a partial sweep, the first one being at its start
+fn cut_normal_tangent(sweep: f64, face: usize) -> ((f64, f64, f64), (f64, f64, f64)) {
+    let (ex, ey) = sweep_edges(sweep)[face];
+    let normal = if face == 0 { (ey, -ex, 0.0) } else { (-ey, ex, 0.0) };
+    (normal, (ex, ey, 0.0))
+}
+
+/// Tangent of the UVs wrapping around the Z axis, where `u` decreases with `atan2(x, y)`
+pub(super) fn azimuthal_tangent(intersection: (f64, f64, f64)) -> (f64, f64, f64) {
+    let (x, y, _) = intersection;
+    if x.abs() < f64::EPSILON && y.abs() < f64::EPSILON {
+        // Undefined on the axis, any horizontal direction will do
+        (1.0, 0.0, 0.0)
+    } else {
+        vec3norm((-y, x, 0.0))
     }
 }
 
 pub fn intersection(ray: &Ray, distance: f64) -> (f64, f64, f64) {
     vec3add(vec3scale(ray.direction, distance), ray.origin)
 }
+
+const fn default_caps() -> bool { true }
+fn default_cone_angle() -> f64 { 0.5f64.atan().to_degrees() }
+const fn default_sweep() -> f64 { 360.0 }
@@ -0,0 +1,172 @@
+use crate::raytracer::{Ray, RayType, RGBA};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::utils::{vec3add, vec3scale};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Length of the segments drawn for rays which don't hit anything, in OBJ dumps
+const MISS_LENGTH: f64 = 100.0;
+
+thread_local! {
+    /// Rays being recorded by the current thread, only while debugging a pixel
+    static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+}
+
+struct Recorder {
+    vertices: Vec<PathVertex>,
+    /// Rays being traced, the last one is the parent of new rays
+    stack: Vec<usize>,
+}
+
+/// Every ray traced for a pixel, by sample
+#[derive(Serialize)]
+pub struct PixelPaths {
+    pub x: u32,
+    pub y: u32,
+    pub samples: Vec<Vec<PathVertex>>,
+}
+
+/// Ray traced for a pixel, the camera ray is the first of each sample
+#[derive(Serialize)]
+pub struct PathVertex {
+    /// Index of the ray whose shading traced this one
+    pub parent: Option<usize>,
+    pub ray_type: RayType,
+    pub depth: u32,
+    pub origin: (f64, f64, f64),
+    pub direction: (f64, f64, f64),
+    /// Distance to the light, for shadow rays towards lights which aren't infinitely far
+    pub light_distance: Option<f64>,
+    pub hit: Option<PathHit>,
+    /// Color returned along the ray, for shadow rays whether the light is visible
+    pub color: (f64, f64, f64, f64),
+}
+
+#[derive(Serialize)]
+pub struct PathHit {
+    pub distance: f64,
+    pub position: (f64, f64, f64),
+    pub normal: (f64, f64, f64),
+    pub front_face: bool,
+    pub object_id: u32,
+    pub object_name: Option<String>,
+    pub material_name: Option<String>,
+}
+
+/// Records the rays traced by `trace` on the current thread
+pub(super) fn record(trace: impl FnOnce()) -> Vec<PathVertex> {
+    RECORDER.set(Some(Recorder { vertices: Vec::new(), stack: Vec::new() }));
+    trace();
+    RECORDER.take().map_or_else(Vec::new, |recorder| recorder.vertices)
+}
+
+/// Starts recording a ray, until [`end_ray`]
+pub(super) fn begin_ray(ray: &Ray) {
+    RECORDER.with_borrow_mut(|recorder| {
+        if let Some(recorder) = recorder {
+            recorder.vertices.push(PathVertex {
+                parent: recorder.stack.last().copied(),
+                ray_type: ray.ray_type,
+                depth: ray.depth,
+                origin: ray.origin,
+                direction: ray.direction,
+                light_distance: None,
+                hit: None,
+                color: (0.0, 0.0, 0.0, 0.0),
+            });
+            recorder.stack.push(recorder.vertices.len() - 1);
+        }
+    });
+}
+
+/// Records what the ray being recorded hit
+pub(super) fn set_hit(oh: &ObjectHit) {
+    RECORDER.with_borrow_mut(|recorder| {
+        if let Some(recorder) = recorder
+            && let Some(&index) = recorder.stack.last()
+        {
+            recorder.vertices[index].hit = Some(PathHit::new(oh));
+        }
+    });
+}
+
+/// Finishes recording a ray with its color
+pub(super) fn end_ray(color: RGBA) {
+    RECORDER.with_borrow_mut(|recorder| {
+        if let Some(recorder) = recorder
+            && let Some(index) = recorder.stack.pop()
+        {
+            recorder.vertices[index].color = (color.r, color.g, color.b, color.a);
+        }
+    });
+}
+
+/// Records a shadow ray towards a light and the object blocking it, if any
+pub(super) fn shadow_ray(ray: &Ray, distance: f64, blocker: Option<&ObjectHit>) {
+    begin_ray(ray);
+    RECORDER.with_borrow_mut(|recorder| {
+        if let Some(recorder) = recorder
+            && let Some(&index) = recorder.stack.last()
+        {
+            recorder.vertices[index].light_distance = distance.is_finite().then_some(distance);
+        }
+    });
+    if let Some(oh) = blocker {
+        set_hit(oh);
+    }
+    let visible = if blocker.is_some() { 0.0 } else { 1.0 };
+    end_ray(RGBA::new(visible, visible, visible, 1.0));
+}
+
+impl PixelPaths {
+    /// Saves the paths as JSON, or as polylines in an OBJ file for `.obj` paths
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let obj = Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("obj"));
+        let data = if obj {
+            self.to_obj()
+        } else {
+            serde_json::to_string_pretty(self).map_err(|err| err.to_string())?
+        };
+
+        fs::write(path, data).map_err(|err| format!("Failed to save pixel paths to {}: {}", path, err))
+    }
+
+    /// Returns the rays as lines, one group per sample
+    pub fn to_obj(&self) -> String {
+        let mut obj = String::new();
+        let mut count = 0;
+        for (sample, vertices) in self.samples.iter().enumerate() {
+            let _ = writeln!(obj, "g sample{}", sample);
+            for vertex in vertices {
+                let end = match (&vertex.hit, vertex.light_distance) {
+                    (Some(hit), _) => hit.position,
+                    (None, Some(distance)) => vec3add(vertex.origin, vec3scale(vertex.direction, distance)),
+                    (None, None) => vec3add(vertex.origin, vec3scale(vertex.direction, MISS_LENGTH)),
+                };
+                for (x, y, z) in [vertex.origin, end] {
+                    let _ = writeln!(obj, "v {} {} {}", x, y, z);
+                }
+                let _ = writeln!(obj, "l {} {}", count + 1, count + 2);
+                count += 2;
+            }
+        }
+        obj
+    }
+}
+
+impl PathHit {
+    fn new(oh: &ObjectHit) -> Self {
+        Self {
+            distance: oh.hit.distance,
+            position: oh.hit.intersection,
+            normal: oh.hit.normal,
+            front_face: oh.hit.front_face,
+            object_id: oh.object.id(),
+            object_name: oh.object.name().map(str::to_string),
+            material_name: oh.material().name().map(str::to_string),
+        }
+    }
+}
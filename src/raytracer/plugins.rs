@@ -0,0 +1,229 @@
+//! Material plugins loaded from shared libraries
+//!
+//! A plugin is a shared library exporting two C functions:
+//!
+//! - `uint32_t crusty_plugin_abi_version(void)`, which must return [`PLUGIN_ABI_VERSION`]
+//! - `const PluginMaterialType *crusty_plugin_material_types(size_t *count)`, which returns an
+//!   array of material types (and writes its length to `count`) that stays valid for as long as
+//!   the library is loaded
+//!
+//! Each material type is registered under its name and can then be used from the scene like any
+//! built-in material. Material instances are shared between the worker threads, so `shade` must be
+//! thread-safe. A plugin is rejected if a type has no name or callback, or a name that is already
+//! registered, so that it can't replace a built-in material. Loading a plugin again does nothing.
+
+use crate::raytracer::{Ray, RGBA};
+use crate::raytracer::materials::{Material, MaterialNewFn, MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use libloading::Library;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fs;
+use std::slice;
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Paths of the plugins loaded, canonicalized
+static LOADED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Version of the plugin C ABI, bumped on every incompatible change
+pub const PLUGIN_ABI_VERSION: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginRay {
+    pub ray_type: u32,
+    pub origin: [f64; 3],
+    pub direction: [f64; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginHit {
+    pub ray: PluginRay,
+    pub distance: f64,
+    pub intersection: [f64; 3],
+    pub normal: [f64; 3],
+    pub uv: [f64; 2],
+    pub front_face: bool,
+}
+
+/// Color premultiplied by its alpha, surfaces with an alpha below 1 are composited over what is
+/// behind them
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginColor {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+/// Callback given to `shade` to trace secondary rays, `ctx` must be passed back as-is
+pub type PluginTraceFn = extern "C" fn(ctx: *mut c_void, ray: *const PluginRay) -> PluginColor;
+
+pub type PluginNewFn = extern "C" fn(data: *const c_char) -> *mut c_void;
+pub type PluginShadeFn = extern "C" fn(
+    material: *const c_void,
+    hit: *const PluginHit,
+    trace: PluginTraceFn,
+    trace_ctx: *mut c_void,
+) -> PluginColor;
+pub type PluginDropFn = extern "C" fn(material: *mut c_void);
+
+/// Material type as written by the plugin, whose fields may be null
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginMaterialType {
+    /// Nul-terminated name of the material type
+    pub name: *const c_char,
+    /// Creates a material from its nul-terminated JSON parameters, returns null if they are invalid
+    pub new: Option<PluginNewFn>,
+    pub shade: Option<PluginShadeFn>,
+    pub drop: Option<PluginDropFn>,
+}
+
+/// Callbacks of a material type, once checked
+#[derive(Clone, Copy)]
+struct Callbacks {
+    new: PluginNewFn,
+    shade: PluginShadeFn,
+    drop: PluginDropFn,
+}
+
+type AbiVersionFn = extern "C" fn() -> u32;
+type MaterialTypesFn = extern "C" fn(count: *mut usize) -> *const PluginMaterialType;
+
+struct PluginMaterial {
+    callbacks: Callbacks,
+    material: *mut c_void,
+    // Keeps the library loaded for as long as the material is alive
+    _library: Arc<Library>,
+}
+
+// The plugin contract requires materials to be usable from any thread
+unsafe impl Send for PluginMaterial {}
+unsafe impl Sync for PluginMaterial {}
+
+struct TraceContext<'a> {
+    parent: Ray,
+    shade_ctx: &'a ShadeContext<'a>,
+}
+
+/// Loads the plugin at `path` and registers all the material types it provides, unless it was
+/// already loaded
+pub fn load(path: &str) -> Result<(), String> {
+    let canonical = fs::canonicalize(path).map_or(path.to_string(), |canonical| canonical.to_string_lossy().into_owned());
+    let mut loaded = LOADED.lock().unwrap();
+    if loaded.contains(&canonical) {
+        return Ok(());
+    }
+
+    let library = unsafe { Library::new(path) }
+        .map_err(|err| format!("Failed to load plugin {}: {}", path, err))?;
+
+    let types = unsafe {
+        let abi_version = library.get::<AbiVersionFn>(b"crusty_plugin_abi_version\0")
+            .map_err(|err| format!("Invalid plugin {}: {}", path, err))?;
+        if abi_version() != PLUGIN_ABI_VERSION {
+            return Err(format!(
+                "Plugin {} was built for ABI version {} (expected {})",
+                path, abi_version(), PLUGIN_ABI_VERSION,
+            ));
+        }
+
+        let material_types = library.get::<MaterialTypesFn>(b"crusty_plugin_material_types\0")
+            .map_err(|err| format!("Invalid plugin {}: {}", path, err))?;
+        let mut count = 0;
+        let types = material_types(&mut count);
+        if types.is_null() { &[] } else { slice::from_raw_parts(types, count) }.to_vec()
+    };
+
+    let library = Arc::new(library);
+    let mut new_types: Vec<(String, MaterialNewFn)> = Vec::new();
+    for vtable in types {
+        if vtable.name.is_null() {
+            return Err(format!("Plugin {} has a material type without a name", path));
+        }
+        let name = unsafe { CStr::from_ptr(vtable.name) }
+            .to_str()
+            .map_err(|_| format!("Plugin {} has a material type with an invalid name", path))?
+            .to_string();
+        let (Some(new), Some(shade), Some(drop)) = (vtable.new, vtable.shade, vtable.drop) else {
+            return Err(format!("Plugin {} has a material type {} without new, shade or drop function", path, name));
+        };
+        let callbacks = Callbacks { new, shade, drop };
+        let library = library.clone();
+        let type_name = name.clone();
+
+        new_types.push((name, Box::new(move |data: &Value| {
+            let data = CString::new(data.to_string()).map_err(|err| err.to_string())?;
+            let material = (callbacks.new)(data.as_ptr());
+            if material.is_null() {
+                return Err(format!("Plugin material {} rejected its parameters", type_name));
+            }
+
+            Ok(Box::new(PluginMaterial {
+                callbacks,
+                material,
+                _library: library.clone(),
+            }))
+        })));
+    }
+
+    Material::register_new_types(new_types).map_err(|err| format!("Invalid plugin {}: {}", path, err))?;
+    loaded.insert(canonical);
+    Ok(())
+}
+
+impl MaterialType for PluginMaterial {
+    fn shade(&self, oh: &ObjectHit, shade_ctx: &ShadeContext) -> RGBA {
+        let hit = PluginHit {
+            ray: PluginRay::from(&oh.ray),
+            distance: oh.hit.distance,
+            intersection: oh.hit.intersection.into(),
+            normal: oh.hit.normal.into(),
+            uv: oh.hit.uv.into(),
+            front_face: oh.hit.front_face,
+        };
+        let mut ctx = TraceContext {
+            parent: oh.ray,
+            shade_ctx,
+        };
+
+        let color = (self.callbacks.shade)(self.material, &hit, trace, &mut ctx as *mut TraceContext as *mut c_void);
+        RGBA::new(color.r, color.g, color.b, color.a)
+    }
+}
+
+impl Drop for PluginMaterial {
+    fn drop(&mut self) {
+        (self.callbacks.drop)(self.material);
+    }
+}
+
+impl From<&Ray> for PluginRay {
+    fn from(ray: &Ray) -> Self {
+        Self {
+            ray_type: ray.ray_type as u32,
+            origin: ray.origin.into(),
+            direction: ray.direction.into(),
+        }
+    }
+}
+
+extern "C" fn trace(ctx: *mut c_void, ray: *const PluginRay) -> PluginColor {
+    let (ctx, ray) = unsafe { (&*(ctx as *const TraceContext), &*ray) };
+    let color = ctx.shade_ctx.trace(Ray {
+        origin: ray.origin.into(),
+        direction: ray.direction.into(),
+        ..ctx.parent
+    });
+
+    PluginColor {
+        r: color.r,
+        g: color.g,
+        b: color.b,
+        a: color.a,
+    }
+}
@@ -0,0 +1,226 @@
+//! Post effects applied to the completed render, in order, before it is displayed and saved
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+static POST_EFFECT_TYPES: LazyLock<Mutex<HashMap<String, PostEffectNewFn>>> =
+    LazyLock::new(|| Mutex::new(HashMap::from([
+        ("bloom".to_string(), new_fn(Bloom::new)),
+        ("chromatic_aberration".to_string(), new_fn(ChromaticAberration::new)),
+        ("vignette".to_string(), new_fn(Vignette::new)),
+    ])));
+
+pub type PostEffectNewFn = Box<dyn Fn(&Value) -> Result<Box<dyn PostEffect + Send + Sync>, String> + Send>;
+
+pub trait PostEffect {
+    fn apply(&self, image: &mut PostImage);
+}
+
+/// Linear HDR image, premultiplied by its alpha
+pub struct PostImage {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixels, row by row
+    pub pixels: Vec<[f32; 4]>,
+}
+
+/// Glow around the parts of the image brighter than a threshold
+struct Bloom {
+    threshold: f32,
+    /// Radius of the glow, relative to the image's height
+    radius: f64,
+    strength: f32,
+}
+
+/// Darkening towards the corners of the image
+struct Vignette {
+    /// Darkening in the corners, from 0 (none) to 1 (black)
+    strength: f32,
+    /// Exponent of the falloff from the center, relative to the distance to the corners
+    falloff: f32,
+}
+
+/// Red and blue channels scaled away from each other around the center of the image, like with
+/// a simple lens
+struct ChromaticAberration {
+    /// Relative scale difference of the red and blue channels with the green one
+    strength: f32,
+}
+
+#[derive(Deserialize)]
+struct BloomData {
+    #[serde(default = "default_bloom_threshold")]
+    threshold: f32,
+    #[serde(default = "default_bloom_radius")]
+    radius: f64,
+    #[serde(default = "default_bloom_strength")]
+    strength: f32,
+}
+
+#[derive(Deserialize)]
+struct VignetteData {
+    #[serde(default = "default_vignette_strength")]
+    strength: f32,
+    #[serde(default = "default_vignette_falloff")]
+    falloff: f32,
+}
+
+#[derive(Deserialize)]
+struct ChromaticAberrationData {
+    #[serde(default = "default_chromatic_aberration_strength")]
+    strength: f32,
+}
+
+pub fn register_post_effect(name: String, new_fn: PostEffectNewFn) {
+    let mut types = POST_EFFECT_TYPES.lock().unwrap();
+    types.insert(name, new_fn);
+}
+
+/// Creates a post effect from its registered type name and parameters
+pub fn new_post_effect(type_name: &String, data: &Value) -> Result<Box<dyn PostEffect + Send + Sync>, String> {
+    let types = POST_EFFECT_TYPES.lock().unwrap();
+    match types.get(type_name) {
+        Some(post_effect_new_fn) => post_effect_new_fn(data),
+        None => Err(format!("Could not find post effect type {}", type_name)),
+    }
+}
+
+/// Wraps a post effect constructor for the post effect type registry
+fn new_fn<T>(new: fn(&Value) -> Result<T, String>) -> PostEffectNewFn
+where
+    T: PostEffect + Sync + Send + 'static
+{
+    Box::new(move |data| Ok(Box::new(new(data)?)))
+}
+
+impl PostImage {
+    /// Samples a channel with bilinear filtering, clamping to the edges
+    fn sample(&self, x: f32, y: f32, channel: usize) -> f32 {
+        let (x, y) = ((x - 0.5).clamp(0.0, self.width as f32 - 1.0), (y - 0.5).clamp(0.0, self.height as f32 - 1.0));
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+
+        let pixel = |x: u32, y: u32| self.pixels[(x + y * self.width) as usize][channel];
+        let top = pixel(x0, y0) + (pixel(x1, y0) - pixel(x0, y0)) * tx;
+        let bottom = pixel(x0, y1) + (pixel(x1, y1) - pixel(x0, y1)) * tx;
+        top + (bottom - top) * ty
+    }
+}
+
+impl Bloom {
+    fn new(data: &Value) -> Result<Self, String> {
+        let data: BloomData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid bloom post effect: {}", err))?;
+        Ok(Self { threshold: data.threshold, radius: data.radius, strength: data.strength })
+    }
+}
+
+impl PostEffect for Bloom {
+    fn apply(&self, image: &mut PostImage) {
+        let (width, height) = (image.width as usize, image.height as usize);
+        let radius = (self.radius * height as f64).round() as usize;
+        if radius == 0 || width == 0 {
+            return;
+        }
+
+        let mut bright: Vec<[f32; 3]> = image.pixels.iter()
+            .map(|p| [0, 1, 2].map(|c| (p[c] - self.threshold).max(0.0)))
+            .collect();
+
+        // Three box blurs approximate a gaussian blur
+        let mut scratch = bright.clone();
+        for _ in 0..3 {
+            box_blur(&bright, &mut scratch, width, height, radius, (1, width));
+            box_blur(&scratch, &mut bright, height, width, radius, (width, 1));
+        }
+
+        for (pixel, glow) in image.pixels.iter_mut().zip(bright) {
+            for c in 0..3 {
+                pixel[c] += glow[c] * self.strength;
+            }
+        }
+    }
+}
+
+/// Blurs `lines` lines of `length` pixels with a box of the given radius, `steps` being the
+/// distances between pixels along the lines and between lines
+fn box_blur(src: &[[f32; 3]], dst: &mut [[f32; 3]], length: usize, lines: usize, radius: usize, steps: (usize, usize)) {
+    let scale = 1.0 / (2 * radius + 1) as f32;
+    for line in 0..lines {
+        let index = |i: usize| line * steps.1 + i.min(length - 1) * steps.0;
+        // Running sum of the box, the edges are repeated outside of the image
+        let mut sum = [0.0f32; 3];
+        for i in 0..=radius {
+            let weight = if i == 0 { radius as f32 + 1.0 } else { 1.0 };
+            for c in 0..3 {
+                sum[c] += src[index(i)][c] * weight;
+            }
+        }
+        for i in 0..length {
+            dst[index(i)] = sum.map(|s| s * scale);
+            let (add, remove) = (index(i + radius + 1), index(i.saturating_sub(radius)));
+            for c in 0..3 {
+                sum[c] += src[add][c] - src[remove][c];
+            }
+        }
+    }
+}
+
+impl Vignette {
+    fn new(data: &Value) -> Result<Self, String> {
+        let data: VignetteData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid vignette post effect: {}", err))?;
+        Ok(Self { strength: data.strength, falloff: data.falloff })
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(&self, image: &mut PostImage) {
+        let center = (image.width as f32 / 2.0, image.height as f32 / 2.0);
+        let corner = (center.0 * center.0 + center.1 * center.1).sqrt();
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = ((i % image.width as usize) as f32 + 0.5, (i / image.width as usize) as f32 + 0.5);
+            let distance = ((x - center.0).powi(2) + (y - center.1).powi(2)).sqrt() / corner;
+            let factor = 1.0 - self.strength * distance.powf(self.falloff);
+            for c in &mut pixel[..3] {
+                *c *= factor;
+            }
+        }
+    }
+}
+
+impl ChromaticAberration {
+    fn new(data: &Value) -> Result<Self, String> {
+        let data: ChromaticAberrationData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid chromatic aberration post effect: {}", err))?;
+        Ok(Self { strength: data.strength })
+    }
+}
+
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, image: &mut PostImage) {
+        let center = (image.width as f32 / 2.0, image.height as f32 / 2.0);
+        let pixels = (0..image.pixels.len())
+            .map(|i| {
+                let (x, y) = ((i % image.width as usize) as f32 + 0.5, (i / image.width as usize) as f32 + 0.5);
+                // Red is magnified and blue shrunk, so they are sampled closer to and farther from the center
+                let sample = |channel: usize, scale: f32| {
+                    image.sample(center.0 + (x - center.0) / scale, center.1 + (y - center.1) / scale, channel)
+                };
+                let pixel = image.pixels[i];
+                [sample(0, 1.0 + self.strength), pixel[1], sample(2, 1.0 - self.strength), pixel[3]]
+            })
+            .collect();
+        image.pixels = pixels;
+    }
+}
+
+const fn default_bloom_threshold() -> f32 { 1.0 }
+const fn default_bloom_radius() -> f64 { 0.02 }
+const fn default_bloom_strength() -> f32 { 0.2 }
+const fn default_vignette_strength() -> f32 { 0.5 }
+const fn default_vignette_falloff() -> f32 { 2.0 }
+const fn default_chromatic_aberration_strength() -> f32 { 0.005 }
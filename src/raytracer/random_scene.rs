@@ -0,0 +1,92 @@
+//! Random scenes of primitives, for demos and to fuzz the intersection and shading code with
+//! transforms and materials nobody would write by hand
+
+use crate::raytracer::{SceneBuilder, SceneObjectMaterial, SceneTransform};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+
+const PRIMITIVES: [&str; 4] = ["sphere", "cube", "cylinder", "cone"];
+
+/// Returns a valid scene generated from the seed, the same seed always giving the same scene
+pub fn random_scene(seed: u64) -> SceneBuilder {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let color = |rng: &mut StdRng| [rng.random(), rng.random(), rng.random()];
+
+    let camera = SceneTransform::default()
+        .translate(0.0, -rng.random_range(12.0..25.0), rng.random_range(1.0..10.0))
+        .rotate(-rng.random_range(5.0..30.0), 0.0, 0.0);
+    let mut scene = SceneBuilder::new()
+        .output(320, 240)
+        .samples(4)
+        .camera(rng.random_range(30.0..90.0), camera)
+        .orbit_camera(rng.random_range(0.0..360.0), [0.0; 3])
+        .background(color(&mut rng), rng.random_range(0.0..1.0));
+
+    let materials = rng.random_range(1..=6);
+    for i in 0..materials {
+        let name = format!("material{}", i + 1);
+        scene = match rng.random_range(0..4) {
+            0 | 1 => scene.material(&name, "diffuse", json!({ "color": color(&mut rng) })),
+            2 => scene.material(&name, "ggx", json!({
+                "color": color(&mut rng),
+                "roughness_u": rng.random_range(0.0..1.0),
+                "roughness_v": rng.random_range(0.0..1.0),
+            })),
+            // Layers over the previous materials
+            _ if i > 0 => scene.material(&name, "layer", json!({
+                "base": format!("material{}", rng.random_range(1..=i)),
+                "coat": format!("material{}", rng.random_range(1..=i)),
+                "weight": rng.random_range(0.0..1.0),
+            })),
+            _ => scene.material(&name, "ggx", json!({ "color": color(&mut rng), "roughness": 0.0 })),
+        };
+    }
+    let material = |rng: &mut StdRng| match rng.random_range(0..=materials) {
+        0 => SceneObjectMaterial::None,
+        i => SceneObjectMaterial::MaterialRef(format!("material{}", i)),
+    };
+
+    if rng.random_bool(0.7) {
+        scene = scene.add_plane(SceneTransform::default().scale(20.0, 20.0, 1.0), material(&mut rng));
+    }
+    for _ in 0..rng.random_range(3..=20) {
+        // Mostly reasonable sizes, with some extreme ones which are still invertible
+        let mut scale = || match rng.random_range(0..20) {
+            0 => 1e-4,
+            1 => 1e4,
+            _ => rng.random_range(0.2..2.0),
+        };
+        let scale = [scale(), scale(), scale()];
+        let transform = SceneTransform::default()
+            .translate(rng.random_range(-6.0..6.0), rng.random_range(-6.0..6.0), rng.random_range(0.0..4.0))
+            .rotate(rng.random_range(0.0..360.0), rng.random_range(0.0..360.0), rng.random_range(0.0..360.0))
+            .scale(scale[0], scale[1], scale[2]);
+        let primitive = PRIMITIVES[rng.random_range(0..PRIMITIVES.len())];
+        let data = match primitive {
+            "cylinder" if rng.random_bool(0.3) => json!({
+                "caps": rng.random_bool(0.5),
+                "sweep": rng.random_range(1.0..=360.0),
+            }),
+            "cone" if rng.random_bool(0.3) => json!({
+                "cap": rng.random_bool(0.5),
+                "angle": rng.random_range(1.0..89.0),
+                "sweep": rng.random_range(1.0..=360.0),
+            }),
+            _ => json!({}),
+        };
+        scene = scene.add_object(primitive, transform, material(&mut rng), data);
+    }
+
+    for _ in 0..rng.random_range(1..=4) {
+        let position = [rng.random_range(-10.0..10.0), rng.random_range(-10.0..10.0), rng.random_range(2.0..12.0)];
+        let direction = [rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0), -rng.random_range(0.1..1.0)];
+        let intensity = rng.random_range(0.5..2.0);
+        scene = match rng.random_range(0..3) {
+            0 => scene.add_point_light(position, color(&mut rng), intensity * 100.0),
+            1 => scene.add_spot_light(position, direction, color(&mut rng), intensity * 100.0, rng.random_range(10.0..90.0)),
+            _ => scene.add_directional_light(direction, color(&mut rng), intensity),
+        };
+    }
+    scene
+}
@@ -1,63 +1,421 @@
-use crate::raytracer::{Camera, Output};
-use crate::raytracer::materials::Material;
+use crate::log;
+use crate::raytracer::{Camera, DepthLimits, Filter, Output, RayType, Raytracer, World};
+use crate::raytracer::bvh::BvhOptions;
+use crate::raytracer::composite::{LayerMaterial, MixMaterial, ResolveMaterialFn};
+use crate::raytracer::debug_shading::{BoundsOverlay, DebugShading};
+use crate::raytracer::image_file::{ImageFile, ImageFormat};
+use crate::raytracer::lights::{Gobo, Light, LightKind, LightSampler, Portal};
+use crate::raytracer::materials::{Material, UvTransform};
 use crate::raytracer::objects::Object;
+use crate::raytracer::post::{self, PostEffect};
+use crate::raytracer::{plugins, scripting};
+use crate::raytracer::sky::{self, Sky};
+use crate::raytracer::spectrum::Spectrum;
+use crate::raytracer::textures::{ColorSpace, Texture, TextureFilter};
+use crate::raytracer::tile::TileOrder;
 use crate::raytracer::transform::Transform;
-use serde::Deserialize;
-use serde_json::Value;
+use crate::raytracer::utils::{self, vec3norm};
+use crate::raytracer::uv_projection::{Projection, UvProjection};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema, Serialize)]
 pub struct Scene {
+    #[serde(default)]
+    pub plugins: Vec<String>,
     pub camera: SceneCamera,
     pub output: SceneOutput,
+    #[serde(default)]
+    pub world: SceneWorld,
+    /// Materials by name, a material can `extends` another to reuse its parameters
+    #[serde(deserialize_with = "deserialize_materials")]
     pub materials: HashMap<String, SceneMaterial>,
     pub objects: Vec<SceneObject>,
+    #[serde(default)]
+    pub lights: Vec<SceneLight>,
+    #[serde(default)]
+    pub generators: Vec<SceneGenerator>,
+    /// Reusable groups of objects, lights and materials by name, placed by `instances` and
+    /// expanded when the scene is loaded
+    #[serde(default)]
+    pub prefabs: HashMap<String, ScenePrefab>,
+    #[serde(default)]
+    pub instances: Vec<SceneInstance>,
+    /// Render layers by name, subsets of the objects rendered one at a time
+    #[serde(default)]
+    pub layers: HashMap<String, SceneLayer>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema, Serialize)]
 pub struct SceneCamera {
     #[serde(default = "default_camera_fov")]
     fov: f64,
+    /// Distance of the image plane, which spans `fov` at a distance of 1 whatever this distance:
+    /// larger distances narrow the actual field of view. It doesn't clip anything, see `clip_near`
     #[serde(default = "default_camera_near")]
     near: f64,
+    /// Distance along the view axis closer than which camera rays go through objects, to see
+    /// inside them for cutaway views. Other rays aren't clipped, clipped objects still cast
+    /// shadows and show in reflections
+    #[serde(default)]
+    clip_near: f64,
+    /// Distance along the view axis past which camera rays go through objects, not clipped if not set
+    #[serde(default)]
+    clip_far: Option<f64>,
+    /// Renders a view for each eye, for VR headsets and stereo displays
+    #[serde(default)]
+    stereo: Option<SceneStereo>,
+    /// Photographic exposure scaling the light the camera sees, so that scenes keep their lights'
+    /// strengths and are exposed like photographs instead, 1 to 1 if not set
+    #[serde(default)]
+    exposure: Option<SceneExposure>,
+    /// Color balance of the camera, so that the light of a color temperature looks white, e.g.
+    /// to neutralize warm lights or a cool sky, neutral if not set
+    #[serde(default)]
+    white_balance: Option<SceneWhiteBalance>,
+    /// Radial distortion of the lens, to match renders to footage of a real lens, none if not set.
+    /// Its lateral chromatic aberration is the `chromatic_aberration` post effect
+    #[serde(default)]
+    distortion: Option<SceneDistortion>,
     transform: SceneTransform,
 }
 
-#[derive(Deserialize)]
+/// Radial lens distortion, with the coefficients of OpenCV's and Brown's model so that those of
+/// calibrated lenses can be used: a point of the image plane at a distance of 1 from the camera
+/// and at `r` from its center is seen `1 + k1 r² + k2 r⁴` times as far from it
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+pub struct SceneDistortion {
+    /// Negative for barrel distortion, positive for pincushion distortion
+    #[serde(default)]
+    k1: f64,
+    /// Distortion towards the image's edges, e.g. for the mustache distortion of wide angle lenses
+    #[serde(default)]
+    k2: f64,
+}
+
+/// White balance of the camera, applied with its exposure
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+pub struct SceneWhiteBalance {
+    /// Color temperature in kelvins of the light which looks white, lower to cool the image down
+    /// and higher to warm it up, 6500 being neutral
+    #[serde(default = "default_white_balance_temperature")]
+    temperature: f64,
+    /// Shift from green (negative) to magenta (positive), in stops of green removed
+    #[serde(default)]
+    tint: f64,
+}
+
+/// Exposure of the camera, applied to the samples before the post effects
+///
+/// The backplate is an image which was already exposed, it is seen as is.
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SceneExposure {
+    /// Exposure compensation in stops, each stop doubling the image's brightness
+    Ev {
+        ev: f64,
+    },
+    /// Settings of a physical camera, for lights in physical units: luminances above 1.2 × 2^EV100
+    /// in cd/m² clip, as with ISO's saturation-based speed. The f-number only sets the exposure,
+    /// it doesn't add depth of field
+    Camera {
+        #[serde(default = "default_exposure_iso")]
+        iso: f64,
+        /// Shutter time in seconds
+        #[serde(default = "default_exposure_shutter")]
+        shutter: f64,
+        #[serde(default = "default_exposure_f_stop")]
+        f_stop: f64,
+        /// Exposure compensation in stops on top of the settings
+        #[serde(default)]
+        ev: f64,
+    },
+}
+
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SceneStereo {
+    /// Left eye view in the left half of the image and right eye view in the right half, both
+    /// with the camera's field of view and parallel
+    SideBySide {
+        /// Distance between the eyes, centered on the camera
+        #[serde(default = "default_stereo_interocular")]
+        interocular: f64,
+    },
+    /// Omnidirectional stereo: equirectangular panoramas of the left eye in the top half of the
+    /// image and of the right eye in the bottom half, the eyes turning with the view direction
+    /// around the camera's vertical axis
+    Ods {
+        #[serde(default = "default_stereo_interocular")]
+        interocular: f64,
+    },
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
 pub struct SceneOutput {
     width: u32,
     height: u32,
     #[serde(default = "default_output_samples")]
     samples: u32,
-    #[serde(default = "default_output_tile_size")]
-    tile_size: u32,
+    /// Size of the tiles in pixels, picked from the resolution and the number of threads if not set
+    #[serde(default)]
+    tile_size: Option<u32>,
+    #[serde(default)]
+    tile_order: TileOrder,
+    /// Reconstruction filter weighting the samples of each pixel
+    #[serde(default)]
+    filter: Filter,
+    #[serde(default = "default_output_ray_epsilon")]
+    ray_epsilon: f64,
+    /// Maximum number of bounces of secondary rays
+    #[serde(default = "default_output_max_depth")]
+    max_depth: u32,
+    /// Maximum number of diffuse bounces, defaults to `max_depth`
+    #[serde(default)]
+    max_diffuse_depth: Option<u32>,
+    /// Maximum number of glossy (reflection) bounces, defaults to `max_depth`
+    #[serde(default)]
+    max_glossy_depth: Option<u32>,
+    /// Maximum number of transmission bounces, defaults to `max_depth`
+    #[serde(default)]
+    max_transmission_depth: Option<u32>,
+    /// Number of lights sampled per shading point in scenes with more lights, 0 to sample all of them
+    #[serde(default = "default_output_light_samples")]
+    light_samples: u32,
+    /// Whether the background is transparent where camera rays miss, it still lights the scene
+    #[serde(default)]
+    transparent_background: bool,
+    /// How the acceleration structures of meshes are built
+    #[serde(default)]
+    pub bvh: BvhOptions,
+    /// Image file the render is saved to once completed
+    #[serde(default)]
+    path: Option<String>,
+    /// Format of the image file, guessed from the path's extension by default
+    #[serde(default)]
+    format: Option<ImageFormat>,
+    /// Image the render is composited over when saved, using the render's alpha
+    #[serde(default)]
+    backplate: Option<SceneBackplate>,
+    /// Seed of the random numbers used for sampling, renders with the same seed are identical
+    #[serde(default)]
+    pub seed: u64,
+    /// Near and far distances the depth pass is normalized between, raw distances if not set
+    #[serde(default)]
+    depth_range: Option<(f64, f64)>,
+    /// Shading replacing the materials, to inspect the geometry
+    #[serde(default)]
+    pub shading: Option<DebugShading>,
+    /// Wireframes of the objects' bounding boxes drawn over the render, to inspect the BVHs
+    #[serde(default)]
+    pub bounds_overlay: Option<BoundsOverlay>,
+    /// Whether camera rays sample wavelengths, so that dielectrics with an Abbe number disperse
+    /// light and lights with a spectrum emit it
+    #[serde(default)]
+    pub spectral: bool,
+    /// Post effects applied in order to the completed render
+    #[serde(default)]
+    post: Vec<ScenePostEffect>,
+    /// Seconds after which no more tiles are started, the render is then completed with the
+    /// tiles rendered so far
+    #[serde(default)]
+    pub time_limit: Option<f64>,
+    /// Passes of `samples` samples per pixel are rendered until the estimated noise (relative error
+    /// of the pixels' luminance) is below this threshold, only one pass is rendered if not set
+    #[serde(default)]
+    pub noise_threshold: Option<f64>,
+    /// Maximum number of samples per pixel with a noise threshold
+    #[serde(default = "default_output_max_samples")]
+    pub max_samples: u32,
+}
+
+/// Image behind the render, stretched to the output's resolution
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneBackplate {
+    path: String,
+    /// Whether camera rays which miss the objects see the backplate while rendering, instead of
+    /// the background
+    #[serde(default)]
+    visible: bool,
+}
+
+/// Environment seen by rays which don't hit any object
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneWorld {
+    #[serde(default)]
+    color: [f64; 3],
+    #[serde(default = "default_world_strength")]
+    strength: f64,
+    /// Sun lighting the scene, with a matching sky replacing the color
+    #[serde(default)]
+    sun: Option<SceneSun>,
+    /// Openings like windows which the environment lights interiors through, as transforms of
+    /// squares like plane objects. Diffuse surfaces sample the environment through them, for
+    /// less noise, so they should cover the openings without overlapping each other
+    #[serde(default)]
+    portals: Vec<SceneTransform>,
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneSun {
+    #[serde(flatten)]
+    position: SceneSunPosition,
+    /// Irradiance of the sun before the atmosphere reddens and dims it
+    #[serde(default = "default_sun_intensity")]
+    intensity: f64,
+    /// Multiplier of the sky's light
+    #[serde(default = "default_sun_sky")]
+    sky: f64,
+    /// Optical depth of the aerosols at 1 µm, 0 for pure air and 0.2 or more for a hazy sky
+    #[serde(default = "default_sun_turbidity")]
+    turbidity: f64,
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[serde(untagged)]
+pub enum SceneSunPosition {
+    /// Angles in degrees, the azimuth going clockwise from north (+y) towards east (+x)
+    Angles { azimuth: f64, elevation: f64 },
+    /// Sun seen from a place on Earth at a local time, north being +y
+    Location {
+        /// Latitude and longitude in degrees, north and east being positive
+        latitude: f64,
+        longitude: f64,
+        /// Date as YYYY-MM-DD
+        date: String,
+        /// Local time as HH:MM or HH:MM:SS
+        time: String,
+        /// Offset of the local time from UTC in hours, the local mean solar time if not set
+        #[serde(default)]
+        utc_offset: Option<f64>,
+    },
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
 pub struct SceneMaterial {
+    #[serde(rename = "type")]
+    type_name: String,
+    #[serde(default = "default_material_double_sided")]
+    double_sided: bool,
+    #[serde(default)]
+    uv_transform: Option<SceneUvTransform>,
+    #[serde(default)]
+    alpha_texture: Option<SceneTexture>,
+    #[serde(default = "default_material_alpha_threshold")]
+    alpha_threshold: f64,
+    #[serde(default)]
+    bump_texture: Option<SceneTexture>,
+    #[serde(default = "default_material_bump_strength")]
+    bump_strength: f64,
+    #[serde(flatten)]
+    data: Value,
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct ScenePostEffect {
     #[serde(rename = "type")]
     type_name: String,
     #[serde(flatten)]
     data: Value,
 }
 
-#[derive(Deserialize)]
+/// Image texture, either its path or its path and sampling options
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(untagged)]
+pub enum SceneTexture {
+    Path(String),
+    Options {
+        path: String,
+        #[serde(default)]
+        filter: TextureFilter,
+        /// How the image's colors are encoded, `srgb` for most 8-bit color textures and `linear`
+        /// (the default) for non-color data like roughness or bump maps
+        #[serde(default)]
+        color_space: ColorSpace,
+    },
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
+pub struct SceneUvTransform {
+    #[serde(default = "default_uv_transform_scale")]
+    scale: [f64; 2],
+    #[serde(default)]
+    offset: [f64; 2],
+    #[serde(default)]
+    rotation: f64,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
 pub struct SceneObject {
     #[serde(rename = "type")]
     type_name: String,
     transform: SceneTransform,
     #[serde(default)]
     material: SceneObjectMaterial,
+    /// Identifier in the object ID pass, defaults to the object's position in the scene starting from 1
+    #[serde(default)]
+    id: Option<u32>,
+    #[serde(default)]
+    name: Option<String>,
+    /// Projection replacing the texture coordinates of the object's type
+    #[serde(default)]
+    uv_projection: Option<SceneUvProjection>,
+    /// Types of rays which go through the object, e.g. `["camera"]` to only see it indirectly
+    #[serde(default)]
+    invisible_to: Vec<RayType>,
+    /// Names of the lights illuminating the object
+    #[serde(default)]
+    lights: SceneLinks,
     #[serde(flatten)]
     data: Value,
 }
 
-#[derive(Deserialize)]
+/// Names of the lights or objects linked together for light linking
+#[derive(Clone, Default, Deserialize, JsonSchema, Serialize)]
+pub struct SceneLinks {
+    /// Names linked, all of them if empty
+    #[serde(default)]
+    include: Vec<String>,
+    /// Names left out
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// Subset of the objects rendered on its own, for compositing or to look at some objects alone
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneLayer {
+    /// Names of the objects rendered, all of them if empty
+    #[serde(default)]
+    include: Vec<String>,
+    /// Names of the objects left out
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Name of a material replacing the objects' materials, e.g. plain gray for clay renders
+    #[serde(default)]
+    material: Option<String>,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
+pub struct SceneUvProjection {
+    #[serde(rename = "type")]
+    projection: Projection,
+    /// Placement of the projection in the object's space
+    #[serde(default)]
+    transform: SceneTransform,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
 pub enum SceneObjectMaterial {
     None,
     MaterialRef(String),
     Material(SceneMaterial),
+    /// Materials by material index, for objects with several materials like meshes with `usemtl` groups
+    Materials(Vec<SceneObjectMaterial>),
 }
 
 impl Default for SceneObjectMaterial {
@@ -66,65 +424,1394 @@ impl Default for SceneObjectMaterial {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
+pub struct SceneLight {
+    #[serde(flatten)]
+    kind: SceneLightKind,
+    #[serde(default = "default_light_color")]
+    color: [f64; 3],
+    /// Emission spectrum replacing the color, as wavelengths in nanometers and relative powers
+    /// interpolated linearly, a constant spectrum of 1 being white
+    #[serde(default)]
+    spectrum: Option<Vec<[f64; 2]>>,
+    /// Color temperature in kelvins replacing the color, as the light of a black body: 1900 for
+    /// candles, 2700 for incandescent bulbs, 5500 for daylight and 6500 or more for overcast skies
+    #[serde(default)]
+    temperature: Option<f64>,
+    #[serde(default = "default_light_intensity")]
+    intensity: f64,
+    /// Exponent of the distance attenuation, 2 being physically correct
+    #[serde(default = "default_light_falloff")]
+    falloff: f64,
+    /// Radius of the light for soft shadows, 0 for hard shadows
+    #[serde(default)]
+    radius: f64,
+    /// Distance past which the light is ignored
+    #[serde(default)]
+    max_distance: Option<f64>,
+    /// Light group the light belongs to, each group's light is output as its own pass
+    #[serde(default)]
+    group: Option<String>,
+    /// Name objects refer to the light by for light linking
+    #[serde(default)]
+    name: Option<String>,
+    /// Names of the objects the light illuminates
+    #[serde(default)]
+    objects: SceneLinks,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SceneLightKind {
+    Point {
+        position: [f64; 3],
+    },
+    Spot {
+        position: [f64; 3],
+        direction: [f64; 3],
+        /// Full angle of the cone, in degrees
+        #[serde(default = "default_spot_angle")]
+        angle: f64,
+        /// Fraction of the cone over which the light fades out towards its edge
+        #[serde(default = "default_spot_blend")]
+        blend: f64,
+        /// Texture projected across the cone, which multiplies the color, its top towards +z (or
+        /// +y for lights pointing up or down)
+        #[serde(default)]
+        gobo: Option<SceneTexture>,
+    },
+    Directional {
+        direction: [f64; 3],
+    },
+}
+
+/// Group of objects and lights with their materials, placed in the scene by instances
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct ScenePrefab {
+    /// Materials added to the scene's, which can only extend the prefab's other materials
+    #[serde(default, deserialize_with = "deserialize_materials")]
+    materials: HashMap<String, SceneMaterial>,
+    #[serde(default)]
+    objects: Vec<SceneObject>,
+    /// Lights of the prefab, their positions and directions in the prefab's space
+    #[serde(default)]
+    lights: Vec<SceneLight>,
+    /// Other prefabs placed in this one
+    #[serde(default)]
+    instances: Vec<SceneInstance>,
+}
+
+/// Copy of a prefab's objects and lights placed in the scene
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneInstance {
+    prefab: String,
+    #[serde(default)]
+    transform: SceneTransform,
+    /// Prefix of the names of the prefab's objects and lights, as `name.object`, so that render
+    /// layers and light links can tell the instances apart
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Serialize)]
+pub struct SceneGenerator {
+    script: String,
+}
+
+#[derive(Clone, Deserialize, JsonSchema, Serialize)]
 pub struct SceneTransform {
     #[serde(default)]
     translate: [f64; 3],
     #[serde(default)]
     rotate: [f64; 3],
+    /// X along Y, X along Z and Y along Z, applied after the scale
+    #[serde(default)]
+    shear: [f64; 3],
     #[serde(default = "default_transform_scale")]
     scale: [f64; 3],
 }
 
+/// Builds a scene programmatically, as an alternative to parsing it from JSON
+///
+/// ```no_run
+/// use crusty::raytracer::{SceneBuilder, SceneObjectMaterial, SceneTransform};
+/// use serde_json::json;
+///
+/// let raytracer = SceneBuilder::new()
+///     .output(640, 360)
+///     .camera(60.0, SceneTransform::default().translate(0.0, -10.0, 2.0))
+///     .material("red", "script", json!({ "source": "fn shade(hit) { [1.0, 0.0, 0.0] }" }))
+///     .add_sphere(SceneTransform::default(), SceneObjectMaterial::MaterialRef("red".to_string()))
+///     .build()?;
+/// # Ok::<(), String>(())
+/// ```
+pub struct SceneBuilder {
+    scene: Scene,
+}
+
 impl From<&SceneCamera> for Camera {
     fn from(scene_camera: &SceneCamera) -> Self {
         Self {
             fov: scene_camera.fov,
             near: scene_camera.near,
+            clip: (scene_camera.clip_near, scene_camera.clip_far.unwrap_or(f64::INFINITY)),
+            stereo: scene_camera.stereo,
+            distortion: scene_camera.distortion.map_or((0.0, 0.0), |distortion| (distortion.k1, distortion.k2)),
+            exposure: {
+                let exposure = scene_camera.exposure.map_or(1.0, |exposure| exposure.scale());
+                let gains = scene_camera.white_balance.map_or((1.0, 1.0, 1.0), |white_balance| white_balance.gains());
+                (gains.0 * exposure, gains.1 * exposure, gains.2 * exposure)
+            },
             transform: Transform::from(&scene_camera.transform),
         }
     }
 }
 
+impl SceneExposure {
+    /// Factor the light the camera sees is multiplied by
+    fn scale(&self) -> f64 {
+        match *self {
+            Self::Ev { ev } => 2f64.powf(ev),
+            Self::Camera { iso, shutter, f_stop, ev } => {
+                let ev100 = (f_stop * f_stop / shutter * 100.0 / iso).log2();
+                2f64.powf(ev - ev100) / 1.2
+            }
+        }
+    }
+
+    /// Returns a problem with the exposure's settings, if any
+    fn check(&self) -> Option<String> {
+        match *self {
+            Self::Ev { ev } | Self::Camera { ev, .. } if !ev.is_finite() => {
+                Some(format!("Camera exposure compensation {} must be a number of stops", ev))
+            }
+            Self::Camera { iso, shutter, f_stop, .. } if !(iso > 0.0 && shutter > 0.0 && f_stop > 0.0) => Some(format!(
+                "Camera ISO {}, shutter time {} and f-number {} must be positive",
+                iso, shutter, f_stop,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl SceneDistortion {
+    /// Returns a problem with the coefficients, if any, like a distortion folding the image back
+    /// on itself before its corners, where the camera rays can't be undistorted
+    fn check(&self, camera: &SceneCamera, output: &SceneOutput) -> Option<String> {
+        let (k1, k2) = (self.k1, self.k2);
+        if !(k1.is_finite() && k2.is_finite()) {
+            return Some(format!("Camera distortion coefficients {} and {} must be numbers", k1, k2));
+        }
+        let aspect = match camera.stereo {
+            None => output.width as f64 / output.height as f64,
+            Some(SceneStereo::SideBySide { .. }) => output.width as f64 / 2.0 / output.height as f64,
+            Some(SceneStereo::Ods { .. }) => return None,
+        };
+        // Distance of the image's corners to its center on the image plane at a distance of 1
+        let corner = (camera.fov.to_radians() / 2.0).tan() * (aspect * aspect + 1.0).sqrt() / camera.near;
+        // The distorted distance must grow with the undistorted one until it reaches the corners
+        let mut r: f64 = 0.0;
+        for _ in 0..1000 {
+            let r2 = r * r;
+            if r * (1.0 + k1 * r2 + k2 * r2 * r2) >= corner {
+                return None;
+            }
+            if 1.0 + 3.0 * k1 * r2 + 5.0 * k2 * r2 * r2 <= 0.0 {
+                break;
+            }
+            r += corner / 100.0;
+        }
+        Some(format!("Camera distortion coefficients {} and {} fold the image back before its corners", k1, k2))
+    }
+}
+
+impl SceneWhiteBalance {
+    /// Factors the channels are multiplied by, keeping the luminance of grays
+    fn gains(&self) -> (f64, f64, f64) {
+        let neutral = Spectrum::blackbody(default_white_balance_temperature()).to_rgb();
+        let white = Spectrum::blackbody(self.temperature).to_rgb();
+        let green = 2f64.powf(-self.tint);
+        let gains = (neutral.0 / white.0, neutral.1 / white.1 * green, neutral.2 / white.2);
+        let luminance = 0.2126 * gains.0 + 0.7152 * gains.1 + 0.0722 * gains.2;
+        (gains.0 / luminance, gains.1 / luminance, gains.2 / luminance)
+    }
+}
+
+impl SceneOutput {
+    pub fn image_file(&self) -> Result<Option<ImageFile>, String> {
+        let Some(path) = self.path.clone() else {
+            return Ok(None);
+        };
+        let file = ImageFile::new(path, self.format)?;
+        match &self.backplate {
+            Some(backplate) => Ok(Some(file.with_backplate(backplate.load()?))),
+            None => Ok(Some(file)),
+        }
+    }
+
+    /// Returns the backplate camera rays see, if visible while rendering
+    pub(super) fn visible_backplate(&self) -> Result<Option<Arc<Texture>>, String> {
+        self.backplate.as_ref()
+            .filter(|backplate| backplate.visible)
+            .map(SceneBackplate::load)
+            .transpose()
+    }
+
+    pub fn post_effects(&self) -> Result<Vec<Box<dyn PostEffect + Send + Sync>>, String> {
+        self.post.iter()
+            .map(|effect| post::new_post_effect(&effect.type_name, &effect.data))
+            .collect()
+    }
+}
+
 impl From<&SceneOutput> for Output {
     fn from(scene_output: &SceneOutput) -> Self {
-        Self::new(
+        let output = Self::new(
             scene_output.width,
             scene_output.height,
             scene_output.samples,
             scene_output.tile_size,
-        )
+            scene_output.tile_order,
+            scene_output.ray_epsilon,
+            DepthLimits {
+                total: scene_output.max_depth,
+                diffuse: scene_output.max_diffuse_depth.unwrap_or(scene_output.max_depth),
+                glossy: scene_output.max_glossy_depth.unwrap_or(scene_output.max_depth),
+                transmission: scene_output.max_transmission_depth.unwrap_or(scene_output.max_depth),
+            },
+        );
+
+        Self { depth_range: scene_output.depth_range, filter: scene_output.filter, ..output }
+    }
+}
+
+impl From<&Scene> for World {
+    fn from(scene: &Scene) -> Self {
+        let [r, g, b] = scene.world.color.map(|c| c * scene.world.strength);
+        Self {
+            background: (r, g, b),
+            transparent_background: scene.output.transparent_background,
+            backplate: None,
+            sky: None,
+            portals: scene.world.portals.iter().map(|transform| Portal::new(Transform::from(transform))).collect(),
+        }
     }
 }
 
-impl TryFrom<&SceneMaterial> for Material {
+impl SceneWorld {
+    /// Returns the sky of the sun, if any
+    pub(super) fn sky(&self) -> Result<Option<Sky>, String> {
+        let Some(sun) = &self.sun else {
+            return Ok(None);
+        };
+        let (azimuth, elevation) = sun.position.angles()?;
+        Ok(Some(Sky::new(azimuth, elevation, sun.intensity, sun.sky, sun.turbidity.max(0.0))))
+    }
+}
+
+impl SceneSunPosition {
+    /// Azimuth and elevation of the sun in degrees
+    fn angles(&self) -> Result<(f64, f64), String> {
+        match self {
+            SceneSunPosition::Angles { azimuth, elevation } => Ok((*azimuth, *elevation)),
+            SceneSunPosition::Location { latitude, longitude, date, time, utc_offset } => {
+                let invalid_date = || format!("Invalid sun date {}, expected YYYY-MM-DD", date);
+                let parts = date.split('-').map(str::parse::<u32>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid_date())?;
+                let &[year, month, day] = parts.as_slice() else {
+                    return Err(invalid_date());
+                };
+                let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+                let days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+                if !(1..=12).contains(&month) || !(1..=days[month as usize - 1]).contains(&day) {
+                    return Err(invalid_date());
+                }
+                let day_of_year = days[..month as usize - 1].iter().sum::<u32>() + day;
+
+                let invalid_time = || format!("Invalid sun time {}, expected HH:MM or HH:MM:SS", time);
+                let parts = time.split(':').map(str::parse::<f64>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid_time())?;
+                let hours = match *parts.as_slice() {
+                    [hours, minutes] => hours + minutes / 60.0,
+                    [hours, minutes, seconds] => hours + minutes / 60.0 + seconds / 3600.0,
+                    _ => return Err(invalid_time()),
+                };
+                if !(0.0..24.0).contains(&hours) {
+                    return Err(invalid_time());
+                }
+
+                let utc_offset = utc_offset.unwrap_or(longitude / 15.0);
+                Ok(sky::solar_angles(*latitude, *longitude, day_of_year, hours - utc_offset))
+            }
+        }
+    }
+}
+
+impl Scene {
+    /// Indices of the lights which don't illuminate the object, as linked by the lights and the object
+    pub(super) fn unlinked_lights(&self, object: &SceneObject) -> Vec<usize> {
+        self.lights.iter()
+            .enumerate()
+            .filter(|(_, light)| !light.objects.contains(object.name.as_ref()) || !object.lights.contains(light.name.as_ref()))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Builds the named materials, materials referenced by others are built first
+    ///
+    /// The materials are built in parallel so that their textures are decoded in parallel, in
+    /// rounds: each round builds the materials whose references were all built by the previous
+    /// ones, so that each material is built once and shared by the materials referencing it.
+    pub(super) fn build_materials(&self) -> Result<HashMap<String, Arc<Material>>, String> {
+        let mut pending = self.materials.keys().collect::<Vec<_>>();
+        pending.sort();
+        let mut materials = HashMap::new();
+        while !pending.is_empty() {
+            let built = utils::parallel_map(&pending, 1, |name| {
+                let mut waiting = false;
+                let material = self.materials[name.as_str()].build(&mut |reference| match materials.get(reference) {
+                    Some(material) => Ok(Arc::clone(material)),
+                    None if self.materials.contains_key(reference) => {
+                        waiting = true;
+                        Err(format!("Material {} isn't built yet", reference))
+                    }
+                    None => Err(format!("Material {} not found", reference)),
+                });
+                match material {
+                    Ok(material) => Ok(Some(Arc::new(material.with_name(Some(name.to_string()))))),
+                    Err(_) if waiting => Ok(None),
+                    Err(err) => Err(err),
+                }
+            });
+
+            let count = materials.len();
+            let mut waiting = Vec::new();
+            for (name, material) in pending.into_iter().zip(built) {
+                match material? {
+                    Some(material) => {
+                        materials.insert(name.clone(), material);
+                    }
+                    None => waiting.push(name),
+                }
+            }
+            // The materials left reference each other in a cycle, building one reports it
+            if !waiting.is_empty() && materials.len() == count {
+                return Err(self.build_material(waiting[0], &mut HashMap::new(), &mut Vec::new())
+                    .err()
+                    .unwrap_or_else(|| format!("Material {} references itself", waiting[0])));
+            }
+            pending = waiting;
+        }
+        Ok(materials)
+    }
+
+    fn build_material(
+        &self,
+        name: &str,
+        materials: &mut HashMap<String, Arc<Material>>,
+        stack: &mut Vec<String>,
+    ) -> Result<Arc<Material>, String> {
+        if let Some(material) = materials.get(name) {
+            return Ok(material.clone());
+        }
+        if stack.iter().any(|parent| parent == name) {
+            return Err(format!("Material {} references itself", name));
+        }
+        let scene_material = self.materials.get(name)
+            .ok_or_else(|| format!("Material {} not found", name))?;
+
+        stack.push(name.to_string());
+        let material = scene_material.build(&mut |name| self.build_material(name, materials, stack))?;
+        let material = Arc::new(material.with_name(Some(name.to_string())));
+        stack.pop();
+        materials.insert(name.to_string(), material.clone());
+
+        Ok(material)
+    }
+}
+
+impl Scene {
+    /// Validates the scene without rendering it, returning every problem found instead of
+    /// stopping at the first one like building it does
+    fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for path in &self.plugins {
+            if let Err(err) = plugins::load(path) {
+                problems.push(err);
+            }
+        }
+
+        let (camera, output) = (&self.camera, &self.output);
+        if !(camera.fov > 0.0 && camera.fov < 180.0) {
+            problems.push(format!("Camera field of view {} must be between 0 and 180 degrees", camera.fov));
+        }
+        if let Some(problem) = camera.transform.check() {
+            problems.push(format!("Camera {}", problem));
+        }
+        if let Some(problem) = camera.exposure.as_ref().and_then(SceneExposure::check) {
+            problems.push(problem);
+        }
+        if let Some(problem) = camera.distortion.as_ref().and_then(|distortion| distortion.check(camera, output)) {
+            problems.push(problem);
+        }
+        if let Some(white_balance) = &camera.white_balance
+            && !(white_balance.temperature > 0.0 && white_balance.tint.is_finite())
+        {
+            problems.push(format!(
+                "Camera white balance temperature {} must be positive and its tint {} a number",
+                white_balance.temperature,
+                white_balance.tint,
+            ));
+        }
+        if camera.clip_near < 0.0 || camera.clip_far.is_some_and(|clip_far| clip_far <= camera.clip_near) {
+            problems.push(format!(
+                "Camera clipping distances {} to {} must be positive and increasing",
+                camera.clip_near,
+                camera.clip_far.unwrap_or(f64::INFINITY),
+            ));
+        }
+        if output.width == 0 || output.height == 0 {
+            problems.push(format!("Output resolution {}x{} is empty", output.width, output.height));
+        }
+        if output.samples == 0 || output.tile_size == Some(0) {
+            problems.push("Output samples and tile size must be at least 1".to_string());
+        }
+        if let Err(err) = output.image_file() {
+            problems.push(err);
+        }
+        if camera.stereo.is_some() && output.backplate.as_ref().is_some_and(|backplate| backplate.visible) {
+            problems.push("Visible backplates aren't supported by stereo cameras, only composited".to_string());
+        }
+        for effect in &output.post {
+            if let Err(err) = post::new_post_effect(&effect.type_name, &effect.data) {
+                problems.push(err);
+            }
+        }
+
+        // Materials are built one by one, those which fail are left out of the map
+        let mut materials = HashMap::new();
+        let mut names: Vec<&String> = self.materials.keys().collect();
+        names.sort();
+        for name in names {
+            if let Err(err) = self.build_material(name, &mut materials, &mut Vec::new()) {
+                problems.push(format!("Material {}: {}", name, err));
+            }
+        }
+
+        let mut objects: Vec<(String, &SceneObject)> = self.objects.iter()
+            .enumerate()
+            .map(|(i, object)| (object.label(i), object))
+            .collect();
+        let mut generated = Vec::new();
+        for generator in &self.generators {
+            match generator.generate() {
+                Ok(generator_objects) => generated.extend(generator_objects),
+                Err(err) => problems.push(err),
+            }
+        }
+        objects.extend(generated.iter().enumerate().map(|(i, object)| (format!("generated {}", object.label(i)), object)));
+
+        for (i, (label, object)) in objects.into_iter().enumerate() {
+            if let Some(problem) = object.transform.check() {
+                problems.push(format!("Object {} {}", label, problem));
+            }
+            if let Some(problem) = object.uv_projection.as_ref().and_then(|projection| projection.transform.check()) {
+                problems.push(format!("Object {} UV projection {}", label, problem));
+            }
+            for light in object.lights.names() {
+                if !self.lights.iter().any(|scene_light| scene_light.name.as_ref() == Some(light)) {
+                    problems.push(format!("Object {} is linked to unknown light {}", label, light));
+                }
+            }
+            let references = object.material.references();
+            for name in &references {
+                if !self.materials.contains_key(*name) {
+                    problems.push(format!("Object {} references unknown material {}", label, name));
+                }
+            }
+            // Materials which failed to build were already reported
+            if references.iter().any(|name| !materials.contains_key(*name)) {
+                continue;
+            }
+            match Object::try_from(i, object, &materials, &output.bvh) {
+                Ok(built) if built.is_empty() => problems.push(format!("Object {} has no surface", label)),
+                Ok(_) => {}
+                Err(err) => problems.push(format!("Object {}: {}", label, err)),
+            }
+        }
+
+        let mut layers: Vec<(&String, &SceneLayer)> = self.layers.iter().collect();
+        layers.sort_by_key(|(name, _)| *name);
+        for (name, layer) in layers {
+            for object in layer.include.iter().chain(&layer.exclude) {
+                if !self.objects.iter().chain(&generated).any(|scene_object| scene_object.name.as_ref() == Some(object)) {
+                    problems.push(format!("Render layer {} references unknown object {}", name, object));
+                }
+            }
+            if let Some(material) = &layer.material
+                && !self.materials.contains_key(material)
+            {
+                problems.push(format!("Render layer {} references unknown material {}", name, material));
+            }
+        }
+
+        if let Err(err) = self.world.sky() {
+            problems.push(err);
+        }
+
+        for (i, light) in self.lights.iter().enumerate() {
+            match &light.spectrum {
+                Some(spectrum) if spectrum.is_empty() => problems.push(format!("Light {} has an empty spectrum", i + 1)),
+                Some(spectrum) if spectrum.iter().flatten().any(|x| !x.is_finite() || *x < 0.0) => {
+                    problems.push(format!("Light {} has negative or invalid spectrum values", i + 1));
+                }
+                _ => {}
+            }
+            match light.temperature {
+                Some(temperature) if !temperature.is_finite() || temperature <= 0.0 => {
+                    problems.push(format!("Light {} has an invalid temperature {}", i + 1, temperature));
+                }
+                Some(_) if light.spectrum.is_some() => problems.push(format!("Light {} has both a spectrum and a temperature", i + 1)),
+                _ => {}
+            }
+            for object in light.objects.names() {
+                if !self.objects.iter().chain(&generated).any(|scene_object| scene_object.name.as_ref() == Some(object)) {
+                    problems.push(format!("Light {} is linked to unknown object {}", i + 1, object));
+                }
+            }
+            let direction = match light.kind {
+                SceneLightKind::Spot { direction, .. } | SceneLightKind::Directional { direction } => direction,
+                SceneLightKind::Point { .. } => continue,
+            };
+            if direction == [0.0; 3] {
+                problems.push(format!("Light {} has no direction", i + 1));
+            }
+        }
+
+        problems
+    }
+}
+
+impl Scene {
+    /// Replaces the instances by copies of their prefab's objects and lights, and adds the
+    /// prefabs' materials to the scene's
+    fn expand_prefabs(&mut self) -> Result<(), String> {
+        let prefabs = std::mem::take(&mut self.prefabs);
+        let mut names: Vec<&String> = prefabs.keys().collect();
+        names.sort();
+        for name in names {
+            for (material_name, material) in &prefabs[name].materials {
+                if self.materials.contains_key(material_name) {
+                    return Err(format!("Prefab {} material {} is already defined", name, material_name));
+                }
+                self.materials.insert(material_name.clone(), material.clone());
+            }
+        }
+
+        for instance in std::mem::take(&mut self.instances) {
+            let (objects, lights) = instance.expand(&prefabs, &mut Vec::new())?;
+            self.objects.extend(objects);
+            self.lights.extend(lights);
+        }
+        Ok(())
+    }
+}
+
+impl SceneInstance {
+    /// Returns copies of the prefab's objects and lights, including the ones of its instances,
+    /// placed by the instance's transform
+    fn expand(&self, prefabs: &HashMap<String, ScenePrefab>, stack: &mut Vec<String>) -> Result<(Vec<SceneObject>, Vec<SceneLight>), String> {
+        if stack.contains(&self.prefab) {
+            return Err(format!("Prefab {} contains itself", self.prefab));
+        }
+        let prefab = prefabs.get(&self.prefab).ok_or_else(|| format!("Prefab {} not found", self.prefab))?;
+        let mut objects = prefab.objects.clone();
+        let mut lights = prefab.lights.clone();
+        stack.push(self.prefab.clone());
+        for instance in &prefab.instances {
+            let (instance_objects, instance_lights) = instance.expand(prefabs, stack)?;
+            objects.extend(instance_objects);
+            lights.extend(instance_lights);
+        }
+        stack.pop();
+
+        let transform = Transform::from(&self.transform);
+        for object in &mut objects {
+            object.transform = SceneTransform::from(&transform.compose(&Transform::from(&object.transform)));
+        }
+        for light in &mut lights {
+            light.kind.transform(&transform);
+        }
+
+        if let Some(name) = &self.name {
+            // Only the links to the prefab's own objects and lights are renamed
+            let object_names: Vec<String> = objects.iter().filter_map(|object| object.name.clone()).collect();
+            let light_names: Vec<String> = lights.iter().filter_map(|light| light.name.clone()).collect();
+            for object in &mut objects {
+                object.name = object.name.take().map(|object_name| format!("{}.{}", name, object_name));
+                object.lights.prefix(&light_names, name);
+            }
+            for light in &mut lights {
+                light.name = light.name.take().map(|light_name| format!("{}.{}", name, light_name));
+                light.objects.prefix(&object_names, name);
+            }
+        }
+        Ok((objects, lights))
+    }
+}
+
+impl SceneLightKind {
+    /// Moves the light's position and direction by a transform
+    fn transform(&mut self, transform: &Transform) {
+        let point = |[x, y, z]: [f64; 3]| {
+            let (x, y, z) = transform.apply((x, y, z));
+            [x, y, z]
+        };
+        let vector = |[x, y, z]: [f64; 3]| {
+            let (x, y, z) = transform.apply_notranslate((x, y, z));
+            [x, y, z]
+        };
+        match self {
+            SceneLightKind::Point { position } => *position = point(*position),
+            SceneLightKind::Spot { position, direction, .. } => {
+                *position = point(*position);
+                *direction = vector(*direction);
+            }
+            SceneLightKind::Directional { direction } => *direction = vector(*direction),
+        }
+    }
+}
+
+/// Deserializes the named materials, merging the parameters of the materials they extend
+fn deserialize_materials<'de, D>(deserializer: D) -> Result<HashMap<String, SceneMaterial>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let definitions = HashMap::<String, Map<String, Value>>::deserialize(deserializer)?;
+
+    definitions.keys()
+        .map(|name| {
+            let params = resolve_extends(name, &definitions, &mut Vec::new()).map_err(D::Error::custom)?;
+            let material = serde_json::from_value(Value::Object(params))
+                .map_err(|err| D::Error::custom(format!("Invalid material {}: {}", name, err)))?;
+            Ok((name.clone(), material))
+        })
+        .collect()
+}
+
+/// Returns the material's parameters, on top of the ones of the materials it extends
+fn resolve_extends(
+    name: &str,
+    definitions: &HashMap<String, Map<String, Value>>,
+    stack: &mut Vec<String>,
+) -> Result<Map<String, Value>, String> {
+    if stack.iter().any(|child| child == name) {
+        return Err(format!("Material {} extends itself", name));
+    }
+    let mut params = definitions.get(name)
+        .ok_or_else(|| format!("Material {} not found", name))?
+        .clone();
+
+    match params.remove("extends") {
+        Some(Value::String(base)) => {
+            stack.push(name.to_string());
+            let mut merged = resolve_extends(&base, definitions, stack)?;
+            stack.pop();
+            merged.extend(params);
+            Ok(merged)
+        }
+        Some(_) => Err(format!("Material {} must extend a material name", name)),
+        None => Ok(params),
+    }
+}
+
+impl SceneMaterial {
+    /// Creates the material, `resolve` provides the named materials it references
+    fn build(&self, resolve: &mut ResolveMaterialFn) -> Result<Material, String> {
+        let mut material = match self.type_name.as_str() {
+            "mix" => Material::from_type(Box::new(MixMaterial::new(&self.data, resolve)?)),
+            "layer" => Material::from_type(Box::new(LayerMaterial::new(&self.data, resolve)?)),
+            _ => Material::new(&self.type_name, &self.data)?,
+        }.with_double_sided(self.double_sided);
+        if let Some(uv_transform) = &self.uv_transform {
+            material = material.with_uv_transform(UvTransform::from(uv_transform));
+        }
+        if let Some(alpha_texture) = &self.alpha_texture {
+            material = material.with_alpha_cutout(alpha_texture.load()?, self.alpha_threshold);
+        }
+        if let Some(bump_texture) = &self.bump_texture {
+            material = material.with_bump(bump_texture.load()?, self.bump_strength);
+        }
+
+        Ok(material)
+    }
+}
+
+impl SceneBackplate {
+    fn load(&self) -> Result<Arc<Texture>, String> {
+        Texture::load(&self.path, TextureFilter::Bilinear, ColorSpace::Linear)
+    }
+}
+
+impl SceneTexture {
+    pub fn load(&self) -> Result<Arc<Texture>, String> {
+        match self {
+            SceneTexture::Path(path) => Texture::load(path, TextureFilter::default(), ColorSpace::default()),
+            SceneTexture::Options { path, filter, color_space } => Texture::load(path, *filter, *color_space),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            SceneTexture::Path(path) | SceneTexture::Options { path, .. } => path,
+        }
+    }
+}
+
+impl TryFrom<&Scene> for LightSampler {
+    type Error = String;
+
+    fn try_from(scene: &Scene) -> Result<Self, String> {
+        let mut groups: Vec<String> = Vec::new();
+        let lights = scene.lights.iter()
+            .map(|scene_light| {
+                let group = scene_light.group.as_ref().map(|name| {
+                    groups.iter().position(|group| group == name).unwrap_or_else(|| {
+                        groups.push(name.clone());
+                        groups.len() - 1
+                    })
+                });
+                Ok(Light::try_from(scene_light)?.with_group(group))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self::new(lights, scene.output.light_samples as usize, groups))
+    }
+}
+
+impl TryFrom<&SceneLight> for Light {
     type Error = String;
 
-    fn try_from(scene_material: &SceneMaterial) -> Result<Self, Self::Error> {
-        Self::new(
-            &scene_material.type_name,
-            &scene_material.data,
+    fn try_from(scene_light: &SceneLight) -> Result<Self, String> {
+        let kind = match scene_light.kind {
+            SceneLightKind::Point { position: [x, y, z] } => LightKind::Point { position: (x, y, z) },
+            SceneLightKind::Spot { position: [x, y, z], direction: [dx, dy, dz], angle, blend, ref gobo } => {
+                let half_angle = (angle / 2.0).clamp(0.0, 180.0).to_radians();
+                let direction = vec3norm((dx, dy, dz));
+                LightKind::Spot {
+                    position: (x, y, z),
+                    direction,
+                    cos_outer: half_angle.cos(),
+                    cos_inner: (half_angle * (1.0 - blend.clamp(0.0, 1.0))).cos(),
+                    gobo: gobo.as_ref()
+                        .map(SceneTexture::load)
+                        .transpose()?
+                        .map(|texture| Gobo::new(texture, direction, half_angle)),
+                }
+            }
+            SceneLightKind::Directional { direction: [x, y, z] } => LightKind::Directional { direction: (x, y, z) },
+        };
+        let spectrum = match (&scene_light.spectrum, scene_light.temperature) {
+            (Some(points), _) => Some(Spectrum::new(points)),
+            (None, Some(temperature)) => Some(Spectrum::blackbody(temperature)),
+            (None, None) => None,
+        }
+        .map(|spectrum| spectrum.scaled(scene_light.intensity));
+        let [r, g, b] = scene_light.color.map(|c| c * scene_light.intensity);
+
+        Ok(Self::new(
+            kind,
+            spectrum.as_ref().map_or((r, g, b), Spectrum::to_rgb),
+            scene_light.falloff,
+            scene_light.radius,
+            scene_light.max_distance.unwrap_or(f64::INFINITY),
         )
+        .with_spectrum(spectrum))
+    }
+}
+
+impl From<&SceneUvTransform> for UvTransform {
+    fn from(scene_uv_transform: &SceneUvTransform) -> Self {
+        let [su, sv] = scene_uv_transform.scale;
+        let [ou, ov] = scene_uv_transform.offset;
+        Self::new((su, sv), (ou, ov), scene_uv_transform.rotation)
     }
 }
 
 impl Object {
-    pub fn try_from(scene_object: &SceneObject, materials: &HashMap<String, Arc<Material>>) -> Result<Self, String> {
-        let material = match &scene_object.material {
+    /// Creates the object at `index` in the scene's objects
+    pub fn try_from(index: usize, scene_object: &SceneObject, materials: &HashMap<String, Arc<Material>>, bvh: &BvhOptions) -> Result<Self, String> {
+        let object = Self::new(
+            &scene_object.type_name,
+            &scene_object.data,
+            bvh,
+            Transform::from(&scene_object.transform),
+            scene_object.material.build(materials)?,
+        )?;
+
+        let uv_projection = scene_object.uv_projection.as_ref()
+            .map(|scene_projection| UvProjection::new(scene_projection.projection, Transform::from(&scene_projection.transform)));
+        Ok(object
+            .with_id(scene_object.id.unwrap_or(index as u32 + 1))
+            .with_name(scene_object.name.clone())
+            .with_uv_projection(uv_projection)
+            .with_invisible_to(&scene_object.invisible_to))
+    }
+}
+
+impl SceneObject {
+    /// Returns the object's name, or its type and position in the scene
+    fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{} #{}", self.type_name, index + 1),
+        }
+    }
+}
+
+impl SceneLinks {
+    /// Whether the named light or object is linked, unnamed ones being linked unless some names
+    /// are included
+    fn contains(&self, name: Option<&String>) -> bool {
+        let listed = |names: &[String]| name.is_some_and(|name| names.contains(name));
+        (self.include.is_empty() || listed(&self.include)) && !listed(&self.exclude)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        self.include.iter().chain(&self.exclude)
+    }
+
+    /// Prefixes the linked names which are in `names`, as `prefix.name`
+    fn prefix(&mut self, names: &[String], prefix: &str) {
+        for name in self.include.iter_mut().chain(&mut self.exclude) {
+            if names.contains(name) {
+                *name = format!("{}.{}", prefix, name);
+            }
+        }
+    }
+}
+
+impl SceneLayer {
+    fn contains(&self, object: &SceneObject) -> bool {
+        let listed = |names: &[String]| object.name.as_ref().is_some_and(|name| names.contains(name));
+        (self.include.is_empty() || listed(&self.include)) && !listed(&self.exclude)
+    }
+}
+
+impl SceneObjectMaterial {
+    /// Returns the names of the materials referenced by the object
+    fn references(&self) -> Vec<&str> {
+        match self {
+            SceneObjectMaterial::MaterialRef(name) => vec![name.as_str()],
+            SceneObjectMaterial::Materials(list) => list.iter().flat_map(SceneObjectMaterial::references).collect(),
+            SceneObjectMaterial::None | SceneObjectMaterial::Material(_) => Vec::new(),
+        }
+    }
+
+    /// Builds the object's materials, there is always at least one
+    fn build(&self, materials: &HashMap<String, Arc<Material>>) -> Result<Vec<Arc<Material>>, String> {
+        let material = match self {
             SceneObjectMaterial::None => Ok(Material::fallback()),
             SceneObjectMaterial::MaterialRef(name) => match materials.get(name) {
                 Some(material) => Ok(material.clone()),
                 None => Err(format!("Material {} not found", name)),
             },
-            SceneObjectMaterial::Material(scene_material) => Material::try_from(scene_material).map(|m| Arc::new(m)),
+            SceneObjectMaterial::Material(scene_material) => scene_material
+                .build(&mut |name| materials.get(name).cloned().ok_or_else(|| format!("Material {} not found", name)))
+                .map(Arc::new),
+            SceneObjectMaterial::Materials(list) if list.is_empty() => Ok(Material::fallback()),
+            SceneObjectMaterial::Materials(list) => return list.iter()
+                .map(|material| match material {
+                    SceneObjectMaterial::Materials(_) => Err("Material lists can't be nested".to_string()),
+                    material => Ok(material.build(materials)?.remove(0)),
+                })
+                .collect(),
         }?;
 
-        Self::new(
-            &scene_object.type_name,
-            &scene_object.data,
-            Transform::from(&scene_object.transform),
+        Ok(vec![material])
+    }
+}
+
+impl SceneMaterial {
+    pub fn new(type_name: &str, data: Value) -> Self {
+        Self {
+            type_name: type_name.to_string(),
+            double_sided: default_material_double_sided(),
+            uv_transform: None,
+            alpha_texture: None,
+            alpha_threshold: default_material_alpha_threshold(),
+            bump_texture: None,
+            bump_strength: default_material_bump_strength(),
+            data,
+        }
+    }
+}
+
+impl SceneGenerator {
+    /// Runs the generator script, producing objects to add to the scene
+    pub fn generate(&self) -> Result<Vec<SceneObject>, String> {
+        scripting::generate_objects(&self.script)?
+            .into_iter()
+            .map(|value| serde_json::from_value(value)
+                .map_err(|err| format!("Generator script returned an invalid object: {}", err)))
+            .collect()
+    }
+}
+
+impl SceneTransform {
+    /// Returns why the transform can't be used, if it can't be inverted or isn't finite
+    fn check(&self) -> Option<String> {
+        let values = self.translate.iter().chain(&self.rotate).chain(&self.shear).chain(&self.scale);
+        if values.into_iter().any(|value| !value.is_finite()) {
+            Some("transform has values which aren't finite".to_string())
+        } else if self.scale.contains(&0.0) {
+            Some(format!("transform has a zero scale {:?}, it can't be inverted", self.scale))
+        } else {
+            None
+        }
+    }
+
+    /// Wraps the rotation angles to [-180, 180) degrees
+    fn normalize(&mut self) {
+        for angle in &mut self.rotate {
+            *angle = (*angle + 180.0).rem_euclid(360.0) - 180.0;
+        }
+    }
+
+    pub const fn translate(mut self, x: f64, y: f64, z: f64) -> Self {
+        self.translate = [x, y, z];
+        self
+    }
+
+    pub const fn rotate(mut self, x: f64, y: f64, z: f64) -> Self {
+        self.rotate = [x, y, z];
+        self
+    }
+
+    pub const fn scale(mut self, x: f64, y: f64, z: f64) -> Self {
+        self.scale = [x, y, z];
+        self
+    }
+
+    /// Shears X along Y and Z, and Y along Z
+    pub const fn shear(mut self, xy: f64, xz: f64, yz: f64) -> Self {
+        self.shear = [xy, xz, yz];
+        self
+    }
+
+    /// Rotates the transform by `angle` degrees around the vertical axis through `center`
+    pub fn orbit(mut self, angle: f64, center: [f64; 3]) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let rotate_z = |(x, y, z): (f64, f64, f64)| (x * cos - y * sin, x * sin + y * cos, z);
+
+        let (x, y, _) = rotate_z((self.translate[0] - center[0], self.translate[1] - center[1], 0.0));
+        self.translate = [center[0] + x, center[1] + y, self.translate[2]];
+
+        // Columns of the rotation matrix Rx·Ry·Rz once rotated, converted back to euler angles
+        let rotation = Transform::new().rotate(self.rotate[0], self.rotate[1], self.rotate[2]);
+        let [c0, c1, c2] = [(1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 0.0, 1.0)]
+            .map(|axis| rotate_z(rotation.apply_notranslate(axis)));
+        self.rotate = [
+            (-c2.1).atan2(c2.2).to_degrees(),
+            c2.0.clamp(-1.0, 1.0).asin().to_degrees(),
+            (-c1.0).atan2(c0.0).to_degrees(),
+        ];
+        self
+    }
+}
+
+impl Default for SceneTransform {
+    fn default() -> Self {
+        Self {
+            translate: [0.0, 0.0, 0.0],
+            rotate: [0.0, 0.0, 0.0],
+            shear: [0.0, 0.0, 0.0],
+            scale: default_transform_scale(),
+        }
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> Self {
+        Self {
+            scene: Scene {
+                plugins: Vec::new(),
+                camera: SceneCamera {
+                    fov: default_camera_fov(),
+                    near: default_camera_near(),
+                    clip_near: 0.0,
+                    clip_far: None,
+                    stereo: None,
+                    exposure: None,
+                    white_balance: None,
+                    distortion: None,
+                    transform: SceneTransform::default(),
+                },
+                output: SceneOutput {
+                    width: 1280,
+                    height: 720,
+                    samples: default_output_samples(),
+                    tile_size: None,
+                    tile_order: TileOrder::default(),
+                    filter: Filter::default(),
+                    ray_epsilon: default_output_ray_epsilon(),
+                    max_depth: default_output_max_depth(),
+                    max_diffuse_depth: None,
+                    max_glossy_depth: None,
+                    max_transmission_depth: None,
+                    light_samples: default_output_light_samples(),
+                    transparent_background: false,
+                    bvh: BvhOptions::default(),
+                    path: None,
+                    format: None,
+                    backplate: None,
+                    seed: 0,
+                    depth_range: None,
+                    shading: None,
+                    bounds_overlay: None,
+                    spectral: false,
+                    post: Vec::new(),
+                    time_limit: None,
+                    noise_threshold: None,
+                    max_samples: default_output_max_samples(),
+                },
+                world: SceneWorld::default(),
+                materials: HashMap::new(),
+                objects: Vec::new(),
+                lights: Vec::new(),
+                generators: Vec::new(),
+                prefabs: HashMap::new(),
+                instances: Vec::new(),
+                layers: HashMap::new(),
+            },
+        }
+    }
+
+    /// Returns the scene as the renderer sees it, as JSON with the defaults filled in, the
+    /// materials' `extends` and the generators resolved, rotations normalized to [-180, 180)
+    /// degrees and the keys sorted so that exports can be diffed
+    pub fn export(mut self) -> Result<String, String> {
+        let scene = &mut self.scene;
+        for generator in std::mem::take(&mut scene.generators) {
+            scene.objects.extend(generator.generate()?);
+        }
+        scene.camera.transform.normalize();
+        for object in &mut scene.objects {
+            object.transform.normalize();
+        }
+        // Converted to a value first for its sorted maps
+        let value = serde_json::to_value(&self.scene).map_err(|err| format!("Failed to export scene: {}", err))?;
+        Ok(serde_json::to_string_pretty(&value).unwrap())
+    }
+
+    /// Returns the JSON Schema of scene files, for editors to autocomplete and validate them. The
+    /// parameters specific to each type of object, material and post effect aren't described.
+    pub fn schema() -> String {
+        serde_json::to_string_pretty(&schemars::schema_for!(Scene)).unwrap()
+    }
+
+    /// Starts from a scene parsed from JSON, to override parts of it
+    pub fn from_reader<R>(reader: R) -> Result<Self, String>
+    where
+        R: std::io::Read
+    {
+        let start = Instant::now();
+        let mut scene: Scene = serde_json::from_reader(reader)
+            .map_err(|err| format!("Failed to parse scene: {}", err))?;
+        scene.expand_prefabs()?;
+        log::debug!("Scene parsed in {:.3}s", start.elapsed().as_secs_f64());
+
+        Ok(Self { scene })
+    }
+
+    pub fn output(mut self, width: u32, height: u32) -> Self {
+        self.scene.output.width = width;
+        self.scene.output.height = height;
+        self
+    }
+
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.scene.output.samples = samples;
+        self
+    }
+
+    pub fn tile_size(mut self, tile_size: u32) -> Self {
+        self.scene.output.tile_size = Some(tile_size);
+        self
+    }
+
+    pub fn tile_order(mut self, tile_order: TileOrder) -> Self {
+        self.scene.output.tile_order = tile_order;
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.scene.output.filter = filter;
+        self
+    }
+
+    /// Renders progressive passes until the noise is below the threshold, or until the maximum
+    /// number of samples per pixel
+    pub fn noise_threshold(mut self, threshold: f64, max_samples: u32) -> Self {
+        self.scene.output.noise_threshold = Some(threshold);
+        self.scene.output.max_samples = max_samples;
+        self
+    }
+
+    /// Sets the number of seconds after which no more tiles are started
+    pub fn time_limit(mut self, seconds: f64) -> Self {
+        self.scene.output.time_limit = Some(seconds);
+        self
+    }
+
+    /// Replaces the materials with a debug shading
+    pub fn shading(mut self, shading: DebugShading) -> Self {
+        self.scene.output.shading = Some(shading);
+        self
+    }
+
+    /// Draws the bounding boxes of the objects, or of their BVH nodes too, over the render
+    pub fn bounds_overlay(mut self, overlay: BoundsOverlay) -> Self {
+        self.scene.output.bounds_overlay = Some(overlay);
+        self
+    }
+
+    /// Sets the image file the render is saved to, its format is guessed from the extension
+    pub fn output_path(mut self, path: &str) -> Self {
+        self.scene.output.path = Some(path.to_string());
+        self.scene.output.format = None;
+        self
+    }
+
+    /// Renders with sampled wavelengths, for dispersion
+    pub fn spectral(mut self, spectral: bool) -> Self {
+        self.scene.output.spectral = spectral;
+        self
+    }
+
+    pub fn transparent_background(mut self, transparent_background: bool) -> Self {
+        self.scene.output.transparent_background = transparent_background;
+        self
+    }
+
+    /// Sets the background color, multiplied by `strength`
+    pub fn background(mut self, color: [f64; 3], strength: f64) -> Self {
+        self.scene.world.color = color;
+        self.scene.world.strength = strength;
+        self
+    }
+
+    /// Lights the scene with a sun at `azimuth` and `elevation` degrees and a matching sky, the
+    /// azimuth going clockwise from north (+y) towards east (+x)
+    pub fn sun(mut self, azimuth: f64, elevation: f64, intensity: f64) -> Self {
+        self.scene.world.sun = Some(SceneSun {
+            position: SceneSunPosition::Angles { azimuth, elevation },
+            intensity,
+            sky: default_sun_sky(),
+            turbidity: default_sun_turbidity(),
+        });
+        self
+    }
+
+    pub fn camera(mut self, fov: f64, transform: SceneTransform) -> Self {
+        self.scene.camera.fov = fov;
+        self.scene.camera.transform = transform;
+        self
+    }
+
+    pub fn stereo(mut self, stereo: Option<SceneStereo>) -> Self {
+        self.scene.camera.stereo = stereo;
+        self
+    }
+
+    /// Sets the photographic exposure of the camera, see [`SceneExposure`]
+    pub fn exposure(mut self, exposure: Option<SceneExposure>) -> Self {
+        self.scene.camera.exposure = exposure;
+        self
+    }
+
+    /// Sets the white balance of the camera, see [`SceneWhiteBalance`]
+    pub fn white_balance(mut self, white_balance: Option<SceneWhiteBalance>) -> Self {
+        self.scene.camera.white_balance = white_balance;
+        self
+    }
+
+    /// Sets the radial distortion of the camera's lens, see [`SceneDistortion`]
+    pub fn distortion(mut self, distortion: Option<SceneDistortion>) -> Self {
+        self.scene.camera.distortion = distortion;
+        self
+    }
+
+    /// Sets the distances along the view axis between which camera rays see objects
+    pub fn camera_clip(mut self, near: f64, far: Option<f64>) -> Self {
+        self.scene.camera.clip_near = near;
+        self.scene.camera.clip_far = far;
+        self
+    }
+
+    /// Rotates the camera by `angle` degrees around the vertical axis through `target`
+    pub fn orbit_camera(mut self, angle: f64, target: [f64; 3]) -> Self {
+        let transform = std::mem::take(&mut self.scene.camera.transform);
+        self.scene.camera.transform = transform.orbit(angle, target);
+        self
+    }
+
+    /// Adds a named material which objects can refer to with [`SceneObjectMaterial::MaterialRef`]
+    pub fn material(mut self, name: &str, type_name: &str, data: Value) -> Self {
+        self.scene.materials.insert(name.to_string(), SceneMaterial::new(type_name, data));
+        self
+    }
+
+    /// Adds a post effect, applied after the ones already added
+    pub fn add_post_effect(mut self, type_name: &str, data: Value) -> Self {
+        self.scene.output.post.push(ScenePostEffect { type_name: type_name.to_string(), data });
+        self
+    }
+
+    pub fn add_object(mut self, type_name: &str, transform: SceneTransform, material: SceneObjectMaterial, data: Value) -> Self {
+        self.scene.objects.push(SceneObject {
+            type_name: type_name.to_string(),
+            transform,
             material,
-        )
+            id: None,
+            name: None,
+            uv_projection: None,
+            invisible_to: Vec::new(),
+            lights: SceneLinks::default(),
+            data,
+        });
+        self
+    }
+
+    /// Adds a point light with inverse-square falloff
+    pub fn add_point_light(mut self, position: [f64; 3], color: [f64; 3], intensity: f64) -> Self {
+        self.scene.lights.push(SceneLight {
+            kind: SceneLightKind::Point { position },
+            color,
+            spectrum: None,
+            temperature: None,
+            intensity,
+            falloff: default_light_falloff(),
+            radius: 0.0,
+            max_distance: None,
+            group: None,
+            name: None,
+            objects: SceneLinks::default(),
+        });
+        self
+    }
+
+    /// Adds a spot light, `angle` being the full angle of its cone in degrees
+    pub fn add_spot_light(mut self, position: [f64; 3], direction: [f64; 3], color: [f64; 3], intensity: f64, angle: f64) -> Self {
+        self.scene.lights.push(SceneLight {
+            kind: SceneLightKind::Spot { position, direction, angle, blend: default_spot_blend(), gobo: None },
+            color,
+            spectrum: None,
+            temperature: None,
+            intensity,
+            falloff: default_light_falloff(),
+            radius: 0.0,
+            max_distance: None,
+            group: None,
+            name: None,
+            objects: SceneLinks::default(),
+        });
+        self
+    }
+
+    /// Adds a light infinitely far away, like the sun
+    pub fn add_directional_light(mut self, direction: [f64; 3], color: [f64; 3], intensity: f64) -> Self {
+        self.scene.lights.push(SceneLight {
+            kind: SceneLightKind::Directional { direction },
+            color,
+            spectrum: None,
+            temperature: None,
+            intensity,
+            falloff: default_light_falloff(),
+            radius: 0.0,
+            max_distance: None,
+            group: None,
+            name: None,
+            objects: SceneLinks::default(),
+        });
+        self
+    }
+
+    pub fn add_cone(self, transform: SceneTransform, material: SceneObjectMaterial) -> Self {
+        self.add_object("cone", transform, material, Value::Object(Map::new()))
+    }
+
+    pub fn add_cube(self, transform: SceneTransform, material: SceneObjectMaterial) -> Self {
+        self.add_object("cube", transform, material, Value::Object(Map::new()))
+    }
+
+    pub fn add_cylinder(self, transform: SceneTransform, material: SceneObjectMaterial) -> Self {
+        self.add_object("cylinder", transform, material, Value::Object(Map::new()))
+    }
+
+    pub fn add_plane(self, transform: SceneTransform, material: SceneObjectMaterial) -> Self {
+        self.add_object("plane", transform, material, Value::Object(Map::new()))
+    }
+
+    pub fn add_sphere(self, transform: SceneTransform, material: SceneObjectMaterial) -> Self {
+        self.add_object("sphere", transform, material, Value::Object(Map::new()))
+    }
+
+    pub fn build(self) -> Result<Arc<Raytracer>, String> {
+        Raytracer::from_scene(self.scene)
+    }
+
+    /// Keeps only the objects of a render layer, with the layer's material if it has one
+    ///
+    /// The generators are run first so that their objects can be in layers, and the objects keep
+    /// the IDs they have in the full scene.
+    pub fn layer(mut self, name: &str) -> Result<Self, String> {
+        let scene = &mut self.scene;
+        let layer = scene.layers.remove(name).ok_or_else(|| format!("Render layer {} not found", name))?;
+        for generator in std::mem::take(&mut scene.generators) {
+            scene.objects.extend(generator.generate()?);
+        }
+
+        for (i, object) in scene.objects.iter_mut().enumerate() {
+            object.id = Some(object.id.unwrap_or(i as u32 + 1));
+            if let Some(material) = &layer.material {
+                object.material = SceneObjectMaterial::MaterialRef(material.clone());
+            }
+        }
+        scene.objects.retain(|object| layer.contains(object));
+        scene.layers.clear();
+        Ok(self)
+    }
+
+    /// Validates the scene without building it, returning every problem found: unknown materials,
+    /// missing files, transforms which can't be inverted, empty meshes...
+    pub fn check(&self) -> Vec<String> {
+        self.scene.check()
+    }
+}
+
+impl Default for SceneWorld {
+    fn default() -> Self {
+        Self {
+            color: [0.0, 0.0, 0.0],
+            strength: default_world_strength(),
+            sun: None,
+            portals: Vec::new(),
+        }
+    }
+}
+
+impl Default for SceneBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&Transform> for SceneTransform {
+    /// Decomposes a transform, rounding off the decomposition's float errors so that it reads like
+    /// a written one
+    fn from(transform: &Transform) -> Self {
+        let round = |values: [f64; 3]| values.map(|value| (value * 1e9).round() / 1e9 + 0.0);
+        let (translate, rotate, shear, scale) = transform.decompose();
+        Self { translate: round(translate), rotate: round(rotate), shear: round(shear), scale: round(scale) }
     }
 }
 
@@ -132,16 +1819,39 @@ impl From<&SceneTransform> for Transform {
     fn from(scene_transform: &SceneTransform) -> Self {
         let [tx, ty, tz] = scene_transform.translate;
         let [rx, ry, rz] = scene_transform.rotate;
+        let [xy, xz, yz] = scene_transform.shear;
         let [sx, sy, sz] = scene_transform.scale;
         Self::new()
             .translate(tx, ty, tz)
             .rotate(rx, ry, rz)
+            .shear(xy, xz, yz)
             .scale(sx, sy, sz)
     }
 }
 
 const fn default_camera_fov() -> f64 { 90.0 }
 const fn default_camera_near() -> f64 { 10.0 }
+const fn default_stereo_interocular() -> f64 { 0.065 }
+const fn default_exposure_iso() -> f64 { 100.0 }
+const fn default_exposure_shutter() -> f64 { 0.01 }
+const fn default_exposure_f_stop() -> f64 { 16.0 }
+const fn default_white_balance_temperature() -> f64 { 6500.0 }
 const fn default_output_samples() -> u32 { 1 }
-const fn default_output_tile_size() -> u32 { 16 }
+const fn default_output_ray_epsilon() -> f64 { 1e-6 }
+const fn default_output_max_depth() -> u32 { 8 }
+const fn default_output_light_samples() -> u32 { 8 }
+const fn default_output_max_samples() -> u32 { 1024 }
+const fn default_world_strength() -> f64 { 1.0 }
+const fn default_sun_intensity() -> f64 { 3.0 }
+const fn default_sun_sky() -> f64 { 1.0 }
+const fn default_sun_turbidity() -> f64 { 0.05 }
+const fn default_light_color() -> [f64; 3] { [1.0, 1.0, 1.0] }
+const fn default_light_intensity() -> f64 { 1.0 }
+const fn default_light_falloff() -> f64 { 2.0 }
+const fn default_spot_angle() -> f64 { 45.0 }
+const fn default_spot_blend() -> f64 { 0.15 }
+const fn default_material_double_sided() -> bool { true }
+const fn default_material_alpha_threshold() -> f64 { 0.5 }
+const fn default_material_bump_strength() -> f64 { 1.0 }
+const fn default_uv_transform_scale() -> [f64; 2] { [1.0, 1.0] }
 const fn default_transform_scale() -> [f64; 3] { [1.0, 1.0, 1.0] }
@@ -0,0 +1,112 @@
+use crate::log;
+use crate::raytracer::RGBA;
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use serde_json::Value;
+use std::sync::{LazyLock, Once};
+
+/// Engine running the `shade` functions of material scripts, for every hit they are shaded at
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| engine(MAX_SHADE_OPERATIONS));
+/// Engine running generator scripts, once per scene build
+static GENERATOR_ENGINE: LazyLock<Engine> = LazyLock::new(|| engine(MAX_GENERATOR_OPERATIONS));
+
+/// Limits of the scripts from scene files, so that a runaway script fails instead of hanging the
+/// render
+const MAX_SHADE_OPERATIONS: u64 = 100_000;
+const MAX_GENERATOR_OPERATIONS: u64 = 100_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FN_EXPR_DEPTH: usize = 32;
+
+const INVALID_COLOR: &str = "shade(hit) must return an [r, g, b] or [r, g, b, a] array of numbers";
+
+/// Material computing its color with a script's `shade(hit)` function
+///
+/// `hit` is a map with the `distance`, `position`, `normal`, `tangent`, `uv` and `front_face` of the
+/// intersection, and its vertex `color` on meshes with vertex colors. The function must return an
+/// `[r, g, b]` or `[r, g, b, a]` array. The color is straight, surfaces with an alpha below 1 are
+/// composited over what is behind them.
+pub struct ScriptMaterial {
+    ast: AST,
+    /// Set once the first script error is logged, the next ones are only shown in the render
+    error_logged: Once,
+}
+
+/// Runs a generator script and returns the objects it evaluates to, as scene JSON values
+pub fn generate_objects(source: &str) -> Result<Vec<Value>, String> {
+    let result = GENERATOR_ENGINE.eval::<Dynamic>(source)
+        .map_err(|err| format!("Generator script failed: {}", err))?;
+    if !result.is_array() {
+        return Err(format!("Generator script must return an array of objects, got {}", result.type_name()));
+    }
+
+    rhai::serde::from_dynamic::<Vec<Value>>(&result)
+        .map_err(|err| format!("Generator script returned invalid objects: {}", err))
+}
+
+impl ScriptMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let source = data.get("source")
+            .and_then(Value::as_str)
+            .ok_or("Script material is missing its source")?;
+        let ast = ENGINE.compile(source)
+            .map_err(|err| format!("Failed to compile material script: {}", err))?;
+        if !ast.iter_functions().any(|f| f.name == "shade" && f.params.len() == 1) {
+            return Err("Material script must define a shade(hit) function".to_string());
+        }
+
+        Ok(Self { ast, error_logged: Once::new() })
+    }
+}
+
+impl MaterialType for ScriptMaterial {
+    fn shade(&self, oh: &ObjectHit, _: &ShadeContext) -> RGBA {
+        let mut hit = Map::new();
+        hit.insert("distance".into(), oh.hit.distance.into());
+        hit.insert("position".into(), vec3_to_array(oh.hit.intersection).into());
+        hit.insert("normal".into(), vec3_to_array(oh.hit.normal).into());
+        hit.insert("tangent".into(), vec3_to_array(oh.hit.tangent).into());
+        hit.insert("uv".into(), Dynamic::from_array(vec![oh.hit.uv.0.into(), oh.hit.uv.1.into()]));
+        hit.insert("front_face".into(), oh.hit.front_face.into());
+        hit.insert("ray_type".into(), oh.ray.ray_type.name().into());
+        if let Some(color) = oh.hit.color {
+            hit.insert("color".into(), vec3_to_array(color).into());
+        }
+
+        let color = ENGINE.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "shade", (hit,))
+            .map_err(|err| err.to_string())
+            .and_then(|color| {
+                color.try_cast::<Array>()
+                    .and_then(|color| color.iter().map(as_f64).collect::<Option<Vec<f64>>>())
+                    .ok_or_else(|| INVALID_COLOR.to_string())
+            });
+
+        match color.as_deref() {
+            Ok(&[r, g, b]) => RGBA::new(r, g, b, 1.0),
+            Ok(&[r, g, b, a]) => RGBA::new(r * a, g * a, b * a, a),
+            result => {
+                let err = result.err().map_or(INVALID_COLOR, String::as_str);
+                self.error_logged.call_once(|| log::error!("Material script failed, its surfaces are shown in magenta: {}", err));
+                // Make script errors stand out in the render
+                RGBA::new(1.0, 0.0, 1.0, 1.0)
+            }
+        }
+    }
+}
+
+fn engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(max_operations)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FN_EXPR_DEPTH);
+    engine
+}
+
+fn vec3_to_array(v: (f64, f64, f64)) -> Array {
+    vec![v.0.into(), v.1.into(), v.2.into()]
+}
+
+fn as_f64(value: &Dynamic) -> Option<f64> {
+    value.as_float().ok().or_else(|| value.as_int().ok().map(|i| i as f64))
+}
@@ -0,0 +1,106 @@
+use crate::raytracer::{Ray, RayType, RGBA};
+use crate::raytracer::materials::{MaterialType, ShadeContext};
+use crate::raytracer::objects::ObjectHit;
+use crate::raytracer::utils::{random_unit_vector, vec3add, vec3dot, vec3norm, vec3scale};
+use serde::Deserialize;
+use serde_json::Value;
+use std::f64::consts::PI;
+
+/// Cloth like velvet and satin: a matte base under a sheen of fibers, which shines at grazing
+/// angles and back towards the light
+///
+/// The sheen follows the "Charlie" distribution of Estevez and Kulla, with Ashikhmin's visibility.
+pub struct SheenMaterial {
+    /// Color of the matte base
+    color: (f64, f64, f64),
+    sheen: (f64, f64, f64),
+    /// Width of the sheen, from sharp at grazing angles to wide
+    roughness: f64,
+}
+
+#[derive(Deserialize)]
+struct SheenData {
+    #[serde(default = "default_sheen_color")]
+    color: [f64; 3],
+    #[serde(default = "default_sheen_sheen")]
+    sheen: [f64; 3],
+    #[serde(default = "default_sheen_roughness")]
+    roughness: f64,
+}
+
+impl SheenMaterial {
+    pub fn new(data: &Value) -> Result<Self, String> {
+        let data: SheenData = serde_json::from_value(data.clone())
+            .map_err(|err| format!("Invalid sheen material: {}", err))?;
+        let [r, g, b] = data.color;
+        let [sr, sg, sb] = data.sheen;
+
+        Ok(Self {
+            color: (r, g, b),
+            sheen: (sr, sg, sb),
+            roughness: data.roughness.clamp(0.01, 1.0),
+        })
+    }
+
+    /// Sheen BRDF between the view and light directions, without its color
+    fn sheen_brdf(&self, normal: (f64, f64, f64), view: (f64, f64, f64), light: (f64, f64, f64)) -> f64 {
+        let (cos_v, cos_l) = (vec3dot(normal, view), vec3dot(normal, light));
+        if cos_v <= 0.0 || cos_l <= 0.0 {
+            return 0.0;
+        }
+        let cos_h = vec3dot(normal, vec3norm(vec3add(view, light))).clamp(0.0, 1.0);
+        let sin_h = (1.0 - cos_h * cos_h).sqrt();
+        let inverse = 1.0 / self.roughness;
+        let d = (2.0 + inverse) * sin_h.powf(inverse) / (2.0 * PI);
+        let visibility = 1.0 / (4.0 * (cos_l + cos_v - cos_l * cos_v));
+        d * visibility
+    }
+}
+
+impl MaterialType for SheenMaterial {
+    fn shade(&self, oh: &ObjectHit, ctx: &ShadeContext) -> RGBA {
+        let (normal, point) = (oh.hit.normal, oh.hit.intersection);
+        let view = vec3scale(oh.ray.direction, -1.0);
+        // Diffuse base and sheen reflectances, weighted by the cosine of the light
+        let reflectance = |light| {
+            let sheen = self.sheen_brdf(normal, view, light) * PI;
+            (
+                self.color.0 + self.sheen.0 * sheen,
+                self.color.1 + self.sheen.1 * sheen,
+                self.color.2 + self.sheen.2 * sheen,
+            )
+        };
+
+        let mut light = (0.0, 0.0, 0.0);
+        for sample in ctx.sample_lights(point).into_iter().chain(ctx.sample_portals(point)) {
+            let cos = vec3dot(normal, sample.direction);
+            if cos > 0.0 {
+                let f = reflectance(sample.direction);
+                light = vec3add(light, (
+                    sample.radiance.0 * f.0 * cos / PI,
+                    sample.radiance.1 * f.1 * cos / PI,
+                    sample.radiance.2 * f.2 * cos / PI,
+                ));
+            }
+        }
+
+        // Cosine-weighted bounce, its probability cancels out with the cosine and the 1/π of the BRDFs
+        let mut direction = vec3norm(vec3add(normal, random_unit_vector()));
+        if direction.0.is_nan() {
+            direction = normal;
+        }
+        let bounce = ctx.trace(Ray {
+            ray_type: RayType::Diffuse,
+            origin: point,
+            direction,
+            ..oh.ray
+        });
+        let f = reflectance(direction);
+
+        RGBA::new(light.0 + bounce.r * f.0, light.1 + bounce.g * f.1, light.2 + bounce.b * f.2, 1.0)
+    }
+}
+
+const fn default_sheen_color() -> [f64; 3] { [0.2, 0.02, 0.05] }
+const fn default_sheen_sheen() -> [f64; 3] { [1.0, 1.0, 1.0] }
+const fn default_sheen_roughness() -> f64 { 0.3 }
@@ -0,0 +1,136 @@
+//! Sun and sky lighting outdoor scenes, from the sun's angles or from a place and time on Earth
+//!
+//! The sky is the sun's light scattered once by air molecules, which turn it blue, and by
+//! aerosols, which whiten it around the sun and towards the horizon, evaluated at one wavelength
+//! per RGB channel. The sun's light goes through the same atmosphere, so that both redden together
+//! at sunset. The z axis is up and north is +y.
+
+use crate::raytracer::lights::{Light, LightKind};
+use crate::raytracer::spectrum::Spectrum;
+use crate::raytracer::utils::{vec3dot, vec3scale};
+use std::f64::consts::PI;
+
+/// Wavelengths in micrometers the RGB channels are evaluated at
+const WAVELENGTHS: (f64, f64, f64) = (0.68, 0.55, 0.44);
+/// Temperature of the sun's surface in kelvins
+const SUN_TEMPERATURE: f64 = 5778.0;
+/// Asymmetry of the scattering by aerosols, mostly forwards
+const AEROSOL_ASYMMETRY: f64 = 0.76;
+/// Elevation in degrees of the sun below the horizon at which the sky is dark, at the end of the
+/// civil twilight
+const TWILIGHT: f64 = -6.0;
+
+pub struct Sky {
+    /// Direction towards the sun
+    sun_direction: (f64, f64, f64),
+    /// Elevation of the sun in degrees
+    sun_elevation: f64,
+    /// Irradiance of the sun above the atmosphere
+    sun_color: (f64, f64, f64),
+    /// Optical depths of the atmosphere towards the zenith, of air molecules and of aerosols
+    rayleigh: (f64, f64, f64),
+    aerosols: (f64, f64, f64),
+    /// Multiplier of the sky's light
+    strength: f64,
+}
+
+impl Sky {
+    /// Creates the sky of a sun at `azimuth` and `elevation` degrees, the azimuth going clockwise
+    /// from north towards east, `turbidity` being the optical depth of aerosols at 1 µm
+    pub fn new(azimuth: f64, elevation: f64, intensity: f64, strength: f64, turbidity: f64) -> Self {
+        let (azimuth, altitude) = (azimuth.to_radians(), elevation.to_radians());
+        let channels = |f: fn(f64, f64) -> f64| (
+            f(WAVELENGTHS.0, turbidity),
+            f(WAVELENGTHS.1, turbidity),
+            f(WAVELENGTHS.2, turbidity),
+        );
+
+        Self {
+            sun_direction: (azimuth.sin() * altitude.cos(), azimuth.cos() * altitude.cos(), altitude.sin()),
+            sun_elevation: elevation,
+            sun_color: vec3scale(Spectrum::blackbody(SUN_TEMPERATURE).to_rgb(), intensity),
+            // Optical depths of Hansen and Travis, and Ångström's turbidity formula
+            rayleigh: channels(|wavelength, _| 0.008569 * wavelength.powi(-4) * (1.0 + 0.0113 * wavelength.powi(-2) + 0.00013 * wavelength.powi(-4))),
+            aerosols: channels(|wavelength, turbidity| turbidity * wavelength.powf(-1.3)),
+            strength,
+        }
+    }
+
+    /// Directional light of the sun, `None` once it has set
+    pub fn sun_light(&self) -> Option<Light> {
+        (self.sun_elevation > 0.0).then(|| Light::new(
+            LightKind::Directional { direction: vec3scale(self.sun_direction, -1.0) },
+            self.sun_radiance(),
+            0.0,
+            0.0,
+            f64::INFINITY,
+        ))
+    }
+
+    /// Light of the sky coming from `direction`, directions below the horizon seeing the horizon
+    pub fn radiance(&self, direction: (f64, f64, f64)) -> (f64, f64, f64) {
+        let twilight = ((self.sun_elevation - TWILIGHT) / -TWILIGHT).clamp(0.0, 1.0);
+        if twilight == 0.0 {
+            return (0.0, 0.0, 0.0);
+        }
+
+        let cos = vec3dot(direction, self.sun_direction);
+        let rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + cos * cos);
+        let g = AEROSOL_ASYMMETRY;
+        let aerosol_phase = (1.0 - g * g) / (4.0 * PI * (1.0 + g * g - 2.0 * g * cos).powf(1.5));
+        let air_mass = air_mass(direction.2.max(0.0).asin().to_degrees());
+        let sun = self.sun_radiance();
+
+        // Light scattered towards the viewer along the view ray, which the atmosphere saturates
+        // towards the horizon
+        let channel = |sun: f64, rayleigh: f64, aerosols: f64| {
+            let depth = rayleigh + aerosols;
+            let phase = (rayleigh * rayleigh_phase + aerosols * aerosol_phase) / depth;
+            sun * phase * (1.0 - (-depth * air_mass).exp()) * self.strength * twilight
+        };
+        (
+            channel(sun.0, self.rayleigh.0, self.aerosols.0),
+            channel(sun.1, self.rayleigh.1, self.aerosols.1),
+            channel(sun.2, self.rayleigh.2, self.aerosols.2),
+        )
+    }
+
+    /// Irradiance of the sun through the atmosphere
+    fn sun_radiance(&self) -> (f64, f64, f64) {
+        let air_mass = air_mass(self.sun_elevation.max(0.0));
+        let transmittance = |color: f64, rayleigh: f64, aerosols: f64| color * (-(rayleigh + aerosols) * air_mass).exp();
+        (
+            transmittance(self.sun_color.0, self.rayleigh.0, self.aerosols.0),
+            transmittance(self.sun_color.1, self.rayleigh.1, self.aerosols.1),
+            transmittance(self.sun_color.2, self.rayleigh.2, self.aerosols.2),
+        )
+    }
+}
+
+/// Azimuth and elevation in degrees of the sun seen from `latitude` and `longitude` degrees, on a
+/// day of the year (1 for January 1st) at a UTC time in hours, with NOAA's approximations
+pub fn solar_angles(latitude: f64, longitude: f64, day: u32, hours: f64) -> (f64, f64) {
+    // Fractional year
+    let year = 2.0 * PI / 365.0 * (day as f64 - 1.0 + (hours - 12.0) / 24.0);
+    let equation_of_time = 229.18 * (0.000075 + 0.001868 * year.cos() - 0.032077 * year.sin()
+        - 0.014615 * (2.0 * year).cos() - 0.040849 * (2.0 * year).sin());
+    let declination = 0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin()
+        - 0.006758 * (2.0 * year).cos() + 0.000907 * (2.0 * year).sin()
+        - 0.002697 * (3.0 * year).cos() + 0.00148 * (3.0 * year).sin();
+
+    // Hour angle from the true solar time in minutes
+    let solar_time = hours * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+    let latitude = latitude.to_radians();
+
+    let sin_elevation = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    let azimuth = hour_angle.sin().atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+    ((azimuth.to_degrees() + 180.0).rem_euclid(360.0), sin_elevation.clamp(-1.0, 1.0).asin().to_degrees())
+}
+
+/// Relative length of the path through the atmosphere towards an elevation in degrees, 1 towards
+/// the zenith (Kasten and Young)
+fn air_mass(elevation: f64) -> f64 {
+    let zenith = 90.0 - elevation;
+    1.0 / (zenith.to_radians().cos() + 0.50572 * (96.07995 - zenith).powf(-1.6364))
+}
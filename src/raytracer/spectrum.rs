@@ -0,0 +1,166 @@
+//! Spectral rendering, with hero wavelength sampling
+//!
+//! Light is still transported as RGB colors, which stand for the light of all wavelengths. In
+//! spectral renders each camera ray also samples a hero wavelength: when a dispersive surface
+//! sends the wavelengths in different directions, the path only carries the hero wavelength from
+//! there on, its color being weighted by the wavelength's RGB response. Averaged over the samples,
+//! the wavelengths add back up to the light's color, with the dispersion RGB rendering can't show.
+
+use crate::raytracer::utils::{random, vec3add};
+use std::sync::LazyLock;
+
+/// Range of visible wavelengths sampled, in nanometers
+const MIN_WAVELENGTH: f64 = 380.0;
+const MAX_WAVELENGTH: f64 = 780.0;
+
+/// Range of black body temperatures in kelvins, past which the color barely changes
+const MIN_TEMPERATURE: f64 = 500.0;
+const MAX_TEMPERATURE: f64 = 100_000.0;
+
+/// Average RGB response over the visible wavelengths, which responses are normalized by
+static MEAN_RESPONSE: LazyLock<(f64, f64, f64)> = LazyLock::new(|| {
+    let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize;
+    let sum = (0..steps)
+        .map(|i| linear_srgb(MIN_WAVELENGTH + i as f64 + 0.5))
+        .fold((0.0, 0.0, 0.0), |sum, rgb| (sum.0 + rgb.0, sum.1 + rgb.1, sum.2 + rgb.2));
+    (sum.0 / steps as f64, sum.1 / steps as f64, sum.2 / steps as f64)
+});
+
+/// Wavelengths of the light a path carries
+#[derive(Clone, Copy, Default)]
+pub enum Wavelengths {
+    /// All wavelengths, as in RGB renders
+    #[default]
+    All,
+    /// All wavelengths, with the hero wavelength (in nanometers) the path keeps at dispersive surfaces
+    Hero(f64),
+    /// Only the hero wavelength, past a dispersive surface
+    Single(f64),
+}
+
+/// Spectral power distribution, linearly interpolated between wavelengths
+pub struct Spectrum {
+    /// Wavelengths in nanometers and their values, sorted by wavelength
+    points: Vec<(f64, f64)>,
+}
+
+impl Wavelengths {
+    /// Samples the hero wavelength of a camera ray
+    pub fn sample() -> Self {
+        Self::Hero(MIN_WAVELENGTH + random::<f64>() * (MAX_WAVELENGTH - MIN_WAVELENGTH))
+    }
+
+    /// Returns the wavelengths past a dispersive surface, the wavelength to disperse the light at
+    /// (`None` for all of them), and the weight of the light coming back along the path
+    pub fn disperse(self) -> (Self, Option<f64>, (f64, f64, f64)) {
+        match self {
+            Self::All => (self, None, (1.0, 1.0, 1.0)),
+            Self::Hero(wavelength) => (Self::Single(wavelength), Some(wavelength), response(wavelength)),
+            Self::Single(wavelength) => (self, Some(wavelength), (1.0, 1.0, 1.0)),
+        }
+    }
+}
+
+impl Spectrum {
+    /// Creates a spectrum from wavelengths in nanometers and their values
+    pub fn new(points: &[[f64; 2]]) -> Self {
+        let mut points: Vec<(f64, f64)> = points.iter().map(|&[wavelength, value]| (wavelength, value)).collect();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { points }
+    }
+
+    /// Spectrum of a black body at a temperature in kelvins, like incandescent lights and the sun,
+    /// scaled to a luminance of 1 so that the temperature only sets its color
+    pub fn blackbody(temperature: f64) -> Self {
+        let temperature = temperature.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE);
+        let points: Vec<[f64; 2]> = (0..=80)
+            .map(|i| {
+                let wavelength = MIN_WAVELENGTH + i as f64 * (MAX_WAVELENGTH - MIN_WAVELENGTH) / 80.0;
+                [wavelength, planck(wavelength, temperature)]
+            })
+            .collect();
+        let spectrum = Self::new(&points);
+        let (r, g, b) = spectrum.to_rgb();
+        spectrum.scaled(1.0 / (0.2126 * r + 0.7152 * g + 0.0722 * b))
+    }
+
+    /// Multiplies the values by `factor`
+    pub fn scaled(self, factor: f64) -> Self {
+        Self { points: self.points.into_iter().map(|(wavelength, value)| (wavelength, value * factor)).collect() }
+    }
+
+    /// Value at a wavelength, the values at the ends extending past them
+    pub fn value(&self, wavelength: f64) -> f64 {
+        let i = self.points.partition_point(|point| point.0 < wavelength);
+        match (self.points.get(i.wrapping_sub(1)), self.points.get(i)) {
+            (Some(&(w0, v0)), Some(&(w1, v1))) => v0 + (v1 - v0) * (wavelength - w0) / (w1 - w0),
+            (Some(&(_, v)), None) | (None, Some(&(_, v))) => v,
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Linear RGB color of the spectrum, a constant spectrum of 1 being white
+    pub fn to_rgb(&self) -> (f64, f64, f64) {
+        integrate((MAX_WAVELENGTH - MIN_WAVELENGTH) as usize, |wavelength| {
+            let value = self.value(wavelength);
+            (value, value, value)
+        })
+    }
+}
+
+/// Linear RGB color of a spectral quantity, each channel of `f` being weighted by the response
+/// of the same channel, over `steps` wavelengths
+///
+/// A constant 1 is white whatever the number of steps.
+pub fn integrate(steps: usize, f: impl Fn(f64) -> (f64, f64, f64)) -> (f64, f64, f64) {
+    let (sum, weights) = (0..steps)
+        .map(|i| {
+            let wavelength = MIN_WAVELENGTH + (i as f64 + 0.5) * (MAX_WAVELENGTH - MIN_WAVELENGTH) / steps as f64;
+            let (r, g, b) = response(wavelength);
+            let value = f(wavelength);
+            ((r * value.0, g * value.1, b * value.2), (r, g, b))
+        })
+        .fold(((0.0, 0.0, 0.0), (0.0, 0.0, 0.0)), |(sum, weights), (value, weight)| {
+            (vec3add(sum, value), vec3add(weights, weight))
+        });
+    (sum.0 / weights.0, sum.1 / weights.1, sum.2 / weights.2)
+}
+
+/// RGB color of the light of a wavelength, normalized so that it averages to white over the
+/// visible wavelengths
+pub fn response(wavelength: f64) -> (f64, f64, f64) {
+    let (r, g, b) = linear_srgb(wavelength);
+    let mean = *MEAN_RESPONSE;
+    (r / mean.0, g / mean.1, b / mean.2)
+}
+
+/// Spectral radiance of a black body at a wavelength in nanometers (Planck's law), up to a
+/// constant factor
+fn planck(wavelength: f64, temperature: f64) -> f64 {
+    // Second radiation constant hc/k, in nanometer kelvins
+    const C2: f64 = 1.438_777e7;
+    1.0 / (wavelength.powi(5) * (C2 / (wavelength * temperature)).exp_m1())
+}
+
+/// Linear sRGB color of a wavelength, with the colors outside of the gamut clipped
+fn linear_srgb(wavelength: f64) -> (f64, f64, f64) {
+    let (x, y, z) = cie_xyz(wavelength);
+    (
+        (3.2406 * x - 1.5372 * y - 0.4986 * z).max(0.0),
+        (-0.9689 * x + 1.8758 * y + 0.0415 * z).max(0.0),
+        (0.0557 * x - 0.2040 * y + 1.0570 * z).max(0.0),
+    )
+}
+
+/// CIE 1931 color matching functions, from the multi-lobe fit of Wyman, Sloan and Shirley
+fn cie_xyz(wavelength: f64) -> (f64, f64, f64) {
+    let lobe = |mean: f64, below: f64, above: f64| {
+        let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    (
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
@@ -0,0 +1,100 @@
+//! Render statistics, counted in debug builds or with the `stats` feature and compiled out otherwise
+
+use crate::raytracer::RayType;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Whether the counters are compiled in
+pub const ENABLED: bool = cfg!(any(debug_assertions, feature = "stats"));
+
+const COUNTERS: usize = 8;
+
+thread_local! {
+    /// Counts of the current thread, added to the render's after each tile
+    static COUNTS: [Cell<u64>; COUNTERS] = const { [const { Cell::new(0) }; COUNTERS] };
+}
+
+#[derive(Clone, Copy)]
+pub enum Counter {
+    CameraRays,
+    ReflectionRays,
+    GlossyRays,
+    DiffuseRays,
+    TransmissionRays,
+    ShadowRays,
+    BvhNodes,
+    TextureLookups,
+}
+
+/// Counts of a render, from all threads
+#[derive(Default)]
+pub struct Stats {
+    counts: [AtomicU64; COUNTERS],
+}
+
+#[inline(always)]
+pub(super) fn count(counter: Counter) {
+    if ENABLED {
+        COUNTS.with(|counts| counts[counter as usize].set(counts[counter as usize].get() + 1));
+    }
+}
+
+#[inline(always)]
+pub(super) fn count_ray(ray_type: RayType) {
+    count(match ray_type {
+        RayType::Camera => Counter::CameraRays,
+        RayType::Reflection => Counter::ReflectionRays,
+        RayType::Diffuse => Counter::DiffuseRays,
+        RayType::Shadow => Counter::ShadowRays,
+        RayType::Transmission => Counter::TransmissionRays,
+        RayType::Glossy => Counter::GlossyRays,
+    });
+}
+
+impl Counter {
+    const ALL: [Counter; COUNTERS] = [
+        Counter::CameraRays,
+        Counter::ReflectionRays,
+        Counter::GlossyRays,
+        Counter::DiffuseRays,
+        Counter::TransmissionRays,
+        Counter::ShadowRays,
+        Counter::BvhNodes,
+        Counter::TextureLookups,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Counter::CameraRays => "camera rays",
+            Counter::ReflectionRays => "reflection rays",
+            Counter::GlossyRays => "glossy rays",
+            Counter::DiffuseRays => "diffuse rays",
+            Counter::TransmissionRays => "transmission rays",
+            Counter::ShadowRays => "shadow rays",
+            Counter::BvhNodes => "BVH nodes visited",
+            Counter::TextureLookups => "texture lookups",
+        }
+    }
+}
+
+impl Stats {
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counts[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Adds the counts of the current thread and resets them
+    pub(super) fn flush(&self) {
+        if ENABLED {
+            COUNTS.with(|counts| {
+                for (total, count) in self.counts.iter().zip(counts) {
+                    total.fetch_add(count.take(), Ordering::Relaxed);
+                }
+            });
+        }
+    }
+
+    /// Returns the counters with their names, in a readable order
+    pub fn report(&self) -> Vec<(&'static str, u64)> {
+        Counter::ALL.iter().map(|&counter| (counter.name(), self.get(counter))).collect()
+    }
+}
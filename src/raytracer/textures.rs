@@ -0,0 +1,214 @@
+use crate::raytracer::RGBA;
+use crate::raytracer::stats::{self, Counter};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Loaded textures, by path, filter and color space, so that textures used by several materials are only loaded once
+///
+/// Each texture is loaded without locking the others, threads loading the same one wait for it.
+/// Textures are loaded again once their file changes, for scenes rebuilt while watching them.
+static TEXTURES: LazyLock<Mutex<HashMap<TextureKey, (FileStamp, LoadedTexture)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+type TextureKey = (String, TextureFilter, ColorSpace);
+/// Modification time and length of a texture's file when it was loaded, if they could be read
+type FileStamp = Option<(SystemTime, u64)>;
+/// Texture or loading error, set once loaded
+type LoadedTexture = Arc<OnceLock<Result<Arc<Texture>, String>>>;
+
+pub struct Texture {
+    /// Mipmap levels, from the full resolution image down to 1x1 (only the first one when not
+    /// filtering with mipmaps)
+    levels: Vec<Level>,
+    filter: TextureFilter,
+    has_alpha: bool,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+    /// Nearest pixel, for pixel art and masks that must stay sharp
+    Nearest,
+    /// Bilinear interpolation of the 4 nearest pixels
+    Bilinear,
+    /// Bilinear interpolation in the two mipmap levels closest to the hit's footprint
+    #[default]
+    Trilinear,
+}
+
+/// How the colors of a texture's image are encoded
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+    /// Values used as stored, for non-color data like roughness, bump and displacement maps, and
+    /// for HDR images
+    #[default]
+    Linear,
+    /// sRGB encoded colors, like most 8-bit painted or photographed color textures, decoded to
+    /// linear values when loaded (the alpha channel is always linear)
+    Srgb,
+}
+
+struct Level {
+    width: u32,
+    height: u32,
+    pixels: Vec<[f32; 4]>,
+}
+
+impl Texture {
+    /// Loads the image texture at `path`, or returns it if it was already loaded with the same
+    /// filter and color space
+    pub fn load(path: &str, filter: TextureFilter, color_space: ColorSpace) -> Result<Arc<Texture>, String> {
+        let key = (path.to_string(), filter, color_space);
+        let stamp = file_stamp(path);
+        let texture = {
+            let mut textures = TEXTURES.lock().unwrap();
+            match textures.get(&key) {
+                Some((loaded_stamp, texture)) if *loaded_stamp == stamp => texture.clone(),
+                _ => textures.entry(key.clone()).insert_entry((stamp, LoadedTexture::default())).get().1.clone(),
+            }
+        };
+        let result = texture.get_or_init(|| Self::decode(path, filter, color_space)).clone();
+        // Failed loads are tried again, once the file is fixed
+        if result.is_err() {
+            TEXTURES.lock().unwrap().remove(&key);
+        }
+        result
+    }
+
+    fn decode(path: &str, filter: TextureFilter, color_space: ColorSpace) -> Result<Arc<Texture>, String> {
+        let image = image::open(path)
+            .map_err(|err| format!("Failed to load texture {}: {}", path, err))?;
+        let has_alpha = image.color().has_alpha();
+        let image = image.into_rgba32f();
+
+        // Decoded before the mipmaps are computed, so that they average linear values
+        let pixels = image.pixels().map(|pixel| match color_space {
+            ColorSpace::Linear => pixel.0,
+            ColorSpace::Srgb => {
+                let [r, g, b, a] = pixel.0;
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+            }
+        });
+        let mut levels = vec![Level {
+            width: image.width(),
+            height: image.height(),
+            pixels: pixels.collect(),
+        }];
+        if filter == TextureFilter::Trilinear {
+            while let Some(level) = levels.last().unwrap().downsample() {
+                levels.push(level);
+            }
+        }
+
+        Ok(Arc::new(Texture { levels, filter, has_alpha }))
+    }
+
+    /// Samples the texture at `uv`, repeating it outside of the [0, 1] range
+    ///
+    /// `footprint` is the approximate width covered by the sample in UV space, used to select the
+    /// mipmap level.
+    pub fn sample(&self, uv: (f64, f64), footprint: f64) -> RGBA {
+        stats::count(Counter::TextureLookups);
+        let [r, g, b, a] = match self.filter {
+            TextureFilter::Nearest => self.levels[0].nearest(uv),
+            TextureFilter::Bilinear => self.levels[0].bilinear(uv),
+            TextureFilter::Trilinear => {
+                let size = self.levels[0].width.max(self.levels[0].height) as f64;
+                let lod = (footprint * size).max(1.0).log2().min((self.levels.len() - 1) as f64);
+                let level = lod as usize;
+                let t = lod - level as f64;
+
+                let fine = self.levels[level].bilinear(uv);
+                match self.levels.get(level + 1) {
+                    Some(coarse) if t > 0.0 => lerp(fine, coarse.bilinear(uv), t),
+                    _ => fine,
+                }
+            }
+        };
+
+        RGBA::new(r, g, b, a)
+    }
+
+    /// Size of a pixel in UV space
+    pub fn texel_size(&self) -> (f64, f64) {
+        (1.0 / self.levels[0].width as f64, 1.0 / self.levels[0].height as f64)
+    }
+
+    /// Samples the opacity at `uv`, from the alpha channel or from the red channel of textures without one
+    pub fn sample_opacity(&self, uv: (f64, f64), footprint: f64) -> f64 {
+        let color = self.sample(uv, footprint);
+        if self.has_alpha { color.a } else { color.r }
+    }
+}
+
+impl Level {
+    fn pixel(&self, x: i64, y: i64) -> [f64; 4] {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.pixels[x + y * self.width as usize].map(f64::from)
+    }
+
+    fn nearest(&self, (u, v): (f64, f64)) -> [f64; 4] {
+        let x = (u * self.width as f64).floor() as i64;
+        let y = ((1.0 - v) * self.height as f64).floor() as i64;
+        self.pixel(x, y)
+    }
+
+    fn bilinear(&self, (u, v): (f64, f64)) -> [f64; 4] {
+        // Pixel centers are at half coordinates
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = lerp(self.pixel(x0, y0), self.pixel(x0 + 1, y0), tx);
+        let bottom = lerp(self.pixel(x0, y0 + 1), self.pixel(x0 + 1, y0 + 1), tx);
+        lerp(top, bottom, ty)
+    }
+
+    /// Returns the next mipmap level, averaging 2x2 blocks of pixels, or `None` at 1x1
+    fn downsample(&self) -> Option<Level> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (x0, y0) = (x * 2, y * 2);
+                let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+                let block = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)]
+                    .map(|(x, y)| self.pixels[(x + y * self.width) as usize]);
+                pixels.push([0, 1, 2, 3].map(|c| block.iter().map(|p| p[c]).sum::<f32>() / 4.0));
+            }
+        }
+
+        Some(Level { width, height, pixels })
+    }
+}
+
+/// Returns the modification time and length of a file, which change when it is edited
+fn file_stamp(path: &str) -> FileStamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Decodes an sRGB encoded value (IEC 61966-2-1)
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn lerp(a: [f64; 4], b: [f64; 4], t: f64) -> [f64; 4] {
+    [0, 1, 2, 3].map(|c| a[c] + (b[c] - a[c]) * t)
+}
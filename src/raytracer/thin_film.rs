@@ -0,0 +1,79 @@
+//! Thin films coating surfaces, like soap bubbles and anti-reflective lens coatings, whose
+//! interferences color the reflections depending on the angle
+
+use crate::raytracer::Wavelengths;
+use crate::raytracer::spectrum;
+use serde::Deserialize;
+
+/// Number of wavelengths the reflectance is evaluated at for RGB colors
+const WAVELENGTH_SAMPLES: usize = 32;
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct ThinFilm {
+    /// Thickness of the film in nanometers, interferences only being visible up to about 1000
+    thickness: f64,
+    #[serde(default = "default_thin_film_ior")]
+    ior: f64,
+}
+
+impl ThinFilm {
+    /// Returns an error if the film's parameters are invalid
+    pub fn check(&self) -> Result<(), String> {
+        if !self.thickness.is_finite() || self.thickness < 0.0 {
+            return Err(format!("thin film thickness {} is negative", self.thickness));
+        }
+        if !self.ior.is_finite() || self.ior <= 0.0 {
+            return Err(format!("thin film index of refraction {} isn't positive", self.ior));
+        }
+        Ok(())
+    }
+
+    /// Reflectance of a surface coated with the film, from the cosine of the incident direction,
+    /// the index of refraction on the incident side and the ones of the base for each channel
+    ///
+    /// Past dispersive surfaces only the path's wavelength is evaluated.
+    pub fn reflectance(&self, cos_i: f64, n_incident: f64, n_base: (f64, f64, f64), wavelengths: Wavelengths) -> (f64, f64, f64) {
+        let reflectance = |wavelength| (
+            self.reflectance_at(cos_i, n_incident, n_base.0, wavelength),
+            self.reflectance_at(cos_i, n_incident, n_base.1, wavelength),
+            self.reflectance_at(cos_i, n_incident, n_base.2, wavelength),
+        );
+        if let Wavelengths::Single(wavelength) = wavelengths {
+            return reflectance(wavelength);
+        }
+
+        spectrum::integrate(WAVELENGTH_SAMPLES, reflectance)
+    }
+
+    /// Unpolarized reflectance at a wavelength, summing the waves reflected back and forth in the
+    /// film (Airy's formula)
+    fn reflectance_at(&self, cos_i: f64, n_incident: f64, n_base: f64, wavelength: f64) -> f64 {
+        let cos = |n: f64| {
+            let sin2 = (n_incident / n).powi(2) * (1.0 - cos_i * cos_i);
+            (sin2 < 1.0).then(|| (1.0 - sin2).sqrt())
+        };
+        // Totally reflected inside the film or at the base
+        let (Some(cos_film), Some(cos_base)) = (cos(self.ior), cos(n_base)) else {
+            return 1.0;
+        };
+
+        let phase = 4.0 * std::f64::consts::PI * self.ior * self.thickness * cos_film / wavelength;
+        let airy = |r12: f64, r23: f64| {
+            let interference = 2.0 * r12 * r23 * phase.cos();
+            (r12 * r12 + r23 * r23 + interference) / (1.0 + r12 * r12 * r23 * r23 + interference)
+        };
+        let s = |n1: f64, cos1: f64, n2: f64, cos2: f64| (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+        let p = |n1: f64, cos1: f64, n2: f64, cos2: f64| (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+        let reflectance_s = airy(s(n_incident, cos_i, self.ior, cos_film), s(self.ior, cos_film, n_base, cos_base));
+        let reflectance_p = airy(p(n_incident, cos_i, self.ior, cos_film), p(self.ior, cos_film, n_base, cos_base));
+        ((reflectance_s + reflectance_p) / 2.0).clamp(0.0, 1.0)
+    }
+}
+
+/// Real index of refraction of a base reflecting `f0` at normal incidence, to approximate metals
+pub fn base_ior(f0: f64) -> f64 {
+    let r = f0.clamp(0.0, 0.999).sqrt();
+    (1.0 + r) / (1.0 - r)
+}
+
+const fn default_thin_film_ior() -> f64 { 1.33 }
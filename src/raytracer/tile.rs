@@ -1,5 +1,22 @@
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::mem::swap;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileOrder {
+    #[default]
+    Hilbert,
+    Scanline,
+    CenterOut,
+    /// Shuffled from the scene's seed, the same for each render of a pass
+    Random,
+}
 
 pub struct Tile {
     pub left: u32,
@@ -8,11 +25,103 @@ pub struct Tile {
     pub bottom: u32,
 }
 
+/// When a worker rendered a tile, recorded with [`Raytracer::record_tiles`](super::Raytracer::record_tiles)
+#[derive(Clone, Copy)]
+pub struct TileTiming {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+    pub pass: u32,
+    /// Index of the worker thread, from 0
+    pub worker: u32,
+    pub start: Instant,
+    pub end: Instant,
+}
+
+/// Smallest tiles picked automatically or split at the end of passes, in pixels
+const MIN_TILE_SIZE: u32 = 8;
+/// Largest tiles picked automatically, in pixels
+const MAX_TILE_SIZE: u32 = 64;
+/// Number of tiles per worker below which smaller tiles are picked, so that the load is balanced
+const TILES_PER_WORKER: u32 = 16;
+
+impl Tile {
+    /// Splits the tile in quarters, if it isn't too small already
+    pub fn split(&self) -> Option<[Tile; 4]> {
+        if self.right - self.left < 2 * MIN_TILE_SIZE || self.bottom - self.top < 2 * MIN_TILE_SIZE {
+            return None;
+        }
+        let (x, y) = ((self.left + self.right) / 2, (self.top + self.bottom) / 2);
+        Some([
+            Tile { left: self.left, right: x, top: self.top, bottom: y },
+            Tile { left: x, right: self.right, top: self.top, bottom: y },
+            Tile { left: self.left, right: x, top: y, bottom: self.bottom },
+            Tile { left: x, right: self.right, top: y, bottom: self.bottom },
+        ])
+    }
+}
+
+/// Picks the largest power of two tile size which still gives each worker enough tiles, larger
+/// tiles having less overhead but leaving workers idle at the end of the render
+pub fn auto_tile_size(width: u32, height: u32, workers: u32) -> u32 {
+    let mut size = MAX_TILE_SIZE;
+    while size > MIN_TILE_SIZE && divide_up(width, size) * divide_up(height, size) < workers * TILES_PER_WORKER {
+        size /= 2;
+    }
+    size
+}
+
 fn divide_up(a: u32, b: u32) -> u32 {
     (a + b - 1) / b
 }
 
-pub fn hilbert_tiles(width: u32, height: u32, tile_sz: u32) -> Vec<Tile> {
+/// Generates the tiles covering the output, in the order they should be rendered, `seed` shuffling
+/// the random order
+pub fn tiles(order: TileOrder, width: u32, height: u32, tile_sz: u32, seed: u64) -> Vec<Tile> {
+    match order {
+        TileOrder::Hilbert => {
+            // The spiral is generated from the outside in, render it from the center out
+            let mut tiles = hilbert_tiles(width, height, tile_sz);
+            tiles.reverse();
+            tiles
+        }
+        TileOrder::Scanline => scanline_tiles(width, height, tile_sz),
+        TileOrder::CenterOut => {
+            let mut tiles = scanline_tiles(width, height, tile_sz);
+            let center = (width as i64, height as i64);
+            tiles.sort_by_key(|tile| {
+                // Doubled coordinates to stay in integers
+                let dx = (tile.left + tile.right) as i64 - center.0;
+                let dy = (tile.top + tile.bottom) as i64 - center.1;
+                dx * dx + dy * dy
+            });
+            tiles
+        }
+        TileOrder::Random => {
+            let mut tiles = scanline_tiles(width, height, tile_sz);
+            tiles.shuffle(&mut StdRng::seed_from_u64(seed));
+            tiles
+        }
+    }
+}
+
+fn scanline_tiles(width: u32, height: u32, tile_sz: u32) -> Vec<Tile> {
+    let mut tiles = Vec::<Tile>::with_capacity((divide_up(width, tile_sz) * divide_up(height, tile_sz)) as usize);
+    for top in (0..height).step_by(tile_sz as usize) {
+        for left in (0..width).step_by(tile_sz as usize) {
+            tiles.push(Tile {
+                left,
+                top,
+                right: left + min(tile_sz, width - left),
+                bottom: top + min(tile_sz, height - top),
+            });
+        }
+    }
+    tiles
+}
+
+fn hilbert_tiles(width: u32, height: u32, tile_sz: u32) -> Vec<Tile> {
     // Generate tiles using the Hilbert Spiral algorithm from Blender's Cycles engine
     // https://github.com/blender/blender/blob/blender-v2.93-release/intern/cycles/render/tile.cpp#L198
 
@@ -1,4 +1,4 @@
-use crate::raytracer::utils::{matmul414, matmul444};
+use crate::raytracer::utils::{matmul414, matmul444, vec3cross, vec3dot, vec3scale, vec3sub};
 
 pub struct Transform {
     matrix: [[f64; 4]; 4],
@@ -78,6 +78,27 @@ impl Transform {
         }
     }
 
+    /// Shears X along Y and Z, and Y along Z, which with rotations and scales can express any
+    /// affine transform
+    pub const fn shear(&self, xy: f64, xz: f64, yz: f64) -> Transform {
+        let shear = [
+            [1., xy, xz, 0.],
+            [0., 1., yz, 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.],
+        ];
+        let invshear = [
+            [1., -xy,   xy*yz-xz,   0.],
+            [0., 1.,    -yz,        0.],
+            [0., 0.,    1.,         0.],
+            [0., 0.,    0.,         1.],
+        ];
+        Transform {
+            matrix: matmul444(&self.matrix, &shear),
+            invmatrix: matmul444(&invshear, &self.invmatrix),
+        }
+    }
+
     pub const fn scale(&self, x: f64, y: f64, z: f64) -> Transform {
         let scale = [
             [x,  0., 0., 0.],
@@ -97,6 +118,56 @@ impl Transform {
         }
     }
 
+    /// Applies `other` before this transform
+    pub const fn compose(&self, other: &Transform) -> Transform {
+        Transform {
+            matrix: matmul444(&self.matrix, &other.matrix),
+            invmatrix: matmul444(&other.invmatrix, &self.invmatrix),
+        }
+    }
+
+    /// Returns the translation, rotation, shear and scale which combined in this order give the
+    /// transform, from the QR decomposition of its linear part into a rotation and a shear scaled
+    /// along the axes
+    pub fn decompose(&self) -> ([f64; 3], [f64; 3], [f64; 3], [f64; 3]) {
+        let m = &self.matrix;
+        let column = |j: usize| (m[0][j], m[1][j], m[2][j]);
+        let (a1, a2, a3) = (column(0), column(1), column(2));
+
+        // Gram-Schmidt orthonormalization of the columns
+        let u11 = vec3dot(a1, a1).sqrt();
+        let q1 = vec3scale(a1, 1.0 / u11);
+        let u12 = vec3dot(q1, a2);
+        let a2 = vec3sub(a2, vec3scale(q1, u12));
+        let u22 = vec3dot(a2, a2).sqrt();
+        let q2 = vec3scale(a2, 1.0 / u22);
+        let (u13, u23) = (vec3dot(q1, a3), vec3dot(q2, a3));
+        let a3 = vec3sub(vec3sub(a3, vec3scale(q1, u13)), vec3scale(q2, u23));
+        let mut u33 = vec3dot(a3, a3).sqrt();
+        let mut q3 = vec3scale(a3, 1.0 / u33);
+        // Mirroring transforms keep a rotation and get a negative scale
+        if vec3dot(q3, vec3cross(q1, q2)) < 0.0 {
+            q3 = vec3scale(q3, -1.0);
+            u33 = -u33;
+        }
+
+        // Euler angles of the rotation, whose matrix is Rx * Ry * Rz with the columns q1, q2, q3
+        let y = q3.0.clamp(-1.0, 1.0).asin();
+        let (x, z) = if y.cos() > 1e-9 {
+            ((-q3.1).atan2(q3.2), (-q2.0).atan2(q1.0))
+        } else {
+            // Gimbal lock, the X and Z rotations are about the same axis
+            (q2.2.atan2(q2.1), 0.0)
+        };
+
+        (
+            [m[0][3], m[1][3], m[2][3]],
+            [x.to_degrees(), y.to_degrees(), z.to_degrees()],
+            [u12 / u22, u13 / u33, u23 / u33],
+            [u11, u22, u33],
+        )
+    }
+
     #[inline]
     pub const fn apply(&self, to: (f64, f64, f64)) -> (f64, f64, f64) {
         let [x, y, z, _] = matmul414(&self.matrix, &[to.0, to.1, to.2, 1.0]);
@@ -110,4 +181,17 @@ impl Transform {
 
         (x, y, z)
     }
+
+    /// Transforms a surface normal with the inverse transpose of the matrix, so that it stays
+    /// perpendicular to the surface under non-uniform scaling (the result is not normalized)
+    #[inline]
+    pub const fn apply_normal(&self, to: (f64, f64, f64)) -> (f64, f64, f64) {
+        let m = &self.invmatrix;
+
+        (
+            m[0][0] * to.0 + m[1][0] * to.1 + m[2][0] * to.2,
+            m[0][1] * to.0 + m[1][1] * to.1 + m[2][1] * to.2,
+            m[0][2] * to.0 + m[1][2] * to.1 + m[2][2] * to.2,
+        )
+    }
 }
@@ -1,9 +1,43 @@
+use rand::SeedableRng;
+use rand::distr::{Distribution, StandardUniform};
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+thread_local! {
+    /// Random numbers of the current thread, reseeded for each pixel so that renders are reproducible
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
+}
+
 // Vector ops
 /// Adds 3D vectors `a` and `b`
 pub(crate) const fn vec3add(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
     (a.0 + b.0, a.1 + b.1, a.2 + b.2)
 }
 
+/// Subtracts 3D vector `b` from `a`
+#[inline]
+pub(crate) const fn vec3sub(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
+    (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+/// Computes the cross product of 3D vectors `a` and `b`
+#[inline]
+pub(crate) const fn vec3cross(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
+    (
+        a.1 * b.2 - a.2 * b.1,
+        a.2 * b.0 - a.0 * b.2,
+        a.0 * b.1 - a.1 * b.0,
+    )
+}
+
+/// Computes the dot product of 3D vectors `a` and `b`
+#[inline]
+pub(crate) const fn vec3dot(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+    a.0 * b.0 + a.1 * b.1 + a.2 * b.2
+}
+
 /// Scales 3D vector `v` by factor `f`
 #[inline]
 pub(crate) const fn vec3scale(v: (f64, f64, f64), f: f64) -> (f64, f64, f64) {
@@ -17,6 +51,71 @@ pub(crate) fn vec3norm(v: (f64, f64, f64)) -> (f64, f64, f64) {
     vec3scale(v, 1.0 / mag)
 }
 
+/// Reseeds the current thread's random numbers for a pixel in a progressive pass, from the scene's seed
+pub(crate) fn seed_random(seed: u64, pass: u32, x: u32, y: u32) {
+    // Spread the passes across the seeds, the first pass keeps the scene's seed
+    let seed = seed.wrapping_add((pass as u64).wrapping_mul(0x9e3779b97f4a7c15));
+    RNG.with_borrow_mut(|rng| *rng = StdRng::seed_from_u64(seed ^ ((y as u64) << 32 | x as u64)));
+}
+
+/// Returns the state of the current thread's random numbers, to draw the same ones again with
+/// [`restore_random`]
+pub(crate) fn random_state() -> StdRng {
+    RNG.with_borrow(StdRng::clone)
+}
+
+pub(crate) fn restore_random(state: StdRng) {
+    RNG.set(state);
+}
+
+/// Returns a random value from the current thread's random numbers, see [`seed_random`]
+pub(crate) fn random<T>() -> T
+where
+    StandardUniform: Distribution<T>
+{
+    RNG.with_borrow_mut(|rng| StandardUniform.sample(rng))
+}
+
+/// Returns a random 3D vector uniformly distributed on the unit sphere
+pub(crate) fn random_unit_vector() -> (f64, f64, f64) {
+    let (u, v): (f64, f64) = random();
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * v;
+    (r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Maps `items` with `f` on one thread per core, keeping their order
+///
+/// The threads take the items in turn by chunks of `chunk_size`, so that a few slow items don't
+/// leave the other threads idle.
+pub(crate) fn parallel_map<T: Sync, U: Send>(items: &[T], chunk_size: usize, f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let chunks = items.chunks(chunk_size.max(1)).collect::<Vec<_>>();
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(chunks.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(chunk) = chunks.get(i) else {
+                        return results;
+                    };
+                    results.push((i, chunk.iter().map(&f).collect::<Vec<_>>()));
+                }
+            }))
+            .collect::<Vec<_>>();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().flat_map(|(_, results)| results).collect()
+}
+
 // Matrix ops
 /// Multiplies 4x4 matrix `a` with 4x1 matrix `b`
 ///
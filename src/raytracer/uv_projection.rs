@@ -0,0 +1,79 @@
+//! Texture coordinates projected onto objects, replacing the ones computed by their shapes
+
+use crate::raytracer::Transform;
+use crate::raytracer::objects::azimuthal_tangent;
+use crate::raytracer::utils::vec3norm;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Projection {
+    /// Longitude and latitude around the Z axis
+    Spherical,
+    /// Angle around the Z axis and height, one unit high
+    Cylindrical,
+    /// X and Y coordinates, projected along the Z axis over one unit
+    Planar,
+    /// Planar projection along the axis the surface faces the most, laid out like the cube's faces
+    Cubic,
+}
+
+pub struct UvProjection {
+    projection: Projection,
+    /// From the projection's space to the object's
+    transform: Transform,
+}
+
+impl UvProjection {
+    pub const fn new(projection: Projection, transform: Transform) -> Self {
+        Self { projection, transform }
+    }
+
+    /// Returns the UVs of a point of the object's surface and the direction in which U increases,
+    /// from and in the object's space
+    pub fn apply(&self, point: (f64, f64, f64), normal: (f64, f64, f64)) -> ((f64, f64), (f64, f64, f64)) {
+        let (x, y, z) = self.transform.inverse().apply(point);
+        let (uv, tangent) = match self.projection {
+            Projection::Spherical => {
+                let radius = (x * x + y * y + z * z).sqrt();
+                let latitude = if radius > 0.0 { (z / radius).clamp(-1.0, 1.0).asin() } else { 0.0 };
+                ((azimuth(x, y), 0.5 + latitude / PI), azimuthal_tangent((x, y, z)))
+            }
+            Projection::Cylindrical => ((azimuth(x, y), z + 0.5), azimuthal_tangent((x, y, z))),
+            Projection::Planar => ((x + 0.5, y + 0.5), (1.0, 0.0, 0.0)),
+            Projection::Cubic => {
+                let (nx, ny, nz) = self.transform.inverse().apply_normal(normal);
+                let axis = if nx.abs() >= ny.abs() && nx.abs() >= nz.abs() {
+                    (nx.signum(), 0.0, 0.0)
+                } else if ny.abs() >= nz.abs() {
+                    (0.0, ny.signum(), 0.0)
+                } else {
+                    (0.0, 0.0, nz.signum())
+                };
+                box_uv(axis, (x, y, z))
+            }
+        };
+        (uv, vec3norm(self.transform.apply_notranslate(tangent)))
+    }
+}
+
+/// Returns the UVs and tangent of a point on the face of a unit cube centered on the origin, the
+/// face being given by its outward axis
+pub fn box_uv(axis: (f64, f64, f64), point: (f64, f64, f64)) -> ((f64, f64), (f64, f64, f64)) {
+    let (x, y, z) = point;
+    match axis {
+        (x_axis, _, _) if x_axis < 0.0 => ((0.5 - y, z + 0.5), (0.0, -1.0, 0.0)),
+        (x_axis, _, _) if x_axis > 0.0 => ((y + 0.5, z + 0.5), (0.0, 1.0, 0.0)),
+        (_, y_axis, _) if y_axis < 0.0 => ((x + 0.5, z + 0.5), (1.0, 0.0, 0.0)),
+        (_, y_axis, _) if y_axis > 0.0 => ((0.5 - x, z + 0.5), (-1.0, 0.0, 0.0)),
+        (_, _, z_axis) if z_axis < 0.0 => ((x + 0.5, 0.5 - y), (1.0, 0.0, 0.0)),
+        _ => ((x + 0.5, y + 0.5), (1.0, 0.0, 0.0)),
+    }
+}
+
+/// U coordinate around the Z axis, like the built-in shapes
+fn azimuth(x: f64, y: f64) -> f64 {
+    0.5 - f64::atan2(x, y) / (2.0 * PI)
+}
@@ -0,0 +1,90 @@
+//! Options of the threads rendering the tiles
+
+/// Nice value of low priority workers, from -20 (highest priority) to 19 (lowest)
+#[cfg(target_os = "linux")]
+const LOW_PRIORITY_NICE: i32 = 10;
+
+/// How the worker threads of a render are run
+#[derive(Clone)]
+pub struct Workers {
+    threads: u32,
+    low_priority: bool,
+    /// Cores the workers are pinned to in turn, any core if empty
+    cores: Vec<usize>,
+}
+
+impl Workers {
+    /// Creates options for the given number of worker threads (at least one)
+    pub fn new(threads: u32) -> Self {
+        Self {
+            threads: threads.max(1),
+            low_priority: false,
+            cores: Vec::new(),
+        }
+    }
+
+    /// Sets whether the workers run at a lower priority than other programs, to keep the machine
+    /// responsive during long renders
+    pub fn with_low_priority(mut self, low_priority: bool) -> Self {
+        self.low_priority = low_priority;
+        self
+    }
+
+    /// Pins the workers to the given cores, in turn when there are more workers than cores
+    pub fn with_cores(mut self, cores: Vec<usize>) -> Self {
+        self.cores = cores;
+        self
+    }
+
+    pub fn threads(&self) -> u32 {
+        self.threads
+    }
+
+    /// Applies the options to the calling worker thread, `index` starting from 0
+    pub(super) fn configure(&self, index: u32) -> Result<(), String> {
+        if self.low_priority {
+            lower_priority().map_err(|err| format!("Failed to lower the priority of worker {}: {}", index + 1, err))?;
+        }
+        if !self.cores.is_empty() {
+            let core = self.cores[index as usize % self.cores.len()];
+            pin(core).map_err(|err| format!("Failed to pin worker {} to core {}: {}", index + 1, core, err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn lower_priority() -> Result<(), String> {
+    // Linux threads have their own nice value, 0 is the calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICE) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pin(core: usize) -> Result<(), String> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err("no such core".to_string());
+    }
+    // SAFETY: cpu_set_t is a plain bit set, valid zeroed
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin(_: usize) -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
@@ -0,0 +1,157 @@
+//! Viewer settings, loaded from `crusty/crusty.toml` in the user's config directory
+//!
+//! ```toml
+//! background = [32, 32, 32]
+//! min_zoom = 0.25
+//! max_zoom = 32
+//!
+//! [keys]
+//! fit = ["F", "R"]
+//! histogram = ["G"]
+//! ```
+
+use sdl2::keyboard::Keycode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{env, fs};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Color around the render
+    #[serde(default = "default_background")]
+    pub background: [u8; 3],
+    /// Zoom limits, as scales of the render fit to the window
+    #[serde(default = "default_min_zoom")]
+    min_zoom: f64,
+    #[serde(default = "default_max_zoom")]
+    max_zoom: f64,
+    #[serde(default)]
+    keys: KeyNames,
+}
+
+/// Viewer actions which can be bound to keys
+#[derive(Clone, Copy)]
+pub enum Action {
+    Quit,
+    Fit,
+    ActualSize,
+    Histogram,
+    Zebra,
+    Difference,
+    Panorama,
+}
+
+/// Names of the keys bound to each action, as named by SDL
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyNames {
+    #[serde(default = "default_quit_keys")]
+    quit: Vec<String>,
+    #[serde(default = "default_fit_keys")]
+    fit: Vec<String>,
+    #[serde(default = "default_actual_size_keys")]
+    actual_size: Vec<String>,
+    #[serde(default = "default_histogram_keys")]
+    histogram: Vec<String>,
+    #[serde(default = "default_zebra_keys")]
+    zebra: Vec<String>,
+    #[serde(default = "default_difference_keys")]
+    difference: Vec<String>,
+    #[serde(default = "default_panorama_keys")]
+    panorama: Vec<String>,
+}
+
+/// Actions of the bound keys
+pub struct Bindings {
+    actions: HashMap<Keycode, Action>,
+}
+
+impl Settings {
+    /// Loads the settings file, the defaults are used if it doesn't exist
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = path() else {
+            return Self::parse("");
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|err| format!("Invalid settings file {}: {}", path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::parse(""),
+            Err(err) => Err(format!("Failed to read settings file {}: {}", path.display(), err)),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let settings: Settings = toml::from_str(text).map_err(|err| err.to_string())?;
+        if !(settings.min_zoom > 0.0 && settings.min_zoom <= 1.0 && settings.max_zoom >= 1.0) {
+            return Err("min_zoom must be between 0 and 1, and max_zoom at least 1".to_string());
+        }
+        Ok(settings)
+    }
+
+    /// Zoom limits, as powers of two
+    pub fn zoom_range(&self) -> (f64, f64) {
+        (self.min_zoom.log2(), self.max_zoom.log2())
+    }
+
+    /// Resolves the names of the bound keys
+    pub fn bindings(&self) -> Result<Bindings, String> {
+        let keys = &self.keys;
+        let mut actions = HashMap::new();
+        for (names, action) in [
+            (&keys.quit, Action::Quit),
+            (&keys.fit, Action::Fit),
+            (&keys.actual_size, Action::ActualSize),
+            (&keys.histogram, Action::Histogram),
+            (&keys.zebra, Action::Zebra),
+            (&keys.difference, Action::Difference),
+            (&keys.panorama, Action::Panorama),
+        ] {
+            for name in names {
+                let keycode = Keycode::from_name(name).ok_or_else(|| format!("Unknown key {} in the settings", name))?;
+                actions.insert(keycode, action);
+            }
+        }
+        Ok(Bindings { actions })
+    }
+}
+
+impl Bindings {
+    pub fn action(&self, keycode: Keycode) -> Option<Action> {
+        self.actions.get(&keycode).copied()
+    }
+}
+
+impl Default for KeyNames {
+    fn default() -> Self {
+        Self {
+            quit: default_quit_keys(),
+            fit: default_fit_keys(),
+            actual_size: default_actual_size_keys(),
+            histogram: default_histogram_keys(),
+            zebra: default_zebra_keys(),
+            difference: default_difference_keys(),
+            panorama: default_panorama_keys(),
+        }
+    }
+}
+
+/// Returns the path of the settings file, in `$XDG_CONFIG_HOME` or `~/.config`
+fn path() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("crusty").join("crusty.toml"))
+}
+
+const fn default_background() -> [u8; 3] { [64, 64, 64] }
+const fn default_min_zoom() -> f64 { 0.0625 }
+const fn default_max_zoom() -> f64 { 16.0 }
+fn default_quit_keys() -> Vec<String> { vec!["Escape".to_string()] }
+fn default_fit_keys() -> Vec<String> { vec!["F".to_string(), "R".to_string()] }
+fn default_actual_size_keys() -> Vec<String> { vec!["1".to_string(), "Keypad 1".to_string()] }
+fn default_histogram_keys() -> Vec<String> { vec!["H".to_string()] }
+fn default_zebra_keys() -> Vec<String> { vec!["Z".to_string()] }
+fn default_difference_keys() -> Vec<String> { vec!["D".to_string()] }
+fn default_panorama_keys() -> Vec<String> { vec!["P".to_string()] }
@@ -0,0 +1,67 @@
+//! Export of the tiles' timings, as CSV or as a Chrome trace (chrome://tracing or Perfetto) to
+//! visualize how the tiles were scheduled on the workers
+
+use crusty::raytracer::{Raytracer, TileTiming};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+/// Writes the timings of the tiles rendered, as a Chrome trace for `.json` paths and as CSV
+/// otherwise, the times being in microseconds from the start of the first tile
+pub fn write(path: &str, raytracer: &Raytracer) -> Result<(), String> {
+    let mut timings = raytracer.tile_timings();
+    timings.sort_by_key(|timing| timing.start);
+    let Some(first) = timings.first().map(|timing| timing.start) else {
+        return Err(format!("No tile timings to write to {}", path));
+    };
+    let micros = |timing: &TileTiming| (
+        timing.start.duration_since(first).as_micros(),
+        timing.end.duration_since(first).as_micros(),
+    );
+
+    let contents = if Path::new(path).extension().is_some_and(|extension| extension == "json") {
+        let workers = timings.iter().map(|timing| timing.worker).max().unwrap_or(0) + 1;
+        // Thread names, then a complete event per tile
+        let events: Vec<_> = (0..workers)
+            .map(|worker| json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": worker + 1,
+                "args": { "name": format!("RT-Worker-{}", worker + 1) },
+            }))
+            .chain(timings.iter().map(|timing| {
+                let (start, end) = micros(timing);
+                json!({
+                    "name": format!("Tile {}x{}", timing.left, timing.top),
+                    "cat": format!("pass {}", timing.pass + 1),
+                    "ph": "X",
+                    "ts": start,
+                    "dur": end - start,
+                    "pid": 1,
+                    "tid": timing.worker + 1,
+                    "args": {
+                        "left": timing.left,
+                        "top": timing.top,
+                        "right": timing.right,
+                        "bottom": timing.bottom,
+                        "pass": timing.pass,
+                    },
+                })
+            }))
+            .collect();
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" }).to_string()
+    } else {
+        let mut csv = "worker,pass,left,top,right,bottom,start_us,end_us\n".to_string();
+        for timing in &timings {
+            let (start, end) = micros(timing);
+            csv += &format!(
+                "{},{},{},{},{},{},{},{}\n",
+                timing.worker, timing.pass, timing.left, timing.top, timing.right, timing.bottom, start, end,
+            );
+        }
+        csv
+    };
+
+    fs::write(path, contents).map_err(|err| format!("Failed to write tile log {}: {}", path, err))
+}
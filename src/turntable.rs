@@ -0,0 +1,69 @@
+//! Turntable mode, rendering frames of the camera orbiting around a target point without a window
+
+use crate::{load_scene, Args};
+use crusty::log;
+use crusty::raytracer::Workers;
+use std::path::Path;
+use std::time::Instant;
+
+pub struct Turntable {
+    frames: u32,
+    /// Point on the vertical axis the camera orbits around
+    target: [f64; 3],
+}
+
+impl Turntable {
+    /// Parses `frames=N[,target=X:Y:Z]`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut turntable = Turntable { frames: 0, target: [0.0; 3] };
+        for option in value.split(',') {
+            match option.split_once('=') {
+                Some(("frames", frames)) => {
+                    turntable.frames = frames.parse().ok()
+                        .filter(|&frames| frames > 0)
+                        .ok_or_else(|| format!("Invalid number of turntable frames {}", frames))?;
+                }
+                Some(("target", target)) => {
+                    let coordinates = target.split(':').map(str::parse).collect::<Result<Vec<f64>, _>>();
+                    turntable.target = coordinates.ok()
+                        .and_then(|coordinates| coordinates.try_into().ok())
+                        .ok_or_else(|| format!("Invalid turntable target {}, expected X:Y:Z", target))?;
+                }
+                _ => return Err(format!("Invalid turntable option {}, expected frames=N or target=X:Y:Z", option)),
+            }
+        }
+
+        if turntable.frames == 0 {
+            return Err("Missing number of turntable frames, expected frames=N".to_string());
+        }
+        Ok(turntable)
+    }
+}
+
+pub fn run(args: &Args, turntable: &Turntable, workers: Workers) -> Result<(), String> {
+    let output = args.output.as_deref().ok_or("Turntable renders need an --output path")?;
+    let path = Path::new(output);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("frame");
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("png");
+
+    let start = Instant::now();
+    for frame in 0..turntable.frames {
+        let frame_path = path.with_file_name(format!("{}_{:04}.{}", stem, frame + 1, extension));
+        let frame_path = frame_path.to_str().ok_or("Invalid output path")?;
+        let angle = 360.0 * frame as f64 / turntable.frames as f64;
+
+        let raytracer = load_scene(args)?
+            .orbit_camera(angle, turntable.target)
+            .output_path(frame_path)
+            .build()?;
+        raytracer.start_with(workers.clone()).join().map_err(|_| "Render thread panicked".to_string())?;
+        log::info!("Frame {}/{} saved to {}", frame + 1, turntable.frames, frame_path);
+    }
+
+    log::info!(
+        "Turntable of {} frames rendered in {:.3}s",
+        turntable.frames,
+        start.elapsed().as_secs_f64(),
+    );
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Watch mode, rendering a scene file again without a window whenever it changes, or each scene
+//! file of a directory whenever one is added or changed, for external scene generators
+//!
+//! Renders are saved next to the --output path with the render's number appended, and the scene
+//! name for directories. A file is only rendered once it stopped changing for a poll, so that
+//! scenes still being written aren't rendered.
+
+use crate::{headless, with_overrides, Args};
+use crusty::log;
+use crusty::raytracer::{SceneBuilder, Workers};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification time and size of a file, which change when it is written
+type Stamp = (SystemTime, u64);
+
+/// State of a watched scene file
+#[derive(Default)]
+struct Watched {
+    /// Stamp at the last poll
+    seen: Option<Stamp>,
+    /// Stamp when last rendered
+    rendered: Option<Stamp>,
+    /// Number of the last render
+    number: u32,
+}
+
+pub fn run(args: &Args, workers: Workers) -> Result<(), String> {
+    let output = args.output.as_deref().ok_or("Watch mode needs an --output path")?;
+    let output = Path::new(output);
+    let watched_path = Path::new(&args.scene_path);
+    let directory = watched_path.is_dir();
+    if !directory && !watched_path.is_file() {
+        return Err(format!("Failed to watch {}: no such file or directory", args.scene_path));
+    }
+
+    headless::handle_signals();
+    log::info!("Watching {} for changes, press Ctrl+C to stop", args.scene_path);
+    let mut watched: HashMap<PathBuf, Watched> = HashMap::new();
+    while !headless::cancelled() {
+        for (path, stamp) in scene_files(watched_path, directory) {
+            let file = watched.entry(path.clone()).or_default();
+            let stable = file.seen == Some(stamp);
+            file.seen = Some(stamp);
+            if !stable || file.rendered == Some(stamp) {
+                continue;
+            }
+
+            file.rendered = Some(stamp);
+            let scene = directory.then(|| path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("scene"));
+            let (number, output_path) = numbered_path(output, scene, file.number)?;
+            file.number = number;
+            render(args, &path, &output_path, workers.clone());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    log::info!("Stopped watching {}", args.scene_path);
+    Ok(())
+}
+
+/// Scene files to render and their stamps, the file itself or the JSON files of the directory
+fn scene_files(path: &Path, directory: bool) -> Vec<(PathBuf, Stamp)> {
+    let stamp = |path: &Path| fs::metadata(path).ok()
+        .filter(|metadata| metadata.is_file())
+        .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+    if !directory {
+        return stamp(path).map(|stamp| (path.to_path_buf(), stamp)).into_iter().collect();
+    }
+
+    let Ok(entries) = fs::read_dir(path) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")))
+        .filter_map(|path| Some((stamp(&path)?, path)))
+        .map(|(stamp, path)| (path, stamp))
+        .collect()
+}
+
+/// Returns the number of the next render after `number` and where it is saved, skipping the
+/// renders saved before the watch started
+fn numbered_path(output: &Path, scene: Option<&str>, mut number: u32) -> Result<(u32, String), String> {
+    let stem = output.file_stem().and_then(|stem| stem.to_str()).unwrap_or("render");
+    let extension = output.extension().and_then(|extension| extension.to_str()).unwrap_or("png");
+    loop {
+        number += 1;
+        let name = match scene {
+            Some(scene) => format!("{}_{}_{:04}.{}", stem, scene, number, extension),
+            None => format!("{}_{:04}.{}", stem, number, extension),
+        };
+        let path = output.with_file_name(name);
+        if !path.exists() {
+            return Ok((number, path.to_str().ok_or("Invalid output path")?.to_string()));
+        }
+    }
+}
+
+/// Renders a scene file, logging the errors so that the watch goes on
+fn render(args: &Args, scene_path: &Path, output_path: &str, workers: Workers) {
+    let start = Instant::now();
+    let result = fs::File::open(scene_path)
+        .map_err(|err| format!("Failed to open scene file {}: {}", scene_path.display(), err))
+        .and_then(SceneBuilder::from_reader)
+        .and_then(|scene| with_overrides(scene, args))
+        .and_then(|scene| scene.output_path(output_path).build())
+        .and_then(|raytracer| {
+            headless::wait(&raytracer, raytracer.start_with(workers))?;
+            match raytracer.file_error() {
+                Some(err) => Err(err),
+                None if raytracer.is_stopped() => Err("Render cancelled".to_string()),
+                None => Ok(()),
+            }
+        });
+
+    match &result {
+        Ok(()) => log::info!(
+            "{} rendered in {:.3}s and saved to {}",
+            scene_path.display(),
+            start.elapsed().as_secs_f64(),
+            output_path,
+        ),
+        Err(err) => log::error!("{}: {}", scene_path.display(), err),
+    }
+    if args.summary {
+        let summary = json!({
+            "status": if result.is_ok() { "completed" } else { "failed" },
+            "error": result.err(),
+            "scene": scene_path.to_string_lossy(),
+            "time": start.elapsed().as_secs_f64(),
+            "output": output_path,
+        });
+        println!("{}", summary);
+    }
+}
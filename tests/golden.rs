@@ -0,0 +1,70 @@
+//! Renders the scenes in `tests/scenes` and compares them against the images in `tests/references`
+//!
+//! After an intended change to the renders, run with `UPDATE_REFERENCES=1` to overwrite the
+//! references, and check the new images before committing them.
+
+use crusty::image_diff::{self, Image, Tolerance};
+use crusty::raytracer::Raytracer;
+use std::env;
+use std::fs;
+
+/// Renders are seeded so they should match exactly, this leaves room for floating point differences
+/// between platforms
+const TOLERANCE: Tolerance = Tolerance {
+    rmse: 0.01,
+    noticeable: 0.01,
+};
+
+fn check(name: &str) {
+    let scene_path = format!("tests/scenes/{}.json", name);
+    let reference_path = format!("tests/references/{}.png", name);
+
+    let scene = fs::File::open(&scene_path).unwrap();
+    let raytracer = Raytracer::new(scene).unwrap();
+    raytracer.start(4).join().unwrap();
+    let image = Image::from_output(raytracer.output());
+
+    if env::var_os("UPDATE_REFERENCES").is_some() {
+        image.save(&reference_path).unwrap();
+        return;
+    }
+
+    let reference = Image::load(&reference_path).unwrap();
+    let diff = image_diff::compare(&image, &reference).unwrap();
+    if !diff.within(&TOLERANCE) {
+        let failed_path = format!("{}/{}.png", env!("CARGO_TARGET_TMPDIR"), name);
+        image.save(&failed_path).unwrap();
+        panic!(
+            "{} differs from its reference (RMSE {:.4}, {:.2}% noticeably different pixels), render saved to {}",
+            name,
+            diff.rmse,
+            diff.noticeable * 100.0,
+            failed_path,
+        );
+    }
+}
+
+#[test]
+fn primitives() {
+    check("primitives");
+}
+
+#[test]
+fn mesh() {
+    check("mesh");
+}
+
+#[test]
+fn lights() {
+    check("lights");
+}
+
+#[test]
+fn materials() {
+    check("materials");
+}
+
+#[test]
+fn graph() {
+    check("graph");
+}